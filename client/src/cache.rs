@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use file_yeet_shared::FileHash;
+use tokio::sync::Mutex;
+
+/// The name of the file recording the state of cached files and the copies made from them.
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Keeps the index from being read and written by several tasks at once.
+static INDEX_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// The hash and state of a file when it was added to or placed from the cache.
+/// A file whose size or modification time differ from its entry has changed and its hash is stale.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct IndexEntry {
+    hash_hex: String,
+    file_size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// A content-addressed directory of downloaded files, named by their hash.
+/// Cached files are copied to the destinations users ask for, so that changing a copy never changes the cache.
+pub struct ContentCache {
+    directory: PathBuf,
+}
+impl ContentCache {
+    /// Use the given directory as the cache, creating it if needed.
+    pub async fn new(directory: PathBuf) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&directory).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to create the cache directory {}: {e}",
+                directory.display()
+            )
+        })?;
+        Ok(Self { directory })
+    }
+
+    /// The path a file with the given hash is cached at.
+    pub fn path_for(&self, hash: &FileHash) -> PathBuf {
+        self.directory.join(hash.to_string())
+    }
+
+    /// Get the size of the cached file for a hash, if it is cached and unchanged.
+    pub async fn lookup(&self, hash: &FileHash) -> Option<u64> {
+        let path = self.path_for(hash);
+        let index = self.read_index().await;
+        let entry = index.get(&index_key(&path))?;
+        if entry.hash_hex != hash.to_string() || !entry.matches(&path).await {
+            return None;
+        }
+        Some(entry.file_size)
+    }
+
+    /// Add a verified download at the cache path for its hash to the cache.
+    pub async fn insert(&self, hash: &FileHash) -> anyhow::Result<()> {
+        self.record(&self.path_for(hash), hash).await
+    }
+
+    /// Place a copy of the cached file for a hash at the destination.
+    /// The copy is a reflink on file systems that support them, so it shares the cached file's blocks until either changes.
+    pub async fn place(&self, hash: &FileHash, destination: &Path) -> anyhow::Result<()> {
+        let cached = self.path_for(hash);
+
+        // Replace any existing file, like a download to the destination would.
+        // Removing it first keeps the copy from writing through a link to another file.
+        if tokio::fs::metadata(destination).await.is_ok() {
+            tokio::fs::remove_file(destination).await?;
+        }
+        tokio::fs::copy(&cached, destination).await?;
+        self.record(destination, hash).await
+    }
+
+    /// Get the size and hash of a file that came from the cache, if it is unchanged since.
+    /// Lets downloaded files be published again without hashing them.
+    pub async fn known_hash(&self, path: &Path) -> Option<(u64, FileHash)> {
+        let index = self.read_index().await;
+        let entry = index.get(&index_key(path))?;
+        if !entry.matches(path).await {
+            return None;
+        }
+        Some((entry.file_size, entry.hash_hex.parse().ok()?))
+    }
+
+    /// Record the current state of a file with a known hash in the index.
+    async fn record(&self, path: &Path, hash: &FileHash) -> anyhow::Result<()> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let _lock = INDEX_LOCK.lock().await;
+        let mut index = self.read_index().await;
+        index.insert(
+            index_key(path),
+            IndexEntry {
+                hash_hex: hash.to_string(),
+                file_size: metadata.len(),
+                modified: metadata.modified().ok(),
+            },
+        );
+
+        // Write the whole index before replacing the old one, so that readers never see a partial index.
+        // The temporary file is named for this process so that other clients sharing the cache don't write to it.
+        let index_path = self.directory.join(INDEX_FILE_NAME);
+        let temporary_path = index_path.with_extension(format!("json.{}.tmp", std::process::id()));
+        tokio::fs::write(&temporary_path, serde_json::to_vec_pretty(&index)?).await?;
+        tokio::fs::rename(temporary_path, index_path).await?;
+        Ok(())
+    }
+
+    /// Read the index, treating a missing or unreadable index as empty.
+    async fn read_index(&self) -> HashMap<String, IndexEntry> {
+        tokio::fs::read(self.directory.join(INDEX_FILE_NAME))
+            .await
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+}
+impl IndexEntry {
+    /// Whether the file at the path still has the recorded size and modification time.
+    async fn matches(&self, path: &Path) -> bool {
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => {
+                metadata.is_file()
+                    && metadata.len() == self.file_size
+                    && metadata.modified().ok() == self.modified
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// The key of a path in the index. Paths are made absolute so the same file always has the same key.
+fn index_key(path: &Path) -> String {
+    std::fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
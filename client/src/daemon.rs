@@ -0,0 +1,720 @@
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use file_yeet_shared::{
+    local_now_fmt, FileHash, HashAlgorithm, GOODBYE_CODE, GOODBYE_MESSAGE, SERVER_MESSAGE_BUFFERS,
+};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _},
+    sync::watch,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{cache, core, hooks, Cli, DownloadOptions, PublishEnd, PublishOptions, PublishTarget};
+
+/// The first wait before reconnecting to the server, doubled after each failed attempt.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The longest wait between attempts to reconnect to the server.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The file name of the control socket when no path is given.
+#[cfg(unix)]
+const CONTROL_SOCKET_NAME: &str = "file_yeet.sock";
+
+/// The named pipe to listen on when no path is given.
+#[cfg(windows)]
+const CONTROL_PIPE_NAME: &str = r"\\.\pipe\file_yeet";
+
+/// A request sent to the daemon's control socket as a single line of JSON.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Hash a file and keep it published until asked to stop.
+    Publish {
+        path: PathBuf,
+        #[serde(default)]
+        hash_algorithm: HashAlgorithm,
+
+        /// Only upload to peers that prove they know this passphrase.
+        #[serde(default)]
+        passphrase: Option<String>,
+
+        /// Stop publishing once this many peers have downloaded the whole file.
+        #[serde(default)]
+        max_downloads: Option<NonZeroUsize>,
+
+        /// Hash the file again even if it is unchanged since it was last hashed.
+        #[serde(default)]
+        rehash: bool,
+    },
+
+    /// Stop publishing a file and cancel its uploads.
+    Unpublish { hash: String },
+
+    /// List the files being published.
+    List,
+
+    /// Download a file by its hash or share link in the background.
+    Download {
+        hash: String,
+        output: Option<PathBuf>,
+
+        /// The passphrase the publisher requires, if any.
+        #[serde(default)]
+        passphrase: Option<String>,
+
+        /// Publish the file once it has been downloaded and verified.
+        #[serde(default)]
+        seed: bool,
+    },
+
+    /// Report the server connection and the state of every download.
+    Status,
+}
+
+/// The daemon's reply to a control request, written as a single line of JSON.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Published { hash: String, file_size: u64 },
+    Unpublished,
+    Publishes { publishes: Vec<PublishStatus> },
+    DownloadStarted { id: u64 },
+    Status(DaemonStatus),
+    Error { message: String },
+}
+
+/// A file the daemon is publishing.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PublishStatus {
+    pub hash: String,
+    pub path: PathBuf,
+    pub file_size: u64,
+
+    /// The number of complete downloads left before the publish ends, if limited.
+    pub remaining_downloads: Option<usize>,
+}
+
+/// The progress of a download started through the daemon.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadState {
+    Downloading,
+    Done,
+    Failed(String),
+}
+
+/// A download started through the daemon.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct DownloadStatus {
+    pub id: u64,
+    pub hash: String,
+    pub output: Option<PathBuf>,
+    pub state: DownloadState,
+}
+
+/// The state of the daemon as reported by the `status` request.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct DaemonStatus {
+    pub connected: bool,
+    pub external_address: Option<String>,
+    pub publishes: usize,
+    pub downloads: Vec<DownloadStatus>,
+}
+
+/// A file the daemon publishes, restarted on every new server connection.
+struct DaemonPublish {
+    target: Arc<PublishTarget>,
+
+    /// The passphrase peers must prove they know, if any.
+    passphrase: Option<Arc<str>>,
+
+    /// Cancels the publish on the current server connection.
+    cancellation_token: CancellationToken,
+}
+
+/// The state shared between the server connection and the control socket.
+#[derive(Default)]
+struct DaemonState {
+    connection: Option<Arc<core::PreparedConnection>>,
+
+    /// Our external address as the current server sees it, watched by the publishes to follow its changes.
+    address_changes: Option<watch::Receiver<String>>,
+
+    /// Cancelled when the current server connection is lost, stopping everything running on it.
+    session_token: CancellationToken,
+
+    publishes: Vec<DaemonPublish>,
+    downloads: Vec<DownloadStatus>,
+    next_download_id: u64,
+}
+
+/// A headless client that keeps publishes alive and accepts requests over a local control socket.
+struct Daemon {
+    state: Mutex<DaemonState>,
+    max_download_size: Option<u64>,
+    relay: bool,
+    room: String,
+    trusted_publishers: Vec<String>,
+    cache: Option<cache::ContentCache>,
+    event_hooks: hooks::EventHooks,
+}
+
+/// The control socket to use when none is given, in the user's runtime directory if there is one.
+pub fn default_control_socket() -> PathBuf {
+    #[cfg(unix)]
+    {
+        dirs::runtime_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join(CONTROL_SOCKET_NAME)
+    }
+    #[cfg(windows)]
+    {
+        PathBuf::from(CONTROL_PIPE_NAME)
+    }
+}
+
+/// Run the daemon until Ctrl-C is pressed, reconnecting to the server whenever the connection is lost.
+pub async fn run(
+    args: &Cli,
+    control_socket: PathBuf,
+    cache: Option<cache::ContentCache>,
+    event_hooks: hooks::EventHooks,
+) -> anyhow::Result<()> {
+    let daemon = Arc::new(Daemon {
+        state: Mutex::default(),
+        max_download_size: args.max_download_size,
+        relay: args.relay,
+        room: args.room.clone(),
+        trusted_publishers: args.trusted_publishers.clone(),
+        cache,
+        event_hooks,
+    });
+
+    // Accept control requests for as long as the daemon runs.
+    let listener = bind_control_socket(&control_socket).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to listen on the control socket {}: {e}",
+            control_socket.display()
+        )
+    })?;
+    let listener = {
+        let daemon = daemon.clone();
+        let control_socket = control_socket.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = serve_control_socket(&control_socket, listener, daemon).await {
+                eprintln!("{} Control socket failed: {e}", local_now_fmt());
+            }
+        })
+    };
+    println!(
+        "{} Listening for control requests on {}",
+        local_now_fmt(),
+        control_socket.display()
+    );
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            println!("{} Ctrl-C detected, stopping the daemon", local_now_fmt());
+        }
+        () = daemon.clone().keep_connected(args) => {}
+    }
+    listener.abort();
+    #[cfg(unix)]
+    {
+        if let Err(e) = std::fs::remove_file(&control_socket) {
+            eprintln!(
+                "{} Failed to remove the control socket: {e}",
+                local_now_fmt()
+            );
+        }
+    }
+
+    // Stop every publish and transfer, then leave the server politely.
+    let connection = {
+        let mut state = daemon.lock();
+        state.session_token.cancel();
+        state.connection.take()
+    };
+    if let Some(connection) = connection {
+        connection
+            .endpoint
+            .close(GOODBYE_CODE, GOODBYE_MESSAGE.as_bytes());
+    }
+    Ok(())
+}
+
+impl Daemon {
+    /// Lock the shared state, even if a task panicked while holding it.
+    fn lock(&self) -> std::sync::MutexGuard<'_, DaemonState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Connect to the server, restore the publishes, and wait for the connection to be lost before trying again.
+    async fn keep_connected(self: Arc<Self>, args: &Cli) {
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        loop {
+            let mut bb = SERVER_MESSAGE_BUFFERS.take();
+            let connection = match args.connect(&mut bb).await {
+                Ok(connection) => Arc::new(connection),
+                Err(e) => {
+                    eprintln!(
+                        "{} Failed to connect to the server, retrying in {}s: {e}",
+                        local_now_fmt(),
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = RECONNECT_INITIAL_BACKOFF;
+
+            // Let subscribers that can't reach us directly ask for a relay instead.
+            if self.relay {
+                if let Err(e) = core::relay_opt_in(&connection.server_connection).await {
+                    eprintln!("{} Failed to enable relays: {e}", local_now_fmt());
+                }
+            }
+
+            // Publish everything again on the new connection.
+            {
+                let mut state = self.lock();
+                state.session_token = CancellationToken::new();
+                state.connection = Some(connection.clone());
+                let session_token = state.session_token.clone();
+
+                // Tell the publishes when our external address changes, so they can bring themselves up to date.
+                let (external_address, address_changes) =
+                    watch::channel(connection.external_address.clone());
+                state.address_changes = Some(address_changes.clone());
+                tokio::task::spawn({
+                    let connection = connection.clone();
+                    let session_token = session_token.clone();
+                    async move {
+                        tokio::select! {
+                            () = session_token.cancelled() => {}
+                            () = core::watch_external_address(
+                                &connection.server_connection,
+                                &external_address,
+                                connection.port_override,
+                            ) => {}
+                        }
+                    }
+                });
+
+                for publish in &mut state.publishes {
+                    publish.cancellation_token = session_token.child_token();
+                    self.spawn_publish(
+                        &connection,
+                        &address_changes,
+                        publish.target.clone(),
+                        publish.passphrase.clone(),
+                        publish.cancellation_token.clone(),
+                    );
+                }
+            }
+
+            let e = connection.server_connection.closed().await;
+            eprintln!(
+                "{} Lost the server connection, reconnecting: {e}",
+                local_now_fmt()
+            );
+            {
+                let mut state = self.lock();
+                state.session_token.cancel();
+                state.connection = None;
+                state.address_changes = None;
+            }
+        }
+    }
+
+    /// Publish a file on the server connection until the connection closes or the publish is cancelled.
+    /// Publishes that reach their download limit are forgotten, so they aren't published again after a reconnect.
+    fn spawn_publish(
+        self: &Arc<Self>,
+        connection: &Arc<core::PreparedConnection>,
+        address_changes: &watch::Receiver<String>,
+        target: Arc<PublishTarget>,
+        passphrase: Option<Arc<str>>,
+        cancellation_token: CancellationToken,
+    ) {
+        let daemon = self.clone();
+        let connection = connection.clone();
+        let address_changes = address_changes.clone();
+        let room = self.room.clone();
+        let event_hooks = self.event_hooks.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                () = cancellation_token.cancelled() => {}
+                r = crate::publish_loop(
+                    &connection,
+                    &target,
+                    PublishOptions {
+                        room: &room,
+                        upload_log: None,
+                        signing_key: None,
+                        passphrase: passphrase.as_deref(),
+                        lan: None,
+
+                        // The download limit is counted on the target, so it lasts across reconnects.
+                        max_downloads: None,
+                    },
+                    &event_hooks,
+                    cancellation_token.clone(),
+                    CancellationToken::new(),
+                    address_changes,
+                ) => match r {
+                    Ok(PublishEnd::Downloaded) => daemon
+                        .lock()
+                        .publishes
+                        .retain(|p| !Arc::ptr_eq(&p.target, &target)),
+                    Ok(PublishEnd::ServerClosed) => {}
+                    Err(e) => eprintln!(
+                        "{} Failed to publish {}: {e}",
+                        local_now_fmt(),
+                        target.path.display()
+                    ),
+                }
+            }
+        });
+    }
+
+    /// Carry out a control request.
+    async fn handle(self: &Arc<Self>, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Publish {
+                path,
+                hash_algorithm,
+                passphrase,
+                max_downloads,
+                rehash,
+            } => {
+                self.publish(path, hash_algorithm, passphrase, max_downloads, rehash)
+                    .await
+            }
+            ControlRequest::Unpublish { hash } => self.unpublish(&hash),
+            ControlRequest::List => ControlResponse::Publishes {
+                publishes: self
+                    .lock()
+                    .publishes
+                    .iter()
+                    .map(|p| PublishStatus {
+                        hash: p.target.hash.to_string(),
+                        path: p.target.path.clone(),
+                        file_size: p.target.file_size,
+                        remaining_downloads: p
+                            .target
+                            .remaining_downloads
+                            .as_ref()
+                            .map(|n| n.load(Ordering::SeqCst)),
+                    })
+                    .collect(),
+            },
+            ControlRequest::Download {
+                hash,
+                output,
+                passphrase,
+                seed,
+            } => self.download(&hash, output, passphrase, seed),
+            ControlRequest::Status => {
+                let state = self.lock();
+                ControlResponse::Status(DaemonStatus {
+                    connected: state.connection.is_some(),
+                    external_address: state.address_changes.as_ref().map(|a| a.borrow().clone()),
+                    publishes: state.publishes.len(),
+                    downloads: state.downloads.clone(),
+                })
+            }
+        }
+    }
+
+    /// Hash a file, unless its hash is cached, and publish it now, if connected, and after every reconnect.
+    async fn publish(
+        self: &Arc<Self>,
+        path: PathBuf,
+        hash_algorithm: HashAlgorithm,
+        passphrase: Option<String>,
+        max_downloads: Option<NonZeroUsize>,
+        rehash: bool,
+    ) -> ControlResponse {
+        if !path.is_file() {
+            return ControlResponse::Error {
+                message: format!("{} is not a file", path.display()),
+            };
+        }
+        let (file_size, hash, chunk_hashes) =
+            match core::cached_file_size_hash_and_chunks(&path, hash_algorithm, None, rehash).await
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    return ControlResponse::Error {
+                        message: format!("Failed to hash file: {e}"),
+                    }
+                }
+            };
+        let target = PublishTarget {
+            path,
+            file_size,
+            hash: FileHash::new(hash_algorithm, hash),
+            chunk_hashes: Arc::new(chunk_hashes),
+            remaining_downloads: max_downloads.map(|n| Arc::new(AtomicUsize::new(n.get()))),
+        };
+        self.add_publish(target, passphrase.map(Arc::from))
+    }
+
+    /// Publish a hashed file now, if connected, and after every reconnect.
+    fn add_publish(
+        self: &Arc<Self>,
+        target: PublishTarget,
+        passphrase: Option<Arc<str>>,
+    ) -> ControlResponse {
+        let mut state = self.lock();
+        let (hash, file_size) = (target.hash, target.file_size);
+        if state.publishes.iter().any(|p| p.target.hash == hash) {
+            return ControlResponse::Error {
+                message: format!("{hash} is already published"),
+            };
+        }
+        println!(
+            "{} Publishing {} with hash {hash}",
+            local_now_fmt(),
+            target.path.display()
+        );
+        let target = Arc::new(target);
+        let cancellation_token = state.session_token.child_token();
+        if let (Some(connection), Some(address_changes)) =
+            (&state.connection, &state.address_changes)
+        {
+            self.spawn_publish(
+                connection,
+                address_changes,
+                target.clone(),
+                passphrase.clone(),
+                cancellation_token.clone(),
+            );
+        }
+        state.publishes.push(DaemonPublish {
+            target,
+            passphrase,
+            cancellation_token,
+        });
+        ControlResponse::Published {
+            hash: hash.to_string(),
+            file_size,
+        }
+    }
+
+    /// Stop publishing a file and cancel its uploads.
+    fn unpublish(&self, hash: &str) -> ControlResponse {
+        let hash = match hash.parse::<FileHash>() {
+            Ok(hash) => hash,
+            Err(e) => {
+                return ControlResponse::Error {
+                    message: format!("Invalid hash: {e}"),
+                }
+            }
+        };
+        let mut state = self.lock();
+        let Some(i) = state.publishes.iter().position(|p| p.target.hash == hash) else {
+            return ControlResponse::Error {
+                message: format!("{hash} is not published"),
+            };
+        };
+        state.publishes.remove(i).cancellation_token.cancel();
+        ControlResponse::Unpublished
+    }
+
+    /// Start downloading a file in the background. Share links are accepted, though the daemon's server is used.
+    fn download(
+        self: &Arc<Self>,
+        hash: &str,
+        output: Option<PathBuf>,
+        passphrase: Option<String>,
+        seed: bool,
+    ) -> ControlResponse {
+        let (hash, output) = if hash.contains("://") {
+            match file_yeet_shared::parse_share_uri(hash) {
+                Ok(uri) => {
+                    let output = output.or_else(|| {
+                        uri.extension.map(|extension| {
+                            std::env::temp_dir().join(format!("{}.{extension}", uri.hash))
+                        })
+                    });
+                    (uri.hash, output)
+                }
+                Err(e) => {
+                    return ControlResponse::Error {
+                        message: format!("Invalid share link: {e}"),
+                    }
+                }
+            }
+        } else {
+            match hash.parse::<FileHash>() {
+                Ok(hash) => (hash, output),
+                Err(e) => {
+                    return ControlResponse::Error {
+                        message: format!("Invalid hash: {e}"),
+                    }
+                }
+            }
+        };
+
+        let mut state = self.lock();
+        let Some(connection) = state.connection.clone() else {
+            return ControlResponse::Error {
+                message: "Not connected to the server".to_owned(),
+            };
+        };
+        let id = state.next_download_id;
+        state.next_download_id += 1;
+        state.downloads.push(DownloadStatus {
+            id,
+            hash: hash.to_string(),
+            output: output.clone(),
+            state: DownloadState::Downloading,
+        });
+        let session_token = state.session_token.clone();
+
+        let daemon = self.clone();
+        tokio::task::spawn(async move {
+            let options = DownloadOptions {
+                max_download_size: daemon.max_download_size,
+                relay: daemon.relay,
+                ask_consent: false,
+                wait: None,
+                max_peers: None,
+                room: &daemon.room,
+                trusted_publishers: &daemon.trusted_publishers,
+                passphrase: passphrase.as_deref(),
+                resume: false,
+                streams: NonZeroUsize::MIN,
+                lan: None,
+                dht: None,
+            };
+            let result = tokio::select! {
+                () = session_token.cancelled() => Err(anyhow::anyhow!("The server connection was lost")),
+                r = crate::subscribe_command(
+                    &connection.endpoint,
+                    std::slice::from_ref(&connection.server_connection),
+                    SERVER_MESSAGE_BUFFERS.take(),
+                    hash.to_string(),
+                    output.map(|p| p.to_string_lossy().into_owned()),
+                    options,
+                    daemon.cache.as_ref(),
+                    &daemon.event_hooks,
+                ) => r,
+            };
+
+            // Become another source for the file now that we have all of it.
+            let download_state = match result {
+                Ok(target) => {
+                    if seed {
+                        if let ControlResponse::Error { message } = daemon.add_publish(target, None)
+                        {
+                            eprintln!("{} Failed to seed the download: {message}", local_now_fmt());
+                        }
+                    }
+                    DownloadState::Done
+                }
+                Err(e) => DownloadState::Failed(e.to_string()),
+            };
+
+            let mut state = daemon.lock();
+            if let Some(download) = state.downloads.iter_mut().find(|d| d.id == id) {
+                download.state = download_state;
+            }
+        });
+        ControlResponse::DownloadStarted { id }
+    }
+}
+
+/// Answer each line of JSON read from a control connection with a line of JSON.
+async fn handle_control_connection<S>(stream: S, daemon: Arc<Daemon>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => daemon.handle(request).await,
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid request: {e}"),
+            },
+        };
+        let Ok(mut json) = serde_json::to_string(&response) else {
+            break;
+        };
+        json.push('\n');
+        if writer.write_all(json.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Listen on a Unix socket that only the current user can access.
+#[cfg(unix)]
+fn bind_control_socket(path: &Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    // Replace the socket left behind by a daemon that did not exit cleanly.
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accept control connections on the Unix socket.
+#[cfg(unix)]
+async fn serve_control_socket(
+    _path: &Path,
+    listener: tokio::net::UnixListener,
+    daemon: Arc<Daemon>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::task::spawn(handle_control_connection(stream, daemon.clone()));
+    }
+}
+
+/// Create the first instance of the named pipe, failing if another daemon already owns it.
+#[cfg(windows)]
+fn bind_control_socket(
+    path: &Path,
+) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)
+}
+
+/// Accept control connections on the named pipe, creating a new instance for each client.
+#[cfg(windows)]
+async fn serve_control_socket(
+    path: &Path,
+    mut server: tokio::net::windows::named_pipe::NamedPipeServer,
+    daemon: Arc<Daemon>,
+) -> std::io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    loop {
+        server.connect().await?;
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(path)?);
+        tokio::task::spawn(handle_control_connection(connected, daemon.clone()));
+    }
+}
@@ -1,29 +1,44 @@
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
-    num::NonZeroU16,
+    num::{NonZeroU16, NonZeroU64, NonZeroUsize},
     ops::Div as _,
-    path::{Path, PathBuf},
-    sync::{Arc, RwLock},
+    path::PathBuf,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use file_yeet_shared::{
-    local_now_fmt, BiStream, HashBytes, DEFAULT_PORT, GOODBYE_CODE, GOODBYE_MESSAGE,
-    MAX_SERVER_COMMUNICATION_SIZE,
+    local_now_fmt, BiStream, CongestionController, FileHash, HashAlgorithm, HashBytes, PeerAddr,
+    PublishControl, ServerCapabilities, DEFAULT_PORT, GOODBYE_CODE, GOODBYE_MESSAGE,
 };
 use futures_util::SinkExt;
 use iced::{
     widget::{self, horizontal_space},
     window, Element,
 };
-use tokio::io::AsyncWriteExt as _;
+use tokio::{io::AsyncWriteExt as _, sync::watch};
 use tokio_util::sync::CancellationToken;
 
 use crate::core::{
-    humanize_bytes, FileYeetCommandType, PortMappingConfig, PreparedConnection,
-    MAX_PEER_COMMUNICATION_SIZE, PEER_CONNECT_TIMEOUT, SERVER_CONNECTION_TIMEOUT,
+    humanize_bytes, FileMetadata, FileYeetCommandType, NetworkRoute, NETWORK_POLL_INTERVAL,
+    SERVER_CONNECTION_TIMEOUT, SOCKET_PING_INTERVAL,
 };
+use crate::hooks::{EventHooks, HookContext, TransferEvent};
+use crate::identity::{short_fingerprint, KnownPeers, PublisherKey};
+use crate::throttle::BandwidthLimits;
+
+mod connection;
+mod download;
+mod publish;
+mod settings;
+mod transfer;
+
+use connection::{ConnectionController, ConnectionMessage};
+use download::{DownloadController, DownloadMessage};
+use publish::{PublishController, PublishMessage};
+
+pub use settings::{export_settings_profile, import_settings_profile};
 
 /// Lazyily initialized regex for parsing server addresses.
 /// Produces match groups `host` and `port` for the server address and optional port.
@@ -32,9 +47,35 @@ static SERVER_ADDRESS_REGEX: once_cell::sync::Lazy<regex::Regex> =
         regex::Regex::new(r"^\s*(?P<host>([^:]|::)+)(?::(?P<port>\d+))?\s*$").unwrap()
     });
 
+/// Parse a server address with an optional port, using the default port if none is given.
+pub(crate) fn parse_server_address(address: &str) -> Option<(String, NonZeroU16)> {
+    let captures = SERVER_ADDRESS_REGEX.captures(address)?;
+    let host = captures.name("host").unwrap().as_str();
+
+    // If there is no port, use the default port. Otherwise, the input must be valid.
+    let port = captures.name("port").map_or(Some(DEFAULT_PORT), |p| {
+        p.as_str().parse::<NonZeroU16>().ok()
+    })?;
+    Some((host.to_owned(), port))
+}
+
+/// Split a list of server addresses separated by commas or spaces.
+fn split_server_list(text: &str) -> Vec<&str> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// The maximum number of status messages to keep in the status log.
+const MAX_STATUS_LOG_LEN: usize = 512;
+
 /// The maximum time to wait before forcing the application to exit.
 const MAX_SHUTDOWN_WAIT: Duration = Duration::from_secs(3);
 
+/// The minimum time between progress updates for a single transfer.
+/// Changes made in between are coalesced into the latest value.
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
 /// The red used to display errors to the user.
 const ERROR_RED_COLOR: iced::Color = iced::Color::from_rgb(1., 0.4, 0.5);
 
@@ -42,7 +83,7 @@ const ERROR_RED_COLOR: iced::Color = iced::Color::from_rgb(1., 0.4, 0.5);
 const PORT_MAPPING_OPTION_LABELS: [&str; 3] = ["None", "Port forward", "NAT-PMP / PCP"];
 
 /// The state of the port mapping options in the GUI.
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
 enum PortMappingGuiOptions {
     #[default]
     None,
@@ -85,7 +126,35 @@ pub enum TransferResult {
     Failure(Arc<anyhow::Error>),
 
     /// The transfer was cancelled.
-    Cancelled,
+    UserCancelled,
+
+    /// The transfer was stopped when leaving the server.
+    Shutdown,
+
+    /// The peer closed the connection.
+    PeerClosed,
+}
+impl TransferResult {
+    /// The result of a transfer whose cancellation token was triggered.
+    fn cancelled(shutdown_token: &CancellationToken) -> Self {
+        if shutdown_token.is_cancelled() {
+            Self::Shutdown
+        } else {
+            Self::UserCancelled
+        }
+    }
+
+    /// The result of a transfer that returned an error, distinguishing a peer that closed the connection.
+    fn from_error(e: anyhow::Error, connection: &quinn::Connection) -> Self {
+        match connection.close_reason() {
+            Some(
+                quinn::ConnectionError::ApplicationClosed(_)
+                | quinn::ConnectionError::ConnectionClosed(_)
+                | quinn::ConnectionError::Reset,
+            ) => Self::PeerClosed,
+            _ => Self::Failure(Arc::new(e)),
+        }
+    }
 }
 
 /// The state of a file transfer with a peer.
@@ -93,7 +162,7 @@ pub enum TransferResult {
 enum TransferProgress {
     Connecting,
     Consent(PeerConnection),
-    Transferring(PeerConnection, Arc<RwLock<f32>>, f32),
+    Transferring(PeerConnection, watch::Receiver<f32>, f32),
     Done(TransferResult),
 }
 
@@ -101,13 +170,22 @@ enum TransferProgress {
 #[derive(Debug)]
 struct Transfer {
     pub nonce: Nonce,
-    pub hash: HashBytes,
+    pub hash: FileHash,
     pub hash_hex: String,
     pub file_size: u64,
     pub peer_string: String,
+
+    /// The fingerprint of the certificate the peer identified itself with, once connected.
+    pub peer_fingerprint: Option<String>,
+
+    /// The fingerprint of the key the publisher signed the file with, if the signature is valid.
+    pub publisher_fingerprint: Option<String>,
     pub path: PathBuf,
     pub progress: TransferProgress,
     pub cancellation_token: CancellationToken,
+
+    /// The passphrase a download proves to its publisher, kept to resume the download from other publishers.
+    pub passphrase: Option<Arc<str>>,
 }
 
 #[derive(Clone, Debug)]
@@ -117,24 +195,47 @@ pub enum PublishRequestResult {
     Cancelled,
 }
 
-/// A file actively being published to the server.
+/// A file actively being published to the servers.
 #[derive(Clone, Debug)]
 struct Publish {
-    pub server_streams: Arc<tokio::sync::Mutex<BiStream>>,
-    pub hash: HashBytes,
+    /// The publish session with each server that accepted the publish.
+    pub server_streams: Vec<PublishStream>,
+    pub hash: FileHash,
     pub hash_hex: String,
     pub file_size: u64,
+
+    /// The hash of each chunk of the file, shared with peers that ask for them.
+    pub chunk_hashes: Arc<Vec<HashBytes>>,
+
+    /// The room and hints the file was published with, to publish it again after our external address changes.
+    pub room: Arc<str>,
+    pub metadata: Arc<FileMetadata>,
+}
+
+/// The publish session with one server, and the connection to publish again on after our external address changes.
+#[derive(Clone, Debug)]
+pub struct PublishStream {
+    pub server: quinn::Connection,
+    pub streams: Arc<tokio::sync::Mutex<BiStream>>,
 }
 
 /// The state of a file publish request.
 #[derive(Clone, Debug)]
 enum PublishState {
-    Hashing(Arc<RwLock<f32>>),
+    Hashing(watch::Receiver<f32>, f32),
     Publishing(Publish),
     Failure(Arc<anyhow::Error>),
     Cancelled,
 }
 
+/// Statistics about the uploads made for a publish.
+#[derive(Clone, Copy, Debug, Default)]
+struct UploadStatistics {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub bytes_sent: u64,
+}
+
 /// An item in the list of publishing requests.
 #[derive(Clone, Debug)]
 struct PublishItem {
@@ -142,6 +243,19 @@ struct PublishItem {
     pub path: PathBuf,
     pub cancellation_token: CancellationToken,
     pub state: PublishState,
+    pub upload_statistics: UploadStatistics,
+
+    /// The uploads started for peers introduced to this publish.
+    pub upload_nonces: HashSet<Nonce>,
+
+    /// The number of active uploads the user is being asked about while cancelling this publish, if any.
+    pub confirming_cancel: Option<usize>,
+
+    /// The passphrase peers must prove they know before we upload to them, if any.
+    pub passphrase: Option<Arc<str>>,
+
+    /// The most successful uploads to make before the publish is cancelled, if limited.
+    pub max_downloads: Option<NonZeroUsize>,
 }
 impl PublishItem {
     /// Make a new publish item in the hashing state.
@@ -149,64 +263,119 @@ impl PublishItem {
         nonce: Nonce,
         path: PathBuf,
         cancellation_token: CancellationToken,
-        hash_progress: Arc<RwLock<f32>>,
+        hash_progress: watch::Receiver<f32>,
+        passphrase: Option<Arc<str>>,
+        max_downloads: Option<NonZeroUsize>,
     ) -> Self {
         Self {
             nonce,
             path,
             cancellation_token,
-            state: PublishState::Hashing(hash_progress),
+            state: PublishState::Hashing(hash_progress, 0.),
+            upload_statistics: UploadStatistics::default(),
+            upload_nonces: HashSet::new(),
+            confirming_cancel: None,
+            passphrase,
+            max_downloads,
         }
     }
 
+    /// The number of successful uploads left before the publish is cancelled, if limited.
+    pub fn remaining_downloads(&self) -> Option<usize> {
+        self.max_downloads
+            .map(|max| max.get().saturating_sub(self.upload_statistics.succeeded))
+    }
+
     /// Upgrade a hashing state to publishing.
-    pub fn upgrade_hashing(
-        &mut self,
-        server_streams: Arc<tokio::sync::Mutex<BiStream>>,
-        hash: HashBytes,
-        file_size: u64,
-    ) {
+    pub fn upgrade_hashing(&mut self, session: IncomingPublishSession) {
+        let IncomingPublishSession {
+            server_streams,
+            hash,
+            file_size,
+            chunk_hashes,
+            room,
+            metadata,
+        } = session;
         self.state = PublishState::Publishing(Publish {
             server_streams,
             hash,
-            hash_hex: faster_hex::hex_string(&hash),
+            hash_hex: hash.to_string(),
             file_size,
+            chunk_hashes,
+            room,
+            metadata,
         });
     }
 }
 
+/// Where to save a download.
+#[derive(Clone, Debug)]
+pub enum DownloadPath {
+    /// A path that was already chosen, such as when resuming a download.
+    Chosen(PathBuf),
+
+    /// Choose a path once the publishers are known, suggesting the file name they gave or else this one.
+    Choose { fallback_name: String },
+}
+
 /// The result of a subscribe request.
 #[derive(Clone, Debug)]
 pub struct IncomingSubscribePeers {
-    pub peers_with_size: Vec<(SocketAddr, u64)>,
-    pub path: PathBuf,
-    pub hash: HashBytes,
+    pub peers_with_size: Vec<(SocketAddr, u64, FileMetadata)>,
+    pub path: DownloadPath,
+    pub hash: FileHash,
+    pub passphrase: Option<Arc<str>>,
 }
 impl IncomingSubscribePeers {
     #[must_use]
-    pub fn new(peers_with_size: Vec<(SocketAddr, u64)>, path: PathBuf, hash: HashBytes) -> Self {
+    pub fn new(
+        peers_with_size: Vec<(SocketAddr, u64, FileMetadata)>,
+        path: DownloadPath,
+        hash: FileHash,
+        passphrase: Option<Arc<str>>,
+    ) -> Self {
         Self {
             peers_with_size,
             path,
             hash,
+            passphrase,
         }
     }
 }
 
-/// The result of a publish request. The bi-directional stream maintains the publish session.
+/// The result of a publish request. The bi-directional stream with each server maintains the publish session.
 #[derive(Clone, Debug)]
 pub struct IncomingPublishSession {
-    pub server_streams: Arc<tokio::sync::Mutex<BiStream>>,
-    pub hash: HashBytes,
+    pub server_streams: Vec<PublishStream>,
+    pub hash: FileHash,
     pub file_size: u64,
+    pub chunk_hashes: Arc<Vec<HashBytes>>,
+    pub room: Arc<str>,
+    pub metadata: Arc<FileMetadata>,
 }
 impl IncomingPublishSession {
     #[must_use]
-    pub fn new(server_streams: BiStream, hash: HashBytes, file_size: u64) -> Self {
+    pub fn new(
+        server_streams: Vec<(quinn::Connection, BiStream)>,
+        hash: FileHash,
+        file_size: u64,
+        chunk_hashes: Vec<HashBytes>,
+        room: &str,
+        metadata: FileMetadata,
+    ) -> Self {
         Self {
-            server_streams: Arc::new(tokio::sync::Mutex::new(server_streams)),
+            server_streams: server_streams
+                .into_iter()
+                .map(|(server, streams)| PublishStream {
+                    server,
+                    streams: Arc::new(tokio::sync::Mutex::new(streams)),
+                })
+                .collect(),
             hash,
             file_size,
+            chunk_hashes: Arc::new(chunk_hashes),
+            room: Arc::from(room),
+            metadata: Arc::new(metadata),
         }
     }
 }
@@ -237,41 +406,137 @@ struct ConnectedState {
     /// Connection with the server.
     server: quinn::Connection,
 
+    /// The address of the server we are connected to, which is a fallback server if the first couldn't be reached.
+    server_address: String,
+
     /// The external address of the client, as seen from the server.
     external_address: String,
 
-    /// The hash input field for creating new subscribe requests.
-    hash_input: String,
+    /// The port override the server was told to use, if any.
+    port_override: Option<NonZeroU16>,
 
-    /// Map of peer socket addresses to QUIC connections.
-    peers: HashMap<SocketAddr, (quinn::Connection, HashSet<Nonce>)>,
+    /// Tells each publish when our external address changes, with the port override to redo if we have one.
+    address_changes: watch::Sender<Option<NonZeroU16>>,
 
-    /// List of download requests to peers.
-    downloads: Vec<Transfer>,
+    /// Stream of notifications pushed to us by the server.
+    server_notifications: Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+
+    /// The other servers we are connected to over the same endpoint. Files are published to every server
+    /// and downloads ask all of them for publishers.
+    additional_servers: Vec<AdditionalServer>,
+
+    /// The optional features and policies the server advertised.
+    capabilities: ServerCapabilities,
+
+    /// The last known network route, used to detect changes such as a VPN going up or down.
+    /// Unknown until the first probe after connecting completes.
+    network_route: Option<NetworkRoute>,
+
+    /// The local UDP port our QUIC endpoint is bound to.
+    local_port: Option<u16>,
+
+    /// The number of failed attempts to acquire a port mapping in the background and when to try again, if retrying.
+    port_mapping_retry: Option<(u32, Instant)>,
+
+    /// The passphrase field for new publishes and downloads. Empty for none.
+    passphrase_input: String,
+
+    /// Map of peer socket addresses to QUIC connections.
+    peers: HashMap<PeerAddr, (quinn::Connection, HashSet<Nonce>)>,
 
     /// List of file uploads to peers.
     uploads: Vec<Transfer>,
 
-    /// List of file publish requests to the server.
-    publishes: Vec<PublishItem>,
-
     /// The transfer view being shown.
     transfer_view: TransferView,
+
+    /// Whether the user is being asked to confirm leaving the server.
+    confirming_leave: bool,
+
+    /// Whether to leave the server once the active transfers have finished.
+    leave_when_done: bool,
+
+    /// Cancelled when leaving the server. The parent of every transfer's cancellation token.
+    shutdown_token: CancellationToken,
 }
 impl ConnectedState {
-    fn new(endpoint: quinn::Endpoint, server: quinn::Connection, external_address: String) -> Self {
+    fn new(
+        endpoint: quinn::Endpoint,
+        server: quinn::Connection,
+        server_address: String,
+        external_address: String,
+        port_override: Option<NonZeroU16>,
+        server_notifications: Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+        additional_servers: Vec<AdditionalServer>,
+        capabilities: ServerCapabilities,
+    ) -> Self {
+        let local_port = endpoint.local_addr().ok().map(|a| a.port());
         Self {
             endpoint,
             server,
+            server_address,
             external_address,
-            hash_input: String::new(),
+            port_override,
+            address_changes: watch::channel(port_override).0,
+            server_notifications,
+            additional_servers,
+            capabilities,
+            network_route: None,
+            local_port,
+            port_mapping_retry: None,
+            passphrase_input: String::new(),
             peers: HashMap::new(),
-            downloads: Vec::new(),
             uploads: Vec::new(),
-            publishes: Vec::new(),
             transfer_view: TransferView::Publishes,
+            confirming_leave: false,
+            leave_when_done: false,
+            shutdown_token: CancellationToken::new(),
         }
     }
+
+    /// The connections to every server, starting with the first.
+    fn servers(&self) -> Vec<quinn::Connection> {
+        std::iter::once(&self.server)
+            .chain(self.additional_servers.iter().map(|s| &s.server))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A connection to a server used alongside the first, sharing its endpoint.
+#[derive(Debug)]
+struct AdditionalServer {
+    /// The address of the server, as the user entered it.
+    server_address: String,
+
+    /// Connection with the server.
+    server: quinn::Connection,
+
+    /// Stream of notifications pushed to us by the server.
+    server_notifications: Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+}
+
+/// The active work that would be interrupted by leaving a server.
+#[derive(Clone, Copy, Debug)]
+struct LeaveImpact {
+    publishes: usize,
+    uploads: usize,
+    downloads: usize,
+}
+impl LeaveImpact {
+    /// Whether leaving would interrupt nothing.
+    fn is_empty(self) -> bool {
+        self.publishes == 0 && self.uploads == 0 && self.downloads == 0
+    }
+}
+impl std::fmt::Display for LeaveImpact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Leaving will stop {} publishes (restored on the next connect), {} uploads, and {} downloads",
+            self.publishes, self.uploads, self.downloads,
+        )
+    }
 }
 
 /// The state of the connection to a `file_yeet` server.
@@ -284,6 +549,13 @@ enum ConnectionState {
     /// Connecting to the server or safely closing the application.
     Stalling { start: Instant, tick: Instant },
 
+    /// Waiting to retry a failed auto-connect attempt.
+    Retrying {
+        attempt: u32,
+        retry_at: Instant,
+        tick: Instant,
+    },
+
     /// A connection to the server is active.
     Connected(ConnectedState),
 }
@@ -298,34 +570,416 @@ impl ConnectionState {
     }
 }
 
+/// Settings for retrying the automatic connection attempt made on startup.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct AutoConnectRetry {
+    /// The maximum number of retries before giving up. Zero disables retrying.
+    pub max_retries: u32,
+
+    /// The wait before the first retry, doubled after each failed attempt.
+    pub initial_backoff_secs: u64,
+
+    /// The longest wait allowed between retries.
+    pub max_backoff_secs: u64,
+}
+impl Default for AutoConnectRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff_secs: 2,
+            max_backoff_secs: 60,
+        }
+    }
+}
+impl AutoConnectRetry {
+    /// Get the wait before the next retry, given the number of attempts that have already failed.
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_secs
+            .saturating_mul(1u64.checked_shl(failed_attempts).unwrap_or(u64::MAX));
+        Duration::from_secs(backoff.min(self.max_backoff_secs))
+    }
+}
+
+/// A rule choosing the directory to save downloads to based on the file hash.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct DownloadDirectoryRule {
+    /// The hexadecimal prefix of the file hashes this rule applies to.
+    pub hash_prefix: String,
+
+    /// The directory to save matching downloads to.
+    pub directory: String,
+}
+
+/// Settings for saving downloads without asking the user for a path each time.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct DownloadDirectorySettings {
+    /// Whether downloads are saved to a default directory instead of asking for a path.
+    pub use_default: bool,
+
+    /// The directory used when no rule matches. The system downloads directory is used if empty.
+    pub default_directory: String,
+
+    /// Rules checked in order, where the first match chooses the directory.
+    pub rules: Vec<DownloadDirectoryRule>,
+}
+impl DownloadDirectorySettings {
+    /// Get the path to save a download to, or `None` if the user should be asked.
+    pub fn resolve(&self, hash_hex: &str) -> Option<PathBuf> {
+        if !self.use_default {
+            return None;
+        }
+
+        let directory = self
+            .rules
+            .iter()
+            .find(|r| {
+                let prefix = r.hash_prefix.trim();
+                !prefix.is_empty()
+                    && hash_hex.len() >= prefix.len()
+                    && hash_hex[..prefix.len()].eq_ignore_ascii_case(prefix)
+            })
+            .map(|r| PathBuf::from(r.directory.trim()))
+            .or_else(|| {
+                let default_directory = self.default_directory.trim();
+                if default_directory.is_empty() {
+                    dirs::download_dir()
+                } else {
+                    Some(PathBuf::from(default_directory))
+                }
+            })?;
+        Some(directory.join(hash_hex))
+    }
+}
+
+/// The scale of the UI as a percentage, applied to every window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+struct UiScale(u16);
+impl UiScale {
+    /// The range of percentages the user may choose from.
+    const RANGE: std::ops::RangeInclusive<u16> = 75..=200;
+
+    /// The scale factor to apply to the GUI.
+    fn factor(self) -> f64 {
+        f64::from(self.0.clamp(*Self::RANGE.start(), *Self::RANGE.end())) / 100.
+    }
+}
+impl Default for UiScale {
+    fn default() -> Self {
+        Self(100)
+    }
+}
+
+/// The spacing and padding of publish and transfer rows.
+#[derive(Clone, Copy)]
+struct RowDensity {
+    spacing: u16,
+    padding: [u16; 4],
+}
+impl RowDensity {
+    fn new(compact: bool) -> Self {
+        // Extra padding on the right because of optional scrollbar.
+        if compact {
+            Self {
+                spacing: 2,
+                padding: [2, 12, 2, 4],
+            }
+        } else {
+            Self {
+                spacing: 6,
+                padding: [6, 12, 6, 6],
+            }
+        }
+    }
+}
+
+/// The bandwidth limits that can be edited in the settings.
+#[derive(Clone, Copy, Debug)]
+enum BandwidthLimitKind {
+    Upload,
+    Download,
+    PerTransfer,
+}
+impl std::fmt::Display for BandwidthLimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upload => write!(f, "Total upload rate"),
+            Self::Download => write!(f, "Total download rate"),
+            Self::PerTransfer => write!(f, "Rate per transfer"),
+        }
+    }
+}
+
 /// The current settings for the app.
-#[derive(Default, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
 struct AppSettings {
     pub server_address: String,
+
+    /// The servers to try in order when the server can't be reached, separated by commas or spaces.
+    #[serde(default)]
+    pub fallback_servers_text: String,
+
+    /// Other servers to connect to alongside the server, separated by commas or spaces.
+    #[serde(default)]
+    pub additional_servers_text: String,
+
+    /// The access token to present to servers that require one. Left out of exported profiles.
+    #[serde(default)]
+    pub auth_token: String,
+
+    /// A file of CA certificates to verify the server against, and the SHA-256 fingerprint of the only
+    /// server certificate to accept, in hex. The server's first certificate is trusted if both are empty.
+    #[serde(default)]
+    pub server_ca_text: String,
+    #[serde(default)]
+    pub server_fingerprint_text: String,
+
+    /// The certificate and private key files to present to servers that only accept clients with a certificate
+    /// from their operator. No certificate is presented if either is empty.
+    #[serde(default)]
+    pub client_cert_text: String,
+    #[serde(default)]
+    pub client_key_text: String,
+
+    /// The room to publish and subscribe in. Empty for the server's shared room.
+    #[serde(default)]
+    pub room: String,
+
+    /// Whether to sign publishes so that subscribers can verify they came from us.
+    #[serde(default)]
+    pub sign_publishes: bool,
+
+    /// The fingerprints of the publishers to accept downloads from, separated by commas or spaces.
+    /// Downloads from any publisher are accepted if empty.
+    #[serde(default)]
+    pub trusted_publishers_text: String,
+
+    /// Whether to publish each verified download so other subscribers can download it from us too.
+    #[serde(default)]
+    pub seed_downloads: bool,
+
     pub gateway_address: Option<String>,
     pub port_forwarding_text: String,
     pub port_mapping: PortMappingGuiOptions,
     pub last_publish_paths: Vec<PathBuf>,
     pub last_downloads: Vec<(PathBuf, HashBytes)>,
+
+    /// The hash algorithm of each of the last downloads. Missing entries are SHA-256.
+    #[serde(default)]
+    pub last_download_algorithms: Vec<HashAlgorithm>,
+
+    /// The algorithm to hash new publishes with.
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+
+    /// The congestion controller to pace uploads with, applied from the next connection.
+    #[serde(default)]
+    pub congestion_controller: CongestionController,
+
+    #[serde(default)]
+    pub auto_connect_retry: AutoConnectRetry,
+
+    #[serde(default)]
+    pub download_directory: DownloadDirectorySettings,
+
+    /// Whether to check for a newer release on startup. Opt-in.
+    #[serde(default)]
+    pub check_for_updates: bool,
+
+    /// The text of the maximum download size field, and the size it was parsed to.
+    #[serde(default)]
+    pub max_download_size_text: String,
+    #[serde(default)]
+    pub max_download_size: Option<u64>,
+
+    /// The text of the bandwidth limit fields, and the limits they were parsed to.
+    #[serde(default)]
+    pub upload_limit_text: String,
+    #[serde(default)]
+    pub download_limit_text: String,
+    #[serde(default)]
+    pub transfer_limit_text: String,
+    #[serde(default)]
+    pub bandwidth_limits: BandwidthLimits,
+
+    /// The text of the internal port range field, and the range it was parsed to.
+    #[serde(default)]
+    pub internal_port_range_text: String,
+    #[serde(default)]
+    pub internal_port_range: Option<crate::core::PortRange>,
+
+    /// Commands to run when transfer events occur.
+    #[serde(default)]
+    pub event_hooks: EventHooks,
+
+    /// Always establish a fresh peer connection for each transfer instead of reusing one.
+    #[serde(default)]
+    pub disable_connection_reuse: bool,
+
+    /// The scale of the UI, and whether to draw publish and transfer rows compactly.
+    #[serde(default)]
+    pub ui_scale: UiScale,
+    #[serde(default)]
+    pub compact_rows: bool,
+}
+impl AppSettings {
+    /// Read the saved settings, or the defaults if there are none.
+    fn load() -> Self {
+        settings_path()
+            .and_then(|p| {
+                // Ensure the settings file and directory exist.
+                if p.exists() {
+                    // Try to read the settings for the app.
+                    let settings = std::fs::read_to_string(p).ok()?;
+                    serde_json::from_str::<AppSettings>(&settings).ok()
+                } else {
+                    // Create the settings file and directory.
+                    std::fs::create_dir_all(p.parent()?).ok()?;
+                    std::fs::write(p, "").ok()?;
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    /// Save the settings to the app settings file.
+    fn save(&self) -> anyhow::Result<()> {
+        let path = settings_path().ok_or_else(|| {
+            anyhow::anyhow!("Could not determine a settings path for this environment.")
+        })?;
+        serde_json::to_writer_pretty(std::fs::File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// The addresses of the fallback servers, in the order to try them.
+    fn fallback_servers(&self) -> Vec<&str> {
+        split_server_list(&self.fallback_servers_text)
+    }
+
+    /// The addresses of the servers to connect to alongside the server.
+    fn additional_servers(&self) -> Vec<&str> {
+        split_server_list(&self.additional_servers_text)
+    }
+
+    /// The fingerprints of the publishers to accept downloads from.
+    fn trusted_publishers(&self) -> Vec<&str> {
+        self.trusted_publishers_text
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
 }
 
 /// The state of the application for interacting with the GUI.
 #[derive(Default)]
 pub struct AppState {
-    connection_state: ConnectionState,
     options: AppSettings,
     status_message: Option<String>,
     modal: bool,
     safely_closing: bool,
-    port_mapping: Option<crab_nat::PortMapping>,
+
+    /// The connection to the server, and the state of connecting to it.
+    connection: ConnectionController,
+
+    /// The files we publish on the server.
+    publish: PublishController,
+
+    /// The files we download from peers.
+    download: DownloadController,
+
+    /// A newer release of the client, if one was found.
+    available_update: Option<crate::update::Release>,
+
+    /// The windows opened in addition to the main window, and what they display.
+    detail_windows: HashMap<window::Id, DetailWindow>,
+
+    /// A history of the status messages shown to the user.
+    status_log: Vec<String>,
+
+    /// The peers we have transferred with before, and the names the user gave them.
+    known_peers: KnownPeers,
+
+    /// The key to sign publishes with, loaded once signing is enabled.
+    publisher_key: Option<Arc<PublisherKey>>,
+}
+
+/// The parts of the app state shared by the controllers, borrowed while one of them handles a message.
+struct AppContext<'a> {
+    options: &'a mut AppSettings,
+    status_message: &'a mut Option<String>,
+    modal: &'a mut bool,
+    known_peers: &'a mut KnownPeers,
+    publisher_key: Option<&'a Arc<PublisherKey>>,
+}
+impl AppContext<'_> {
+    /// Run the user's hook for an event, if one is configured.
+    fn run_hook(&self, event: TransferEvent, context: HookContext) -> iced::Command<Message> {
+        match crate::hooks::run(&self.options.event_hooks, event, context) {
+            Some(future) => iced::Command::perform(
+                async move { future.await.map_err(Arc::new) },
+                Message::HookFinished,
+            ),
+            None => iced::Command::none(),
+        }
+    }
+}
+
+/// The content of a window opened in addition to the main window.
+#[derive(Clone, Copy, Debug)]
+enum DetailWindow {
+    /// A single upload or download.
+    Transfer(Nonce, FileYeetCommandType),
+
+    /// The history of status messages.
+    StatusLog,
 }
 
 /// The messages that can be sent to the update loop of the application.
 #[derive(Clone, Debug)]
 pub enum Message {
+    /// A message for the connection controller.
+    Connection(ConnectionMessage),
+
+    /// A message for the publish controller.
+    Publish(PublishMessage),
+
+    /// A message for the download controller.
+    Download(DownloadMessage),
+
     /// The server text field was changed.
     ServerAddressChanged(String),
 
+    /// The fallback servers text field was changed.
+    FallbackServersChanged(String),
+
+    /// The additional servers text field was changed.
+    AdditionalServersChanged(String),
+
+    /// The access token text field was changed.
+    AuthTokenChanged(String),
+
+    /// The server CA file text field was changed.
+    ServerCaChanged(String),
+
+    /// The server certificate fingerprint text field was changed.
+    ServerFingerprintChanged(String),
+
+    /// The client certificate file text field was changed.
+    ClientCertChanged(String),
+
+    /// The client private key file text field was changed.
+    ClientKeyChanged(String),
+
+    /// The room text field was changed.
+    RoomChanged(String),
+
+    /// The choice of whether to sign publishes was changed.
+    SignPublishesChanged(bool),
+
+    /// The trusted publishers text field was changed.
+    TrustedPublishersChanged(String),
+
     /// The port mapping radio button was changed.
     PortMappingRadioChanged(&'static str),
 
@@ -335,66 +989,99 @@ pub enum Message {
     /// The gateway text field was changed.
     GatewayTextChanged(String),
 
-    /// The connect button was clicked.
-    ConnectClicked,
+    /// The choice between asking for a download path and using a default directory was changed.
+    UseDefaultDirectoryChanged(bool),
 
-    /// A moment in time has passed, update the animations.
-    AnimationTick,
+    /// The default download directory text field was changed.
+    DefaultDirectoryChanged(String),
 
-    /// The result of a server connection attempt.
-    ConnectResulted(Result<crate::core::PreparedConnection, Arc<anyhow::Error>>),
+    /// A new download directory rule was added.
+    DownloadRuleAdded,
 
-    /// Copy the server address to the clipboard.
-    CopyServer,
+    /// The hash prefix of a download directory rule was changed.
+    DownloadRulePrefixChanged(usize, String),
 
-    /// Leave the server and disconnect.
-    SafelyLeaveServer,
+    /// The directory of a download directory rule was changed.
+    DownloadRuleDirectoryChanged(usize, String),
 
-    /// All async actions to leave a server have completed.
-    LeftServer,
+    /// A download directory rule was removed.
+    DownloadRuleRemoved(usize),
 
-    /// The transfer view radio buttons were changed.
-    TransferViewChanged(TransferView),
+    /// The maximum download size text field was changed.
+    MaxDownloadSizeChanged(String),
+
+    /// The internal port range text field was changed.
+    InternalPortRangeChanged(String),
+
+    /// The command for an event hook was edited.
+    EventHookChanged(TransferEvent, String),
+
+    /// The text of a bandwidth limit field was changed.
+    BandwidthLimitChanged(BandwidthLimitKind, String),
+
+    /// An event hook command finished running.
+    HookFinished(Result<(), Arc<anyhow::Error>>),
+
+    /// The choice of whether to check for updates on startup was changed.
+    CheckForUpdatesChanged(bool),
+
+    /// The choice of whether to reuse existing peer connections was changed.
+    ConnectionReuseChanged(bool),
+
+    /// The choice of whether to publish verified downloads was changed.
+    SeedDownloadsChanged(bool),
+
+    /// The UI scale percentage was changed.
+    UiScaleChanged(u16),
+
+    /// The choice of whether to draw rows compactly was changed.
+    CompactRowsChanged(bool),
+
+    /// The hash algorithm for new publishes was changed.
+    HashAlgorithmChanged(HashAlgorithm),
 
-    /// The hash input field was changed.
-    HashInputChanged(String),
+    /// The congestion controller for new connections was changed.
+    CongestionControllerChanged(CongestionController),
 
-    /// The publish button was clicked.
-    PublishClicked,
+    /// The result of checking for a newer release.
+    UpdateChecked(Result<crate::update::Release, Arc<anyhow::Error>>),
 
-    /// The path to a file to publish was chosen or cancelled.
-    PublishPathChosen(Option<PathBuf>),
+    /// Open the page of the available update in the browser.
+    OpenUpdatePage,
 
-    /// The result of a publish request.
-    PublishRequestResulted(Nonce, PathBuf, PublishRequestResult),
+    /// Hide the available update banner.
+    DismissUpdate,
 
-    /// The result of trying to recieve a peer to publish to from the server.
-    PublishPeerReceived(Nonce, Result<SocketAddr, Arc<anyhow::Error>>),
+    /// The progress of a transfer or a publish being hashed has changed.
+    ProgressChanged(Nonce, f32),
 
-    /// The result of trying to connect to a peer to publish to.
-    PublishPeerConnectResulted(Nonce, Option<PeerConnection>),
+    /// The connection to the server closed without us leaving.
+    ServerConnectionLost(quinn::ConnectionError),
 
-    /// The subscribe button was clicked or the hash field was submitted.
-    SubscribeStarted,
+    /// The leave button was clicked. Asks for confirmation if there is active work.
+    LeaveServerClicked,
 
-    /// The path to save a file to was chosen or cancelled.
-    SubscribePathChosen(Option<PathBuf>),
+    /// The user chose to leave the server once the active transfers have finished.
+    LeaveWhenTransfersDone,
 
-    /// A subscribe request was completed.
-    SubscribePeersResult(Result<IncomingSubscribePeers, Arc<anyhow::Error>>),
+    /// The user chose to stay connected instead of leaving the server.
+    CancelLeaveServer,
 
-    /// A subscribe connection attempt was completed.
-    SubscribePeerConnectResulted(Nonce, Option<PeerConnection>),
+    /// Leave the server and disconnect.
+    SafelyLeaveServer,
+
+    /// All async actions to leave a server have completed.
+    LeftServer,
+
+    /// The transfer view radio buttons were changed.
+    TransferViewChanged(TransferView),
 
-    // A download was accepted, initiate the download.
-    AcceptDownload(Nonce),
+    /// The passphrase input field was changed.
+    PassphraseInputChanged(String),
 
     /// Copy a hash to the clipboard.
     CopyHash(String),
 
-    /// Cancel publishing a file.
-    CancelPublish(Nonce),
-
     /// Cancel a transfer that is in-progress.
     CancelTransfer(Nonce, FileYeetCommandType),
 
@@ -410,6 +1097,30 @@ pub enum Message {
     /// An unhandled event occurred.
     UnhandledEvent(iced::Event),
 
+    /// Open a transfer in its own window.
+    OpenTransferWindow(Nonce, FileYeetCommandType),
+
+    /// Open the status log in its own window.
+    OpenStatusLogWindow,
+
+    /// The name of a known peer was edited.
+    PeerNameChanged(String, String),
+
+    /// The name of a known peer was submitted and should be saved.
+    PeerNameSubmitted,
+
+    /// The export settings button was clicked.
+    ExportSettingsClicked,
+
+    /// A file to export the settings profile to was chosen.
+    ExportSettingsPathChosen(Option<PathBuf>),
+
+    /// The import settings button was clicked.
+    ImportSettingsClicked,
+
+    /// A settings profile file to import was chosen.
+    ImportSettingsPathChosen(Option<PathBuf>),
+
     /// Exit the application immediately. Ensure we aren't waiting for async tasks forever.
     ForceExit,
 }
@@ -423,7 +1134,7 @@ fn settings_path() -> Option<std::path::PathBuf> {
 }
 
 /// The application state and logic.
-impl iced::Application for AppState {
+impl iced::multi_window::Application for AppState {
     type Message = Message;
     type Theme = iced::Theme;
     type Executor = iced::executor::Default;
@@ -432,37 +1143,98 @@ impl iced::Application for AppState {
     /// Create a new application state.
     fn new(args: Self::Flags) -> (AppState, iced::Command<Message>) {
         // Get base settings from the settings file, or default.
-        let mut settings = settings_path()
-            .and_then(|p| {
-                // Ensure the settings file and directory exist.
-                if p.exists() {
-                    // Try to read the settings for the app.
-                    let settings = std::fs::read_to_string(p).ok()?;
-                    serde_json::from_str::<AppSettings>(&settings).ok()
-                } else {
-                    // Create the settings file and directory.
-                    std::fs::create_dir_all(p.parent()?).ok()?;
-                    std::fs::write(p, "").ok()?;
-                    None
-                }
-            })
-            .unwrap_or_default();
+        let mut settings = AppSettings::load();
 
         // The CLI arguments take final precedence on start.
         if let Some(crate::Cli {
             server_address,
+            fallback_servers,
+            additional_servers,
             port_override,
             gateway,
             nat_map,
+            max_download_size,
+            internal_port_range,
+            upload_limit,
+            download_limit,
+            transfer_limit,
+            on_download_complete,
+            on_upload_complete,
+            on_publish_failed,
+            room,
+            trusted_publishers,
+            congestion_controller,
             ..
         }) = args
         {
+            for (command, event) in [
+                (on_download_complete, TransferEvent::DownloadComplete),
+                (on_upload_complete, TransferEvent::UploadComplete),
+                (on_publish_failed, TransferEvent::PublishFailed),
+            ] {
+                if let Some(command) = command {
+                    *settings.event_hooks.command_mut(event) = command;
+                }
+            }
+            if let Some(range) = internal_port_range {
+                settings.internal_port_range_text = range.to_string();
+                settings.internal_port_range = Some(range);
+            }
+            for (limit, text, parsed) in [
+                (
+                    upload_limit,
+                    &mut settings.upload_limit_text,
+                    &mut settings.bandwidth_limits.upload,
+                ),
+                (
+                    download_limit,
+                    &mut settings.download_limit_text,
+                    &mut settings.bandwidth_limits.download,
+                ),
+                (
+                    transfer_limit,
+                    &mut settings.transfer_limit_text,
+                    &mut settings.bandwidth_limits.per_transfer,
+                ),
+            ] {
+                if let Some(limit) = limit {
+                    *text = limit.to_string();
+                    *parsed = NonZeroU64::new(limit);
+                }
+            }
+            if let Some(max) = max_download_size {
+                settings.max_download_size_text = max.to_string();
+                settings.max_download_size = Some(max);
+            }
             if let Some(server_address) = server_address {
                 settings.server_address = server_address;
             }
+            if !fallback_servers.is_empty() {
+                settings.fallback_servers_text = fallback_servers
+                    .iter()
+                    .map(|(host, port)| format!("{host}:{port}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
+            if !additional_servers.is_empty() {
+                settings.additional_servers_text = additional_servers
+                    .iter()
+                    .map(|(host, port)| format!("{host}:{port}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+            }
             if let Some(gateway) = gateway {
                 settings.gateway_address = Some(gateway);
             }
+            if !room.is_empty() {
+                settings.room = room;
+            }
+            if let Some(congestion_controller) = congestion_controller {
+                settings.congestion_controller = congestion_controller;
+            }
+            if !trusted_publishers.is_empty() {
+                settings.trusted_publishers_text = trusted_publishers.join(", ");
+            }
             if let Some(port) = port_override {
                 settings.port_forwarding_text = port.to_string();
                 settings.port_mapping = PortMappingGuiOptions::PortForwarding(Some(port));
@@ -471,194 +1243,74 @@ impl iced::Application for AppState {
             }
         }
         let server_address_is_empty = settings.server_address.is_empty();
+        crate::throttle::set_bandwidth_limits(settings.bandwidth_limits);
+        file_yeet_shared::set_congestion_controller(settings.congestion_controller);
 
         // Create the initial state with the settings.
+        let publisher_key = settings
+            .sign_publishes
+            .then(|| Arc::new(PublisherKey::load_or_create()));
         let mut initial_state = Self {
             options: settings,
+            known_peers: KnownPeers::load(),
+            publisher_key,
             ..Self::default()
         };
 
-        // Try connecting immediately if the server address is already set.
-        let command = if server_address_is_empty {
+        // Only check for updates if the user opted in.
+        let update_command = if initial_state.options.check_for_updates {
+            iced::Command::perform(
+                async { crate::update::latest_release().await.map_err(Arc::new) },
+                Message::UpdateChecked,
+            )
+        } else {
+            iced::Command::none()
+        };
+
+        // Try connecting immediately if the server address is already set.
+        let connect_command = if server_address_is_empty {
             iced::Command::none()
         } else {
-            initial_state.update_connect_clicked()
+            let (connection, _, _, mut context) = initial_state.controllers();
+            connection.auto_connect_attempt = Some(0);
+            connection.connect(&mut context)
         };
-        (initial_state, command)
+        (
+            initial_state,
+            iced::Command::batch([update_command, connect_command]),
+        )
     }
 
-    /// Get the application title text.
-    fn title(&self) -> String {
-        String::from("file_yeet_client")
+    /// Get the title text of a window.
+    fn title(&self, window: window::Id) -> String {
+        match self.detail_windows.get(&window) {
+            None => String::from("file_yeet_client"),
+            Some(DetailWindow::Transfer(_, FileYeetCommandType::Pub)) => {
+                String::from("file_yeet_client - Upload")
+            }
+            Some(DetailWindow::Transfer(_, FileYeetCommandType::Sub)) => {
+                String::from("file_yeet_client - Download")
+            }
+            Some(DetailWindow::StatusLog) => String::from("file_yeet_client - Status log"),
+        }
     }
 
     /// Update the application state based on a message.
     fn update(&mut self, message: Message) -> iced::Command<Message> {
-        match message {
-            // Handle the server address being changed.
-            Message::ServerAddressChanged(address) => {
-                self.options.server_address = address;
-                iced::Command::none()
-            }
-
-            // Handle the port mapping radio button being changed.
-            Message::PortMappingRadioChanged(label) => self.update_port_radio_changed(label),
-
-            // Handle the port forward text field being changed.
-            Message::PortForwardTextChanged(text) => self.update_port_forward_text(text),
-
-            // Handle the gateway text field being changed.
-            Message::GatewayTextChanged(text) => self.update_gateway_text(text),
-
-            // Handle the publish button being clicked by picking a file to publish.
-            Message::ConnectClicked => self.update_connect_clicked(),
-
-            // The animation tick doesn't need anything special besides updating the tick state.
-            Message::AnimationTick => self.update_animation_tick(),
-
-            // Handle the result of a connection attempt.
-            Message::ConnectResulted(r) => self.update_connect_resulted(r),
-
-            // Copy the connected server address to the clipboard.
-            Message::CopyServer => iced::clipboard::write(self.options.server_address.clone()),
-
-            // Leave the server and disconnect.
-            Message::SafelyLeaveServer => self.safely_close(CloseType::Connections),
-
-            // All async actions to leave a server have completed.
-            Message::LeftServer => {
-                self.safely_closing = false;
-                self.connection_state = ConnectionState::Disconnected;
-                iced::Command::none()
-            }
-
-            // The transfer view radio buttons were changed.
-            Message::TransferViewChanged(view) => {
-                if let ConnectionState::Connected(connected_state) = &mut self.connection_state {
-                    connected_state.transfer_view = view;
-                }
-                iced::Command::none()
-            }
-
-            // Handle the hash input being changed.
-            Message::HashInputChanged(hash) => {
-                if let ConnectionState::Connected(ConnectedState { hash_input, .. }) =
-                    &mut self.connection_state
-                {
-                    *hash_input = hash;
+        let previous_status = self.status_message.clone();
+        let command = self.handle_message(message);
+
+        // Keep a log of status messages that can be viewed in a separate window.
+        if self.status_message != previous_status {
+            if let Some(status) = &self.status_message {
+                if self.status_log.len() >= MAX_STATUS_LOG_LEN {
+                    self.status_log.remove(0);
                 }
-                iced::Command::none()
-            }
-
-            // Handle the publish button being clicked by picking a file to publish.
-            Message::PublishClicked => {
-                // Clear the status message before starting the publish attempt.
-                self.status_message = None;
-
-                // Let state know that a modal dialog is open.
-                self.modal = true;
-
-                iced::Command::perform(
-                    rfd::AsyncFileDialog::new()
-                        .set_title("Choose a file to publish")
-                        .pick_file(),
-                    |f| Message::PublishPathChosen(f.map(PathBuf::from)),
-                )
-            }
-
-            // Begin the process of publishing a file to the server.
-            Message::PublishPathChosen(path) => self.update_publish_path_chosen(path),
-
-            // Handle the result of a publish request.
-            Message::PublishRequestResulted(nonce, path, r) => {
-                self.update_publish_request_resulted(nonce, &path, r)
-            }
-
-            // Handle a peer connection being received for a publish request.
-            Message::PublishPeerReceived(nonce, r) => self.update_publish_peer_received(nonce, r),
-
-            // Handle the result of a peer connection attempt for a publish request.
-            Message::PublishPeerConnectResulted(pub_nonce, peer) => {
-                self.update_publish_peer_connect_resulted(pub_nonce, peer)
-            }
-
-            // Handle the subscribe button being clicked by choosing a save location.
-            Message::SubscribeStarted => {
-                // Clear the status message before starting the subscribe attempt.
-                self.status_message = None;
-
-                // Let state know that a modal dialog is open.
-                self.modal = true;
-
-                iced::Command::perform(
-                    rfd::AsyncFileDialog::new()
-                        .set_title("Choose a file path to save to")
-                        .save_file(),
-                    |f| Message::SubscribePathChosen(f.map(PathBuf::from)),
-                )
-            }
-
-            // Begin the process of subscribing to a file from the server.
-            Message::SubscribePathChosen(path) => self.update_subscribe_path_chosen(path),
-
-            // Handle the result of a subscribe request.
-            Message::SubscribePeersResult(r) => self.update_subscribe_peers_result(r),
-
-            // Handle the result of a subscribe connection attempt to a peer.
-            Message::SubscribePeerConnectResulted(nonce, r) => {
-                self.update_subscribe_connect_resulted(nonce, r)
-            }
-
-            // Handle the download being accepted, initiate the download.
-            Message::AcceptDownload(nonce) => self.update_accept_download(nonce),
-
-            // Copy a hash to the clipboard.
-            Message::CopyHash(hash) => iced::clipboard::write(hash),
-
-            // Set the cancellation token to notify the publishing thread to cancel.
-            Message::CancelPublish(nonce) => self.update_cancel_publish(nonce),
-
-            // Handle a transfer being cancelled.
-            Message::CancelTransfer(nonce, transfer_type) => {
-                self.update_cancel_transfer(nonce, transfer_type)
-            }
-
-            // Handle the conclusive result of a transfer.
-            Message::TransferResulted(nonce, r, transfer_type) => {
-                self.update_transfer_resulted(nonce, r, transfer_type)
-            }
-
-            // Handle a file being opened.
-            Message::OpenFile(path) => {
-                open::that(path).unwrap_or_else(|e| {
-                    eprintln!("{} Failed to open file: {e}", local_now_fmt());
-                });
-                iced::Command::none()
-            }
-
-            // Handle a transfer being removed from the downloads list.
-            Message::RemoveFromTransfers(nonce, transfer_type) => {
-                self.update_remove_from_transfers(nonce, transfer_type)
+                self.status_log
+                    .push(format!("{} {status}", local_now_fmt()));
             }
-
-            // Handle an event that iced did not handle itself.
-            // This is used to allow for custom exit handling in this instance.
-            Message::UnhandledEvent(event) => match event {
-                iced::Event::Window(id, window::Event::CloseRequested) => {
-                    if id == window::Id::MAIN && !self.safely_closing {
-                        self.safely_close(CloseType::Application)
-                    } else {
-                        // Non-main windows (if ever implemented) can be closed immediately.
-                        // This is also used to cancel the safe-close operation.
-                        window::close(id)
-                    }
-                }
-                _ => iced::Command::none(),
-            },
-
-            // Exit the application immediately.
-            Message::ForceExit => window::close(window::Id::MAIN),
         }
+        command
     }
 
     /// Listen for events that should be translated into messages.
@@ -667,58 +1319,157 @@ impl iced::Application for AppState {
         let close_event = || iced::event::listen().map(Message::UnhandledEvent);
 
         // Listen for timing intervals to update animations.
-        let animation =
-            || iced::time::every(Duration::from_millis(33)).map(|_| Message::AnimationTick);
+        let animation = || {
+            iced::time::every(Duration::from_millis(33))
+                .map(|_| Message::Connection(ConnectionMessage::AnimationTick))
+        };
 
-        match &self.connection_state {
-            // Listen for close events and animation ticks when connecting/stalling.
-            ConnectionState::Stalling { .. } => {
+        match &self.connection.state {
+            // Listen for close events and animation ticks when connecting/stalling or waiting to retry.
+            ConnectionState::Stalling { .. } | ConnectionState::Retrying { .. } => {
                 iced::Subscription::batch([close_event(), animation()])
             }
 
-            ConnectionState::Connected(ConnectedState { publishes, .. }) => {
-                let pubs = publishes.iter().filter_map(|publish| {
+            ConnectionState::Connected(ConnectedState {
+                server,
+                uploads,
+                server_notifications,
+                additional_servers,
+                address_changes,
+                ..
+            }) => {
+                let pubs = self.publish.publishes.iter().flat_map(|publish| {
                     // If the publish is still hashing, nothing to loop yet.
-                    let PublishItem { nonce, cancellation_token, state: PublishState::Publishing(publish), .. } = &publish else { return None; };
+                    let PublishItem { nonce, cancellation_token, state: PublishState::Publishing(publish), .. } = &publish else { return Vec::new(); };
                     let nonce = *nonce;
-                    let cancellation_token = cancellation_token.clone();
-                    let publish = publish.clone();
-
-                    // Subscribe to the server for new peers to upload to.
-                    Some(iced::subscription::channel(nonce, 10, move |mut output| async move {
-                        loop {
-                            let mut server = publish.server_streams.lock().await;
-
-                            tokio::select! {
-                                // Let the task be cancelled.
-                                () = cancellation_token.cancelled() => {
-                                    if let Err(e) = server.send.write_u8(0).await {
-                                        eprintln!("{} Failed to cancel publish: {e}", local_now_fmt());
+
+                    // Subscribe to each server for new peers to upload to.
+                    publish.server_streams.iter().enumerate().map(|(i, server_streams)| {
+                        let cancellation_token = cancellation_token.clone();
+                        let PublishStream { server: server_connection, streams: server_streams } = server_streams.clone();
+                        let mut address_changes = address_changes.subscribe();
+                        let Publish { hash, file_size, room, metadata, .. } = publish.clone();
+                        iced::subscription::channel((nonce, i), 10, move |mut output| async move {
+                            loop {
+                                let mut server = server_streams.lock().await;
+
+                                tokio::select! {
+                                    // Let the task be cancelled.
+                                    () = cancellation_token.cancelled() => {
+                                        if let Err(e) = server.send.write_u8(PublishControl::Cancel as u8).await {
+                                            eprintln!("{} Failed to cancel publish: {e}", local_now_fmt());
+                                        }
+
+                                        // Provide a brief wait for the task to be cancelled.
+                                        tokio::time::sleep(Duration::from_millis(200)).await;
+                                        println!("{} Dead publish task is still running...", local_now_fmt());
                                     }
 
-                                    // Provide a brief wait for the task to be cancelled.
-                                    tokio::time::sleep(Duration::from_millis(200)).await;
-                                    println!("{} Dead publish task is still running...", local_now_fmt());
-                                }
+                                    // Redo the port override or the publish, so that subscribers are introduced to where we are now.
+                                    Ok(()) = address_changes.changed() => {
+                                        let port_override = *address_changes.borrow_and_update();
+                                        if let Err(e) = crate::core::refresh_publish(&server_connection, &mut server, port_override, hash, file_size, &room, &metadata).await {
+                                            eprintln!("{} Failed to update the publish: {e}", local_now_fmt());
+                                        }
+                                    }
 
-                                // Await the server to send a peer connection.
-                                result = crate::core::read_subscribing_peer(&mut server.recv) => {
-                                    if let Err(e) = output
-                                        .send(Message::PublishPeerReceived(
-                                            nonce,
-                                            result.map_err(Arc::new),
-                                        ))
-                                        .await
-                                    {
-                                        eprintln!("{} Failed to perform internal message passing: {e}", local_now_fmt());
+                                    // Await the server to send a peer connection.
+                                    result = crate::core::read_subscribing_peer(&mut server) => {
+                                        if let Err(e) = output
+                                            .send(Message::Publish(PublishMessage::PeerReceived(
+                                                nonce,
+                                                result
+                                                    .and_then(|(peer, span)| match peer {
+                                                        crate::core::SubscribingPeer::Direct(address) => Ok((address, span)),
+
+                                                        // The app doesn't opt in to relays, so the server shouldn't offer them.
+                                                        crate::core::SubscribingPeer::Relay { .. } => {
+                                                            Err(anyhow::anyhow!("Server offered an unexpected relay"))
+                                                        }
+                                                    })
+                                                    .map_err(Arc::new),
+                                            )))
+                                            .await
+                                        {
+                                            eprintln!("{} Failed to perform internal message passing: {e}", local_now_fmt());
+                                        }
                                     }
                                 }
                             }
-                        }
-                    }))
+                        })
+                    }).collect::<Vec<_>>()
                 });
 
-                iced::Subscription::batch([close_event(), animation()].into_iter().chain(pubs))
+                // Periodically re-ping the server to notice if our external address changes.
+                let socket_ping = iced::time::every(SOCKET_PING_INTERVAL)
+                    .map(|_| Message::Connection(ConnectionMessage::SocketPingTick));
+
+                // Poll the default network route to notice VPN or interface changes.
+                let network_poll = iced::time::every(NETWORK_POLL_INTERVAL)
+                    .map(|_| Message::Connection(ConnectionMessage::NetworkPollTick));
+
+                // Listen for notifications pushed by each server.
+                // Key each listener by its connection, so that it survives other servers being added or removed.
+                let notifications = std::iter::once((server, server_notifications))
+                    .chain(
+                        additional_servers
+                            .iter()
+                            .map(|s| (&s.server, &s.server_notifications)),
+                    )
+                    .map(|(server, server_notifications)| {
+                        notification_subscription(server.stable_id(), server_notifications.clone())
+                    });
+
+                // Notice when the server goes away, such as when it restarts, so that we can reconnect.
+                let server = server.clone();
+                let connection_lost = iced::subscription::channel(
+                    ("server_connection", server.stable_id()),
+                    1,
+                    move |mut output| async move {
+                        let e = server.closed().await;
+                        if let Err(e) = output.send(Message::ServerConnectionLost(e)).await {
+                            eprintln!(
+                                "{} Failed to perform internal message passing: {e}",
+                                local_now_fmt()
+                            );
+                        }
+
+                        // Nothing more will happen on a closed connection.
+                        std::future::pending().await
+                    },
+                );
+
+                // Listen for progress on active transfers and publishes being hashed.
+                let transfer_progress = self
+                    .download
+                    .downloads
+                    .iter()
+                    .chain(uploads.iter())
+                    .filter_map(|t| match &t.progress {
+                        TransferProgress::Transferring(_, progress, _) => {
+                            Some(progress_subscription(t.nonce, progress.clone()))
+                        }
+                        _ => None,
+                    });
+                let hash_progress =
+                    self.publish
+                        .publishes
+                        .iter()
+                        .filter_map(|pi| match &pi.state {
+                            PublishState::Hashing(progress, _) => {
+                                Some(progress_subscription(pi.nonce, progress.clone()))
+                            }
+                            _ => None,
+                        });
+
+                iced::Subscription::batch(
+                    [close_event(), socket_ping, network_poll, connection_lost]
+                        .into_iter()
+                        .chain(notifications)
+                        .chain(pubs)
+                        .chain(transfer_progress)
+                        .chain(hash_progress),
+                )
             }
 
             // Listen for close events alone when disconnected.
@@ -727,9 +1478,14 @@ impl iced::Application for AppState {
     }
 
     /// Draw the application GUI.
-    fn view(&self) -> iced::Element<Message> {
+    fn view(&self, window: window::Id) -> iced::Element<Message> {
+        // Detail windows have their own views.
+        if let Some(&detail) = self.detail_windows.get(&window) {
+            return self.view_detail_window(detail);
+        }
+
         // Create a different top-level page based on the connection state.
-        let page: Element<Message> = match &self.connection_state {
+        let page: Element<Message> = match &self.connection.state {
             // Display a prompt for the server address when disconnected.
             ConnectionState::Disconnected => self.view_disconnected_page(),
 
@@ -746,6 +1502,13 @@ impl iced::Application for AppState {
                 }
             }
 
+            // Display a countdown until the next auto-connect attempt.
+            &ConnectionState::Retrying {
+                attempt,
+                retry_at,
+                tick,
+            } => self.view_retrying_page(attempt, retry_at, tick),
+
             // Display the main application controls when connected.
             ConnectionState::Connected(connected_state) => {
                 self.view_connected_page(connected_state)
@@ -763,16 +1526,222 @@ impl iced::Application for AppState {
         } else {
             widget::horizontal_space().into()
         });
-        widget::column!(page, status_bar).padding(6).into()
+
+        // Display a banner at the top when a newer release is available.
+        let update_banner = widget::container(if let Some(release) = &self.available_update {
+            Element::from(
+                widget::row!(
+                    widget::text(format!(
+                        "Version {} is available, you are using {}",
+                        release.tag_name,
+                        crate::update::CURRENT_VERSION
+                    ))
+                    .width(iced::Length::Fill),
+                    widget::button(widget::text("View release").size(12))
+                        .on_press(Message::OpenUpdatePage),
+                    widget::button(widget::text("Dismiss").size(12))
+                        .on_press(Message::DismissUpdate),
+                )
+                .align_items(iced::Alignment::Center)
+                .spacing(12),
+            )
+        } else {
+            widget::horizontal_space().into()
+        });
+        widget::column!(update_banner, page, status_bar)
+            .padding(6)
+            .into()
     }
 
     /// Prefer a dark theme.
-    fn theme(&self) -> iced::Theme {
+    fn theme(&self, _window: window::Id) -> iced::Theme {
         iced::Theme::Dark
     }
+
+    /// Scale every window by the user's chosen UI scale.
+    fn scale_factor(&self, _window: window::Id) -> f64 {
+        self.options.ui_scale.factor()
+    }
 }
 
 impl AppState {
+    /// Handle a message sent to the update loop of the application.
+    fn handle_message(&mut self, message: Message) -> iced::Command<Message> {
+        // Hand each controller the messages of its domain, along with the state the controllers share.
+        let message = match message {
+            Message::Connection(message) => {
+                let (connection, _, _, mut context) = self.controllers();
+                return connection.update(message, &mut context);
+            }
+            Message::Publish(message) => {
+                let (connection, publish, _, mut context) = self.controllers();
+                return publish.update(message, connection.connected_mut(), &mut context);
+            }
+            Message::Download(message) => {
+                let (connection, _, download, mut context) = self.controllers();
+                return download.update(message, connection.connected_mut(), &mut context);
+            }
+            message => message,
+        };
+
+        // Settings and active transfers span the app state, then the app-wide messages are handled here.
+        let message = match self
+            .update_settings(message)
+            .or_else(|m| self.update_transfer(m))
+        {
+            Ok(command) => return command,
+            Err(message) => message,
+        };
+
+        match message {
+            // Let the user know if one of their hooks failed.
+            Message::HookFinished(result) => {
+                if let Err(e) = result {
+                    self.status_message = Some(e.to_string());
+                }
+                iced::Command::none()
+            }
+
+            // Show a banner if a newer release is available.
+            Message::UpdateChecked(r) => {
+                match r {
+                    Ok(release) if release.is_newer() => self.available_update = Some(release),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("{} Failed to check for updates: {e}", local_now_fmt()),
+                }
+                iced::Command::none()
+            }
+
+            // Open the release page so the user can choose to update.
+            Message::OpenUpdatePage => {
+                if let Some(release) = &self.available_update {
+                    open::that(&release.html_url).unwrap_or_else(|e| {
+                        eprintln!("{} Failed to open the release page: {e}", local_now_fmt());
+                    });
+                }
+                iced::Command::none()
+            }
+
+            // Hide the update banner.
+            Message::DismissUpdate => {
+                self.available_update = None;
+                iced::Command::none()
+            }
+
+            // Show the latest progress of a transfer or hash.
+            Message::ProgressChanged(nonce, p) => self.update_progress_changed(nonce, p),
+
+            // Copy a hash to the clipboard.
+            Message::CopyHash(hash) => iced::clipboard::write(hash),
+
+            // Handle an event that iced did not handle itself.
+            // This is used to allow for custom exit handling in this instance.
+            Message::UnhandledEvent(event) => match event {
+                iced::Event::Window(id, window::Event::CloseRequested) => {
+                    if id != window::Id::MAIN {
+                        // Detail windows can be closed immediately.
+                        self.detail_windows.remove(&id);
+                        window::close(id)
+                    } else if !self.safely_closing {
+                        self.safely_close(CloseType::Application)
+                    } else {
+                        // Closing a second time cancels the safe-close operation.
+                        self.close_all_windows()
+                    }
+                }
+
+                // Publish files dropped onto the main window. Each file arrives as its own event.
+                iced::Event::Window(window::Id::MAIN, window::Event::FileDropped(path)) => {
+                    self.handle_message(Message::Publish(PublishMessage::FileDropped(path)))
+                }
+                _ => iced::Command::none(),
+            },
+
+            // Open a transfer in its own window.
+            Message::OpenTransferWindow(nonce, transfer_type) => {
+                self.open_detail_window(DetailWindow::Transfer(nonce, transfer_type))
+            }
+
+            // Open the status log in its own window.
+            Message::OpenStatusLogWindow => self.open_detail_window(DetailWindow::StatusLog),
+
+            // Rename a known peer.
+            Message::PeerNameChanged(fingerprint, name) => {
+                self.known_peers.set_name(&fingerprint, &name);
+                iced::Command::none()
+            }
+
+            // Save the names given to known peers.
+            Message::PeerNameSubmitted => {
+                if let Err(e) = self.known_peers.save() {
+                    self.status_message = Some(format!("Failed to save known peers: {e}"));
+                }
+                iced::Command::none()
+            }
+
+            // Handle the passphrase input being changed.
+            Message::PassphraseInputChanged(passphrase) => {
+                if let Some(connected_state) = self.connection.connected_mut() {
+                    connected_state.passphrase_input = passphrase;
+                }
+                iced::Command::none()
+            }
+
+            // Reconnect when the server goes away without us leaving.
+            Message::ServerConnectionLost(e) => self.update_server_connection_lost(&e),
+
+            // Ask for confirmation before leaving the server if there is active work.
+            Message::LeaveServerClicked => {
+                let leave_impact = self.leave_impact();
+                if let Some(connected_state) = self.connection.connected_mut() {
+                    if !leave_impact.is_empty() {
+                        connected_state.confirming_leave = true;
+                        return iced::Command::none();
+                    }
+                }
+                self.safely_close(CloseType::Connections)
+            }
+
+            // Wait for the active transfers to finish before leaving the server.
+            Message::LeaveWhenTransfersDone => {
+                let has_transferring = self.has_transferring();
+                if let Some(connected_state) = self.connection.connected_mut() {
+                    connected_state.confirming_leave = false;
+                    if has_transferring {
+                        connected_state.leave_when_done = true;
+                        return iced::Command::none();
+                    }
+                }
+                self.safely_close(CloseType::Connections)
+            }
+
+            // Stay connected to the server.
+            Message::CancelLeaveServer => {
+                if let Some(connected_state) = self.connection.connected_mut() {
+                    connected_state.confirming_leave = false;
+                    connected_state.leave_when_done = false;
+                }
+                iced::Command::none()
+            }
+
+            // Leave the server and disconnect.
+            Message::SafelyLeaveServer => self.safely_close(CloseType::Connections),
+
+            // All async actions to leave a server have completed.
+            Message::LeftServer => {
+                self.safely_closing = false;
+                self.connection.state = ConnectionState::Disconnected;
+                iced::Command::none()
+            }
+
+            // Exit the application immediately.
+            Message::ForceExit => self.close_all_windows(),
+
+            // Every other message belongs to one of the controllers above.
+            message => unreachable!("{message:?} was not handled by any controller"),
+        }
+    }
+
     /// Draw the disconnected page with a server address input and connect button.
     fn view_disconnected_page(&self) -> iced::Element<Message> {
         let mut server_address = widget::text_input(
@@ -780,7 +1749,30 @@ impl AppState {
             &self.options.server_address,
         );
 
+        let mut fallback_servers = widget::text_input(
+            "Fallback servers to try in order if the server can't be reached, separated by commas",
+            &self.options.fallback_servers_text,
+        );
+
+        let mut additional_servers = widget::text_input(
+            "Other servers to also publish to and download from, separated by commas",
+            &self.options.additional_servers_text,
+        );
+
+        let mut auth_token = widget::text_input(
+            "Access token, if the server requires one",
+            &self.options.auth_token,
+        )
+        .secure(true);
+
+        let mut room = widget::text_input(
+            "Room to share in, or leave empty for the shared room",
+            &self.options.room,
+        );
+
         let mut connect_button = widget::button("Connect");
+        let mut export_settings_button = widget::button(widget::text("Export settings").size(12));
+        let mut import_settings_button = widget::button(widget::text("Import settings").size(12));
         let mut port_forward_text = widget::text_input(
             "External port forward. E.g., 8888",
             &self.options.port_forwarding_text,
@@ -789,8 +1781,25 @@ impl AppState {
         if !self.modal {
             server_address = server_address
                 .on_input(Message::ServerAddressChanged)
-                .on_submit(Message::ConnectClicked);
-            connect_button = connect_button.on_press(Message::ConnectClicked);
+                .on_submit(Message::Connection(ConnectionMessage::ConnectClicked));
+            fallback_servers = fallback_servers
+                .on_input(Message::FallbackServersChanged)
+                .on_submit(Message::Connection(ConnectionMessage::ConnectClicked));
+            additional_servers = additional_servers
+                .on_input(Message::AdditionalServersChanged)
+                .on_submit(Message::Connection(ConnectionMessage::ConnectClicked));
+            auth_token = auth_token
+                .on_input(Message::AuthTokenChanged)
+                .on_submit(Message::Connection(ConnectionMessage::ConnectClicked));
+            room = room
+                .on_input(Message::RoomChanged)
+                .on_submit(Message::Connection(ConnectionMessage::ConnectClicked));
+            connect_button =
+                connect_button.on_press(Message::Connection(ConnectionMessage::ConnectClicked));
+            export_settings_button =
+                export_settings_button.on_press(Message::ExportSettingsClicked);
+            import_settings_button =
+                import_settings_button.on_press(Message::ImportSettingsClicked);
 
             if let PortMappingGuiOptions::PortForwarding(_) = &self.options.port_mapping {
                 port_forward_text = port_forward_text.on_input(Message::PortForwardTextChanged);
@@ -842,40 +1851,424 @@ impl AppState {
         .spacing(6)
         .align_items(iced::Alignment::Center);
 
+        // Create text inputs for verifying the server without trusting its first certificate,
+        // and for authenticating to servers that require a client certificate.
+        let server_trust = widget::column!(
+            widget::row!(
+                widget::text("Server CA file:"),
+                widget::text_input(
+                    "Path to CA certificates, or leave empty",
+                    &self.options.server_ca_text,
+                )
+                .on_input(Message::ServerCaChanged),
+            )
+            .spacing(6)
+            .align_items(iced::Alignment::Center),
+            widget::row!(
+                widget::text("Server fingerprint:"),
+                widget::text_input(
+                    "SHA-256 of the server certificate, or leave empty",
+                    &self.options.server_fingerprint_text,
+                )
+                .on_input(Message::ServerFingerprintChanged),
+            )
+            .spacing(6)
+            .align_items(iced::Alignment::Center),
+            widget::row!(
+                widget::text("Client certificate:"),
+                widget::text_input(
+                    "Path to a certificate, if the server requires one",
+                    &self.options.client_cert_text,
+                )
+                .on_input(Message::ClientCertChanged),
+                widget::text_input("Path to its private key", &self.options.client_key_text,)
+                    .on_input(Message::ClientKeyChanged),
+            )
+            .spacing(6)
+            .align_items(iced::Alignment::Center),
+        )
+        .spacing(6);
+
         widget::container(
             widget::column!(
                 widget::vertical_space(),
                 server_address,
+                fallback_servers,
+                additional_servers,
+                auth_token,
+                room,
                 connect_button,
+                self.view_server_identity_warning(),
                 widget::vertical_space().height(iced::Length::FillPortion(2)),
                 choose_port_mapping,
                 gateway,
-            )
-            .align_items(iced::Alignment::Center)
-            .spacing(6),
-        )
-        .width(iced::Length::Fill)
-        .height(iced::Length::Fill)
-        .center_x()
-        .center_y()
-        .padding(12)
-        .into()
-    }
-
-    /// Draw the connecting page with a spinner.
-    fn view_connecting_page<'a>(
-        start: Instant,
-        tick: Instant,
-        max_duration: Duration,
-    ) -> iced::Element<'a, Message> {
-        let fraction_waited = (tick - start)
-            .as_secs_f32()
-            .div(max_duration.as_secs_f32())
-            .min(1.);
-        let spinner =
-            widget::container::Container::new(widget::progress_bar(0.0..=1., fraction_waited))
-                .width(iced::Length::Fill)
-                .height(iced::Length::Fill)
+                server_trust,
+                self.view_download_directory_settings(),
+                widget::row!(
+                    widget::text("Maximum download size:"),
+                    widget::text_input(
+                        "E.g., 4GB, or leave empty for no limit",
+                        &self.options.max_download_size_text,
+                    )
+                    .on_input(Message::MaxDownloadSizeChanged),
+                )
+                .spacing(6)
+                .align_items(iced::Alignment::Center),
+                self.view_event_hooks_settings(),
+                self.view_bandwidth_settings(),
+                widget::row!(
+                    widget::text("Internal port range:"),
+                    widget::text_input(
+                        "E.g., 50000-50100, or leave empty for any port",
+                        &self.options.internal_port_range_text,
+                    )
+                    .on_input(Message::InternalPortRangeChanged),
+                )
+                .spacing(6)
+                .align_items(iced::Alignment::Center),
+                self.view_port_conflict_warning(),
+                widget::row!(
+                    widget::radio(
+                        "Reuse peer connections",
+                        false,
+                        Some(self.options.disable_connection_reuse),
+                        Message::ConnectionReuseChanged,
+                    ),
+                    widget::radio(
+                        "Always connect fresh for each transfer",
+                        true,
+                        Some(self.options.disable_connection_reuse),
+                        Message::ConnectionReuseChanged,
+                    ),
+                )
+                .spacing(32),
+                widget::row!(
+                    widget::radio(
+                        "Don't seed downloads",
+                        false,
+                        Some(self.options.seed_downloads),
+                        Message::SeedDownloadsChanged,
+                    ),
+                    widget::radio(
+                        "Publish downloads once they're verified",
+                        true,
+                        Some(self.options.seed_downloads),
+                        Message::SeedDownloadsChanged,
+                    ),
+                )
+                .spacing(32),
+                widget::row!(
+                    widget::radio(
+                        "Don't check for updates",
+                        false,
+                        Some(self.options.check_for_updates),
+                        Message::CheckForUpdatesChanged,
+                    ),
+                    widget::radio(
+                        "Check for updates on startup",
+                        true,
+                        Some(self.options.check_for_updates),
+                        Message::CheckForUpdatesChanged,
+                    ),
+                )
+                .spacing(32),
+                widget::row!(
+                    widget::radio(
+                        "Hash publishes with SHA-256",
+                        HashAlgorithm::Sha256,
+                        Some(self.options.hash_algorithm),
+                        Message::HashAlgorithmChanged,
+                    ),
+                    widget::radio(
+                        "Hash publishes with BLAKE3 (faster, newer peers only)",
+                        HashAlgorithm::Blake3,
+                        Some(self.options.hash_algorithm),
+                        Message::HashAlgorithmChanged,
+                    ),
+                )
+                .spacing(32),
+                widget::row!(
+                    widget::radio(
+                        "Cubic congestion control",
+                        CongestionController::Cubic,
+                        Some(self.options.congestion_controller),
+                        Message::CongestionControllerChanged,
+                    ),
+                    widget::radio(
+                        "BBR congestion control (faster on long, lossy links)",
+                        CongestionController::Bbr,
+                        Some(self.options.congestion_controller),
+                        Message::CongestionControllerChanged,
+                    ),
+                )
+                .spacing(32),
+                self.view_signing_settings(),
+                self.view_appearance_settings(),
+                widget::row!(export_settings_button, import_settings_button).spacing(6),
+            )
+            .align_items(iced::Alignment::Center)
+            .spacing(6),
+        )
+        .width(iced::Length::Fill)
+        .height(iced::Length::Fill)
+        .center_x()
+        .center_y()
+        .padding(12)
+        .into()
+    }
+
+    /// Draw the publish signing and trusted publisher settings.
+    fn view_signing_settings(&self) -> iced::Element<Message> {
+        let fingerprint = match (&self.publisher_key, self.options.sign_publishes) {
+            (Some(key), true) => format!("Signing as {}", key.fingerprint()),
+            _ => String::new(),
+        };
+        widget::column!(
+            widget::row!(
+                widget::radio(
+                    "Don't sign publishes",
+                    false,
+                    Some(self.options.sign_publishes),
+                    Message::SignPublishesChanged,
+                ),
+                widget::radio(
+                    "Sign publishes",
+                    true,
+                    Some(self.options.sign_publishes),
+                    Message::SignPublishesChanged,
+                ),
+                widget::text(fingerprint).size(12),
+            )
+            .spacing(32)
+            .align_items(iced::Alignment::Center),
+            widget::text_input(
+                "Trusted publisher fingerprints, separated by commas. Leave empty to trust any publisher",
+                &self.options.trusted_publishers_text,
+            )
+            .on_input(Message::TrustedPublishersChanged),
+        )
+        .spacing(6)
+        .into()
+    }
+
+    /// Draw the UI scale and row density settings.
+    fn view_appearance_settings(&self) -> iced::Element<Message> {
+        widget::row!(
+            widget::text(format!("UI scale: {}%", self.options.ui_scale.0)),
+            widget::slider(
+                UiScale::RANGE,
+                self.options.ui_scale.0,
+                Message::UiScaleChanged
+            )
+            .step(5u16)
+            .width(200),
+            widget::radio(
+                "Comfortable rows",
+                false,
+                Some(self.options.compact_rows),
+                Message::CompactRowsChanged,
+            ),
+            widget::radio(
+                "Compact rows",
+                true,
+                Some(self.options.compact_rows),
+                Message::CompactRowsChanged,
+            ),
+        )
+        .spacing(32)
+        .align_items(iced::Alignment::Center)
+        .into()
+    }
+
+    /// Draw an actionable warning when the internal port range is in use by other sockets.
+    fn view_port_conflict_warning(&self) -> iced::Element<Message> {
+        if !self.connection.port_conflict {
+            return widget::row!().into();
+        }
+
+        let mut use_random_port =
+            widget::button(widget::text("Use a random port instead").size(12));
+        if !self.modal {
+            use_random_port =
+                use_random_port.on_press(Message::Connection(ConnectionMessage::UseRandomPort));
+        }
+        widget::row!(
+            widget::text(
+                "The internal port range is in use. Close any other file_yeet instance using it, or:"
+            )
+            .style(ERROR_RED_COLOR),
+            use_random_port,
+        )
+        .spacing(6)
+        .align_items(iced::Alignment::Center)
+        .into()
+    }
+
+    /// Draw a prompt to accept a server's new certificate after it refused a connection.
+    fn view_server_identity_warning(&self) -> iced::Element<Message> {
+        let Some(changed) = &self.connection.server_identity_changed else {
+            return widget::row!().into();
+        };
+
+        let mut accept = widget::button(widget::text("Trust the new certificate").size(12));
+        if !self.modal {
+            accept = accept.on_press(Message::Connection(ConnectionMessage::AcceptServerIdentity));
+        }
+        widget::column!(
+            widget::text(format!(
+                "{} presented a different certificate than before. Only continue if you know the server was reinstalled.",
+                changed.server
+            ))
+            .style(ERROR_RED_COLOR),
+            widget::text(format!(
+                "Previously: {}\nNow: {}",
+                changed.expected, changed.presented
+            ))
+            .size(12),
+            accept,
+        )
+        .spacing(6)
+        .align_items(iced::Alignment::Center)
+        .into()
+    }
+
+    /// Draw the editor for the commands run on transfer events.
+    fn view_event_hooks_settings(&self) -> iced::Element<Message> {
+        widget::column(
+            [
+                TransferEvent::DownloadComplete,
+                TransferEvent::UploadComplete,
+                TransferEvent::PublishFailed,
+            ]
+            .into_iter()
+            .map(|event| {
+                widget::row!(
+                    widget::text(format!("Run on {event}:")),
+                    widget::text_input(
+                        "Command, or leave empty to run nothing",
+                        self.options.event_hooks.command(event).unwrap_or_default(),
+                    )
+                    .on_input(move |command| Message::EventHookChanged(event, command)),
+                )
+                .spacing(6)
+                .align_items(iced::Alignment::Center)
+                .into()
+            }),
+        )
+        .spacing(6)
+        .into()
+    }
+
+    /// Draw the fields for limiting the bandwidth of transfers.
+    fn view_bandwidth_settings(&self) -> iced::Element<Message> {
+        widget::column(
+            [
+                (BandwidthLimitKind::Upload, &self.options.upload_limit_text),
+                (
+                    BandwidthLimitKind::Download,
+                    &self.options.download_limit_text,
+                ),
+                (
+                    BandwidthLimitKind::PerTransfer,
+                    &self.options.transfer_limit_text,
+                ),
+            ]
+            .into_iter()
+            .map(|(kind, text)| {
+                widget::row!(
+                    widget::text(format!("{kind}:")),
+                    widget::text_input(
+                        "Bytes per second, e.g., 2MB, or leave empty for no limit",
+                        text
+                    )
+                    .on_input(move |text| Message::BandwidthLimitChanged(kind, text)),
+                )
+                .spacing(6)
+                .align_items(iced::Alignment::Center)
+                .into()
+            }),
+        )
+        .spacing(6)
+        .into()
+    }
+
+    /// Draw the settings for saving downloads to a default directory, including the rules editor.
+    fn view_download_directory_settings(&self) -> iced::Element<Message> {
+        let settings = &self.options.download_directory;
+        let use_default = Some(settings.use_default);
+
+        let choose_mode = widget::row!(
+            widget::radio(
+                "Ask where to save downloads",
+                false,
+                use_default,
+                Message::UseDefaultDirectoryChanged,
+            ),
+            widget::radio(
+                "Save downloads to a default directory",
+                true,
+                use_default,
+                Message::UseDefaultDirectoryChanged,
+            ),
+        )
+        .spacing(32);
+
+        if !settings.use_default {
+            return choose_mode.into();
+        }
+
+        let default_directory = widget::row!(
+            widget::text("Default directory:"),
+            widget::text_input(
+                "Leave empty for the system downloads directory",
+                &settings.default_directory,
+            )
+            .on_input(Message::DefaultDirectoryChanged),
+        )
+        .spacing(6)
+        .align_items(iced::Alignment::Center);
+
+        // One editable row per rule, in the order they are checked.
+        let rules = settings.rules.iter().enumerate().map(|(i, rule)| {
+            widget::row!(
+                widget::text_input("Hash prefix", &rule.hash_prefix)
+                    .on_input(move |s| Message::DownloadRulePrefixChanged(i, s))
+                    .width(iced::Length::FillPortion(1)),
+                widget::text_input("Directory", &rule.directory)
+                    .on_input(move |s| Message::DownloadRuleDirectoryChanged(i, s))
+                    .width(iced::Length::FillPortion(3)),
+                widget::button(widget::text("Remove").size(12))
+                    .on_press(Message::DownloadRuleRemoved(i)),
+            )
+            .spacing(6)
+            .align_items(iced::Alignment::Center)
+            .into()
+        });
+
+        widget::column!(
+            choose_mode,
+            default_directory,
+            widget::column(rules).spacing(6),
+            widget::button(widget::text("Add rule").size(12)).on_press(Message::DownloadRuleAdded),
+        )
+        .spacing(6)
+        .into()
+    }
+
+    /// Draw the connecting page with a spinner.
+    fn view_connecting_page<'a>(
+        start: Instant,
+        tick: Instant,
+        max_duration: Duration,
+    ) -> iced::Element<'a, Message> {
+        let fraction_waited = (tick - start)
+            .as_secs_f32()
+            .div(max_duration.as_secs_f32())
+            .min(1.);
+        let spinner =
+            widget::container::Container::new(widget::progress_bar(0.0..=1., fraction_waited))
+                .width(iced::Length::Fill)
+                .height(iced::Length::Fill)
                 .padding(24)
                 .center_x()
                 .center_y();
@@ -883,9 +2276,59 @@ impl AppState {
         Element::<'a>::from(spinner)
     }
 
+    /// Draw the page shown while waiting to retry a failed auto-connect.
+    fn view_retrying_page(
+        &self,
+        attempt: u32,
+        retry_at: Instant,
+        tick: Instant,
+    ) -> iced::Element<Message> {
+        let remaining = retry_at.saturating_duration_since(tick).as_secs() + 1;
+        widget::container(
+            widget::column!(
+                widget::text(format!(
+                    "Retrying connection in {remaining}s (attempt {attempt} of {})",
+                    self.options.auto_connect_retry.max_retries
+                ))
+                .size(24),
+                widget::button("Cancel")
+                    .on_press(Message::Connection(ConnectionMessage::CancelRetry)),
+            )
+            .align_items(iced::Alignment::Center)
+            .spacing(12),
+        )
+        .width(iced::Length::Fill)
+        .height(iced::Length::Fill)
+        .center_x()
+        .center_y()
+        .padding(12)
+        .into()
+    }
+
+    /// Draw who signed the publish of a download, flagging publishers that aren't trusted.
+    fn draw_publisher_signature<'b>(
+        transfer: &Transfer,
+        trusted_publishers: &[&str],
+    ) -> iced::Element<'b, Message> {
+        let text = widget::text(transfer.publisher_fingerprint.as_ref().map_or_else(
+            || "Unsigned".to_owned(),
+            |fingerprint| format!("Signed by {}", short_fingerprint(fingerprint)),
+        ))
+        .size(12);
+        if is_trusted_publisher(transfer, trusted_publishers) {
+            text.into()
+        } else {
+            text.style(iced::theme::Text::Color(ERROR_RED_COLOR)).into()
+        }
+    }
+
     fn draw_transfers<'a, 'b, I>(
         transfers: I,
         transfer_type: FileYeetCommandType,
+        max_download_size: Option<u64>,
+        density: RowDensity,
+        known_peers: &KnownPeers,
+        trusted_publishers: &[&str],
     ) -> iced::Element<'b, Message>
     where
         I: Iterator<Item = &'a Transfer>,
@@ -894,13 +2337,33 @@ impl AppState {
             let progress = match &t.progress {
                 TransferProgress::Connecting => Element::from(widget::text("Connecting...")),
                 TransferProgress::Consent(_) => widget::row!(
-                    widget::text(format!(
-                        "Accept download of size {}",
-                        humanize_bytes(t.file_size)
+                    // Let the user know whether they have transferred with this peer before.
+                    widget::text(t.peer_fingerprint.as_ref().map_or_else(
+                        || "Unknown peer".to_owned(),
+                        |fingerprint| known_peers.describe(fingerprint),
                     ))
+                    .size(12),
+                    Self::draw_publisher_signature(t, trusted_publishers),
+                    // Flag offers larger than the user's maximum download size.
+                    if let Some(max) = max_download_size.filter(|&max| t.file_size > max) {
+                        widget::text(format!(
+                            "Accept download of size {}? Exceeds your maximum of {}",
+                            humanize_bytes(t.file_size),
+                            humanize_bytes(max),
+                        ))
+                        .style(iced::theme::Text::Color(ERROR_RED_COLOR))
+                    } else {
+                        widget::text(format!(
+                            "Accept download of size {}",
+                            humanize_bytes(t.file_size)
+                        ))
+                    }
                     .width(iced::Length::Fill),
-                    widget::button(widget::text("Accept").size(12))
-                        .on_press(Message::AcceptDownload(t.nonce)),
+                    // Only accept downloads signed by a trusted publisher when any are trusted.
+                    widget::button(widget::text("Accept").size(12)).on_press_maybe(
+                        is_trusted_publisher(t, trusted_publishers)
+                            .then_some(Message::Download(DownloadMessage::Accept(t.nonce)))
+                    ),
                     widget::button(widget::text("Cancel").size(12))
                         .on_press(Message::CancelTransfer(t.nonce, transfer_type))
                 )
@@ -940,51 +2403,105 @@ impl AppState {
                 }
             };
 
-            widget::container(widget::column!(
-                progress,
-                widget::text(&t.hash_hex).size(12),
-                widget::row!(
-                    widget::text(&t.peer_string).size(12),
-                    widget::horizontal_space(),
-                    widget::text(&t.path.to_string_lossy()).size(12),
+            widget::container(
+                widget::column!(
+                    progress,
+                    widget::row!(
+                        widget::text(&t.hash_hex).size(12),
+                        widget::horizontal_space(),
+                        widget::button(widget::text("Pop out").size(12))
+                            .on_press(Message::OpenTransferWindow(t.nonce, transfer_type)),
+                    )
+                    .spacing(6),
+                    widget::row!(
+                        widget::text(peer_label(t, known_peers)).size(12),
+                        widget::horizontal_space(),
+                        widget::text(&t.path.to_string_lossy()).size(12),
+                    )
+                    .spacing(6),
                 )
-                .spacing(6),
-            ))
+                .spacing(density.spacing),
+            )
             .style(iced::theme::Container::Box)
             .width(iced::Length::Fill)
-            .padding([6, 12, 6, 6]) // Extra padding on the right because of optional scrollbar.
+            .padding(density.padding)
             .into()
         }))
-        .spacing(6)
+        .spacing(density.spacing)
         .into()
     }
 
-    fn draw_pubs<'a>(publishes: &[PublishItem]) -> iced::Element<'a, Message> {
+    fn draw_pubs<'a>(publishes: &[PublishItem], density: RowDensity) -> iced::Element<'a, Message> {
         let publish_views = publishes.iter().map(|pi| {
+            // Ask whether the uploads started by this publish should be cancelled with it.
+            if let Some(active_uploads) = pi.confirming_cancel {
+                return widget::container(
+                    widget::row!(
+                        widget::column!(
+                            widget::text(format!(
+                                "{active_uploads} upload{} still in progress",
+                                if active_uploads == 1 { " is" } else { "s are" }
+                            )),
+                            widget::text(&pi.path.to_string_lossy()).size(12),
+                        )
+                        .width(iced::Length::Fill),
+                        widget::button(widget::text("Cancel uploads").size(12)).on_press(
+                            Message::Publish(PublishMessage::ConfirmCancel(pi.nonce, true))
+                        ),
+                        widget::button(widget::text("Let them finish").size(12)).on_press(
+                            Message::Publish(PublishMessage::ConfirmCancel(pi.nonce, false))
+                        ),
+                        widget::button(widget::text("Keep publishing").size(12))
+                            .on_press(Message::Publish(PublishMessage::DismissCancel(pi.nonce))),
+                    )
+                    .align_items(iced::Alignment::Center)
+                    .spacing(12),
+                )
+                .style(iced::theme::Container::Box)
+                .width(iced::Length::Fill)
+                .padding(density.padding)
+                .into();
+            }
+
             widget::container(
                 match &pi.state {
-                    PublishState::Hashing(progress) => widget::row!(
+                    PublishState::Hashing(_, progress) => widget::row!(
                         widget::column!(
                             widget::row!(
                                 widget::text("Hashing..."),
-                                widget::progress_bar(0.0..=1., *progress.read().unwrap()),
+                                widget::progress_bar(0.0..=1., *progress),
                             )
                             .spacing(6),
                             widget::text(&pi.path.to_string_lossy()).size(12),
                         )
                         .spacing(6),
-                        widget::button("Cancel").on_press(Message::CancelPublish(pi.nonce))
+                        widget::button("Cancel")
+                            .on_press(Message::Publish(PublishMessage::Cancel(pi.nonce)))
                     ),
                     PublishState::Publishing(p) => widget::row!(
                         widget::column!(
-                            widget::text(&p.hash_hex).size(12),
-                            widget::text(&pi.path.to_string_lossy()).size(12)
+                            widget::text(match pi.remaining_downloads() {
+                                Some(1) => format!("{} (1 download left)", p.hash_hex),
+                                Some(left) => format!("{} ({left} downloads left)", p.hash_hex),
+                                None => p.hash_hex.clone(),
+                            })
+                            .size(12),
+                            widget::text(&pi.path.to_string_lossy()).size(12),
+                            widget::text(format!(
+                                "Uploads: {} complete, {} failed, {} sent",
+                                pi.upload_statistics.succeeded,
+                                pi.upload_statistics.failed,
+                                humanize_bytes(pi.upload_statistics.bytes_sent),
+                            ))
+                            .size(12),
                         ),
                         widget::horizontal_space(),
                         widget::button(widget::text("Copy Hash").size(12))
                             .on_press(Message::CopyHash(p.hash_hex.clone())),
+                        widget::button(widget::text("Copy link").size(12))
+                            .on_press(Message::Publish(PublishMessage::CopyLink(pi.nonce))),
                         widget::button(widget::text("Cancel").size(12))
-                            .on_press(Message::CancelPublish(pi.nonce))
+                            .on_press(Message::Publish(PublishMessage::Cancel(pi.nonce)))
                     ),
                     PublishState::Failure(e) => widget::row!(
                         widget::column!(
@@ -994,7 +2511,7 @@ impl AppState {
                         )
                         .width(iced::Length::Fill),
                         widget::button(widget::text("Remove").size(12))
-                            .on_press(Message::CancelPublish(pi.nonce))
+                            .on_press(Message::Publish(PublishMessage::Cancel(pi.nonce)))
                     ),
                     PublishState::Cancelled => widget::row!(
                         widget::column!(
@@ -1003,7 +2520,7 @@ impl AppState {
                         )
                         .width(iced::Length::Fill),
                         widget::button(widget::text("Remove").size(12))
-                            .on_press(Message::CancelPublish(pi.nonce))
+                            .on_press(Message::Publish(PublishMessage::Cancel(pi.nonce)))
                     ),
                 }
                 .align_items(iced::Alignment::Center)
@@ -1011,11 +2528,13 @@ impl AppState {
             )
             .style(iced::theme::Container::Box)
             .width(iced::Length::Fill)
-            .padding([6, 12, 6, 6]) // Extra padding on the right because of optional scrollbar.
+            .padding(density.padding)
             .into()
         });
 
-        widget::column(publish_views).spacing(6).into()
+        widget::column(publish_views)
+            .spacing(density.spacing)
+            .into()
     }
 
     /// Draw the main application controls when connected to a server.
@@ -1029,40 +2548,124 @@ impl AppState {
 
         // Define the elements that we want to be modal aware first.
         let mut publish_button = widget::button("Publish");
+        let mut max_downloads_text_input =
+            widget::text_input("Download limit", &self.publish.max_downloads_input).width(110);
         let mut download_button = widget::button("Download");
-        let mut hash_text_input = widget::text_input("Hash", &connected_state.hash_input);
+        let mut hash_text_input =
+            widget::text_input("Hash or fyeet:// link", &self.download.hash_input)
+                .width(iced::Length::FillPortion(2));
+        let mut passphrase_text_input = widget::text_input(
+            "Passphrase for new publishes and downloads, if any",
+            &connected_state.passphrase_input,
+        )
+        .secure(true)
+        .width(iced::Length::FillPortion(1));
         let mut leave_server_button = widget::button(widget::text("Leave").size(12));
 
         // Disable the inputs while a modal is open.
         if !self.modal {
-            publish_button = publish_button.on_press(Message::PublishClicked);
-            hash_text_input = hash_text_input.on_input(Message::HashInputChanged);
-            leave_server_button = leave_server_button.on_press(Message::SafelyLeaveServer);
-
-            // Enable the download button if the hash is valid.
-            if connected_state.hash_input.len() == file_yeet_shared::HASH_BYTE_COUNT << 1
-                && faster_hex::hex_check(connected_state.hash_input.as_bytes())
-            {
-                download_button = download_button.on_press(Message::SubscribeStarted);
-                hash_text_input = hash_text_input.on_submit(Message::SubscribeStarted);
+            max_downloads_text_input = max_downloads_text_input.on_input(|input| {
+                Message::Publish(PublishMessage::MaxDownloadsInputChanged(input))
+            });
+
+            // Enable the publish button if the download limit is empty or a positive number.
+            if publish::parse_max_downloads_input(&self.publish.max_downloads_input).is_ok() {
+                publish_button = publish_button.on_press(Message::Publish(PublishMessage::Clicked));
             }
+            hash_text_input = hash_text_input
+                .on_input(|input| Message::Download(DownloadMessage::HashInputChanged(input)));
+            passphrase_text_input = passphrase_text_input.on_input(Message::PassphraseInputChanged);
+            leave_server_button = leave_server_button.on_press(Message::LeaveServerClicked);
+
+            // Enable the download button if the hash or share link is valid.
+            if download::parse_hash_input(&self.download.hash_input).is_ok() {
+                download_button =
+                    download_button.on_press(Message::Download(DownloadMessage::Started));
+                hash_text_input =
+                    hash_text_input.on_submit(Message::Download(DownloadMessage::Started));
+            }
+        }
+
+        // Name the additional servers alongside the server we connected to first.
+        let mut server_label = if connected_state
+            .capabilities
+            .contains(ServerCapabilities::EPHEMERAL)
+        {
+            format!("{} (ephemeral)", connected_state.server_address)
+        } else {
+            connected_state.server_address.clone()
+        };
+        if !connected_state.additional_servers.is_empty() {
+            let additional = connected_state
+                .additional_servers
+                .iter()
+                .map(|s| s.server_address.as_str())
+                .collect::<Vec<_>>();
+            server_label = format!("{server_label}, also {}", additional.join(", "));
         }
 
         // Define a header exposing the server address and how the server sees us (our IP address).
         let header = widget::row!(
             widget::text("Server address:"),
-            widget::text(&self.options.server_address),
-            widget::button(widget::text("Copy").size(12)).on_press(Message::CopyServer),
+            widget::text(server_label),
+            widget::button(widget::text("Copy").size(12))
+                .on_press(Message::Connection(ConnectionMessage::CopyServer)),
             leave_server_button,
+            widget::button(widget::text("Status log").size(12))
+                .on_press(Message::OpenStatusLogWindow),
+            widget::button(widget::text("Sync publishes").size(12))
+                .on_press(Message::Publish(PublishMessage::SyncClicked)),
             widget::horizontal_space(),
+            widget::text(format!(
+                "Local port: {}",
+                connected_state
+                    .local_port
+                    .map_or_else(|| "unknown".to_owned(), |p| p.to_string())
+            )),
+            widget::text(
+                match (
+                    &self.connection.port_mapping,
+                    connected_state.port_mapping_retry
+                ) {
+                    (Some(mapping), _) => format!("Mapped port: {}", mapping.external_port()),
+                    (None, Some(_)) => "Port mapping: retrying".to_owned(),
+                    (None, None) => String::new(),
+                }
+            ),
             widget::text("Our External Address:"),
             widget::text(&connected_state.external_address),
         )
         .align_items(iced::alignment::Alignment::Center)
         .spacing(6);
 
-        // Hash input and download button.
-        let download_input = widget::row!(hash_text_input, download_button).spacing(6);
+        // Replace the header with a confirmation prompt while the user decides whether to leave.
+        let header: Element<Message> = if connected_state.confirming_leave {
+            widget::row!(
+                widget::text(self.leave_impact()).width(iced::Length::Fill),
+                widget::button(widget::text("Leave").size(12)).on_press(Message::SafelyLeaveServer),
+                widget::button(widget::text("Leave after transfers").size(12))
+                    .on_press(Message::LeaveWhenTransfersDone),
+                widget::button(widget::text("Stay").size(12)).on_press(Message::CancelLeaveServer),
+            )
+            .align_items(iced::alignment::Alignment::Center)
+            .spacing(12)
+            .into()
+        } else if connected_state.leave_when_done {
+            widget::row!(
+                widget::text("Leaving the server once active transfers finish...")
+                    .width(iced::Length::Fill),
+                widget::button(widget::text("Stay").size(12)).on_press(Message::CancelLeaveServer),
+            )
+            .align_items(iced::alignment::Alignment::Center)
+            .spacing(12)
+            .into()
+        } else {
+            header.into()
+        };
+
+        // Hash and passphrase inputs and the download button.
+        let download_input =
+            widget::row!(hash_text_input, passphrase_text_input, download_button).spacing(6);
 
         // Radio buttons for choosing the transfer view.
         let transfer_view_choice = widget::row(
@@ -1080,55 +2683,71 @@ impl AppState {
         )
         .spacing(12);
 
-        // Create a view of transfers.
+        // Create a view of transfers, spaced according to the user's row density.
+        let density = RowDensity::new(self.options.compact_rows);
+        let section_spacing = if self.options.compact_rows { 6 } else { 12 };
+        let trusted_publishers = self.options.trusted_publishers();
         let transfer_content = match connected_state.transfer_view {
             // Create a list of published files and uploads.
             TransferView::Publishes => {
                 match (
-                    connected_state.publishes.is_empty(),
+                    self.publish.publishes.is_empty(),
                     connected_state.uploads.is_empty(),
                 ) {
                     // Both are empty, show nothing.
                     (true, true) => iced::widget::space::Space::new(0, 0).into(),
 
                     // Only uploads are empty, show publishes.
-                    (false, true) => Self::draw_pubs(&connected_state.publishes),
+                    (false, true) => Self::draw_pubs(&self.publish.publishes, density),
 
                     // Only publishes are empty, show uploads.
                     (true, false) => Self::draw_transfers(
                         connected_state.uploads.iter(),
                         FileYeetCommandType::Pub,
+                        self.options.max_download_size,
+                        density,
+                        &self.known_peers,
+                        &trusted_publishers,
                     ),
 
                     // Show both publishes and uploads. Separate them with a line.
                     (false, false) => widget::column!(
-                        Self::draw_pubs(&connected_state.publishes),
+                        Self::draw_pubs(&self.publish.publishes, density),
                         horizontal_line(),
                         Self::draw_transfers(
                             connected_state.uploads.iter(),
                             FileYeetCommandType::Pub,
+                            self.options.max_download_size,
+                            density,
+                            &self.known_peers,
+                            &trusted_publishers,
                         ),
                     )
-                    .spacing(12)
+                    .spacing(section_spacing)
                     .into(),
                 }
             }
 
             // Create a list of download attempts.
-            TransferView::Downloads => {
-                Self::draw_transfers(connected_state.downloads.iter(), FileYeetCommandType::Sub)
-            }
+            TransferView::Downloads => Self::draw_transfers(
+                self.download.downloads.iter(),
+                FileYeetCommandType::Sub,
+                self.options.max_download_size,
+                density,
+                &self.known_peers,
+                &trusted_publishers,
+            ),
         };
 
         widget::container(
             widget::column!(
                 header,
                 horizontal_line(),
-                widget::row!(publish_button, download_input).spacing(6),
+                widget::row!(publish_button, max_downloads_text_input, download_input).spacing(6),
                 transfer_view_choice,
                 widget::scrollable(transfer_content),
             )
-            .spacing(12),
+            .spacing(section_spacing),
         )
         .width(iced::Length::Fill)
         .height(iced::Length::Fill)
@@ -1136,849 +2755,237 @@ impl AppState {
         .into()
     }
 
-    /// Handle the port mapping radio button being changed.
-    fn update_port_radio_changed(&mut self, label: &'static str) -> iced::Command<Message> {
-        self.options.port_mapping = match label {
-            "None" => {
-                self.status_message = None;
-                PortMappingGuiOptions::None
-            }
-            "Port forward" => PortMappingGuiOptions::PortForwarding({
-                let o = self
-                    .options
-                    .port_forwarding_text
-                    .trim()
-                    .parse::<NonZeroU16>()
-                    .ok();
-                if o.is_none() {
-                    self.status_message = Some(INVALID_PORT_FORWARD.to_owned());
-                }
-                o
-            }),
-            "NAT-PMP / PCP" => {
-                self.status_message = None;
-                PortMappingGuiOptions::TryPcpNatPmp
-            }
-            _ => unreachable!(),
-        };
-        iced::Command::none()
+    /// Borrow each controller along with the app state the controllers share.
+    fn controllers(
+        &mut self,
+    ) -> (
+        &mut ConnectionController,
+        &mut PublishController,
+        &mut DownloadController,
+        AppContext<'_>,
+    ) {
+        (
+            &mut self.connection,
+            &mut self.publish,
+            &mut self.download,
+            AppContext {
+                options: &mut self.options,
+                status_message: &mut self.status_message,
+                modal: &mut self.modal,
+                known_peers: &mut self.known_peers,
+                publisher_key: self.publisher_key.as_ref(),
+            },
+        )
     }
 
-    /// Update the state after the port forward text field was changed.
-    fn update_port_forward_text(&mut self, text: String) -> iced::Command<Message> {
-        self.options.port_forwarding_text = text;
-        if let PortMappingGuiOptions::PortForwarding(port) = &mut self.options.port_mapping {
-            if let Ok(p) = self
-                .options
-                .port_forwarding_text
-                .trim()
-                .parse::<NonZeroU16>()
-            {
-                *port = Some(p);
-                self.status_message = None;
-            } else {
-                *port = None;
-                self.status_message = Some(INVALID_PORT_FORWARD.to_owned());
-            }
-        }
-        iced::Command::none()
+    /// Whether any upload or download is actively transferring data.
+    fn has_transferring(&self) -> bool {
+        let uploads = self
+            .connection
+            .connected()
+            .map_or(&[][..], |c| c.uploads.as_slice());
+        uploads
+            .iter()
+            .chain(self.download.downloads.iter())
+            .any(|t| matches!(t.progress, TransferProgress::Transferring(..)))
     }
 
-    // Update the state after the gateway text field was changed.
-    fn update_gateway_text(&mut self, text: String) -> iced::Command<Message> {
-        if text.trim().is_empty() {
-            self.options.gateway_address = None;
-        } else {
-            self.options.gateway_address = Some(text);
+    /// Summarize the work that would be interrupted by leaving the server.
+    fn leave_impact(&self) -> LeaveImpact {
+        let uploads = self
+            .connection
+            .connected()
+            .map_or(&[][..], |c| c.uploads.as_slice());
+        LeaveImpact {
+            publishes: self
+                .publish
+                .publishes
+                .iter()
+                .filter(|p| {
+                    matches!(
+                        p.state,
+                        PublishState::Hashing(..) | PublishState::Publishing(_)
+                    )
+                })
+                .count(),
+            uploads: uploads
+                .iter()
+                .filter(|t| !matches!(t.progress, TransferProgress::Done(_)))
+                .count(),
+            downloads: self
+                .download
+                .downloads
+                .iter()
+                .filter(|t| !matches!(t.progress, TransferProgress::Done(_)))
+                .count(),
         }
-        iced::Command::none()
     }
 
-    /// Update the state after the connect button was clicked.
-    fn update_connect_clicked(&mut self) -> iced::Command<Message> {
-        // Clear the status message before starting the connection attempt.
-        self.status_message = None;
-
-        // Determine if a valid server address was entered.
-        let regex_match = if self.options.server_address.trim().is_empty() {
-            // If empty, use sane defaults.
-            self.options.server_address = "localhost".to_owned();
-            Some((Some(self.options.server_address.clone()), DEFAULT_PORT))
-        } else {
-            // Otherwise, parse the server address and optional port.
-            SERVER_ADDRESS_REGEX
-                .captures(&self.options.server_address)
-                .and_then(|captures| {
-                    let host = captures.name("host").unwrap().as_str();
-
-                    // If there is no port, use the default port. Otherwise, the input must be valid.
-                    let port = captures.name("port").map_or(Some(DEFAULT_PORT), |p| {
-                        p.as_str().parse::<NonZeroU16>().ok()
-                    })?;
-                    Some((Some(host.to_owned()), port))
-                })
-        };
-
-        // If the server address is invalid, display an error message and return.
-        let Some((server_address, port)) = regex_match else {
-            self.status_message = Some("Invalid server address".to_owned());
+    /// Update the progress bar of a transfer or a publish being hashed.
+    fn update_progress_changed(&mut self, nonce: Nonce, p: f32) -> iced::Command<Message> {
+        let Some(ConnectedState { uploads, .. }) = self.connection.connected_mut() else {
             return iced::Command::none();
         };
 
-        // Set the state to `Stalling` before starting the connection attempt.
-        self.connection_state = ConnectionState::new_stalling();
-
-        // Try to get the user's intent from the GUI options.
-        let port_mapping = match self.options.port_mapping {
-            PortMappingGuiOptions::None | PortMappingGuiOptions::PortForwarding(None) => {
-                PortMappingConfig::None
-            }
-            PortMappingGuiOptions::PortForwarding(Some(port)) => {
-                PortMappingConfig::PortForwarding(port)
-            }
-            PortMappingGuiOptions::TryPcpNatPmp => {
-                PortMappingConfig::PcpNatPmp(self.port_mapping.take())
-            }
-        };
-        let gateway = self.options.gateway_address.clone();
-
-        // Try to connect to the server in a new task.
-        iced::Command::perform(
-            async move {
-                let mut bb = bytes::BytesMut::with_capacity(MAX_PEER_COMMUNICATION_SIZE);
-                crate::core::prepare_server_connection(
-                    server_address.as_deref(),
-                    port,
-                    gateway.as_deref(),
-                    port_mapping,
-                    &mut bb,
-                )
-                .await
-                .map_err(Arc::new)
-            },
-            Message::ConnectResulted,
-        )
-    }
-
-    /// Update the state after a tick when animations are occurring.
-    fn update_animation_tick(&mut self) -> iced::Command<Message> {
-        match &mut self.connection_state {
-            ConnectionState::Stalling { tick, .. } => *tick = Instant::now(),
-            ConnectionState::Connected(ConnectedState {
-                downloads, uploads, ..
-            }) => {
-                for t in downloads.iter_mut().chain(uploads.iter_mut()) {
-                    if let TransferProgress::Transferring(_, lock, progress) = &mut t.progress {
-                        let Ok(p) = lock.read() else {
-                            // Note that this transfer has had its lock poisoned and continue.
-                            t.progress = TransferProgress::Done(TransferResult::Failure(Arc::new(
-                                anyhow::anyhow!("Lock poisoned"),
-                            )));
-                            continue;
-                        };
-
-                        // Update the progress bar with the most recent value.
-                        *progress = *p;
-                    }
-                }
-            }
-            ConnectionState::Disconnected => {}
+        if let Some(TransferProgress::Transferring(_, _, progress)) = self
+            .download
+            .downloads
+            .iter_mut()
+            .chain(uploads.iter_mut())
+            .find(|t| t.nonce == nonce)
+            .map(|t| &mut t.progress)
+        {
+            *progress = p;
+        } else if let Some(PublishState::Hashing(_, progress)) = self
+            .publish
+            .publishes
+            .iter_mut()
+            .find(|pi| pi.nonce == nonce)
+            .map(|pi| &mut pi.state)
+        {
+            *progress = p;
         }
         iced::Command::none()
     }
 
-    /// Update the state after a connection attempt to the server completed.
-    fn update_connect_resulted(
+    /// Update the state after the server connection closed. Unless we closed it, stash the session so that
+    /// active publishes and downloads resume, then reconnect with the auto-connect backoff.
+    fn update_server_connection_lost(
         &mut self,
-        result: Result<PreparedConnection, Arc<anyhow::Error>>,
+        error: &quinn::ConnectionError,
     ) -> iced::Command<Message> {
-        match result {
-            Ok(prepared) => {
-                let PreparedConnection {
-                    endpoint,
-                    server_connection,
-                    external_address,
-                    port_mapping,
-                } = prepared;
-                self.connection_state = ConnectionState::Connected(ConnectedState::new(
-                    endpoint,
-                    server_connection,
-                    external_address,
-                ));
-                self.port_mapping = port_mapping;
-
-                // Attempt to recreate previous publish tasks.
-                if !self.options.last_publish_paths.is_empty() {
-                    return iced::Command::batch(self.options.last_publish_paths.drain(..).map(
-                        |p| {
-                            iced::Command::perform(
-                                std::future::ready(Some(p)),
-                                Message::PublishPathChosen,
-                            )
-                        },
-                    ));
-                }
-            }
-            Err(e) => {
-                self.status_message = Some(format!("Error connecting: {e}"));
-                self.connection_state = ConnectionState::Disconnected;
-            }
-        }
-        iced::Command::none()
-    }
-
-    /// Update the state after the publish button was clicked. Begins a publish request if a file was chosen.
-    fn update_publish_path_chosen(&mut self, path: Option<PathBuf>) -> iced::Command<Message> {
-        self.modal = false;
-
-        // Ensure a path was chosen.
-        let Some(path) = path else {
+        // Ignore connections other than the current one, and closures we caused.
+        let Some(ConnectedState { server, .. }) = self.connection.connected() else {
             return iced::Command::none();
         };
-
-        // Ensure the client is connected to a server.
-        let ConnectionState::Connected(ConnectedState {
-            server,
-            publishes,
-            transfer_view,
-            ..
-        }) = &mut self.connection_state
-        else {
+        if server.close_reason().is_none() || !crate::core::is_server_connection_lost(error) {
             return iced::Command::none();
-        };
-
-        // Ensure the transfer view is set to publishing to see the new item.
-        *transfer_view = TransferView::Publishes;
-
-        let server = server.clone();
-        let progress = Arc::new(RwLock::new(0.));
-        let nonce = rand::random();
-        let cancellation_token = CancellationToken::new();
-        let cancellation_path = path.clone();
-
-        publishes.push(PublishItem::new(
-            nonce,
-            path.clone(),
-            cancellation_token.clone(),
-            progress.clone(),
-        ));
-        iced::Command::perform(
-            async move {
-                tokio::select! {
-                    // Allow cancelling the publish request thread.
-                    () = cancellation_token.cancelled() => (PublishRequestResult::Cancelled, cancellation_path),
-
-                    r = async move {
-                        // Get the file size and hash of the chosen file to publish.
-                        let (file_size, hash) =
-                            match crate::core::file_size_and_hash(&path, Some(progress)).await {
-                                Ok(p) => p,
-                                Err(e) => {
-                                    return (
-                                        PublishRequestResult::Failure(Arc::new(anyhow::anyhow!(
-                                            "Error getting file size and hash: {e}"
-                                        ))),
-                                        path,
-                                    );
-                                }
-                            };
-
-                        // Create a memory buffer with sufficient capacity for the publish request.
-                        let bb = bytes::BytesMut::with_capacity(MAX_SERVER_COMMUNICATION_SIZE);
-
-                        // Create a bi-directional stream to the server for this publish request.
-                        (
-                            match crate::core::publish(&server, bb, hash, file_size).await {
-                                Ok(b) => PublishRequestResult::Success(IncomingPublishSession::new(b, hash, file_size)),
-                                Err(e) => PublishRequestResult::Failure(Arc::new(e)),
-                            },
-                            path,
-                        )
-                    } => r
-                }
-            },
-            move |(r, p)| Message::PublishRequestResulted(nonce, p, r),
-        )
-    }
-
-    /// Update after the server has accepted a publish request, or there was an error.
-    fn update_publish_request_resulted(
-        &mut self,
-        nonce: Nonce,
-        path: &Path,
-        result: PublishRequestResult,
-    ) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState { publishes, .. }) =
-            &mut self.connection_state
-        {
-            match (result, publishes.iter().position(|p| p.nonce == nonce)) {
-                (
-                    PublishRequestResult::Success(IncomingPublishSession {
-                        server_streams,
-                        hash,
-                        file_size,
-                    }),
-                    Some(i),
-                ) => {
-                    publishes[i].upgrade_hashing(server_streams, hash, file_size);
-                }
-                (PublishRequestResult::Failure(e), Some(i)) => {
-                    publishes[i].state = PublishState::Failure(e);
-                }
-                (PublishRequestResult::Cancelled, Some(i)) => {
-                    publishes[i].state = PublishState::Cancelled;
-                }
-                (e, None) => {
-                    self.status_message =
-                        Some(format!("Error publishing {}: {e:?}", path.display()));
-                }
-            }
         }
-        iced::Command::none()
-    }
 
-    /// Update after the server has sent a peer to publish to, or there was an error.
-    fn update_publish_peer_received(
-        &mut self,
-        nonce: Nonce,
-        result: Result<SocketAddr, Arc<anyhow::Error>>,
-    ) -> iced::Command<Message> {
-        let ConnectionState::Connected(ConnectedState {
-            endpoint,
-            peers,
-            publishes,
-            ..
-        }) = &mut self.connection_state
-        else {
-            return iced::Command::none();
-        };
-
-        let publish = publishes
-            .iter()
-            .find_map(|p| {
-                if let PublishState::Publishing(publishing) = &p.state {
-                    if p.nonce == nonce {
-                        Some(publishing)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .cloned();
-        match (result, publish) {
-            (Ok(peer), Some(publish)) => {
-                // TODO: A task will listen for connected peers. At that point we should only attempt something if not already connected.
-                let data = if let Some((c, _)) = peers.get(&peer) {
-                    Ok(c.clone())
-                } else {
-                    Err(endpoint.clone())
-                };
-                let hash = publish.hash;
-                iced::Command::perform(
-                    async move {
-                        match data {
-                            Ok(c) => crate::core::peer_connection_into_stream(
-                                &c,
-                                hash,
-                                FileYeetCommandType::Pub,
-                            )
-                            .await
-                            .map(|b| (c, b)),
-                            Err(e) => {
-                                crate::core::udp_holepunch(FileYeetCommandType::Pub, hash, e, peer)
-                                    .await
-                            }
-                        }
-                    },
-                    move |r| {
-                        Message::PublishPeerConnectResulted(
-                            nonce,
-                            r.map(Into::<PeerConnection>::into),
-                        )
-                    },
-                )
-            }
-            (Err(e), _) => {
-                self.status_message = Some(format!("Error receiving peer: {e}"));
-                iced::Command::none()
-            }
-            (_, None) => iced::Command::none(),
-        }
+        eprintln!("{} Lost the server connection: {error}", local_now_fmt());
+        self.status_message = Some(format!("Lost the connection to the server: {error}"));
+        self.stash_session();
+        self.connection.reconnect(&self.options)
     }
 
-    /// Update after a connection attempt to a peer for publishing has completed.
-    fn update_publish_peer_connect_resulted(
-        &mut self,
-        pub_nonce: Nonce,
-        peer: Option<PeerConnection>,
-    ) -> iced::Command<Message> {
-        let ConnectionState::Connected(ConnectedState {
-            peers,
-            uploads,
-            publishes,
-            ..
-        }) = &mut self.connection_state
-        else {
-            return iced::Command::none();
-        };
-        let Some(peer) = peer else {
-            // Silently fail if the peer connection was not successful.
-            return iced::Command::none();
-        };
-        let Some((publishing, path)) = publishes.iter().find_map(|pi| {
-            if let PublishState::Publishing(p) = &pi.state {
-                if pi.nonce == pub_nonce {
-                    Some((p, pi.path.clone()))
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }) else {
-            return iced::Command::none();
-        };
-
-        let upload_nonce = rand::random();
-        let progress_lock = Arc::new(RwLock::new(0.));
-        let cancellation_token = CancellationToken::new();
-        uploads.push(Transfer {
-            nonce: upload_nonce,
-            hash: publishing.hash,
-            hash_hex: faster_hex::hex_string(&publishing.hash),
-            file_size: publishing.file_size,
-            peer_string: peer.connection.remote_address().to_string(),
-            path: path.clone(),
-            progress: TransferProgress::Transferring(peer.clone(), progress_lock.clone(), 0.),
-            cancellation_token: cancellation_token.clone(),
+    /// Open a new window displaying the given details.
+    fn open_detail_window(&mut self, detail: DetailWindow) -> iced::Command<Message> {
+        let (id, spawn) = window::spawn(window::Settings {
+            size: iced::Size::new(640., 200.),
+            exit_on_close_request: false,
+            ..window::Settings::default()
         });
+        self.detail_windows.insert(id, detail);
+        spawn
+    }
 
-        let peer_address = peer.connection.remote_address();
-        match peers.entry(peer_address) {
-            std::collections::hash_map::Entry::Vacant(e) => {
-                // Add the peer into our map of known peer addresses.
-                e.insert((peer.connection.clone(), HashSet::from([upload_nonce])));
-            }
-            std::collections::hash_map::Entry::Occupied(mut e) => {
-                // Add the transfer nonce to the peer's set of known transfer nonces.
-                e.get_mut().1.insert(upload_nonce);
-            }
-        }
-
-        let file_size = publishing.file_size;
-        iced::Command::perform(
-            async move {
-                let file = match tokio::fs::File::open(path).await {
-                    Ok(f) => f,
-                    Err(e) => {
-                        return TransferResult::Failure(Arc::new(anyhow::anyhow!(
-                            "Failed to open the file: {e}"
-                        )))
-                    }
-                };
-
-                // Prepare a reader for the file to upload.
-                let reader = tokio::io::BufReader::new(file);
-
-                // Try to upload the file to the peer connection.
-                let mut streams = peer.streams.lock().await;
-
-                tokio::select! {
-                    () = cancellation_token.cancelled() => TransferResult::Cancelled,
-                    result = Box::pin(crate::core::upload_to_peer(
-                        &mut streams,
-                        file_size,
-                        reader,
-                        Some(progress_lock),
-                    )) => match result {
-                        Ok(()) => TransferResult::Success,
-                        Err(e) => TransferResult::Failure(Arc::new(e)),
-                    }
-                }
-            },
-            move |r| Message::TransferResulted(upload_nonce, r, FileYeetCommandType::Pub),
+    /// Close every window, exiting the application.
+    fn close_all_windows(&self) -> iced::Command<Message> {
+        iced::Command::batch(
+            self.detail_windows
+                .keys()
+                .copied()
+                .chain(std::iter::once(window::Id::MAIN))
+                .map(window::close),
         )
     }
 
-    /// Update the state after the publish button was clicked. Begins a subscribe request.
-    fn update_subscribe_path_chosen(&mut self, path: Option<PathBuf>) -> iced::Command<Message> {
-        self.modal = false;
-
-        // Ensure a path was chosen.
-        let Some(path) = path else {
-            return iced::Command::none();
+    /// Draw the contents of a detail window.
+    /// Draw what we know about the peer of a transfer, and allow the user to name it.
+    fn view_peer_identity(&self, transfer: &Transfer) -> iced::Element<Message> {
+        let Some(fingerprint) = &transfer.peer_fingerprint else {
+            return widget::text("The peer did not identify itself").into();
         };
-
-        // Ensure the client is connected to a server.
-        let ConnectionState::Connected(ConnectedState {
-            server,
-            hash_input,
-            transfer_view,
-            ..
-        }) = &mut self.connection_state
-        else {
-            return iced::Command::none();
+        let Some(peer) = self.known_peers.get(fingerprint) else {
+            return widget::text(format!("Peer {}", short_fingerprint(fingerprint))).into();
         };
 
-        // Ensure the hash is valid.
-        let mut hash = HashBytes::default();
-        if let Err(e) = faster_hex::hex_decode(hash_input.as_bytes(), &mut hash) {
-            self.status_message = Some(format!("Invalid hash: {e}"));
-            return iced::Command::none();
-        }
-
-        // Ensure the transfer view is set to downloads to see the new item.
-        *transfer_view = TransferView::Downloads;
-
-        let server = server.clone();
-        iced::Command::perform(
-            async move {
-                let mut bb = bytes::BytesMut::with_capacity(MAX_PEER_COMMUNICATION_SIZE);
-                crate::core::subscribe(&server, &mut bb, hash)
-                    .await
-                    .map(|peers| IncomingSubscribePeers::new(peers, path, hash))
-                    .map_err(Arc::new)
-            },
-            Message::SubscribePeersResult,
+        widget::column!(
+            widget::text(format!("Peer fingerprint: {fingerprint}")).size(12),
+            widget::text(format!(
+                "Transfers with this peer: {}, first seen {} days ago",
+                peer.times_seen,
+                peer.first_seen
+                    .elapsed()
+                    .map_or(0, |elapsed| elapsed.as_secs() / (24 * 60 * 60)),
+            ))
+            .size(12),
+            widget::text_input("Name this peer", peer.name.as_deref().unwrap_or_default())
+                .on_input({
+                    let fingerprint = fingerprint.clone();
+                    move |name| Message::PeerNameChanged(fingerprint.clone(), name)
+                })
+                .on_submit(Message::PeerNameSubmitted),
         )
+        .spacing(6)
+        .into()
     }
 
-    /// Update after server has responded to a subscribe request.
-    fn update_subscribe_peers_result(
-        &mut self,
-        result: Result<IncomingSubscribePeers, Arc<anyhow::Error>>,
-    ) -> iced::Command<Message> {
-        match result {
-            Ok(IncomingSubscribePeers {
-                peers_with_size,
-                path,
-                hash,
-            }) => {
-                if let ConnectionState::Connected(ConnectedState {
-                    endpoint,
-                    hash_input,
-                    peers,
-                    downloads,
-                    ..
-                }) = &mut self.connection_state
-                {
-                    // Let the user know why nothing else is happening.
-                    if peers_with_size.is_empty() {
-                        self.status_message = Some("No peers available".to_owned());
-                        return iced::Command::none();
+    fn view_detail_window(&self, detail: DetailWindow) -> iced::Element<Message> {
+        let content: Element<Message> = match detail {
+            DetailWindow::Transfer(nonce, transfer_type) => {
+                let transfer = match transfer_type {
+                    FileYeetCommandType::Pub => self
+                        .connection
+                        .connected()
+                        .and_then(|c| c.uploads.iter().find(|t| t.nonce == nonce)),
+                    FileYeetCommandType::Sub => {
+                        self.download.downloads.iter().find(|t| t.nonce == nonce)
                     }
+                };
 
-                    // Create a new transfer state and connection attempt for each peer.
-                    let transfers_commands_iter =
-                        peers_with_size.into_iter().map(|(peer, file_size)| {
-                            // Create a nonce to identify the transfer.
-                            let nonce = rand::random();
-
-                            // New transfer state for this request.
-                            let transfer = Transfer {
-                                nonce,
-                                hash,
-                                hash_hex: hash_input.clone(),
-                                file_size,
-                                peer_string: peer.to_string(),
-                                path: path.clone(),
-                                progress: TransferProgress::Connecting,
-                                cancellation_token: CancellationToken::new(),
-                            };
-
-                            // New connection attempt for this peer with result command identified by the nonce.
-                            let command = {
-                                // Allow creating a new connection or opening a stream on an existing one.
-                                // TODO: Create an enum for this instead of using a `Result`.
-                                let data = {
-                                    if let Some((c, _)) = peers.get(&peer) {
-                                        Ok(c.clone())
-                                    } else {
-                                        Err(endpoint.clone())
-                                    }
-                                };
-                                // The future to use to create the connection.
-                                let future = async move {
-                                    tokio::time::timeout(PEER_CONNECT_TIMEOUT, async move {
-                                        match data {
-                                            Ok(c) => crate::core::peer_connection_into_stream(
-                                                &c,
-                                                hash,
-                                                FileYeetCommandType::Sub,
-                                            )
-                                            .await
-                                            .map(|s| (c, s)),
-                                            Err(e) => {
-                                                crate::core::udp_holepunch(
-                                                    FileYeetCommandType::Sub,
-                                                    hash,
-                                                    e,
-                                                    peer,
-                                                )
-                                                .await
-                                            }
-                                        }
-                                        .map(PeerConnection::from)
-                                    })
-                                    .await
-                                    .ok()
-                                    .flatten()
-                                };
-                                iced::Command::perform(future, move |r| {
-                                    Message::SubscribePeerConnectResulted(nonce, r)
-                                })
-                            };
-
-                            // Return the pair to be separated later.
-                            (transfer, command)
-                        });
-
-                    // Create a new transfer for each peer.
-                    let (mut new_transfers, connect_commands): (
-                        Vec<Transfer>,
-                        Vec<iced::Command<Message>>,
-                    ) = transfers_commands_iter.unzip();
-
-                    // Add the new transfers to the list of active transfers.
-                    downloads.append(&mut new_transfers);
-                    iced::Command::batch(connect_commands)
+                if let Some(t) = transfer {
+                    widget::column!(
+                        Self::draw_transfers(
+                            std::iter::once(t),
+                            transfer_type,
+                            self.options.max_download_size,
+                            RowDensity::new(self.options.compact_rows),
+                            &self.known_peers,
+                            &self.options.trusted_publishers(),
+                        ),
+                        widget::text(format!("File size: {}", humanize_bytes(t.file_size))),
+                        self.view_peer_identity(t),
+                    )
+                    .spacing(12)
+                    .into()
                 } else {
-                    iced::Command::none()
+                    widget::text("This transfer is no longer available").into()
                 }
             }
-            Err(e) => {
-                self.status_message = Some(format!("Error subscribing to the server: {e}"));
-                iced::Command::none()
-            }
-        }
-    }
-
-    /// Update the state after a subscribe connection attempt to a peer completed.
-    fn update_subscribe_connect_resulted(
-        &mut self,
-        nonce: Nonce,
-        result: Option<PeerConnection>,
-    ) -> iced::Command<Message> {
-        let ConnectionState::Connected(ConnectedState {
-            peers, downloads, ..
-        }) = &mut self.connection_state
-        else {
-            return iced::Command::none();
-        };
-
-        // Find the transfer with the matching nonce.
-        let Some((index, transfer)) = downloads
-            .iter_mut()
-            .enumerate()
-            .find(|(_, t)| t.nonce == nonce)
-        else {
-            return iced::Command::none();
-        };
-
-        // Update the state of the transfer with the result.
-        if let Some(connection) = result {
-            let peer_address = connection.connection.remote_address();
-            // TODO: Refactor into a function.
-            match peers.entry(peer_address) {
-                std::collections::hash_map::Entry::Vacant(e) => {
-                    // Add the peer into our map of known peer addresses.
-                    e.insert((connection.connection.clone(), HashSet::from([nonce])));
-                }
-                std::collections::hash_map::Entry::Occupied(mut e) => {
-                    // Add the transfer nonce to the peer's set of known transfers.
-                    e.get_mut().1.insert(nonce);
-                }
-            }
-
-            transfer.progress = TransferProgress::Consent(connection);
-        } else {
-            // Remove unreachable peers from view.
-            downloads.remove(index);
-        }
-        iced::Command::none()
-    }
-
-    /// Tell the peer to send the file and begin recieving and writing the file.
-    fn update_accept_download(&mut self, nonce: Nonce) -> iced::Command<Message> {
-        let ConnectionState::Connected(ConnectedState { downloads, .. }) =
-            &mut self.connection_state
-        else {
-            return iced::Command::none();
-        };
-
-        // Get the current transfer status.
-        let Some(transfer) = downloads.iter_mut().find(|t| t.nonce == nonce) else {
-            return iced::Command::none();
-        };
-        let hash = transfer.hash;
-        let file_size = transfer.file_size;
-        let peer_streams = if let TransferProgress::Consent(p) = &mut transfer.progress {
-            p.clone()
-        } else {
-            return iced::Command::none();
+            DetailWindow::StatusLog => widget::scrollable(
+                widget::column(
+                    self.status_log
+                        .iter()
+                        .map(|status| widget::text(status).size(12).into()),
+                )
+                .spacing(6),
+            )
+            .into(),
         };
 
-        // Begin the transfer.
-        let byte_progress = Arc::new(RwLock::new(0.));
-        transfer.progress =
-            TransferProgress::Transferring(peer_streams.clone(), byte_progress.clone(), 0.);
-        let output_path = transfer.path.clone();
-        let cancellation_token = transfer.cancellation_token.clone();
-
-        iced::Command::perform(
-            async move {
-                let mut peer_streams_lock = peer_streams.streams.lock().await;
-
-                // Create a buffer for the file transfer range. Need to send a `u64` start index and `u64` length.
-                let mut bb = bytes::BytesMut::with_capacity(16);
-                tokio::select! {
-                    // Let the transfer be cancelled. This is not an error if cancelled.
-                    () = cancellation_token.cancelled() => TransferResult::Cancelled,
-
-                    // Await the file to be downloaded.
-                    result = Box::pin(crate::core::download_from_peer(
-                        hash,
-                        &mut peer_streams_lock,
-                        file_size,
-                        &output_path,
-                        &mut bb,
-                        Some(byte_progress),
-                    )) => {
-                        match result {
-                            Ok(()) => TransferResult::Success,
-                            Err(e) => TransferResult::Failure(Arc::new(anyhow::anyhow!("Download failed: {e}"))),
-                        }
-                    }
-                }
-            },
-            move |r| Message::TransferResulted(nonce, r, FileYeetCommandType::Sub),
-        )
-    }
-
-    /// Update the state after a transfer was cancelled.
-    fn update_cancel_publish(&mut self, nonce: Nonce) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState { publishes, .. }) =
-            &mut self.connection_state
-        {
-            if let Some(i) = publishes.iter().position(|p| p.nonce == nonce) {
-                // Cancel the publish task.
-                publishes[i].cancellation_token.cancel();
-
-                // If we have finished hashing, remove the publish from the list.
-                if !matches!(&publishes[i].state, PublishState::Hashing(_)) {
-                    publishes.remove(i);
-                }
-            }
-        }
-        iced::Command::none()
-    }
-
-    /// Update the state after a transfer was cancelled.
-    fn update_cancel_transfer(
-        &mut self,
-        nonce: Nonce,
-        transfer_type: FileYeetCommandType,
-    ) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState {
-            downloads, uploads, ..
-        }) = &mut self.connection_state
-        {
-            let transfers = match transfer_type {
-                FileYeetCommandType::Sub => downloads,
-                FileYeetCommandType::Pub => uploads,
-            };
-            if let Some(t) = transfers.iter_mut().find(|t| t.nonce == nonce) {
-                // Cancel the download task.
-                t.cancellation_token.cancel();
-
-                // If waiting for user interaction, mark the transfer as cancelled.
-                if matches!(t.progress, TransferProgress::Consent(_)) {
-                    t.progress = TransferProgress::Done(TransferResult::Cancelled);
-                }
-            }
-        }
-        iced::Command::none()
-    }
-
-    /// Update the state after a transfer was completed.
-    fn update_transfer_resulted(
-        &mut self,
-        nonce: Nonce,
-        result: TransferResult,
-        transfer_type: FileYeetCommandType,
-    ) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState {
-            peers,
-            downloads,
-            uploads,
-            ..
-        }) = &mut self.connection_state
-        {
-            let mut transfers = match transfer_type {
-                FileYeetCommandType::Sub => downloads.iter_mut(),
-                FileYeetCommandType::Pub => uploads.iter_mut(),
-            };
-
-            if let Some(t) = transfers.find(|t| t.nonce == nonce) {
-                // If the transfer was connected to a peer, remove the peer from the list of known peers.
-                if let TransferProgress::Transferring(p, _, _) | TransferProgress::Consent(p) =
-                    &t.progress
-                {
-                    let connection = &p.connection;
-                    let peer_address = connection.remote_address();
-                    if let std::collections::hash_map::Entry::Occupied(mut e) =
-                        peers.entry(peer_address)
-                    {
-                        let nonces = &mut e.get_mut().1;
-                        nonces.remove(&nonce);
-
-                        // If there are no more streams to the peer, close the connection.
-                        if nonces.is_empty() {
-                            connection.close(GOODBYE_CODE, GOODBYE_MESSAGE.as_bytes());
-                            peers.remove(&peer_address);
-                        }
-                    }
-                }
-
-                t.progress = TransferProgress::Done(result);
-            }
-        }
-        iced::Command::none()
-    }
-
-    /// Update the state after the user has chosen to remove a transfer entry.
-    fn update_remove_from_transfers(
-        &mut self,
-        nonce: Nonce,
-        transfer_type: FileYeetCommandType,
-    ) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState {
-            downloads, uploads, ..
-        }) = &mut self.connection_state
-        {
-            let transfers = match transfer_type {
-                FileYeetCommandType::Sub => downloads,
-                FileYeetCommandType::Pub => uploads,
-            };
-            if let Some(i) = transfers.iter().position(|t| t.nonce == nonce) {
-                transfers.remove(i);
-            }
-        }
-        iced::Command::none()
+        widget::container(content)
+            .width(iced::Length::Fill)
+            .height(iced::Length::Fill)
+            .padding(12)
+            .into()
     }
 
-    /// Try to safely close.
-    fn safely_close(&mut self, close_type: CloseType) -> iced::Command<Message> {
-        if let ConnectionState::Connected(ConnectedState {
+    /// Cancel the active work on the server connection, remember what to resume on the next connection,
+    /// and close the endpoint.
+    fn stash_session(&mut self) {
+        if let Some(ConnectedState {
             endpoint,
-            downloads,
-            publishes,
+            shutdown_token,
             ..
-        }) = &mut self.connection_state
+        }) = self.connection.connected()
         {
-            self.options.last_publish_paths = publishes
+            // Let transfers know they are stopping because we are leaving, not because of the user.
+            shutdown_token.cancel();
+
+            self.options.last_publish_paths = self
+                .publish
+                .publishes
                 .drain(..)
                 .filter_map(|p| {
                     // Ensure all publish tasks are cancelled.
@@ -1987,7 +2994,7 @@ impl AppState {
                     // If the publish is valid or in progress, add it to the list of open publishes.
                     if matches!(
                         p.state,
-                        PublishState::Publishing(_) | PublishState::Hashing(_)
+                        PublishState::Publishing(_) | PublishState::Hashing(..)
                     ) {
                         Some(p.path)
                     } else {
@@ -1996,7 +3003,12 @@ impl AppState {
                 })
                 .collect();
 
-            self.options.last_downloads = downloads
+            (
+                self.options.last_downloads,
+                self.options.last_download_algorithms,
+            ) = self
+                .download
+                .downloads
                 .drain(..)
                 .filter_map(|d| {
                     // If the download is in progress, cancel it.
@@ -2004,30 +3016,29 @@ impl AppState {
 
                     // Ensure all downloads that were in-progress are saved.
                     if matches!(d.progress, TransferProgress::Transferring(_, _, _)) {
-                        Some((d.path, d.hash))
+                        Some(((d.path, d.hash.bytes), d.hash.algorithm))
                     } else {
                         None
                     }
                 })
-                .collect();
+                .unzip();
 
             endpoint.close(GOODBYE_CODE, GOODBYE_MESSAGE.as_bytes());
 
             // Save the app settings when closing our connections.
-            if let Err(e) = settings_path()
-                .ok_or_else(|| {
-                    anyhow::anyhow!("Could not determine a settings path for this environment.")
-                })
-                .and_then(|p| Ok(std::fs::File::create(p)?))
-                .and_then(|f| Ok(serde_json::to_writer_pretty(f, &self.options)?))
-            {
+            if let Err(e) = self.options.save() {
                 eprintln!("{} Could not save settings: {e}", local_now_fmt());
             }
-        };
+        }
+    }
+
+    /// Try to safely close.
+    fn safely_close(&mut self, close_type: CloseType) -> iced::Command<Message> {
+        self.stash_session();
 
-        if let Some(port_mapping) = self.port_mapping.take() {
+        if let Some(port_mapping) = self.connection.port_mapping.take() {
             // Set the state to `Stalling` before waiting for the safe close to complete.
-            self.connection_state = ConnectionState::new_stalling();
+            self.connection.state = ConnectionState::new_stalling();
 
             self.safely_closing = true;
             let port_mapping_timeout = Duration::from_millis(500);
@@ -2051,10 +3062,10 @@ impl AppState {
         } else {
             match close_type {
                 // Immediately exit if there isn't a port mapping to remove.
-                CloseType::Application => window::close(window::Id::MAIN),
+                CloseType::Application => self.close_all_windows(),
 
                 CloseType::Connections => {
-                    self.connection_state = ConnectionState::Disconnected;
+                    self.connection.state = ConnectionState::Disconnected;
                     iced::Command::none()
                 }
             }
@@ -2062,6 +3073,135 @@ impl AppState {
     }
 }
 
+/// Report the notifications a server pushes to us as messages.
+/// The ID is the stable ID of the server connection the notifications come from.
+fn notification_subscription(
+    id: usize,
+    server_notifications: Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+) -> iced::Subscription<Message> {
+    iced::subscription::channel(
+        ("server_notifications", id),
+        10,
+        move |mut output| async move {
+            let mut recv = server_notifications.lock().await;
+            loop {
+                match crate::core::read_server_notification(&mut recv).await {
+                    Ok((kind, message)) => {
+                        if let Err(e) = output
+                            .send(Message::Connection(ConnectionMessage::ServerNotified(
+                                kind, message,
+                            )))
+                            .await
+                        {
+                            eprintln!(
+                                "{} Failed to perform internal message passing: {e}",
+                                local_now_fmt()
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{} Server notifications ended: {e}", local_now_fmt());
+
+                        // Nothing more will arrive on this stream.
+                        std::future::pending::<()>().await;
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Report changes to a progress channel as messages, at most once per update interval.
+/// The channel only holds the latest value, so a busy GUI never falls behind a fast transfer.
+fn progress_subscription(
+    nonce: Nonce,
+    mut progress: watch::Receiver<f32>,
+) -> iced::Subscription<Message> {
+    iced::subscription::channel(("progress", nonce), 1, move |mut output| async move {
+        loop {
+            // The sender is dropped when the work ends, and its result arrives in another message.
+            if progress.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+
+            let p = *progress.borrow_and_update();
+            if let Err(e) = output.send(Message::ProgressChanged(nonce, p)).await {
+                eprintln!(
+                    "{} Failed to perform internal message passing: {e}",
+                    local_now_fmt()
+                );
+            }
+            tokio::time::sleep(PROGRESS_UPDATE_INTERVAL).await;
+        }
+    })
+}
+
+/// Whether the publisher of a download is one the user trusts. Every publisher is trusted when none are listed.
+fn is_trusted_publisher(transfer: &Transfer, trusted_publishers: &[&str]) -> bool {
+    trusted_publishers.is_empty()
+        || transfer
+            .publisher_fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| {
+                trusted_publishers
+                    .iter()
+                    .any(|trusted| crate::identity::fingerprint_matches(fingerprint, trusted))
+            })
+}
+
+/// Record a transfer with the peer on this connection, returning its fingerprint if it identified itself.
+fn remember_peer(known_peers: &mut KnownPeers, connection: &quinn::Connection) -> Option<String> {
+    let fingerprint = crate::identity::peer_fingerprint(connection)?;
+    known_peers.record_seen(&fingerprint);
+    if let Err(e) = known_peers.save() {
+        eprintln!("{} Failed to save known peers: {e}", local_now_fmt());
+    }
+    Some(fingerprint)
+}
+
+/// The peer of a transfer as shown to the user, including the name they gave it.
+fn peer_label(transfer: &Transfer, known_peers: &KnownPeers) -> String {
+    match transfer
+        .peer_fingerprint
+        .as_ref()
+        .and_then(|f| known_peers.get(f))
+        .and_then(|p| p.name.as_ref())
+    {
+        Some(name) => format!("{name} ({})", transfer.peer_string),
+        None => transfer.peer_string.clone(),
+    }
+}
+
+/// Add a transfer to the map of known peer connections.
+/// If the transfer uses a different connection than the one known for the peer, the newer connection
+/// becomes the one to reuse.
+fn track_peer_connection(
+    peers: &mut HashMap<PeerAddr, (quinn::Connection, HashSet<Nonce>)>,
+    peer_address: PeerAddr,
+    connection: &quinn::Connection,
+    nonce: Nonce,
+) {
+    match peers.entry(peer_address) {
+        std::collections::hash_map::Entry::Vacant(e) => {
+            // Add the peer into our map of known peer addresses.
+            e.insert((connection.clone(), HashSet::from([nonce])));
+        }
+        std::collections::hash_map::Entry::Occupied(mut e) => {
+            // Add the transfer nonce to the peer's set of known transfers.
+            let (known, nonces) = e.get_mut();
+            if known.stable_id() != connection.stable_id() {
+                *known = connection.clone();
+            }
+            nonces.insert(nonce);
+        }
+    }
+}
+
+/// Whether the endpoint is bound to an IPv4 socket, and should use IPv4 routes.
+fn endpoint_is_ipv4(endpoint: &quinn::Endpoint) -> bool {
+    endpoint.local_addr().map_or(true, |a| a.is_ipv4())
+}
+
 /// Either close all connections or the entire application.
 #[derive(Clone, Copy, Debug)]
 enum CloseType {
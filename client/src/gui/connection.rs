@@ -0,0 +1,990 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+
+use file_yeet_shared::{
+    local_now_fmt, FileHash, ServerNotification, DEFAULT_PORT, SERVER_MESSAGE_BUFFERS,
+};
+
+use super::{
+    download::DownloadController, endpoint_is_ipv4, parse_server_address, publish::PublishMessage,
+    AdditionalServer, AppContext, AppSettings, ConnectedState, ConnectionState, DownloadPath,
+    Message, PortMappingGuiOptions,
+};
+use crate::core::{
+    NetworkRoute, PortMappingConfig, PreparedConnection, MAX_PORT_MAPPING_RETRY_BACKOFF,
+    PORT_MAPPING_DROP_TIMEOUT, PORT_MAPPING_RETRY_BACKOFF,
+};
+use crate::identity::ServerIdentityChanged;
+
+/// The messages handled by the connection controller.
+#[derive(Clone, Debug)]
+pub enum ConnectionMessage {
+    /// The connect button was clicked.
+    ConnectClicked,
+
+    /// Clear the internal port range and connect using a random port.
+    UseRandomPort,
+
+    /// Trust the server's changed certificate and connect again.
+    AcceptServerIdentity,
+
+    /// Stop waiting to retry a failed auto-connect attempt.
+    CancelRetry,
+
+    /// The result of connecting to the server, with the address of the server that accepted us
+    /// and the additional servers that could be connected to.
+    ConnectResulted(
+        Result<
+            (
+                String,
+                PreparedConnection,
+                Vec<(String, PreparedConnection)>,
+            ),
+            Arc<anyhow::Error>,
+        >,
+    ),
+
+    /// A moment in time has passed, update the animations.
+    AnimationTick,
+
+    /// Time to re-ping the server to detect changes to our external address.
+    SocketPingTick,
+
+    /// The result of re-pinging the server. Contains the new external address if it changed.
+    ExternalAddressRefreshed(Result<Option<String>, Arc<anyhow::Error>>),
+
+    /// The server pushed a notification to show the user.
+    ServerNotified(ServerNotification, String),
+
+    /// The lost server connection was cleaned up and a reconnect should be scheduled.
+    ServerReconnectScheduled,
+
+    /// Time to probe the default network route for changes.
+    NetworkPollTick,
+
+    /// The result of probing the default network route.
+    NetworkRouteProbed(Result<NetworkRoute, Arc<anyhow::Error>>),
+
+    /// The result of replacing our port mapping after the network route changed.
+    PortMappingRenewed(Result<crab_nat::PortMapping, Arc<anyhow::Error>>),
+
+    /// Time to try again to acquire a port mapping in the background.
+    PortMappingRetryTick,
+
+    /// The result of a background attempt to acquire a port mapping.
+    PortMappingRetried(Result<crab_nat::PortMapping, Arc<anyhow::Error>>),
+
+    /// Copy the connected server address to the clipboard.
+    CopyServer,
+}
+
+/// Handles connecting to the server and keeping the connection healthy.
+#[derive(Default)]
+pub(super) struct ConnectionController {
+    /// The state of the server connection.
+    pub(super) state: ConnectionState,
+
+    /// The port mapping of the current connection, kept to be renewed or released.
+    pub(super) port_mapping: Option<crab_nat::PortMapping>,
+
+    /// The number of failed auto-connect attempts, if the current attempt is automatic.
+    pub(super) auto_connect_attempt: Option<u32>,
+
+    /// Whether the last connection attempt failed because every port in the internal port range was in use.
+    pub(super) port_conflict: bool,
+
+    /// The server's changed certificate, waiting for the user to accept or ignore it.
+    pub(super) server_identity_changed: Option<ServerIdentityChanged>,
+}
+impl ConnectionController {
+    /// The connected state, if connected to a server.
+    pub(super) fn connected(&self) -> Option<&ConnectedState> {
+        match &self.state {
+            ConnectionState::Connected(connected_state) => Some(connected_state),
+            _ => None,
+        }
+    }
+
+    /// The mutable connected state, if connected to a server.
+    pub(super) fn connected_mut(&mut self) -> Option<&mut ConnectedState> {
+        match &mut self.state {
+            ConnectionState::Connected(connected_state) => Some(connected_state),
+            _ => None,
+        }
+    }
+
+    /// Update the connection state for a connection message.
+    pub(super) fn update(
+        &mut self,
+        message: ConnectionMessage,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match message {
+            // Handle the connect button being clicked. Manual attempts are not retried.
+            ConnectionMessage::ConnectClicked => {
+                self.auto_connect_attempt = None;
+                self.connect(ctx)
+            }
+
+            // Stop restricting the internal port and try connecting again.
+            ConnectionMessage::UseRandomPort => {
+                self.port_conflict = false;
+                ctx.options.internal_port_range = None;
+                ctx.options.internal_port_range_text.clear();
+                self.connect(ctx)
+            }
+
+            // Trust the server's new certificate in place of the old one, then try connecting again.
+            ConnectionMessage::AcceptServerIdentity => {
+                let Some(changed) = self.server_identity_changed.take() else {
+                    return iced::Command::none();
+                };
+                let mut known_servers = crate::identity::KnownServers::load();
+                known_servers.pin(&changed.server, &changed.presented);
+                if let Err(e) = known_servers.save() {
+                    *ctx.status_message =
+                        Some(format!("Failed to remember the server's certificate: {e}"));
+                    return iced::Command::none();
+                }
+                self.connect(ctx)
+            }
+
+            // Stop waiting to retry the auto-connect.
+            ConnectionMessage::CancelRetry => {
+                self.auto_connect_attempt = None;
+                self.state = ConnectionState::Disconnected;
+                iced::Command::none()
+            }
+
+            // Handle the result of a connection attempt.
+            ConnectionMessage::ConnectResulted(r) => self.update_connect_resulted(r, ctx),
+
+            // Update the connection animations, and retry the auto-connect once its backoff elapses.
+            ConnectionMessage::AnimationTick => self.update_animation_tick(ctx),
+
+            // Re-ping the server to detect changes to our external address.
+            ConnectionMessage::SocketPingTick => self.update_socket_ping_tick(),
+
+            // Handle the result of re-pinging the server.
+            ConnectionMessage::ExternalAddressRefreshed(r) => {
+                self.update_external_address_refreshed(r, ctx)
+            }
+
+            // Display notifications from the server to the user.
+            ConnectionMessage::ServerNotified(kind, message) => {
+                *ctx.status_message = Some(format!("{kind}: {message}"));
+                iced::Command::none()
+            }
+
+            // Wait to reconnect now that the lost connection was cleaned up.
+            ConnectionMessage::ServerReconnectScheduled => {
+                let now = Instant::now();
+                self.auto_connect_attempt = Some(1);
+                self.state = ConnectionState::Retrying {
+                    attempt: 1,
+                    retry_at: now + ctx.options.auto_connect_retry.backoff(0),
+                    tick: now,
+                };
+                iced::Command::none()
+            }
+
+            // Probe the default network route for changes.
+            ConnectionMessage::NetworkPollTick => self.update_network_poll_tick(),
+
+            // Handle the result of probing the default network route.
+            ConnectionMessage::NetworkRouteProbed(r) => self.update_network_route_probed(r, ctx),
+
+            // Handle the result of replacing our port mapping.
+            ConnectionMessage::PortMappingRenewed(r) => self.update_port_mapping_renewed(r, ctx),
+
+            // Try again to acquire a port mapping that failed when connecting.
+            ConnectionMessage::PortMappingRetryTick => self.update_port_mapping_retry_tick(ctx),
+
+            // Handle the result of a background port mapping attempt.
+            ConnectionMessage::PortMappingRetried(r) => self.update_port_mapping_retried(r, ctx),
+
+            // Copy the connected server address to the clipboard.
+            ConnectionMessage::CopyServer => match self.connected() {
+                Some(ConnectedState { server_address, .. }) => {
+                    iced::clipboard::write(server_address.clone())
+                }
+                None => iced::Command::none(),
+            },
+        }
+    }
+
+    /// Stall until the connection animation ends, or retry the auto-connect once its backoff has elapsed.
+    fn update_animation_tick(&mut self, ctx: &mut AppContext) -> iced::Command<Message> {
+        match &mut self.state {
+            ConnectionState::Stalling { tick, .. } => *tick = Instant::now(),
+            ConnectionState::Retrying { retry_at, tick, .. } => {
+                *tick = Instant::now();
+
+                // Begin the next connection attempt once the backoff has elapsed.
+                if *tick >= *retry_at {
+                    return self.connect(ctx);
+                }
+            }
+            ConnectionState::Connected(_) | ConnectionState::Disconnected => {}
+        }
+        iced::Command::none()
+    }
+
+    /// Schedule a reconnect after the server connection was lost, releasing our port mapping first
+    /// since the next connection creates its own.
+    pub(super) fn reconnect(&mut self, options: &AppSettings) -> iced::Command<Message> {
+        // Reconnecting is disabled along with auto-connect retries.
+        let next = if options.auto_connect_retry.max_retries > 0 {
+            Message::Connection(ConnectionMessage::ServerReconnectScheduled)
+        } else {
+            Message::LeftServer
+        };
+
+        self.state = ConnectionState::new_stalling();
+        if let Some(port_mapping) = self.port_mapping.take() {
+            iced::Command::perform(
+                tokio::time::timeout(PORT_MAPPING_DROP_TIMEOUT, port_mapping.try_drop()),
+                move |_| next,
+            )
+        } else {
+            iced::Command::perform(std::future::ready(()), move |()| next)
+        }
+    }
+
+    /// Update the state after the connect button was clicked.
+    pub(super) fn connect(&mut self, ctx: &mut AppContext) -> iced::Command<Message> {
+        // Clear the status message before starting the connection attempt.
+        *ctx.status_message = None;
+
+        // Determine if a valid server address was entered.
+        let regex_match = if ctx.options.server_address.trim().is_empty() {
+            // If empty, use sane defaults.
+            ctx.options.server_address = "localhost".to_owned();
+            Some((Some(ctx.options.server_address.clone()), DEFAULT_PORT))
+        } else {
+            // Otherwise, parse the server address and optional port.
+            parse_server_address(&ctx.options.server_address).map(|(host, port)| (Some(host), port))
+        };
+
+        // If the server address is invalid, display an error message and return.
+        let Some((server_address, port)) = regex_match else {
+            *ctx.status_message = Some("Invalid server address".to_owned());
+            return iced::Command::none();
+        };
+
+        // Try the fallback servers in order after the server, keeping the text of each to show once connected.
+        let mut servers = vec![(
+            ctx.options.server_address.trim().to_owned(),
+            server_address,
+            port,
+        )];
+        for fallback in ctx.options.fallback_servers() {
+            let Some((host, port)) = parse_server_address(fallback) else {
+                *ctx.status_message = Some(format!("Invalid fallback server address: {fallback}"));
+                return iced::Command::none();
+            };
+            servers.push((fallback.to_owned(), Some(host), port));
+        }
+
+        // Connect to the additional servers alongside whichever server accepts us.
+        let mut additional_servers = Vec::new();
+        for additional in ctx.options.additional_servers() {
+            let Some((host, port)) = parse_server_address(additional) else {
+                *ctx.status_message =
+                    Some(format!("Invalid additional server address: {additional}"));
+                return iced::Command::none();
+            };
+            additional_servers.push((additional.to_owned(), host, port));
+        }
+
+        // Verify the server against the given CA file or fingerprint, if any, rather than trusting its first certificate.
+        let server_ca = ctx.options.server_ca_text.trim();
+        let server_fingerprint = ctx.options.server_fingerprint_text.trim();
+        let server_trust = if !server_ca.is_empty() {
+            crate::core::ServerTrust::Ca(PathBuf::from(server_ca))
+        } else if !server_fingerprint.is_empty() {
+            match file_yeet_shared::parse_certificate_fingerprint(server_fingerprint) {
+                Ok(fingerprint) => crate::core::ServerTrust::Fingerprint(fingerprint),
+                Err(e) => {
+                    *ctx.status_message = Some(e);
+                    return iced::Command::none();
+                }
+            }
+        } else {
+            crate::core::ServerTrust::FirstUse
+        };
+
+        // Present a client certificate to servers that require one, if both files were given.
+        let client_cert = ctx.options.client_cert_text.trim();
+        let client_key = ctx.options.client_key_text.trim();
+        let client_certificate = (!client_cert.is_empty() && !client_key.is_empty()).then(|| {
+            crate::core::ClientCertificate {
+                cert: PathBuf::from(client_cert),
+                key: PathBuf::from(client_key),
+            }
+        });
+
+        // Set the state to `Stalling` before starting the connection attempt.
+        self.state = ConnectionState::new_stalling();
+
+        // Try to get the user's intent from the GUI options.
+        let port_mapping = match ctx.options.port_mapping {
+            PortMappingGuiOptions::None | PortMappingGuiOptions::PortForwarding(None) => {
+                PortMappingConfig::None
+            }
+            PortMappingGuiOptions::PortForwarding(Some(port)) => {
+                PortMappingConfig::PortForwarding(port)
+            }
+            PortMappingGuiOptions::TryPcpNatPmp => {
+                PortMappingConfig::PcpNatPmp(self.port_mapping.take())
+            }
+        };
+        let gateway = ctx.options.gateway_address.clone();
+        let internal_port_range = ctx.options.internal_port_range;
+        let auth_token = Some(ctx.options.auth_token.trim().to_owned()).filter(|t| !t.is_empty());
+
+        // Try to connect to each server in a new task, until one succeeds.
+        iced::Command::perform(
+            async move {
+                let mut bb = SERVER_MESSAGE_BUFFERS.take();
+                let mut port_mapping = port_mapping;
+                let mut servers = servers.into_iter().peekable();
+                while let Some((server_text, server_address, port)) = servers.next() {
+                    let next_port_mapping = renewed_port_mapping(&port_mapping);
+                    match crate::core::prepare_server_connection(
+                        server_address.as_deref(),
+                        port,
+                        gateway.as_deref(),
+                        port_mapping,
+                        internal_port_range,
+                        None,
+                        auth_token.as_deref(),
+                        &server_trust,
+                        client_certificate.as_ref(),
+                        &mut bb,
+                    )
+                    .await
+                    {
+                        Ok(prepared) => {
+                            // Share the endpoint so that peers introduced by any server reach the same port.
+                            let mut additional = Vec::with_capacity(additional_servers.len());
+                            for (additional_text, host, port) in additional_servers {
+                                match crate::core::prepare_additional_server_connection(
+                                    &prepared,
+                                    &host,
+                                    port,
+                                    auth_token.as_deref(),
+                                    &server_trust,
+                                    client_certificate.as_ref(),
+                                    &mut bb,
+                                )
+                                .await
+                                {
+                                    Ok(connection) => additional.push((additional_text, connection)),
+                                    Err(e) => eprintln!(
+                                        "{} Failed to connect to the additional server {additional_text}: {e}",
+                                        local_now_fmt()
+                                    ),
+                                }
+                            }
+                            return Ok((server_text, prepared, additional));
+                        }
+                        Err(e)
+                            if servers.peek().is_some()
+                                && crate::core::should_try_next_server(&e) =>
+                        {
+                            eprintln!(
+                                "{} Failed to connect to {server_text}, trying the next server: {e}",
+                                local_now_fmt()
+                            );
+                            port_mapping = next_port_mapping;
+                        }
+                        Err(e) => return Err(Arc::new(e)),
+                    }
+                }
+                unreachable!("The server list always holds the server")
+            },
+            |r| Message::Connection(ConnectionMessage::ConnectResulted(r)),
+        )
+    }
+
+    /// Update the state after a connection attempt to the server completed.
+    fn update_connect_resulted(
+        &mut self,
+        result: Result<
+            (
+                String,
+                PreparedConnection,
+                Vec<(String, PreparedConnection)>,
+            ),
+            Arc<anyhow::Error>,
+        >,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match result {
+            Ok((server_address, prepared, additional)) => {
+                self.auto_connect_attempt = None;
+                let PreparedConnection {
+                    endpoint,
+                    server_connection,
+                    external_address,
+                    port_mapping,
+                    port_override,
+                    server_notifications,
+                    capabilities,
+                } = prepared;
+                let additional_servers = additional
+                    .into_iter()
+                    .map(|(server_address, prepared)| AdditionalServer {
+                        server_address,
+                        server: prepared.server_connection,
+                        server_notifications: prepared.server_notifications,
+                    })
+                    .collect();
+                let connected_state = ConnectedState::new(
+                    endpoint,
+                    server_connection,
+                    server_address,
+                    external_address,
+                    port_override,
+                    server_notifications,
+                    additional_servers,
+                    capabilities,
+                );
+                let servers = connected_state.servers();
+                self.state = ConnectionState::Connected(connected_state);
+
+                // Learn the network route we connected over without blocking the UI, to notice when it changes.
+                let probe_route = self.update_network_poll_tick();
+
+                // Keep trying to acquire a port mapping in the background if the initial attempt failed.
+                let retry_mapping = if port_mapping.is_none()
+                    && matches!(
+                        ctx.options.port_mapping,
+                        PortMappingGuiOptions::TryPcpNatPmp
+                    ) {
+                    self.schedule_port_mapping_retry(0)
+                } else {
+                    iced::Command::none()
+                };
+                self.port_mapping = port_mapping;
+
+                // Attempt to recreate previous publish tasks.
+                let publish_commands = ctx.options.last_publish_paths.drain(..).map(|p| {
+                    iced::Command::perform(std::future::ready(Some(p)), |p| {
+                        Message::Publish(PublishMessage::PathChosen(p))
+                    })
+                });
+
+                // Concurrently subscribe to the previous downloads so that peer connections
+                // are ready by the time the user chooses to resume them.
+                let mut algorithms =
+                    std::mem::take(&mut ctx.options.last_download_algorithms).into_iter();
+                let room = ctx.options.room.clone();
+                let download_commands = ctx.options.last_downloads.drain(..).map(|(path, hash)| {
+                    let algorithm = algorithms.next().unwrap_or_default();
+                    DownloadController::subscribe_command(
+                        servers.clone(),
+                        DownloadPath::Chosen(path),
+                        FileHash::new(algorithm, hash),
+                        room.clone(),
+                        None,
+                    )
+                });
+
+                return iced::Command::batch(
+                    [probe_route, retry_mapping]
+                        .into_iter()
+                        .chain(publish_commands)
+                        .chain(download_commands),
+                );
+            }
+            Err(e) => {
+                *ctx.status_message = Some(format!("Error connecting: {e}"));
+
+                // Ask the user about a changed server certificate instead of retrying.
+                if let Some(changed) = e.downcast_ref::<ServerIdentityChanged>() {
+                    self.server_identity_changed = Some(changed.clone());
+                    self.auto_connect_attempt = None;
+                    self.state = ConnectionState::Disconnected;
+                    return iced::Command::none();
+                }
+
+                // Offer to use a random port instead of retrying when the configured ports are taken.
+                if e.downcast_ref::<crate::core::PortRangeInUse>().is_some() {
+                    self.port_conflict = true;
+                    self.auto_connect_attempt = None;
+                    self.state = ConnectionState::Disconnected;
+                    return iced::Command::none();
+                }
+
+                // Schedule another attempt if this was an auto-connect with retries remaining.
+                let retry = &ctx.options.auto_connect_retry;
+                match self.auto_connect_attempt {
+                    Some(failed) if failed < retry.max_retries => {
+                        let now = Instant::now();
+                        self.auto_connect_attempt = Some(failed + 1);
+                        self.state = ConnectionState::Retrying {
+                            attempt: failed + 1,
+                            retry_at: now + retry.backoff(failed),
+                            tick: now,
+                        };
+                    }
+                    _ => {
+                        self.auto_connect_attempt = None;
+                        self.state = ConnectionState::Disconnected;
+                    }
+                }
+            }
+        }
+        iced::Command::none()
+    }
+
+    /// Re-ping the server to compare against our cached external address.
+    fn update_socket_ping_tick(&mut self) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            server,
+            external_address,
+            port_override,
+            ..
+        }) = &self.state
+        else {
+            return iced::Command::none();
+        };
+
+        let server = server.clone();
+        let cached_address = external_address.clone();
+        let port_override = *port_override;
+        iced::Command::perform(
+            async move {
+                crate::core::refresh_external_address(&server, &cached_address, port_override)
+                    .await
+                    .map_err(Arc::new)
+            },
+            |r| Message::Connection(ConnectionMessage::ExternalAddressRefreshed(r)),
+        )
+    }
+
+    /// Update the state after re-pinging the server. Notify the user and each publish if our external address changed.
+    fn update_external_address_refreshed(
+        &mut self,
+        result: Result<Option<String>, Arc<anyhow::Error>>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            external_address,
+            port_override,
+            address_changes,
+            ..
+        }) = &mut self.state
+        else {
+            return iced::Command::none();
+        };
+
+        match result {
+            Ok(Some(new_address)) => {
+                *ctx.status_message = Some(format!(
+                    "External address changed from {external_address} to {new_address}. Updating publishes"
+                ));
+                *external_address = new_address;
+                address_changes.send_replace(*port_override);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{} Failed to re-ping the server: {e}", local_now_fmt()),
+        }
+        iced::Command::none()
+    }
+
+    /// Probe the default network route in the background.
+    fn update_network_poll_tick(&mut self) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState { endpoint, .. }) = &self.state else {
+            return iced::Command::none();
+        };
+
+        let using_ipv4 = endpoint_is_ipv4(endpoint);
+        iced::Command::perform(
+            async move {
+                tokio::task::spawn_blocking(move || crate::core::probe_network_route(using_ipv4))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(std::convert::identity)
+                    .map_err(Arc::new)
+            },
+            |r| Message::Connection(ConnectionMessage::NetworkRouteProbed(r)),
+        )
+    }
+
+    /// Update the state after probing the default network route.
+    /// If the route changed, move the endpoint to a new socket, republish over the migrated connections,
+    /// re-ping the server, and renew any port mapping against the new gateway.
+    fn update_network_route_probed(
+        &mut self,
+        result: Result<NetworkRoute, Arc<anyhow::Error>>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            endpoint,
+            server,
+            network_route,
+            local_port,
+            port_override,
+            address_changes,
+            ..
+        }) = &mut self.state
+        else {
+            return iced::Command::none();
+        };
+
+        let route = match result {
+            Ok(route) => route,
+            Err(e) => {
+                eprintln!("{} Failed to probe the network route: {e}", local_now_fmt());
+                return iced::Command::none();
+            }
+        };
+        if network_route.as_ref() == Some(&route) {
+            return iced::Command::none();
+        }
+
+        // The first probe after connecting only learns the route we connected over.
+        if network_route.is_none() {
+            *network_route = Some(route);
+            return iced::Command::none();
+        }
+
+        println!(
+            "{} Network route changed to local IP {} via gateway {}",
+            local_now_fmt(),
+            route.local_ip,
+            route.gateway,
+        );
+        *network_route = Some(route);
+        *ctx.status_message = Some("Network changed. Moving publishes to the new route".to_owned());
+
+        // Packets sent from the old socket may still leave over the stale route, so rebind before anything else.
+        // The server follows the migration, and every publish refreshes itself over the new path.
+        match crate::core::rebind_endpoint(endpoint) {
+            Ok(port) => *local_port = Some(port),
+            Err(e) => eprintln!("{} Failed to rebind the endpoint: {e}", local_now_fmt()),
+        }
+        address_changes.send_replace(*port_override);
+
+        // Renew the port mapping against the new gateway, unless the user specified one explicitly.
+        let renew_mapping = self.port_mapping.take().map(|stale_mapping| {
+            let gateway = self
+                .options
+                .gateway_address
+                .as_deref()
+                .and_then(|g| g.parse().ok())
+                .unwrap_or(route.gateway);
+            let local_address = SocketAddr::new(
+                route.local_ip,
+                endpoint.local_addr().map_or(0, |a| a.port()),
+            );
+            let server = server.clone();
+            iced::Command::perform(
+                async move {
+                    crate::core::renew_port_mapping(
+                        &server,
+                        local_address,
+                        gateway,
+                        Some(stale_mapping),
+                    )
+                    .await
+                    .map_err(Arc::new)
+                },
+                |r| Message::Connection(ConnectionMessage::PortMappingRenewed(r)),
+            )
+        });
+
+        // Re-ping the server to learn whether our external address changed with the route.
+        let ping = self.update_socket_ping_tick();
+        iced::Command::batch(std::iter::once(ping).chain(renew_mapping))
+    }
+
+    /// Update the state after replacing our port mapping.
+    fn update_port_mapping_renewed(
+        &mut self,
+        result: Result<crab_nat::PortMapping, Arc<anyhow::Error>>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match result {
+            Ok(mapping) => {
+                if let ConnectionState::Connected(ConnectedState { port_override, .. }) =
+                    &mut self.state
+                {
+                    *port_override = Some(mapping.external_port());
+                }
+                self.port_mapping = Some(mapping);
+
+                // The server now knows our new external port, refresh our view of it.
+                self.update_socket_ping_tick()
+            }
+            Err(e) => {
+                *ctx.status_message = Some(format!("Failed to renew the port mapping: {e}"));
+                iced::Command::none()
+            }
+        }
+    }
+
+    /// Schedule the next background attempt to acquire a port mapping after some number of failed attempts.
+    fn schedule_port_mapping_retry(&mut self, failed_attempts: u32) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            port_mapping_retry, ..
+        }) = &mut self.state
+        else {
+            return iced::Command::none();
+        };
+
+        let backoff = PORT_MAPPING_RETRY_BACKOFF
+            .saturating_mul(1 << failed_attempts.min(16))
+            .min(MAX_PORT_MAPPING_RETRY_BACKOFF);
+        *port_mapping_retry = Some((failed_attempts, Instant::now() + backoff));
+        iced::Command::perform(tokio::time::sleep(backoff), |()| {
+            Message::Connection(ConnectionMessage::PortMappingRetryTick)
+        })
+    }
+
+    /// Try to acquire a port mapping in the background against the current default gateway.
+    fn update_port_mapping_retry_tick(&mut self, ctx: &AppContext) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            endpoint,
+            server,
+            port_mapping_retry: Some((_, retry_at)),
+            ..
+        }) = &self.state
+        else {
+            return iced::Command::none();
+        };
+
+        // Ignore stale ticks, such as from a previous connection, and stop once a mapping exists.
+        if Instant::now() < *retry_at
+            || self.port_mapping.is_some()
+            || !matches!(
+                ctx.options.port_mapping,
+                PortMappingGuiOptions::TryPcpNatPmp
+            )
+        {
+            return iced::Command::none();
+        }
+
+        let using_ipv4 = endpoint_is_ipv4(endpoint);
+        let local_port = endpoint.local_addr().map_or(0, |a| a.port());
+        let gateway_override = self
+            .options
+            .gateway_address
+            .as_deref()
+            .and_then(|g| g.parse().ok());
+        let server = server.clone();
+        iced::Command::perform(
+            async move {
+                let route = tokio::task::spawn_blocking(move || {
+                    crate::core::probe_network_route(using_ipv4)
+                })
+                .await??;
+                crate::core::renew_port_mapping(
+                    &server,
+                    SocketAddr::new(route.local_ip, local_port),
+                    gateway_override.unwrap_or(route.gateway),
+                    None,
+                )
+                .await
+            },
+            |r| Message::Connection(ConnectionMessage::PortMappingRetried(r.map_err(Arc::new))),
+        )
+    }
+
+    /// Update the state after a background attempt to acquire a port mapping.
+    /// Applies the new port override on success, otherwise schedules another attempt with a longer backoff.
+    fn update_port_mapping_retried(
+        &mut self,
+        result: Result<crab_nat::PortMapping, Arc<anyhow::Error>>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let ConnectionState::Connected(ConnectedState {
+            port_override,
+            port_mapping_retry,
+            ..
+        }) = &mut self.state
+        else {
+            return iced::Command::none();
+        };
+
+        match result {
+            Ok(mapping) => {
+                *port_override = Some(mapping.external_port());
+                *port_mapping_retry = None;
+                *ctx.status_message = Some(format!(
+                    "Acquired a port mapping for external port {}",
+                    mapping.external_port()
+                ));
+                self.port_mapping = Some(mapping);
+
+                // The server now knows our new external port, refresh our view of it.
+                self.update_socket_ping_tick()
+            }
+            Err(e) => {
+                let failed_attempts = port_mapping_retry.map_or(0, |(n, _)| n) + 1;
+                eprintln!(
+                    "{} Port mapping attempt {failed_attempts} failed: {e}",
+                    local_now_fmt()
+                );
+                self.schedule_port_mapping_retry(failed_attempts)
+            }
+        }
+    }
+}
+
+/// The port mapping to ask for when trying another server, since a mapping reused by a failed attempt is gone.
+fn renewed_port_mapping(config: &PortMappingConfig) -> PortMappingConfig {
+    match config {
+        PortMappingConfig::None => PortMappingConfig::None,
+        PortMappingConfig::PortForwarding(port) => PortMappingConfig::PortForwarding(*port),
+        PortMappingConfig::PcpNatPmp(_) => PortMappingConfig::PcpNatPmp(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU16, time::Duration};
+
+    use super::*;
+    use crate::gui::AppState;
+
+    /// Handle a connection message against the app state.
+    fn update(app: &mut AppState, message: ConnectionMessage) {
+        let (connection, _, _, mut context) = app.controllers();
+        let _ = connection.update(message, &mut context);
+    }
+
+    #[test]
+    fn cancel_retry_disconnects() {
+        let mut app = AppState::default();
+        app.connection.auto_connect_attempt = Some(2);
+        app.connection.state = ConnectionState::new_stalling();
+
+        update(&mut app, ConnectionMessage::CancelRetry);
+        assert_eq!(app.connection.auto_connect_attempt, None);
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Disconnected
+        ));
+    }
+
+    #[test]
+    fn reconnect_schedules_the_first_retry() {
+        let mut app = AppState::default();
+        update(&mut app, ConnectionMessage::ServerReconnectScheduled);
+        assert_eq!(app.connection.auto_connect_attempt, Some(1));
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Retrying { attempt: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn elapsed_retry_connects_again() {
+        let mut app = AppState::default();
+        let now = Instant::now();
+        app.connection.state = ConnectionState::Retrying {
+            attempt: 1,
+            retry_at: now.checked_sub(Duration::from_secs(1)).unwrap_or(now),
+            tick: now,
+        };
+
+        update(&mut app, ConnectionMessage::AnimationTick);
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Stalling { .. }
+        ));
+        assert_eq!(app.options.server_address, "localhost");
+    }
+
+    #[test]
+    fn invalid_server_address_is_reported() {
+        let mut app = AppState::default();
+        app.options.server_address = "example.com:0".to_owned();
+
+        update(&mut app, ConnectionMessage::ConnectClicked);
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Invalid server address")
+        );
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Disconnected
+        ));
+    }
+
+    #[test]
+    fn failed_auto_connect_is_retried() {
+        let mut app = AppState::default();
+        app.connection.auto_connect_attempt = Some(0);
+        app.connection.state = ConnectionState::new_stalling();
+
+        update(
+            &mut app,
+            ConnectionMessage::ConnectResulted(Err(Arc::new(anyhow::anyhow!("unreachable")))),
+        );
+        assert_eq!(app.connection.auto_connect_attempt, Some(1));
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Retrying { attempt: 1, .. }
+        ));
+        assert!(app.status_message.is_some());
+    }
+
+    #[test]
+    fn failed_manual_connect_is_not_retried() {
+        let mut app = AppState::default();
+        app.connection.state = ConnectionState::new_stalling();
+
+        update(
+            &mut app,
+            ConnectionMessage::ConnectResulted(Err(Arc::new(anyhow::anyhow!("unreachable")))),
+        );
+        assert_eq!(app.connection.auto_connect_attempt, None);
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Disconnected
+        ));
+    }
+
+    #[test]
+    fn port_range_in_use_offers_a_random_port() {
+        let mut app = AppState::default();
+        app.connection.auto_connect_attempt = Some(0);
+        app.connection.state = ConnectionState::new_stalling();
+        let port = NonZeroU16::new(50_000).unwrap();
+        let error = anyhow::Error::new(crate::core::PortRangeInUse(crate::core::PortRange {
+            start: port,
+            end: port,
+        }));
+
+        update(
+            &mut app,
+            ConnectionMessage::ConnectResulted(Err(Arc::new(error))),
+        );
+        assert!(app.connection.port_conflict);
+        assert_eq!(app.connection.auto_connect_attempt, None);
+        assert!(matches!(
+            app.connection.state,
+            ConnectionState::Disconnected
+        ));
+    }
+
+    #[test]
+    fn server_notifications_are_shown() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            ConnectionMessage::ServerNotified(
+                ServerNotification::Broadcast,
+                "Maintenance at noon".to_owned(),
+            ),
+        );
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Server broadcast: Maintenance at noon")
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use file_yeet_shared::{local_now_fmt, FileHash, PeerAddr, SERVER_MESSAGE_BUFFERS};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    remember_peer, track_peer_connection, AppContext, ConnectedState, DownloadPath,
+    IncomingSubscribePeers, Message, Nonce, PeerConnection, Transfer, TransferProgress,
+    TransferResult, TransferView,
+};
+use crate::core::{FileMetadata, FileYeetCommandType, PEER_CONNECT_TIMEOUT};
+
+/// Parse the hash input as either a file hash or a `fyeet://` share link.
+/// Returns the hash and the link's file extension hint, if any.
+pub(super) fn parse_hash_input(input: &str) -> Result<(FileHash, Option<String>), String> {
+    let input = input.trim();
+    if input.contains("://") {
+        file_yeet_shared::parse_share_uri(input)
+            .map(|uri| (uri.hash, uri.extension))
+            .map_err(|e| format!("Invalid share link: {e}"))
+    } else {
+        input
+            .parse::<FileHash>()
+            .map(|hash| (hash, None))
+            .map_err(|e| format!("Invalid hash: {e}"))
+    }
+}
+
+/// The messages handled by the download controller.
+#[derive(Clone, Debug)]
+pub enum DownloadMessage {
+    /// The hash input field was changed.
+    HashInputChanged(String),
+
+    /// The download button was clicked.
+    Started,
+
+    /// The path to save new downloads to was chosen, or `None` if the choice was cancelled.
+    PathChosen(Vec<Nonce>, Option<PathBuf>),
+
+    /// The server responded with the peers publishing a file.
+    PeersResult(Result<IncomingSubscribePeers, Arc<anyhow::Error>>),
+
+    /// The result of connecting to a peer publishing a file we want.
+    PeerConnectResulted(Nonce, Option<PeerConnection>),
+
+    /// The user accepted the download from a peer.
+    Accept(Nonce),
+}
+
+/// Handles subscribing to files and connecting to the peers publishing them.
+#[derive(Default)]
+pub(super) struct DownloadController {
+    /// List of file downloads from peers, kept across reconnects by stashing their paths.
+    pub(super) downloads: Vec<Transfer>,
+
+    /// The hash or share link field for new downloads.
+    pub(super) hash_input: String,
+}
+impl DownloadController {
+    /// Update the downloads for a download message. Subscribing needs a server connection.
+    pub(super) fn update(
+        &mut self,
+        message: DownloadMessage,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match message {
+            // Handle the hash input being changed.
+            DownloadMessage::HashInputChanged(hash) => {
+                self.hash_input = hash;
+                iced::Command::none()
+            }
+
+            // Handle the subscribe button being clicked by requesting the publishers from the server.
+            // The save location is chosen once the publishers' file name is known.
+            DownloadMessage::Started => self.update_started(connected, ctx),
+
+            // Save the new downloads to the chosen path, or drop them if the choice was cancelled.
+            DownloadMessage::PathChosen(nonces, path) => {
+                *ctx.modal = false;
+                self.update_path_chosen(&nonces, path);
+                iced::Command::none()
+            }
+
+            // Handle the result of a subscribe request.
+            DownloadMessage::PeersResult(r) => self.update_peers_result(r, connected, ctx),
+
+            // Handle the result of a subscribe connection attempt to a peer.
+            DownloadMessage::PeerConnectResulted(nonce, r) => {
+                self.update_peer_connect_resulted(nonce, r, connected, ctx);
+                iced::Command::none()
+            }
+
+            // Handle the download being accepted, initiate the download.
+            DownloadMessage::Accept(nonce) => match connected {
+                Some(ConnectedState { shutdown_token, .. }) => {
+                    self.update_accept(nonce, shutdown_token)
+                }
+                None => iced::Command::none(),
+            },
+        }
+    }
+
+    /// Update the state after the download button was clicked. Begins a subscribe request.
+    fn update_started(
+        &mut self,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        // Clear the status message before starting the subscribe attempt.
+        *ctx.status_message = None;
+
+        // Ensure the client is connected to a server.
+        let Some(connected_state) = connected else {
+            return iced::Command::none();
+        };
+        let servers = connected_state.servers();
+        let ConnectedState {
+            passphrase_input,
+            transfer_view,
+            ..
+        } = connected_state;
+
+        // Ensure the hash is valid.
+        // Unless the publishers name the file, it is named by its hash with the extension hint of a share link.
+        let (hash, fallback_name) = match parse_hash_input(&self.hash_input) {
+            Ok((hash, Some(extension))) => (hash, format!("{hash}.{extension}")),
+            Ok((hash, None)) => (hash, hash.to_string()),
+            Err(e) => {
+                *ctx.status_message = Some(e);
+                return iced::Command::none();
+            }
+        };
+
+        // Ensure the transfer view is set to downloads to see the new item.
+        *transfer_view = TransferView::Downloads;
+
+        Self::subscribe_command(
+            servers,
+            DownloadPath::Choose { fallback_name },
+            hash,
+            ctx.options.room.clone(),
+            Some(passphrase_input.as_str())
+                .filter(|p| !p.is_empty())
+                .map(Arc::from),
+        )
+    }
+
+    /// Update the state after the path to save new downloads to was chosen or cancelled.
+    fn update_path_chosen(&mut self, nonces: &[Nonce], path: Option<PathBuf>) {
+        if let Some(path) = path {
+            for transfer in self
+                .downloads
+                .iter_mut()
+                .filter(|t| nonces.contains(&t.nonce))
+            {
+                transfer.path.clone_from(&path);
+            }
+        } else {
+            // Drop the downloads and their connection attempts when the user doesn't want the file.
+            self.downloads.retain(|t| {
+                let keep = !nonces.contains(&t.nonce);
+                if !keep {
+                    t.cancellation_token.cancel();
+                }
+                keep
+            });
+        }
+    }
+
+    /// Create a command to request the peers publishing a file hash from every server.
+    /// Each publisher is connected to once, however many servers introduce it.
+    /// The passphrase, if any, is proven to each publisher connected to.
+    pub(super) fn subscribe_command(
+        servers: Vec<quinn::Connection>,
+        path: DownloadPath,
+        hash: FileHash,
+        room: String,
+        passphrase: Option<Arc<str>>,
+    ) -> iced::Command<Message> {
+        iced::Command::perform(
+            async move {
+                let mut bb = SERVER_MESSAGE_BUFFERS.take();
+                let mut peers_with_size: Vec<(SocketAddr, u64, FileMetadata)> = Vec::new();
+                let mut error = None;
+                let mut answered = false;
+                for server in &servers {
+                    match crate::core::subscribe(server, &mut bb, hash, &room).await {
+                        Ok(peers) => {
+                            answered = true;
+                            for peer in peers {
+                                if !peers_with_size.iter().any(|(a, _, _)| *a == peer.0) {
+                                    peers_with_size.push(peer);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "{} Failed to subscribe with the server at {}: {e}",
+                                local_now_fmt(),
+                                server.remote_address()
+                            );
+                            error = Some(e);
+                        }
+                    }
+                }
+                match error {
+                    Some(e) if !answered => Err(Arc::new(e)),
+                    _ => Ok(IncomingSubscribePeers::new(
+                        peers_with_size,
+                        path,
+                        hash,
+                        passphrase,
+                    )),
+                }
+            },
+            |r| Message::Download(DownloadMessage::PeersResult(r)),
+        )
+    }
+
+    /// Update after server has responded to a subscribe request.
+    fn update_peers_result(
+        &mut self,
+        result: Result<IncomingSubscribePeers, Arc<anyhow::Error>>,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match result {
+            Ok(IncomingSubscribePeers {
+                peers_with_size,
+                path,
+                hash,
+                passphrase,
+            }) => {
+                // Save the file under the name its publishers gave it, asking where unless there is a default directory.
+                let (path, choose_file_name) = match path {
+                    DownloadPath::Chosen(path) => (path, None),
+                    DownloadPath::Choose { fallback_name } => {
+                        let file_name = peers_with_size
+                            .iter()
+                            .find_map(|(_, _, metadata)| metadata.safe_file_name())
+                            .map_or(fallback_name, str::to_owned);
+                        match ctx.options.download_directory.resolve(&file_name) {
+                            Some(path) => (path, None),
+
+                            // The path is filled in once the user chooses it.
+                            None => (PathBuf::new(), Some(file_name)),
+                        }
+                    }
+                };
+
+                let disable_connection_reuse = ctx.options.disable_connection_reuse;
+                if let Some(ConnectedState {
+                    endpoint,
+                    peers,
+                    shutdown_token,
+                    ..
+                }) = connected
+                {
+                    // Let the user know why nothing else is happening.
+                    if peers_with_size.is_empty() {
+                        *ctx.status_message = Some("No peers available".to_owned());
+                        return iced::Command::none();
+                    }
+
+                    // Create a new transfer state and connection attempt for each peer.
+                    let transfers_commands_iter =
+                        peers_with_size.into_iter().map(|(peer, file_size, hints)| {
+                            // Create a nonce to identify the transfer.
+                            let nonce = rand::random();
+
+                            // New transfer state for this request.
+                            let transfer = Transfer {
+                                nonce,
+                                hash,
+                                hash_hex: hash.to_string(),
+                                file_size,
+                                peer_string: peer.to_string(),
+                                peer_fingerprint: None,
+                                publisher_fingerprint: hints
+                                    .verified_publisher(&hash.bytes, file_size),
+                                path: path.clone(),
+                                progress: TransferProgress::Connecting,
+                                cancellation_token: shutdown_token.child_token(),
+                                passphrase: passphrase.clone(),
+                            };
+
+                            // New connection attempt for this peer with result command identified by the nonce.
+                            let command = {
+                                // Allow creating a new connection or opening a stream on an existing one.
+                                let existing = if disable_connection_reuse {
+                                    None
+                                } else {
+                                    peers.get(&PeerAddr::from(peer)).map(|(c, _)| c.clone())
+                                };
+                                let endpoint = endpoint.clone();
+                                let passphrase = passphrase.clone();
+
+                                // The future to use to create the connection.
+                                let future = async move {
+                                    tokio::time::timeout(
+                                        PEER_CONNECT_TIMEOUT,
+                                        crate::core::reuse_or_holepunch(
+                                            existing,
+                                            FileYeetCommandType::Sub,
+                                            hash.bytes,
+                                            passphrase.as_deref(),
+                                            endpoint,
+                                            peer,
+                                        ),
+                                    )
+                                    .await
+                                    .ok()
+                                    .flatten()
+                                    .map(PeerConnection::from)
+                                };
+                                iced::Command::perform(future, move |r| {
+                                    Message::Download(DownloadMessage::PeerConnectResulted(
+                                        nonce, r,
+                                    ))
+                                })
+                            };
+
+                            // Return the pair to be separated later.
+                            (transfer, command)
+                        });
+
+                    // Create a new transfer for each peer.
+                    let (mut new_transfers, connect_commands): (
+                        Vec<Transfer>,
+                        Vec<iced::Command<Message>>,
+                    ) = transfers_commands_iter.unzip();
+
+                    // Ask where to save the file while the peers are connecting.
+                    let choose_path = if let Some(file_name) = choose_file_name {
+                        let nonces: Vec<Nonce> = new_transfers.iter().map(|t| t.nonce).collect();
+                        *ctx.modal = true;
+                        iced::Command::perform(
+                            rfd::AsyncFileDialog::new()
+                                .set_title("Choose a file path to save to")
+                                .set_file_name(file_name)
+                                .save_file(),
+                            move |f| {
+                                Message::Download(DownloadMessage::PathChosen(
+                                    nonces,
+                                    f.map(PathBuf::from),
+                                ))
+                            },
+                        )
+                    } else {
+                        iced::Command::none()
+                    };
+
+                    // Add the new transfers to the list of active transfers.
+                    self.downloads.append(&mut new_transfers);
+                    iced::Command::batch(connect_commands.into_iter().chain([choose_path]))
+                } else {
+                    iced::Command::none()
+                }
+            }
+            Err(e) => {
+                *ctx.status_message = Some(format!("Error subscribing to the server: {e}"));
+                iced::Command::none()
+            }
+        }
+    }
+
+    /// Update the state after a subscribe connection attempt to a peer completed.
+    fn update_peer_connect_resulted(
+        &mut self,
+        nonce: Nonce,
+        result: Option<PeerConnection>,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) {
+        // Find the transfer with the matching nonce.
+        let Some(index) = self.downloads.iter().position(|t| t.nonce == nonce) else {
+            return;
+        };
+
+        // Update the state of the transfer with the result.
+        match (result, connected) {
+            (Some(connection), Some(ConnectedState { peers, .. })) => {
+                let peer_address = PeerAddr::from(connection.connection.remote_address());
+                track_peer_connection(peers, peer_address, &connection.connection, nonce);
+
+                let transfer = &mut self.downloads[index];
+                transfer.peer_fingerprint = remember_peer(ctx.known_peers, &connection.connection);
+                transfer.progress = TransferProgress::Consent(connection);
+            }
+
+            // The connection is no longer needed once we have left the server.
+            (Some(_), None) => {}
+
+            // Remove unreachable peers from view and don't reuse them for this hash.
+            (None, _) => {
+                let transfer = self.downloads.remove(index);
+                crate::core::invalidate_subscribe_cache(&transfer.hash);
+            }
+        }
+    }
+
+    /// Tell the peer to send the file and begin recieving and writing the file.
+    fn update_accept(
+        &mut self,
+        nonce: Nonce,
+        shutdown_token: &CancellationToken,
+    ) -> iced::Command<Message> {
+        // Get the current transfer status.
+        let Some(transfer) = self.downloads.iter_mut().find(|t| t.nonce == nonce) else {
+            return iced::Command::none();
+        };
+        let hash = transfer.hash;
+        let file_size = transfer.file_size;
+        let peer_streams = if let TransferProgress::Consent(p) = &mut transfer.progress {
+            p.clone()
+        } else {
+            return iced::Command::none();
+        };
+
+        // Begin the transfer.
+        let (byte_progress, progress_receiver) = watch::channel(0.);
+        transfer.progress =
+            TransferProgress::Transferring(peer_streams.clone(), progress_receiver, 0.);
+        let output_path = transfer.path.clone();
+        let cancellation_token = transfer.cancellation_token.clone();
+        let shutdown_token = shutdown_token.clone();
+
+        iced::Command::perform(
+            async move {
+                let mut peer_streams_lock = peer_streams.streams.lock().await;
+
+                // Create a buffer for the file transfer range. Need to send a `u64` start index and `u64` length.
+                let mut bb = bytes::BytesMut::with_capacity(16);
+                tokio::select! {
+                    // Let the transfer be cancelled. This is not an error if cancelled.
+                    () = cancellation_token.cancelled() => TransferResult::cancelled(&shutdown_token),
+
+                    // Await the file to be downloaded.
+                    result = Box::pin(crate::core::download_from_peer(
+                        hash,
+                        &mut peer_streams_lock,
+                        file_size,
+                        &output_path,
+                        &mut bb,
+                        Some(byte_progress),
+                    )) => {
+                        match result {
+                            Ok(()) => TransferResult::Success,
+                            Err(e) => TransferResult::from_error(anyhow::anyhow!("Download failed: {e}"), &peer_streams.connection),
+                        }
+                    }
+                }
+            },
+            move |r| Message::TransferResulted(nonce, r, FileYeetCommandType::Sub),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gui::AppState;
+
+    /// Handle a download message against the app state.
+    fn update(app: &mut AppState, message: DownloadMessage) {
+        let (connection, _, download, mut context) = app.controllers();
+        let _ = download.update(message, connection.connected_mut(), &mut context);
+    }
+
+    /// Add a download that is still connecting to its peer, returning its nonce.
+    fn add_connecting_download(app: &mut AppState) -> Nonce {
+        let nonce = rand::random();
+        let hash = FileHash::new(file_yeet_shared::HashAlgorithm::default(), [7; 32]);
+        app.download.downloads.push(Transfer {
+            nonce,
+            hash,
+            hash_hex: hash.to_string(),
+            file_size: 1024,
+            peer_string: "127.0.0.1:7828".to_owned(),
+            peer_fingerprint: None,
+            publisher_fingerprint: None,
+            path: PathBuf::new(),
+            progress: TransferProgress::Connecting,
+            cancellation_token: CancellationToken::new(),
+            passphrase: None,
+        });
+        nonce
+    }
+
+    #[test]
+    fn hash_input_is_kept() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            DownloadMessage::HashInputChanged("abc".to_owned()),
+        );
+        assert_eq!(app.download.hash_input, "abc");
+    }
+
+    #[test]
+    fn chosen_path_is_applied() {
+        let mut app = AppState::default();
+        let nonce = add_connecting_download(&mut app);
+        app.modal = true;
+
+        update(
+            &mut app,
+            DownloadMessage::PathChosen(vec![nonce], Some(PathBuf::from("file.txt"))),
+        );
+        assert!(!app.modal);
+        assert_eq!(app.download.downloads[0].path, PathBuf::from("file.txt"));
+    }
+
+    #[test]
+    fn cancelled_path_choice_drops_the_downloads() {
+        let mut app = AppState::default();
+        let nonce = add_connecting_download(&mut app);
+        let cancellation_token = app.download.downloads[0].cancellation_token.clone();
+
+        update(&mut app, DownloadMessage::PathChosen(vec![nonce], None));
+        assert!(app.download.downloads.is_empty());
+        assert!(cancellation_token.is_cancelled());
+    }
+
+    #[test]
+    fn unreachable_peer_is_removed() {
+        let mut app = AppState::default();
+        let nonce = add_connecting_download(&mut app);
+
+        update(&mut app, DownloadMessage::PeerConnectResulted(nonce, None));
+        assert!(app.download.downloads.is_empty());
+    }
+
+    #[test]
+    fn failed_subscribe_is_reported() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            DownloadMessage::PeersResult(Err(Arc::new(anyhow::anyhow!("no server")))),
+        );
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Error subscribing to the server: no server")
+        );
+    }
+}
@@ -0,0 +1,862 @@
+use std::{
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use file_yeet_shared::{local_now_fmt, FileHash, HashBytes, PeerAddr, SERVER_MESSAGE_BUFFERS};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument as _;
+
+use super::{
+    parse_server_address, remember_peer, track_peer_connection, AppContext, ConnectedState,
+    IncomingPublishSession, Message, Nonce, PeerConnection, PublishItem, PublishRequestResult,
+    PublishState, Transfer, TransferProgress, TransferResult, TransferView,
+};
+use crate::core::{FileMetadata, FileYeetCommandType, RegisteredPublish};
+use crate::hooks::{HookContext, TransferEvent};
+use crate::identity::PublisherKey;
+
+/// Ask every server to publish a hashed file. Succeeds if any server accepts the publish.
+/// Suggests the file's name to subscribers, and signs the publish if a key is given.
+async fn publish_request(
+    servers: &[quinn::Connection],
+    path: &Path,
+    hash: FileHash,
+    file_size: u64,
+    chunk_hashes: Vec<HashBytes>,
+    room: &str,
+    signing_key: Option<&PublisherKey>,
+) -> PublishRequestResult {
+    let mut metadata = FileMetadata::from_path(path);
+    if let Some(key) = signing_key {
+        metadata = metadata.signed(key, &hash.bytes, file_size);
+    }
+
+    // Create a bi-directional stream to each server for this publish request.
+    let results = futures_util::future::join_all(servers.iter().map(|server| {
+        // Create a memory buffer with sufficient capacity for the publish request.
+        let bb = SERVER_MESSAGE_BUFFERS.take();
+        crate::core::publish(server, bb, hash, file_size, room, &metadata)
+    }))
+    .await;
+
+    let mut server_streams = Vec::with_capacity(results.len());
+    let mut error = None;
+    for (server, result) in servers.iter().zip(results) {
+        match result {
+            Ok(b) => server_streams.push((server.clone(), b)),
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to publish to the server at {}: {e}",
+                    local_now_fmt(),
+                    server.remote_address()
+                );
+                error = Some(e);
+            }
+        }
+    }
+    match error {
+        Some(e) if server_streams.is_empty() => PublishRequestResult::Failure(Arc::new(e)),
+        _ => PublishRequestResult::Success(IncomingPublishSession::new(
+            server_streams,
+            hash,
+            file_size,
+            chunk_hashes,
+            room,
+            metadata,
+        )),
+    }
+}
+
+/// Parse the download limit input. Empty input means no limit.
+pub(super) fn parse_max_downloads_input(input: &str) -> Result<Option<NonZeroUsize>, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        Ok(None)
+    } else {
+        input
+            .parse::<NonZeroUsize>()
+            .map(Some)
+            .map_err(|e| format!("Invalid download limit: {e}"))
+    }
+}
+
+/// The messages handled by the publish controller.
+#[derive(Clone, Debug)]
+pub enum PublishMessage {
+    /// The publish button was clicked.
+    Clicked,
+
+    /// The download limit input field was changed.
+    MaxDownloadsInputChanged(String),
+
+    /// The file picker dialog has completed.
+    PathChosen(Option<PathBuf>),
+
+    /// A file was dropped onto the main window to be published.
+    FileDropped(PathBuf),
+
+    /// The result of a publish request.
+    RequestResulted(Nonce, PathBuf, PublishRequestResult),
+
+    /// The server introduced a peer to one of our publishes, with the span to connect to and serve them in.
+    PeerReceived(
+        Nonce,
+        Result<(SocketAddr, tracing::Span), Arc<anyhow::Error>>,
+    ),
+
+    /// The result of connecting to a peer introduced to one of our publishes.
+    PeerConnectResulted(Nonce, Option<PeerConnection>, tracing::Span),
+
+    /// The cancel or remove button of a publish was clicked.
+    Cancel(Nonce),
+
+    /// The user decided whether cancelling a publish also cancels its active uploads.
+    ConfirmCancel(Nonce, bool),
+
+    /// The user decided not to cancel a publish.
+    DismissCancel(Nonce),
+
+    /// Copy a share link for a publish to the clipboard.
+    CopyLink(Nonce),
+
+    /// The button to compare our publishes with the server's was clicked.
+    SyncClicked,
+
+    /// The publishes the server has registered for us.
+    SyncResulted(Result<Vec<RegisteredPublish>, Arc<anyhow::Error>>),
+}
+
+/// Handles publishing files and connecting to the peers introduced to our publishes.
+#[derive(Default)]
+pub(super) struct PublishController {
+    /// List of publish requests, kept across reconnects by stashing their paths.
+    pub(super) publishes: Vec<PublishItem>,
+
+    /// The download limit field for new publishes. Empty for no limit.
+    pub(super) max_downloads_input: String,
+}
+impl PublishController {
+    /// Update the publishes for a publish message. Requests need a server connection.
+    pub(super) fn update(
+        &mut self,
+        message: PublishMessage,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        match message {
+            // Handle the publish button being clicked by picking a file to publish.
+            PublishMessage::Clicked => {
+                // Clear the status message before starting the publish attempt.
+                *ctx.status_message = None;
+
+                // Let state know that a modal dialog is open.
+                *ctx.modal = true;
+
+                iced::Command::perform(
+                    rfd::AsyncFileDialog::new()
+                        .set_title("Choose a file to publish")
+                        .pick_file(),
+                    |f| Message::Publish(PublishMessage::PathChosen(f.map(PathBuf::from))),
+                )
+            }
+
+            // Handle the download limit input being changed.
+            PublishMessage::MaxDownloadsInputChanged(input) => {
+                self.max_downloads_input = input;
+                iced::Command::none()
+            }
+
+            // Begin the process of publishing a file to the server.
+            PublishMessage::PathChosen(path) => {
+                *ctx.modal = false;
+                match (path, connected) {
+                    (Some(path), Some(connected_state)) => {
+                        self.publish_path(path, connected_state, ctx)
+                    }
+                    _ => iced::Command::none(),
+                }
+            }
+
+            // Start publishing a file dropped onto the window, as if it had been chosen in the file picker.
+            PublishMessage::FileDropped(path) => self.update_file_dropped(path, connected, ctx),
+
+            // Handle the result of a publish request.
+            PublishMessage::RequestResulted(nonce, path, r) => {
+                self.update_request_resulted(nonce, &path, r, ctx)
+            }
+
+            // Handle a peer connection being received for a publish request.
+            PublishMessage::PeerReceived(nonce, r) => match connected {
+                Some(connected_state) => self.update_peer_received(nonce, r, connected_state, ctx),
+                None => iced::Command::none(),
+            },
+
+            // Handle the result of a peer connection attempt for a publish request.
+            PublishMessage::PeerConnectResulted(pub_nonce, peer, span) => match (peer, connected) {
+                (Some(peer), Some(connected_state)) => {
+                    self.update_peer_connect_resulted(pub_nonce, peer, span, connected_state, ctx)
+                }
+
+                // Silently fail if the peer connection was not successful.
+                _ => iced::Command::none(),
+            },
+
+            // Set the cancellation token to notify the publishing thread to cancel.
+            PublishMessage::Cancel(nonce) => {
+                let uploads = connected.map_or(&[][..], |c| c.uploads.as_slice());
+                self.update_cancel(nonce, uploads);
+                iced::Command::none()
+            }
+
+            // Cancel the publish once the user decided what happens to its active uploads.
+            PublishMessage::ConfirmCancel(nonce, cancel_uploads) => {
+                let uploads = connected.map_or(&[][..], |c| c.uploads.as_slice());
+                self.cancel(nonce, cancel_uploads, uploads);
+                iced::Command::none()
+            }
+
+            // Stop asking about cancelling the publish.
+            PublishMessage::DismissCancel(nonce) => {
+                if let Some(pi) = self.publishes.iter_mut().find(|p| p.nonce == nonce) {
+                    pi.confirming_cancel = None;
+                }
+                iced::Command::none()
+            }
+
+            // Copy a link that names both the server and the file to download.
+            PublishMessage::CopyLink(nonce) => match connected {
+                Some(connected_state) => {
+                    self.update_copy_link(nonce, &connected_state.server_address, ctx)
+                }
+                None => iced::Command::none(),
+            },
+
+            // Ask the first server which of our publishes it has.
+            PublishMessage::SyncClicked => {
+                let Some(ConnectedState { server, .. }) = connected else {
+                    return iced::Command::none();
+                };
+                let server = server.clone();
+                iced::Command::perform(
+                    async move { crate::core::list_publishes(&server).await.map_err(Arc::new) },
+                    |r| Message::Publish(PublishMessage::SyncResulted(r)),
+                )
+            }
+
+            // Compare the server's publishes with ours.
+            PublishMessage::SyncResulted(result) => {
+                self.update_sync_resulted(result, ctx);
+                iced::Command::none()
+            }
+        }
+    }
+
+    /// Begin hashing a chosen file and then publishing it to the server.
+    fn publish_path(
+        &mut self,
+        path: PathBuf,
+        connected_state: &mut ConnectedState,
+        ctx: &AppContext,
+    ) -> iced::Command<Message> {
+        let servers = connected_state.servers();
+        let ConnectedState {
+            passphrase_input,
+            transfer_view,
+            ..
+        } = connected_state;
+
+        // Ensure the transfer view is set to publishing to see the new item.
+        *transfer_view = TransferView::Publishes;
+
+        let (progress, progress_receiver) = watch::channel(0.);
+        let nonce = rand::random();
+        let cancellation_token = CancellationToken::new();
+        let cancellation_path = path.clone();
+        let hash_algorithm = ctx.options.hash_algorithm;
+        let room = ctx.options.room.clone();
+        let signing_key = ctx
+            .options
+            .sign_publishes
+            .then(|| ctx.publisher_key.cloned())
+            .flatten();
+
+        self.publishes.push(PublishItem::new(
+            nonce,
+            path.clone(),
+            cancellation_token.clone(),
+            progress_receiver,
+            Some(passphrase_input.as_str())
+                .filter(|p| !p.is_empty())
+                .map(Arc::from),
+            parse_max_downloads_input(&self.max_downloads_input)
+                .ok()
+                .flatten(),
+        ));
+        iced::Command::perform(
+            async move {
+                tokio::select! {
+                    // Allow cancelling the publish request thread.
+                    () = cancellation_token.cancelled() => (PublishRequestResult::Cancelled, cancellation_path),
+
+                    r = async move {
+                        // Get the file size and hash of the chosen file to publish, reusing them if the file is unchanged since it was last hashed.
+                        let (file_size, hash, chunk_hashes) =
+                            match crate::core::cached_file_size_hash_and_chunks(&path, hash_algorithm, Some(progress), false).await {
+                                Ok((file_size, hash, chunk_hashes)) => (file_size, FileHash::new(hash_algorithm, hash), chunk_hashes),
+                                Err(e) => {
+                                    return (
+                                        PublishRequestResult::Failure(Arc::new(anyhow::anyhow!(
+                                            "Error getting file size and hash: {e}"
+                                        ))),
+                                        path,
+                                    );
+                                }
+                            };
+
+                        (
+                            publish_request(&servers, &path, hash, file_size, chunk_hashes, &room, signing_key.as_deref()).await,
+                            path,
+                        )
+                    } => r
+                }
+            },
+            move |(r, p)| Message::Publish(PublishMessage::RequestResulted(nonce, p, r)),
+        )
+    }
+
+    /// Publish a verified download under its hash so other subscribers can download it from us too.
+    /// Downloads that are already published are left alone.
+    pub(super) fn seed_download(
+        &mut self,
+        path: PathBuf,
+        hash: FileHash,
+        file_size: u64,
+        servers: Vec<quinn::Connection>,
+        ctx: &AppContext,
+    ) -> iced::Command<Message> {
+        if self
+            .publishes
+            .iter()
+            .any(|pi| matches!(&pi.state, PublishState::Publishing(p) if p.hash == hash))
+        {
+            return iced::Command::none();
+        }
+
+        let nonce = rand::random();
+        let cancellation_token = CancellationToken::new();
+        let room = ctx.options.room.clone();
+        let signing_key = ctx
+            .options
+            .sign_publishes
+            .then(|| ctx.publisher_key.cloned())
+            .flatten();
+
+        // The file was verified while downloading, so it doesn't need to be hashed again.
+        let (_, progress_receiver) = watch::channel(1.);
+        let mut item = PublishItem::new(
+            nonce,
+            path.clone(),
+            cancellation_token.clone(),
+            progress_receiver,
+            None,
+            None,
+        );
+        if let PublishState::Hashing(_, progress) = &mut item.state {
+            *progress = 1.;
+        }
+        self.publishes.push(item);
+        iced::Command::perform(
+            async move {
+                let result = tokio::select! {
+                    () = cancellation_token.cancelled() => PublishRequestResult::Cancelled,
+                    r = publish_request(&servers, &path, hash, file_size, Vec::new(), &room, signing_key.as_deref()) => r,
+                };
+                (result, path)
+            },
+            move |(r, p)| Message::Publish(PublishMessage::RequestResulted(nonce, p, r)),
+        )
+    }
+
+    /// Start publishing a file dropped onto the window, as if it had been chosen in the file picker.
+    fn update_file_dropped(
+        &mut self,
+        path: PathBuf,
+        connected: Option<&mut ConnectedState>,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        // Leave any open dialog to decide what happens next.
+        if *ctx.modal {
+            return iced::Command::none();
+        }
+        let Some(connected_state) = connected else {
+            *ctx.status_message = Some("Connect to a server to publish dropped files".to_owned());
+            return iced::Command::none();
+        };
+        if !path.is_file() {
+            *ctx.status_message = Some(format!(
+                "Only files can be published, not {}",
+                path.display()
+            ));
+            return iced::Command::none();
+        }
+        self.publish_path(path, connected_state, ctx)
+    }
+
+    /// Update after the server has accepted a publish request, or there was an error.
+    fn update_request_resulted(
+        &mut self,
+        nonce: Nonce,
+        path: &Path,
+        result: PublishRequestResult,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let publishes = &mut self.publishes;
+        match (result, publishes.iter().position(|p| p.nonce == nonce)) {
+            (PublishRequestResult::Success(session), Some(i)) => {
+                publishes[i].upgrade_hashing(session);
+            }
+            (PublishRequestResult::Failure(e), Some(i)) => {
+                let context = HookContext {
+                    path: path.to_path_buf(),
+                    error: Some(e.to_string()),
+                    ..HookContext::default()
+                };
+                publishes[i].state = PublishState::Failure(e);
+                return ctx.run_hook(TransferEvent::PublishFailed, context);
+            }
+            (PublishRequestResult::Cancelled, Some(i)) => {
+                publishes[i].state = PublishState::Cancelled;
+            }
+            (e, None) => {
+                *ctx.status_message = Some(format!("Error publishing {}: {e:?}", path.display()));
+            }
+        }
+        iced::Command::none()
+    }
+
+    /// Update after the server has sent a peer to publish to, or there was an error.
+    fn update_peer_received(
+        &mut self,
+        nonce: Nonce,
+        result: Result<(SocketAddr, tracing::Span), Arc<anyhow::Error>>,
+        connected_state: &mut ConnectedState,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let ConnectedState {
+            endpoint, peers, ..
+        } = connected_state;
+
+        let publish = self.publishes.iter().find_map(|p| {
+            if let PublishState::Publishing(publishing) = &p.state {
+                if p.nonce == nonce {
+                    Some((publishing.clone(), p.passphrase.clone()))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        });
+        match (result, publish) {
+            (Ok((peer, span)), Some((publish, passphrase))) => {
+                // TODO: A task will listen for connected peers. At that point we should only attempt something if not already connected.
+                let existing = if ctx.options.disable_connection_reuse {
+                    None
+                } else {
+                    peers.get(&PeerAddr::from(peer)).map(|(c, _)| c.clone())
+                };
+                let endpoint = endpoint.clone();
+                let hash = publish.hash.bytes;
+                iced::Command::perform(
+                    async move {
+                        crate::core::reuse_or_holepunch(
+                            existing,
+                            FileYeetCommandType::Pub,
+                            hash,
+                            passphrase.as_deref(),
+                            endpoint,
+                            peer,
+                        )
+                        .await
+                    }
+                    .instrument(span.clone()),
+                    move |r| {
+                        Message::Publish(PublishMessage::PeerConnectResulted(
+                            nonce,
+                            r.map(Into::<PeerConnection>::into),
+                            span,
+                        ))
+                    },
+                )
+            }
+            (Err(e), _) => {
+                *ctx.status_message = Some(format!("Error receiving peer: {e}"));
+                iced::Command::none()
+            }
+            (_, None) => iced::Command::none(),
+        }
+    }
+
+    /// Update after a connection to a peer for publishing has been made. Starts uploading the file to them.
+    fn update_peer_connect_resulted(
+        &mut self,
+        pub_nonce: Nonce,
+        peer: PeerConnection,
+        span: tracing::Span,
+        connected_state: &mut ConnectedState,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let ConnectedState {
+            peers,
+            uploads,
+            shutdown_token,
+            ..
+        } = connected_state;
+        let Some((publishing, path, upload_nonces)) = self.publishes.iter_mut().find_map(|pi| {
+            if let PublishState::Publishing(p) = &pi.state {
+                if pi.nonce == pub_nonce {
+                    Some((p, pi.path.clone(), &mut pi.upload_nonces))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        }) else {
+            return iced::Command::none();
+        };
+        // Remember the upload so it can be cancelled along with the publish.
+        let upload_nonce = rand::random();
+        upload_nonces.insert(upload_nonce);
+        let (progress, progress_receiver) = watch::channel(0.);
+        let cancellation_token = shutdown_token.child_token();
+        let shutdown_token = shutdown_token.clone();
+        let peer_address = PeerAddr::from(peer.connection.remote_address());
+        let peer_fingerprint = remember_peer(ctx.known_peers, &peer.connection);
+        uploads.push(Transfer {
+            nonce: upload_nonce,
+            hash: publishing.hash,
+            hash_hex: publishing.hash.to_string(),
+            file_size: publishing.file_size,
+            peer_string: peer_address.to_string(),
+            peer_fingerprint,
+            publisher_fingerprint: None,
+            path: path.clone(),
+            progress: TransferProgress::Transferring(peer.clone(), progress_receiver, 0.),
+            cancellation_token: cancellation_token.clone(),
+            passphrase: None,
+        });
+
+        track_peer_connection(peers, peer_address, &peer.connection, upload_nonce);
+
+        let file_size = publishing.file_size;
+        let chunk_hashes = publishing.chunk_hashes.clone();
+        iced::Command::perform(
+            async move {
+                let file = match tokio::fs::File::open(path).await {
+                    Ok(f) => f,
+                    Err(e) => {
+                        return TransferResult::Failure(Arc::new(anyhow::anyhow!(
+                            "Failed to open the file: {e}"
+                        )))
+                    }
+                };
+
+                // Prepare a reader for the file to upload.
+                let reader = tokio::io::BufReader::new(file);
+
+                // Try to upload the file to the peer connection.
+                let mut streams = peer.streams.lock().await;
+
+                tokio::select! {
+                    () = cancellation_token.cancelled() => TransferResult::cancelled(&shutdown_token),
+                    result = Box::pin(crate::core::upload_to_peer(
+                        &mut streams,
+                        file_size,
+                        &chunk_hashes,
+                        reader,
+                        Some(progress),
+                        None,
+                    )) => match result {
+                        Ok(()) => TransferResult::Success,
+                        Err(e) => TransferResult::from_error(e, &peer.connection),
+                    }
+                }
+            }
+            .instrument(span),
+            move |r| Message::TransferResulted(upload_nonce, r, FileYeetCommandType::Pub),
+        )
+    }
+
+    /// Update the state after a publish was cancelled or removed.
+    /// Asks whether to also cancel the publish's uploads if any are still active.
+    fn update_cancel(&mut self, nonce: Nonce, uploads: &[Transfer]) {
+        let Some(pi) = self.publishes.iter_mut().find(|p| p.nonce == nonce) else {
+            return;
+        };
+
+        let active_uploads = uploads
+            .iter()
+            .filter(|t| {
+                pi.upload_nonces.contains(&t.nonce)
+                    && !matches!(t.progress, TransferProgress::Done(_))
+            })
+            .count();
+        if active_uploads > 0 {
+            pi.confirming_cancel = Some(active_uploads);
+        } else {
+            self.cancel(nonce, false, uploads);
+        }
+    }
+
+    /// Cancel the publish task and, if requested, every upload it started.
+    pub(super) fn cancel(&mut self, nonce: Nonce, cancel_uploads: bool, uploads: &[Transfer]) {
+        let Some(i) = self.publishes.iter().position(|p| p.nonce == nonce) else {
+            return;
+        };
+
+        // Cancel the publish task.
+        let publish = &mut self.publishes[i];
+        publish.cancellation_token.cancel();
+        publish.confirming_cancel = None;
+
+        if cancel_uploads {
+            for t in uploads
+                .iter()
+                .filter(|t| publish.upload_nonces.contains(&t.nonce))
+            {
+                t.cancellation_token.cancel();
+            }
+        }
+
+        // If we have finished hashing, remove the publish from the list.
+        if !matches!(&publish.state, PublishState::Hashing(..)) {
+            self.publishes.remove(i);
+        }
+    }
+
+    /// Report whether the server has every publish we think we have, and nothing else.
+    fn update_sync_resulted(
+        &self,
+        result: Result<Vec<RegisteredPublish>, Arc<anyhow::Error>>,
+        ctx: &mut AppContext,
+    ) {
+        let registered = match result {
+            Ok(registered) => registered,
+            Err(e) => {
+                *ctx.status_message = Some(format!("Failed to list the server's publishes: {e}"));
+                return;
+            }
+        };
+        let local: Vec<_> = self
+            .publishes
+            .iter()
+            .filter_map(|pi| match &pi.state {
+                PublishState::Publishing(publish) => Some((pi, publish)),
+                _ => None,
+            })
+            .collect();
+        let missing: Vec<_> = local
+            .iter()
+            .filter(|(_, publish)| !registered.iter().any(|r| r.hash == publish.hash.bytes))
+            .map(|(pi, _)| pi.path.display().to_string())
+            .collect();
+        let unknown = registered
+            .iter()
+            .filter(|r| {
+                !local
+                    .iter()
+                    .any(|(_, publish)| publish.hash.bytes == r.hash)
+            })
+            .count();
+
+        *ctx.status_message = Some(if missing.is_empty() && unknown == 0 {
+            format!("The server has all {} of our publishes", local.len())
+        } else if missing.is_empty() {
+            format!("The server has {unknown} publishes for us that we are not serving")
+        } else {
+            format!(
+                "The server is missing {} of our publishes: {}{}",
+                missing.len(),
+                missing.join(", "),
+                if unknown > 0 {
+                    format!(". It also has {unknown} publishes for us that we are not serving")
+                } else {
+                    String::new()
+                }
+            )
+        });
+    }
+
+    /// Copy a share link for a publish, naming the server it is published on and its file extension.
+    fn update_copy_link(
+        &self,
+        nonce: Nonce,
+        server_address: &str,
+        ctx: &mut AppContext,
+    ) -> iced::Command<Message> {
+        let Some((pi, publish)) = self.publishes.iter().find_map(|pi| match &pi.state {
+            PublishState::Publishing(publish) if pi.nonce == nonce => Some((pi, publish)),
+            _ => None,
+        }) else {
+            return iced::Command::none();
+        };
+        let Some((server_address, server_port)) = parse_server_address(server_address) else {
+            *ctx.status_message = Some("Invalid server address".to_owned());
+            return iced::Command::none();
+        };
+
+        // Only keep extensions that a share link can carry.
+        iced::clipboard::write(file_yeet_shared::format_share_uri(
+            &server_address,
+            server_port,
+            &publish.hash,
+            file_yeet_shared::share_extension(&pi.path),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gui::AppState;
+
+    /// Handle a publish message against the app state.
+    fn update(app: &mut AppState, message: PublishMessage) {
+        let (connection, publish, _, mut context) = app.controllers();
+        let _ = publish.update(message, connection.connected_mut(), &mut context);
+    }
+
+    /// Add a publish that is still hashing its file, returning its nonce.
+    fn add_hashing_publish(app: &mut AppState) -> Nonce {
+        let nonce = rand::random();
+        let (_, progress_receiver) = watch::channel(0.);
+        app.publish.publishes.push(PublishItem::new(
+            nonce,
+            PathBuf::from("file.txt"),
+            CancellationToken::new(),
+            progress_receiver,
+            None,
+            None,
+        ));
+        nonce
+    }
+
+    #[test]
+    fn max_downloads_input_is_kept() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            PublishMessage::MaxDownloadsInputChanged("3".to_owned()),
+        );
+        assert_eq!(app.publish.max_downloads_input, "3");
+    }
+
+    #[test]
+    fn failed_request_marks_the_publish() {
+        let mut app = AppState::default();
+        let nonce = add_hashing_publish(&mut app);
+
+        update(
+            &mut app,
+            PublishMessage::RequestResulted(
+                nonce,
+                PathBuf::from("file.txt"),
+                PublishRequestResult::Failure(Arc::new(anyhow::anyhow!("rejected"))),
+            ),
+        );
+        assert!(matches!(
+            app.publish.publishes[0].state,
+            PublishState::Failure(_)
+        ));
+    }
+
+    #[test]
+    fn cancelled_request_marks_the_publish() {
+        let mut app = AppState::default();
+        let nonce = add_hashing_publish(&mut app);
+
+        update(
+            &mut app,
+            PublishMessage::RequestResulted(
+                nonce,
+                PathBuf::from("file.txt"),
+                PublishRequestResult::Cancelled,
+            ),
+        );
+        assert!(matches!(
+            app.publish.publishes[0].state,
+            PublishState::Cancelled
+        ));
+    }
+
+    #[test]
+    fn result_for_an_unknown_publish_is_reported() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            PublishMessage::RequestResulted(
+                rand::random(),
+                PathBuf::from("file.txt"),
+                PublishRequestResult::Cancelled,
+            ),
+        );
+        assert!(app.status_message.is_some());
+    }
+
+    #[test]
+    fn cancel_stops_hashing_and_removes_finished_publishes() {
+        let mut app = AppState::default();
+        let nonce = add_hashing_publish(&mut app);
+
+        // A publish that is still hashing waits for its task to report the cancellation.
+        update(&mut app, PublishMessage::Cancel(nonce));
+        assert!(app.publish.publishes[0].cancellation_token.is_cancelled());
+        assert_eq!(app.publish.publishes.len(), 1);
+
+        app.publish.publishes[0].state = PublishState::Cancelled;
+        update(&mut app, PublishMessage::Cancel(nonce));
+        assert!(app.publish.publishes.is_empty());
+    }
+
+    #[test]
+    fn dismissing_cancel_stops_asking() {
+        let mut app = AppState::default();
+        let nonce = add_hashing_publish(&mut app);
+        app.publish.publishes[0].confirming_cancel = Some(2);
+
+        update(&mut app, PublishMessage::DismissCancel(nonce));
+        assert_eq!(app.publish.publishes[0].confirming_cancel, None);
+    }
+
+    #[test]
+    fn dropped_file_needs_a_connection() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            PublishMessage::FileDropped(PathBuf::from("file.txt")),
+        );
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Connect to a server to publish dropped files")
+        );
+        assert!(app.publish.publishes.is_empty());
+    }
+
+    #[test]
+    fn failed_sync_is_reported() {
+        let mut app = AppState::default();
+        update(
+            &mut app,
+            PublishMessage::SyncResulted(Err(Arc::new(anyhow::anyhow!("timed out")))),
+        );
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Failed to list the server's publishes: timed out")
+        );
+    }
+}
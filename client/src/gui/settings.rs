@@ -0,0 +1,471 @@
+use std::{
+    num::{NonZeroU16, NonZeroU64},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::identity::PublisherKey;
+
+use super::{
+    AppSettings, AppState, BandwidthLimitKind, DownloadDirectoryRule, Message,
+    PortMappingGuiOptions, UiScale, INVALID_PORT_FORWARD,
+};
+
+impl AppState {
+    /// The settings controller. Handles edits to the app settings, which are saved when the app closes.
+    /// Returns the message back if it is not about the settings.
+    pub(super) fn update_settings(
+        &mut self,
+        message: Message,
+    ) -> Result<iced::Command<Message>, Message> {
+        let command = match message {
+            // Handle the server address being changed.
+            Message::ServerAddressChanged(address) => {
+                self.options.server_address = address;
+                self.connection.server_identity_changed = None;
+                iced::Command::none()
+            }
+
+            // Handle the fallback servers being changed.
+            Message::FallbackServersChanged(text) => {
+                self.options.fallback_servers_text = text;
+                iced::Command::none()
+            }
+
+            // Handle the additional servers being changed.
+            Message::AdditionalServersChanged(text) => {
+                self.options.additional_servers_text = text;
+                iced::Command::none()
+            }
+
+            // Handle the access token being changed.
+            Message::AuthTokenChanged(token) => {
+                self.options.auth_token = token;
+                iced::Command::none()
+            }
+
+            // Handle the server CA file or certificate fingerprint being changed.
+            Message::ServerCaChanged(path) => {
+                self.options.server_ca_text = path;
+                self.connection.server_identity_changed = None;
+                iced::Command::none()
+            }
+            Message::ServerFingerprintChanged(fingerprint) => {
+                self.options.server_fingerprint_text = fingerprint;
+                self.connection.server_identity_changed = None;
+                iced::Command::none()
+            }
+
+            // Handle the client certificate or key file being changed.
+            Message::ClientCertChanged(path) => {
+                self.options.client_cert_text = path;
+                iced::Command::none()
+            }
+            Message::ClientKeyChanged(path) => {
+                self.options.client_key_text = path;
+                iced::Command::none()
+            }
+
+            // Handle the room being changed, ignoring edits that make the name too long to send.
+            Message::RoomChanged(room) => {
+                if room.len() <= file_yeet_shared::MAX_ROOM_NAME_LENGTH {
+                    self.options.room = room;
+                }
+                iced::Command::none()
+            }
+
+            // Handle the choice of whether to sign publishes, loading the signing key the first time.
+            Message::SignPublishesChanged(sign) => {
+                self.options.sign_publishes = sign;
+                if sign && self.publisher_key.is_none() {
+                    self.publisher_key = Some(Arc::new(PublisherKey::load_or_create()));
+                }
+                iced::Command::none()
+            }
+
+            // Handle the trusted publishers being changed.
+            Message::TrustedPublishersChanged(text) => {
+                self.options.trusted_publishers_text = text;
+                iced::Command::none()
+            }
+
+            // Handle the port mapping radio button being changed.
+            Message::PortMappingRadioChanged(label) => self.update_port_radio_changed(label),
+
+            // Handle the port forward text field being changed.
+            Message::PortForwardTextChanged(text) => self.update_port_forward_text(text),
+
+            // Handle the gateway text field being changed.
+            Message::GatewayTextChanged(text) => self.update_gateway_text(text),
+
+            // Handle changes to the download directory settings.
+            Message::UseDefaultDirectoryChanged(use_default) => {
+                self.options.download_directory.use_default = use_default;
+                iced::Command::none()
+            }
+
+            Message::DefaultDirectoryChanged(directory) => {
+                self.options.download_directory.default_directory = directory;
+                iced::Command::none()
+            }
+
+            Message::DownloadRuleAdded => {
+                self.options
+                    .download_directory
+                    .rules
+                    .push(DownloadDirectoryRule::default());
+                iced::Command::none()
+            }
+
+            Message::DownloadRulePrefixChanged(i, prefix) => {
+                if let Some(rule) = self.options.download_directory.rules.get_mut(i) {
+                    rule.hash_prefix = prefix;
+                }
+                iced::Command::none()
+            }
+
+            Message::DownloadRuleDirectoryChanged(i, directory) => {
+                if let Some(rule) = self.options.download_directory.rules.get_mut(i) {
+                    rule.directory = directory;
+                }
+                iced::Command::none()
+            }
+
+            Message::DownloadRuleRemoved(i) => {
+                if i < self.options.download_directory.rules.len() {
+                    self.options.download_directory.rules.remove(i);
+                }
+                iced::Command::none()
+            }
+
+            // Parse the maximum download size as it is typed. An empty field means no limit.
+            Message::MaxDownloadSizeChanged(text) => {
+                self.options.max_download_size = if text.trim().is_empty() {
+                    self.status_message = None;
+                    None
+                } else {
+                    match crate::core::parse_byte_size(&text) {
+                        Ok(size) => {
+                            self.status_message = None;
+                            Some(size)
+                        }
+                        Err(e) => {
+                            self.status_message = Some(e);
+                            None
+                        }
+                    }
+                };
+                self.options.max_download_size_text = text;
+                iced::Command::none()
+            }
+
+            // Update the command of an event hook as it is typed.
+            Message::EventHookChanged(event, command) => {
+                *self.options.event_hooks.command_mut(event) = command;
+                iced::Command::none()
+            }
+
+            // Parse a bandwidth limit as it is typed, and apply it to transfers right away.
+            Message::BandwidthLimitChanged(kind, text) => {
+                let limit = if text.trim().is_empty() {
+                    self.status_message = None;
+                    None
+                } else {
+                    match crate::core::parse_byte_size(&text) {
+                        Ok(size) => {
+                            self.status_message = None;
+                            NonZeroU64::new(size)
+                        }
+                        Err(e) => {
+                            self.status_message = Some(e);
+                            None
+                        }
+                    }
+                };
+                let limits = &mut self.options.bandwidth_limits;
+                let (limit_text, parsed) = match kind {
+                    BandwidthLimitKind::Upload => {
+                        (&mut self.options.upload_limit_text, &mut limits.upload)
+                    }
+                    BandwidthLimitKind::Download => {
+                        (&mut self.options.download_limit_text, &mut limits.download)
+                    }
+                    BandwidthLimitKind::PerTransfer => (
+                        &mut self.options.transfer_limit_text,
+                        &mut limits.per_transfer,
+                    ),
+                };
+                *limit_text = text;
+                *parsed = limit;
+                crate::throttle::set_bandwidth_limits(self.options.bandwidth_limits);
+                iced::Command::none()
+            }
+
+            // Parse the internal port range as it is typed. An empty field allows any port.
+            Message::InternalPortRangeChanged(text) => {
+                self.options.internal_port_range = if text.trim().is_empty() {
+                    self.status_message = None;
+                    None
+                } else {
+                    match crate::core::parse_port_range(&text) {
+                        Ok(range) => {
+                            self.status_message = None;
+                            Some(range)
+                        }
+                        Err(e) => {
+                            self.status_message = Some(e);
+                            None
+                        }
+                    }
+                };
+                self.options.internal_port_range_text = text;
+                self.connection.port_conflict = false;
+                iced::Command::none()
+            }
+
+            // Handle the choice of whether to check for updates on startup.
+            Message::CheckForUpdatesChanged(check) => {
+                self.options.check_for_updates = check;
+                iced::Command::none()
+            }
+
+            // Handle the choice of whether to reuse existing peer connections.
+            Message::ConnectionReuseChanged(disable) => {
+                self.options.disable_connection_reuse = disable;
+                iced::Command::none()
+            }
+
+            // Handle the choice of whether to publish verified downloads.
+            Message::SeedDownloadsChanged(seed) => {
+                self.options.seed_downloads = seed;
+                iced::Command::none()
+            }
+
+            // Handle the UI scale and row density being changed.
+            Message::UiScaleChanged(percent) => {
+                self.options.ui_scale = UiScale(percent);
+                iced::Command::none()
+            }
+
+            Message::CompactRowsChanged(compact) => {
+                self.options.compact_rows = compact;
+                iced::Command::none()
+            }
+
+            // Handle the choice of hash algorithm for new publishes.
+            Message::HashAlgorithmChanged(algorithm) => {
+                self.options.hash_algorithm = algorithm;
+                iced::Command::none()
+            }
+
+            // Handle the choice of congestion controller, which new connections are configured with.
+            Message::CongestionControllerChanged(congestion_controller) => {
+                self.options.congestion_controller = congestion_controller;
+                file_yeet_shared::set_congestion_controller(congestion_controller);
+                iced::Command::none()
+            }
+
+            // Choose where to export the settings profile to.
+            Message::ExportSettingsClicked => {
+                self.modal = true;
+                iced::Command::perform(
+                    rfd::AsyncFileDialog::new()
+                        .set_title("Export settings")
+                        .set_file_name("file_yeet_profile.toml")
+                        .add_filter("Settings profile", &["toml", "json"])
+                        .save_file(),
+                    |f| Message::ExportSettingsPathChosen(f.map(PathBuf::from)),
+                )
+            }
+
+            Message::ExportSettingsPathChosen(path) => {
+                self.modal = false;
+                if let Some(path) = path {
+                    self.status_message = Some(match write_profile(&self.options, &path) {
+                        Ok(()) => format!("Exported settings to {}", path.display()),
+                        Err(e) => format!("Failed to export settings: {e}"),
+                    });
+                }
+                iced::Command::none()
+            }
+
+            // Choose a settings profile to import.
+            Message::ImportSettingsClicked => {
+                self.modal = true;
+                iced::Command::perform(
+                    rfd::AsyncFileDialog::new()
+                        .set_title("Import settings")
+                        .add_filter("Settings profile", &["toml", "json"])
+                        .pick_file(),
+                    |f| Message::ImportSettingsPathChosen(f.map(PathBuf::from)),
+                )
+            }
+
+            Message::ImportSettingsPathChosen(path) => {
+                self.modal = false;
+                if let Some(path) = path {
+                    self.status_message = Some(match read_profile(&path) {
+                        Ok(profile) => {
+                            self.options.apply_profile(profile);
+                            crate::throttle::set_bandwidth_limits(self.options.bandwidth_limits);
+                            file_yeet_shared::set_congestion_controller(
+                                self.options.congestion_controller,
+                            );
+                            format!("Imported settings from {}", path.display())
+                        }
+                        Err(e) => format!("Failed to import settings: {e}"),
+                    });
+                }
+                iced::Command::none()
+            }
+
+            message => return Err(message),
+        };
+        Ok(command)
+    }
+
+    /// Handle the port mapping radio button being changed.
+    fn update_port_radio_changed(&mut self, label: &'static str) -> iced::Command<Message> {
+        self.options.port_mapping = match label {
+            "None" => {
+                self.status_message = None;
+                PortMappingGuiOptions::None
+            }
+            "Port forward" => PortMappingGuiOptions::PortForwarding({
+                let o = self
+                    .options
+                    .port_forwarding_text
+                    .trim()
+                    .parse::<NonZeroU16>()
+                    .ok();
+                if o.is_none() {
+                    self.status_message = Some(INVALID_PORT_FORWARD.to_owned());
+                }
+                o
+            }),
+            "NAT-PMP / PCP" => {
+                self.status_message = None;
+                PortMappingGuiOptions::TryPcpNatPmp
+            }
+            _ => unreachable!(),
+        };
+        iced::Command::none()
+    }
+
+    /// Update the state after the port forward text field was changed.
+    fn update_port_forward_text(&mut self, text: String) -> iced::Command<Message> {
+        self.options.port_forwarding_text = text;
+        if let PortMappingGuiOptions::PortForwarding(port) = &mut self.options.port_mapping {
+            if let Ok(p) = self
+                .options
+                .port_forwarding_text
+                .trim()
+                .parse::<NonZeroU16>()
+            {
+                *port = Some(p);
+                self.status_message = None;
+            } else {
+                *port = None;
+                self.status_message = Some(INVALID_PORT_FORWARD.to_owned());
+            }
+        }
+        iced::Command::none()
+    }
+
+    // Update the state after the gateway text field was changed.
+    fn update_gateway_text(&mut self, text: String) -> iced::Command<Message> {
+        if text.trim().is_empty() {
+            self.options.gateway_address = None;
+        } else {
+            self.options.gateway_address = Some(text);
+        }
+        iced::Command::none()
+    }
+}
+
+impl AppSettings {
+    /// The settings to share with other machines. Leaves out the publishes and downloads to resume
+    /// and the server CA and client certificate files, since their paths only make sense on this machine,
+    /// and the access token, which is a secret.
+    fn to_profile(&self) -> Self {
+        Self {
+            auth_token: String::new(),
+            server_ca_text: String::new(),
+            client_cert_text: String::new(),
+            client_key_text: String::new(),
+            last_publish_paths: Vec::new(),
+            last_downloads: Vec::new(),
+            last_download_algorithms: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Replace the settings with those of an imported profile, keeping the publishes and downloads to resume,
+    /// the server CA and client certificate files, and the access token.
+    fn apply_profile(&mut self, profile: Self) {
+        let last_publish_paths = std::mem::take(&mut self.last_publish_paths);
+        let last_downloads = std::mem::take(&mut self.last_downloads);
+        let last_download_algorithms = std::mem::take(&mut self.last_download_algorithms);
+        let auth_token = std::mem::take(&mut self.auth_token);
+        let server_ca_text = std::mem::take(&mut self.server_ca_text);
+        let client_cert_text = std::mem::take(&mut self.client_cert_text);
+        let client_key_text = std::mem::take(&mut self.client_key_text);
+        *self = Self {
+            auth_token,
+            server_ca_text,
+            client_cert_text,
+            client_key_text,
+            last_publish_paths,
+            last_downloads,
+            last_download_algorithms,
+            ..profile
+        };
+    }
+}
+
+/// Whether a profile path should be written as TOML rather than JSON, by its extension.
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+/// Write the portable part of the settings to a profile file, as TOML or JSON by the file's extension.
+fn write_profile(settings: &AppSettings, path: &Path) -> anyhow::Result<()> {
+    let profile = settings.to_profile();
+    let contents = if is_toml(path) {
+        toml::to_string_pretty(&profile)?
+    } else {
+        serde_json::to_string_pretty(&profile)?
+    };
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Read a settings profile file, as TOML or JSON by the file's extension.
+fn read_profile(path: &Path) -> anyhow::Result<AppSettings> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(if is_toml(path) {
+        toml::from_str(&contents)?
+    } else {
+        serde_json::from_str(&contents)?
+    })
+}
+
+/// Export the saved app settings to a portable profile file, as TOML or JSON by the file's extension.
+///
+/// # Errors
+/// Fails if the profile cannot be serialized or written.
+pub fn export_settings_profile(path: &Path) -> anyhow::Result<()> {
+    write_profile(&AppSettings::load(), path)
+}
+
+/// Import a profile file into the saved app settings, keeping the publishes and downloads to resume.
+///
+/// # Errors
+/// Fails if the profile cannot be read or parsed, or the settings cannot be saved.
+pub fn import_settings_profile(path: &Path) -> anyhow::Result<()> {
+    let profile = read_profile(path)?;
+    let mut settings = AppSettings::load();
+    settings.apply_profile(profile);
+    settings.save()
+}
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use file_yeet_shared::{local_now_fmt, PeerAddr, GOODBYE_CODE, GOODBYE_MESSAGE};
+
+use super::{
+    download::DownloadController, AppState, CloseType, ConnectedState, DownloadPath, Message,
+    Nonce, PublishState, Transfer, TransferProgress, TransferResult,
+};
+use crate::core::FileYeetCommandType;
+use crate::hooks::{HookContext, TransferEvent};
+
+impl AppState {
+    /// The transfer controller. Handles uploads and downloads once they have started.
+    /// Returns the message back if it is not about an active or finished transfer.
+    pub(super) fn update_transfer(
+        &mut self,
+        message: Message,
+    ) -> Result<iced::Command<Message>, Message> {
+        let command = match message {
+            // The transfer view radio buttons were changed.
+            Message::TransferViewChanged(view) => {
+                if let Some(connected_state) = self.connection.connected_mut() {
+                    connected_state.transfer_view = view;
+                }
+                iced::Command::none()
+            }
+
+            // Handle a transfer being cancelled.
+            Message::CancelTransfer(nonce, transfer_type) => {
+                self.update_cancel_transfer(nonce, transfer_type)
+            }
+
+            // Handle the conclusive result of a transfer.
+            Message::TransferResulted(nonce, r, transfer_type) => {
+                self.update_transfer_resulted(nonce, r, transfer_type)
+            }
+
+            // Handle a file being opened.
+            Message::OpenFile(path) => {
+                open::that(path).unwrap_or_else(|e| {
+                    eprintln!("{} Failed to open file: {e}", local_now_fmt());
+                });
+                iced::Command::none()
+            }
+
+            // Handle a transfer being removed from the downloads list.
+            Message::RemoveFromTransfers(nonce, transfer_type) => {
+                self.update_remove_from_transfers(nonce, transfer_type)
+            }
+
+            message => return Err(message),
+        };
+        Ok(command)
+    }
+
+    /// Update the state after a transfer was cancelled.
+    fn update_cancel_transfer(
+        &mut self,
+        nonce: Nonce,
+        transfer_type: FileYeetCommandType,
+    ) -> iced::Command<Message> {
+        if let Some(t) = self
+            .transfers_mut(transfer_type)
+            .and_then(|transfers| transfers.iter_mut().find(|t| t.nonce == nonce))
+        {
+            // Cancel the download task.
+            t.cancellation_token.cancel();
+
+            // If waiting for user interaction, mark the transfer as cancelled.
+            if matches!(t.progress, TransferProgress::Consent(_)) {
+                t.progress = TransferProgress::Done(TransferResult::UserCancelled);
+            }
+        }
+        iced::Command::none()
+    }
+
+    /// Update the state after a transfer was completed.
+    fn update_transfer_resulted(
+        &mut self,
+        nonce: Nonce,
+        result: TransferResult,
+        transfer_type: FileYeetCommandType,
+    ) -> iced::Command<Message> {
+        let mut resume = None;
+        let mut hook = iced::Command::none();
+        let mut download_limit_reached = None;
+        let mut seed = None;
+        if let Some(connected_state) = self.connection.connected_mut() {
+            let servers = connected_state.servers();
+            let ConnectedState { peers, uploads, .. } = connected_state;
+            let mut transfers = match transfer_type {
+                FileYeetCommandType::Sub => self.download.downloads.iter_mut(),
+                FileYeetCommandType::Pub => uploads.iter_mut(),
+            };
+
+            if let Some(t) = transfers.find(|t| t.nonce == nonce) {
+                // Ask the server for fresh peers the next time this download is attempted.
+                if let (
+                    FileYeetCommandType::Sub,
+                    TransferResult::Failure(_) | TransferResult::PeerClosed,
+                ) = (transfer_type, &result)
+                {
+                    crate::core::invalidate_subscribe_cache(&t.hash);
+                }
+
+                // Keep per-publish statistics of upload outcomes.
+                if let FileYeetCommandType::Pub = transfer_type {
+                    if let Some(pi) = self.publish.publishes.iter_mut().find(
+                        |pi| matches!(&pi.state, PublishState::Publishing(p) if p.hash == t.hash),
+                    ) {
+                        let statistics = &mut pi.upload_statistics;
+                        match &result {
+                            TransferResult::Success => {
+                                statistics.succeeded += 1;
+                                statistics.bytes_sent += t.file_size;
+                            }
+                            TransferResult::Failure(_) | TransferResult::PeerClosed => {
+                                statistics.failed += 1;
+                            }
+                            TransferResult::UserCancelled | TransferResult::Shutdown => {}
+                        }
+
+                        // Stop publishing once the download limit is reached.
+                        if pi.remaining_downloads() == Some(0) {
+                            download_limit_reached = Some(pi.nonce);
+                        }
+                    }
+                }
+
+                // Look for other peers to resume a download from when the peer went away.
+                if let (FileYeetCommandType::Sub, TransferResult::PeerClosed) =
+                    (transfer_type, &result)
+                {
+                    resume = Some(DownloadController::subscribe_command(
+                        servers.clone(),
+                        DownloadPath::Chosen(t.path.clone()),
+                        t.hash,
+                        self.options.room.clone(),
+                        t.passphrase.clone(),
+                    ));
+                }
+
+                // If the transfer was connected to a peer, remove the peer from the list of known peers.
+                if let TransferProgress::Transferring(p, _, _) | TransferProgress::Consent(p) =
+                    &t.progress
+                {
+                    let connection = &p.connection;
+                    let peer_address = PeerAddr::from(connection.remote_address());
+                    if let std::collections::hash_map::Entry::Occupied(mut e) =
+                        peers.entry(peer_address)
+                    {
+                        let nonces = &mut e.get_mut().1;
+                        nonces.remove(&nonce);
+
+                        // If there are no more streams to the peer, close the connection.
+                        if nonces.is_empty() {
+                            connection.close(GOODBYE_CODE, GOODBYE_MESSAGE.as_bytes());
+                            peers.remove(&peer_address);
+                        }
+                    }
+                }
+
+                // Let the user's hooks process completed transfers.
+                if let TransferResult::Success = result {
+                    let event = match transfer_type {
+                        FileYeetCommandType::Sub => TransferEvent::DownloadComplete,
+                        FileYeetCommandType::Pub => TransferEvent::UploadComplete,
+                    };
+                    let context = HookContext {
+                        hash_hex: t.hash_hex.clone(),
+                        path: t.path.clone(),
+                        file_size: Some(t.file_size),
+                        peer: Some(t.peer_string.clone()),
+                        error: None,
+                    };
+                    if let Some(future) =
+                        crate::hooks::run(&self.options.event_hooks, event, context)
+                    {
+                        hook = iced::Command::perform(
+                            async move { future.await.map_err(Arc::new) },
+                            Message::HookFinished,
+                        );
+                    }
+
+                    // Become another source for a verified download if the user asked to.
+                    if matches!(transfer_type, FileYeetCommandType::Sub)
+                        && self.options.seed_downloads
+                    {
+                        seed = Some((t.path.clone(), t.hash, t.file_size));
+                    }
+                }
+
+                t.progress = TransferProgress::Done(result);
+            }
+        }
+        if let Some(pub_nonce) = download_limit_reached {
+            let uploads = self
+                .connection
+                .connected()
+                .map_or(&[][..], |c| c.uploads.as_slice());
+            self.publish.cancel(pub_nonce, false, uploads);
+            self.status_message =
+                Some("A publish reached its download limit and is no longer published".to_owned());
+        }
+        if let Some((path, hash, file_size)) = seed {
+            let (connection, publish, _, context) = self.controllers();
+            if let Some(connected_state) = connection.connected() {
+                let servers = connected_state.servers();
+                let seed = publish.seed_download(path, hash, file_size, servers, &context);
+                hook = iced::Command::batch([hook, seed]);
+            }
+        }
+        if let Some(resume) = resume {
+            self.status_message =
+                Some("Peer closed the connection, looking for other peers".to_owned());
+            return iced::Command::batch([resume, hook]);
+        }
+
+        // Leave the server if the user was only waiting for the active transfers to finish.
+        if let Some(connected_state) = self.connection.connected() {
+            if connected_state.leave_when_done && !self.has_transferring() {
+                return iced::Command::batch([self.safely_close(CloseType::Connections), hook]);
+            }
+        }
+        hook
+    }
+
+    /// Update the state after the user has chosen to remove a transfer entry.
+    fn update_remove_from_transfers(
+        &mut self,
+        nonce: Nonce,
+        transfer_type: FileYeetCommandType,
+    ) -> iced::Command<Message> {
+        if let Some(transfers) = self.transfers_mut(transfer_type) {
+            if let Some(i) = transfers.iter().position(|t| t.nonce == nonce) {
+                transfers.remove(i);
+            }
+        }
+        iced::Command::none()
+    }
+
+    /// The downloads, or the uploads if connected to a server.
+    fn transfers_mut(&mut self, transfer_type: FileYeetCommandType) -> Option<&mut Vec<Transfer>> {
+        match transfer_type {
+            FileYeetCommandType::Sub => Some(&mut self.download.downloads),
+            FileYeetCommandType::Pub => self.connection.connected_mut().map(|c| &mut c.uploads),
+        }
+    }
+}
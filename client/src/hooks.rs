@@ -0,0 +1,123 @@
+use std::{future::Future, path::PathBuf};
+
+/// The transfer events that can run a user-configured command.
+#[derive(Clone, Copy, Debug, displaydoc::Display)]
+pub enum TransferEvent {
+    /// download complete
+    DownloadComplete,
+
+    /// upload complete
+    UploadComplete,
+
+    /// publish failed
+    PublishFailed,
+}
+impl TransferEvent {
+    /// The value of the `FILE_YEET_EVENT` environment variable for this event.
+    fn env_value(self) -> &'static str {
+        match self {
+            Self::DownloadComplete => "download_complete",
+            Self::UploadComplete => "upload_complete",
+            Self::PublishFailed => "publish_failed",
+        }
+    }
+}
+
+/// Commands to run when transfer events occur, e.g., to scan or move downloaded files.
+/// Empty commands are not run.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct EventHooks {
+    #[serde(default)]
+    pub download_complete: String,
+    #[serde(default)]
+    pub upload_complete: String,
+    #[serde(default)]
+    pub publish_failed: String,
+}
+impl EventHooks {
+    /// Get the command configured for an event, if any.
+    #[must_use]
+    pub fn command(&self, event: TransferEvent) -> Option<&str> {
+        let command = match event {
+            TransferEvent::DownloadComplete => &self.download_complete,
+            TransferEvent::UploadComplete => &self.upload_complete,
+            TransferEvent::PublishFailed => &self.publish_failed,
+        };
+        Some(command.as_str()).filter(|c| !c.trim().is_empty())
+    }
+
+    /// Get a mutable reference to the command for an event, for editing.
+    pub fn command_mut(&mut self, event: TransferEvent) -> &mut String {
+        match event {
+            TransferEvent::DownloadComplete => &mut self.download_complete,
+            TransferEvent::UploadComplete => &mut self.upload_complete,
+            TransferEvent::PublishFailed => &mut self.publish_failed,
+        }
+    }
+}
+
+/// Details about a transfer, passed to hook commands as environment variables.
+#[derive(Clone, Debug, Default)]
+pub struct HookContext {
+    pub hash_hex: String,
+    pub path: PathBuf,
+    pub file_size: Option<u64>,
+    pub peer: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Create a future that runs the command configured for an event and waits for it to exit.
+/// Returns `None` if no command is configured for the event.
+pub fn run(
+    hooks: &EventHooks,
+    event: TransferEvent,
+    context: HookContext,
+) -> Option<impl Future<Output = anyhow::Result<()>> + 'static> {
+    let mut process = shell_command(hooks.command(event)?);
+    process
+        .env("FILE_YEET_EVENT", event.env_value())
+        .env("FILE_YEET_HASH", &context.hash_hex)
+        .env("FILE_YEET_PATH", &context.path);
+    if let Some(file_size) = context.file_size {
+        process.env("FILE_YEET_FILE_SIZE", file_size.to_string());
+    }
+    if let Some(peer) = &context.peer {
+        process.env("FILE_YEET_PEER", peer);
+    }
+    if let Some(error) = &context.error {
+        process.env("FILE_YEET_ERROR", error);
+    }
+
+    Some(async move {
+        let status = process
+            .status()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to run the {event} hook: {e}"))?;
+        if !status.success() {
+            anyhow::bail!("The {event} hook exited with {status}");
+        }
+        Ok(())
+    })
+}
+
+/// Create a command that runs the given text with the platform's shell.
+fn shell_command(command: &str) -> tokio::process::Command {
+    #[cfg(target_os = "windows")]
+    {
+        /// Avoid flashing a console window when running hooks from the GUI.
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+        let mut process = tokio::process::Command::new("cmd");
+        process
+            .arg("/C")
+            .arg(command)
+            .creation_flags(CREATE_NO_WINDOW);
+        process
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let mut process = tokio::process::Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use file_yeet_shared::local_now_fmt;
+use tokio::sync::watch;
+
+/// The least time between progress events for a single transfer or hash.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Whether the CLI was asked for JSON lines instead of human-readable output.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Print events as JSON lines on stdout, and move human-readable messages to stderr.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether events are being printed as JSON lines.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Print a human-readable message, to stderr when stdout is reserved for JSON lines.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::json_output::enabled() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use status;
+
+/// Which way a transfer's bytes are moving.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// A step in a publish or subscribe, printed as one JSON line.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A file is being hashed before it is published.
+    Hashing { path: PathBuf, progress: f32 },
+
+    /// A file is published and waiting for peers.
+    Published {
+        path: PathBuf,
+        hash: String,
+        file_size: u64,
+    },
+
+    /// A peer was reached to transfer a file with.
+    Connected {
+        direction: Direction,
+        hash: String,
+        peer: SocketAddr,
+        relayed: bool,
+    },
+
+    /// The fraction of a transfer that has completed.
+    Progress {
+        direction: Direction,
+        hash: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peer: Option<SocketAddr>,
+        progress: f32,
+    },
+
+    /// A peer received the whole file, or the whole file was downloaded.
+    Completed {
+        direction: Direction,
+        hash: String,
+        path: PathBuf,
+        file_size: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peer: Option<SocketAddr>,
+    },
+
+    /// What became of each file of a publish or subscribe of several files.
+    Summary {
+        succeeded: usize,
+        failed: usize,
+        files: Vec<crate::table::FileRow>,
+    },
+
+    /// The publish or subscribe failed.
+    Error { message: String },
+}
+
+/// An event with the time it happened.
+#[derive(serde::Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Print an event as a JSON line if JSON output is enabled.
+pub fn emit(event: &Event) {
+    if !enabled() {
+        return;
+    }
+    let line = Line {
+        time: local_now_fmt().to_string(),
+        event,
+    };
+    match serde_json::to_string(&line) {
+        Ok(line) => println!("{line}"),
+        Err(e) => eprintln!("{} Failed to serialize an event: {e}", local_now_fmt()),
+    }
+}
+
+/// Create a progress sender that emits an event for its updates until it is dropped, if JSON output is enabled.
+pub fn progress_events(
+    event: impl FnMut(f32) -> Event + Send + 'static,
+) -> Option<watch::Sender<f32>> {
+    if !enabled() {
+        return None;
+    }
+    let (progress, progress_receiver) = watch::channel(0.);
+    watch_progress(progress_receiver, event);
+    Some(progress)
+}
+
+/// Emit an event for the updates of an existing progress channel until its sender is dropped, if JSON output is enabled.
+pub fn watch_progress(
+    mut progress_receiver: watch::Receiver<f32>,
+    mut event: impl FnMut(f32) -> Event + Send + 'static,
+) {
+    if !enabled() {
+        return;
+    }
+    tokio::task::spawn(async move {
+        while progress_receiver.changed().await.is_ok() {
+            let progress = *progress_receiver.borrow_and_update();
+            emit(&event(progress));
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+        }
+    });
+}
@@ -1,20 +1,66 @@
-use std::{io::Write as _, num::NonZeroU16, path::Path};
+use std::{
+    collections::HashMap,
+    io::Write as _,
+    net::SocketAddr,
+    num::{NonZeroU16, NonZeroU64, NonZeroUsize},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use file_yeet_shared::{
-    local_now_fmt, BiStream, HashBytes, GOODBYE_CODE, GOODBYE_MESSAGE,
-    MAX_SERVER_COMMUNICATION_SIZE,
+    local_now_fmt, BiStream, FileHash, HashAlgorithm, HashBytes, PooledBuffer, PublishControl,
+    GOODBYE_CODE, GOODBYE_MESSAGE, SERVER_MESSAGE_BUFFERS,
 };
 use futures_util::{stream::FuturesUnordered, StreamExt};
-use iced::Application;
+use iced::multi_window::Application;
+use tokio::io::AsyncWriteExt as _;
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument as _;
 
 use crate::core::{humanize_bytes, FileYeetCommandType, PreparedConnection};
+use crate::json_output::status;
 
-mod core;
+// The connection and transfer logic lives in its own crate so that other applications can embed it.
+use file_yeet_client_core::{self as core, identity, throttle};
+
+mod cache;
+mod daemon;
 mod gui;
+mod hooks;
+mod json_output;
+mod manifest;
+mod progress;
+mod table;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod update;
+mod upload_log;
+mod verify;
 #[cfg(target_os = "windows")]
 mod win_cmd;
 
+/// The first wait before asking the server for publishers again, doubled after each time there are none.
+const WAIT_POLL_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait between asking the server for publishers again.
+const WAIT_POLL_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long to look for publishers on the local network before connecting to the ones found.
+const LAN_LOOKUP_DURATION: Duration = Duration::from_secs(2);
+
+/// How often the spinner advances while waiting for a publisher.
+const SPINNER_INTERVAL: Duration = Duration::from_millis(125);
+
+/// The frames of the spinner shown while waiting for a publisher.
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// The path given to `pub` to publish the data piped to standard input.
+const STDIN_PATH: &str = "-";
+
 /// The command line interface for `file_yeet_client`.
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
@@ -27,6 +73,16 @@ pub struct Cli {
     #[arg(short='p', long, default_value_t = file_yeet_shared::DEFAULT_PORT)]
     server_port: NonZeroU16,
 
+    /// Another rendezvous server to connect to at the same time, as `host` or `host:port`. May be given more than once.
+    /// Files are published to every server, and downloads use the publishers found on any of them.
+    #[arg(long = "additional-server", value_parser = parse_server_arg)]
+    additional_servers: Vec<(String, NonZeroU16)>,
+
+    /// A server to connect to instead when the servers before it can't be reached, as `host` or `host:port`.
+    /// May be given more than once, and they are tried in order. The daemon also tries them when its connection is lost.
+    #[arg(long = "fallback-server", value_parser = parse_server_arg)]
+    fallback_servers: Vec<(String, NonZeroU16)>,
+
     /// Override the port seen by the server to communicate a custom port to peers.
     /// Useful when port-forwarding.
     #[arg(short = 'o', long)]
@@ -41,31 +97,518 @@ pub struct Cli {
     #[arg(short, long)]
     nat_map: bool,
 
+    /// Only bind to a local UDP port in this range, e.g., `50000-50100`.
+    /// Ports are tried in order until one is available.
+    #[arg(long, value_parser = core::parse_port_range)]
+    internal_port_range: Option<core::PortRange>,
+
+    /// The largest download to accept, e.g., `4GB`. Larger offers are rejected automatically.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    max_download_size: Option<u64>,
+
+    /// The combined rate of every upload, in bytes per second, e.g., `2MB`. Zero or unset means no limit.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    upload_limit: Option<u64>,
+
+    /// The combined rate of every download, in bytes per second, e.g., `10MB`. Zero or unset means no limit.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    download_limit: Option<u64>,
+
+    /// The rate of each individual transfer stream, in bytes per second. Zero or unset means no limit.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    transfer_limit: Option<u64>,
+
+    /// The most unacknowledged bytes a peer may send on a single QUIC stream, e.g., `8MB`.
+    /// Raise it, with `--receive-window`, for fast links with high latency.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    stream_receive_window: Option<u64>,
+
+    /// The most unacknowledged bytes a peer may send across every stream of a QUIC connection.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    receive_window: Option<u64>,
+
+    /// The most unacknowledged bytes to send across every stream of a QUIC connection.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    send_window: Option<u64>,
+
+    /// The size of the UDP datagrams to send before path MTU discovery finds a larger size, in bytes.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1200..))]
+    initial_mtu: Option<u16>,
+
+    /// The number of seconds of inactivity before a QUIC connection is closed. The default is 120.
+    #[arg(long)]
+    idle_timeout: Option<NonZeroU64>,
+
+    /// The number of seconds between keep-alive packets on idle QUIC connections.
+    #[arg(long)]
+    keep_alive_interval: Option<NonZeroU64>,
+
+    /// The congestion controller to pace uploads with, `cubic` or `bbr`. The default is `cubic`.
+    /// BBR keeps long-distance transfers over lossy links fast, while Cubic yields more readily to other traffic.
+    #[arg(long)]
+    congestion_controller: Option<file_yeet_shared::CongestionController>,
+
+    /// The size of the reads made while hashing files that can't be memory mapped, e.g., `16MB`. Defaults to 8 MiB.
+    #[arg(long, value_parser = core::parse_byte_size)]
+    hash_buffer_size: Option<u64>,
+
+    /// A command to run after a download completes. Transfer details are passed in `FILE_YEET_*` environment variables.
+    #[arg(long)]
+    on_download_complete: Option<String>,
+
+    /// A command to run after an upload to a peer completes.
+    #[arg(long)]
+    on_upload_complete: Option<String>,
+
+    /// A command to run when a publish fails.
+    #[arg(long)]
+    on_publish_failed: Option<String>,
+
+    /// A directory to cache downloads in, keyed by their hash.
+    /// Cached files are linked or copied to their destination instead of being downloaded again,
+    /// and can be published again without hashing them.
+    #[arg(long)]
+    cache_dir: Option<std::path::PathBuf>,
+
+    /// The access token to present to servers that require one.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Verify the server's certificate against the CA certificates in this PEM or DER file,
+    /// instead of trusting the certificate seen on first connection.
+    #[arg(long, conflicts_with = "server_fingerprint")]
+    server_ca: Option<std::path::PathBuf>,
+
+    /// Only accept the server certificate with this SHA-256 fingerprint, given in hex,
+    /// instead of trusting the certificate seen on first connection.
+    #[arg(long, value_parser = file_yeet_shared::parse_certificate_fingerprint)]
+    server_fingerprint: Option<[u8; 32]>,
+
+    /// A certificate chain file, in PEM or DER format, to present to servers that only accept clients
+    /// with a certificate from their operator.
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<std::path::PathBuf>,
+
+    /// The private key file, in PEM or DER format, for the certificate given with `--client-cert`.
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<std::path::PathBuf>,
+
+    /// The room to publish and subscribe in. Hashes are only visible to clients in the same room.
+    /// Defaults to the server's shared room.
+    #[arg(long, default_value = "", value_parser = core::parse_room)]
+    room: String,
+
+    /// Only download from publishers that signed the file with a key of this fingerprint.
+    /// May be given more than once. The whole fingerprint must be given.
+    #[arg(long = "trusted-publisher")]
+    trusted_publishers: Vec<String>,
+
+    /// Relay transfers through the server when peers cannot connect directly, if the server allows it.
+    /// The peer connection runs end-to-end through the relay, so the server only forwards ciphertext.
+    #[arg(long)]
+    relay: bool,
+
+    /// Also announce publishes to, and look for publishers on, the local network with mDNS.
+    /// Peers found this way are connected to directly, without hole punching.
+    #[arg(long)]
+    lan: bool,
+
+    /// Experimental: find peers on a DHT formed by other clients instead of through a server.
+    /// Only `pub` and `sub` can be used, and publishers must be reachable without hole punching, e.g., with a port forward.
+    #[arg(long)]
+    dht: bool,
+
+    /// A node to join the DHT through, such as another client run with `--dht-port`. May be given more than once.
+    #[arg(long, requires = "dht")]
+    dht_bootstrap: Vec<SocketAddr>,
+
+    /// The UDP port to run our DHT node on, so other clients can join the DHT through it. Any free port by default.
+    #[arg(long, requires = "dht", default_value_t = 0)]
+    dht_port: u16,
+
+    /// Print publish and subscribe progress and results as JSON lines on stdout, for scripts and other programs.
+    /// Human-readable messages are written to stderr instead.
+    #[arg(long)]
+    json: bool,
+
+    /// Export publish, subscribe, and transfer spans to the OpenTelemetry collector at this OTLP/gRPC endpoint,
+    /// e.g., `http://localhost:4317`. Transfers can be followed through servers and peers that export them too.
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Connect to the server and peers over Unix domain sockets in this directory instead of UDP.
+    /// For local testing, with a server run using the same directory.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    #[arg(long)]
+    unix_socket_dir: Option<std::path::PathBuf>,
+
     #[command(subcommand)]
     cmd: Option<FileYeetCommand>,
 }
+impl Cli {
+    /// The QUIC transport tuning given on the command line.
+    fn transport_tuning(&self) -> file_yeet_shared::TransportTuning {
+        file_yeet_shared::TransportTuning {
+            stream_receive_window: self.stream_receive_window.and_then(NonZeroU64::new),
+            receive_window: self.receive_window.and_then(NonZeroU64::new),
+            send_window: self.send_window.and_then(NonZeroU64::new),
+            initial_mtu: self.initial_mtu,
+            idle_timeout: self.idle_timeout,
+            keep_alive_interval: self.keep_alive_interval,
+            congestion_controller: self.congestion_controller.unwrap_or_default(),
+        }
+    }
+
+    /// The bandwidth limits given on the command line.
+    fn bandwidth_limits(&self) -> throttle::BandwidthLimits {
+        throttle::BandwidthLimits {
+            upload: self.upload_limit.and_then(NonZeroU64::new),
+            download: self.download_limit.and_then(NonZeroU64::new),
+            per_transfer: self.transfer_limit.and_then(NonZeroU64::new),
+        }
+    }
+
+    /// Replace a share link given to `sub` with its hash, connecting to the link's server unless one was given explicitly.
+    /// The link's extension hint names the default output file.
+    fn resolve_share_uri(
+        &mut self,
+        cmd: FileYeetCommand,
+    ) -> Result<FileYeetCommand, file_yeet_shared::ShareUriError> {
+        let FileYeetCommand::Sub {
+            sha256_hex,
+            output,
+            directory,
+            wait,
+            max_peers,
+            passphrase,
+            seed,
+            resume,
+            streams,
+        } = cmd
+        else {
+            return Ok(cmd);
+        };
+        if !sha256_hex.contains("://") {
+            return Ok(FileYeetCommand::Sub {
+                sha256_hex,
+                output,
+                directory,
+                wait,
+                max_peers,
+                passphrase,
+                seed,
+                resume,
+                streams,
+            });
+        }
+
+        let uri = file_yeet_shared::parse_share_uri(&sha256_hex)?;
+        if self.server_address.is_none() {
+            self.server_address = Some(uri.server_address);
+            self.server_port = uri.server_port;
+        }
+        let output = output.or_else(|| {
+            uri.extension.map(|extension| {
+                std::env::temp_dir()
+                    .join(format!("{}.{extension}", uri.hash))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        });
+        Ok(FileYeetCommand::Sub {
+            sha256_hex: uri.hash.to_string(),
+            output,
+            directory,
+            wait,
+            max_peers,
+            passphrase,
+            seed,
+            resume,
+            streams,
+        })
+    }
+
+    /// Connect to the server with the port mapping options given on the command line.
+    /// If it can't be reached, each fallback server is tried in order.
+    async fn connect(&self, bb: &mut bytes::BytesMut) -> anyhow::Result<PreparedConnection> {
+        let mut fallbacks = self.fallback_servers.iter();
+        let (mut server_address, mut server_port) =
+            (self.server_address.as_deref(), self.server_port);
+        loop {
+            let result = core::prepare_server_connection(
+                server_address,
+                server_port,
+                self.gateway.as_deref(),
+                if let Some(g) = self.port_override {
+                    // Use the provided port override.
+                    core::PortMappingConfig::PortForwarding(g)
+                } else if self.nat_map {
+                    // Try to create a new port mapping using NAT-PMP or PCP.
+                    core::PortMappingConfig::PcpNatPmp(None)
+                } else {
+                    core::PortMappingConfig::None
+                },
+                self.internal_port_range,
+                self.local_socket_dir(),
+                self.token.as_deref(),
+                &self.server_trust(),
+                self.client_certificate().as_ref(),
+                bb,
+            )
+            .await;
+            match result {
+                Err(e) if core::should_try_next_server(&e) => {
+                    let Some((next_address, next_port)) = fallbacks.next() else {
+                        return Err(e);
+                    };
+                    eprintln!(
+                        "{} Failed to connect to the server, trying {next_address} next: {e}",
+                        local_now_fmt()
+                    );
+                    (server_address, server_port) = (Some(next_address.as_str()), *next_port);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Connect to each additional server over the endpoint of the first server connection.
+    /// Servers that can't be connected to are reported and skipped.
+    async fn connect_additional(
+        &self,
+        prepared_connection: &PreparedConnection,
+        bb: &mut bytes::BytesMut,
+    ) -> Vec<PreparedConnection> {
+        let mut connections = Vec::new();
+        for (address, port) in &self.additional_servers {
+            match core::prepare_additional_server_connection(
+                prepared_connection,
+                address,
+                *port,
+                self.token.as_deref(),
+                &self.server_trust(),
+                self.client_certificate().as_ref(),
+                bb,
+            )
+            .await
+            {
+                Ok(connection) => connections.push(connection),
+                Err(e) => eprintln!(
+                    "{} Failed to connect to the additional server {address}: {e}",
+                    local_now_fmt()
+                ),
+            }
+        }
+        connections
+    }
+
+    /// How the server's certificate should be verified, from the command line options.
+    fn server_trust(&self) -> core::ServerTrust {
+        if let Some(ca) = &self.server_ca {
+            core::ServerTrust::Ca(ca.clone())
+        } else if let Some(fingerprint) = self.server_fingerprint {
+            core::ServerTrust::Fingerprint(fingerprint)
+        } else {
+            core::ServerTrust::FirstUse
+        }
+    }
+
+    /// The certificate to present to servers that require one, from the command line options.
+    fn client_certificate(&self) -> Option<core::ClientCertificate> {
+        Some(core::ClientCertificate {
+            cert: self.client_cert.clone()?,
+            key: self.client_key.clone()?,
+        })
+    }
+
+    /// The event hooks given on the command line.
+    fn event_hooks(&self) -> hooks::EventHooks {
+        hooks::EventHooks {
+            download_complete: self.on_download_complete.clone().unwrap_or_default(),
+            upload_complete: self.on_upload_complete.clone().unwrap_or_default(),
+            publish_failed: self.on_publish_failed.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The directory of Unix domain sockets to use instead of UDP, if any.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    fn local_socket_dir(&self) -> Option<&Path> {
+        self.unix_socket_dir.as_deref()
+    }
+    #[cfg(not(all(unix, feature = "unix-socket")))]
+    #[allow(clippy::unused_self)]
+    fn local_socket_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Parse a server address given on the command line, as `host` or `host:port`.
+fn parse_server_arg(address: &str) -> Result<(String, NonZeroU16), String> {
+    gui::parse_server_address(address)
+        .ok_or_else(|| format!("`{address}` is not a host with an optional port"))
+}
 
 /// The subcommands for `file_yeet_client`.
 #[derive(clap::Subcommand)]
 enum FileYeetCommand {
-    /// Publish a file to the server.
-    Pub { file_path: String },
+    /// Publish files to the server, all over the same connection.
+    /// Directories are published as a manifest listing every file, alongside the files themselves.
+    Pub {
+        /// The files and directories to publish. `-` publishes the data piped to standard input.
+        #[arg(required = true)]
+        file_paths: Vec<String>,
+
+        /// Append a JSON line describing each upload attempt to this file.
+        #[arg(long)]
+        upload_log: Option<std::path::PathBuf>,
+
+        /// The algorithm to hash the file with, `sha256` or `blake3`.
+        /// BLAKE3 is much faster for large files, but only peers that support it can download them.
+        /// Directories are always hashed with SHA-256.
+        #[arg(long, default_value_t)]
+        hash_algorithm: HashAlgorithm,
+
+        /// Sign the publish so that subscribers can verify it came from this client.
+        /// Subscribers trust the signing key by the fingerprint printed when publishing.
+        #[arg(long)]
+        sign: bool,
+
+        /// Only upload to peers that prove they know this passphrase. Subscribers give it with `sub --passphrase`.
+        #[arg(long)]
+        passphrase: Option<String>,
+
+        /// Stop publishing once this many peers have downloaded the whole file.
+        /// When publishing a directory, each file may be downloaded this many times.
+        #[arg(long)]
+        max_downloads: Option<NonZeroUsize>,
+
+        /// Stop publishing once a single peer has downloaded the whole file, to send it to exactly one recipient.
+        /// The same as `--max-downloads 1`.
+        #[arg(long, conflicts_with = "max_downloads")]
+        once: bool,
+
+        /// Hash every file again, even those unchanged since they were last hashed.
+        /// Hashes are otherwise reused while a file keeps the same size and modification time.
+        #[arg(long)]
+        rehash: bool,
+    },
 
     /// Subscribe to a file from the server.
+    /// BLAKE3 hashes are given with a `b3-` prefix.
+    /// A `fyeet://server:port/hash[:ext]` share link may be given in place of the hash to also choose the server.
     Sub {
         sha256_hex: String,
         output: Option<String>,
+
+        /// Treat the hash as a directory manifest and download every file it lists into the output directory.
+        #[arg(short, long)]
+        directory: bool,
+
+        /// Keep asking the server until a publisher appears instead of giving up when there are none.
+        /// Optionally give up after a duration, e.g., `--wait=10m`.
+        #[arg(long, num_args = 0..=1, require_equals = true, value_parser = core::parse_duration)]
+        wait: Option<Option<Duration>>,
+
+        /// Ask the server for up to this many publishers, across several responses if needed, to download from more
+        /// of them at once. By default only the publishers that fit in a single response are used.
+        #[arg(long)]
+        max_peers: Option<NonZeroUsize>,
+
+        /// The passphrase the publisher requires, if any.
+        #[arg(long)]
+        passphrase: Option<String>,
+
+        /// Publish the file under the same hash once it has been downloaded and verified, to help other subscribers.
+        #[arg(long, conflicts_with = "directory")]
+        seed: bool,
+
+        /// Continue from a partial output file left by an interrupted download instead of starting over.
+        /// The whole file is still verified against the hash once it is complete.
+        #[arg(long)]
+        resume: bool,
+
+        /// The number of streams to download different ranges of the file over from each publisher at once.
+        /// More streams keep transfers over lossy links moving, since a lost packet only stalls its own stream.
+        #[arg(long, default_value = "1", conflicts_with = "resume")]
+        streams: NonZeroUsize,
+    },
+
+    /// Check whether peers can reach this client through the server's echo peer.
+    Diagnose,
+
+    /// Check for a newer release and, with consent, replace this binary with it.
+    /// Only releases whose checksum is signed by the release key built into this client are installed.
+    SelfUpdate,
+
+    /// Hash a file without connecting to a server and print the `hash[:ext]` that subscribers download it with.
+    Hash {
+        path: std::path::PathBuf,
+
+        /// The algorithm to hash the file with, `sha256` or `blake3`.
+        #[arg(long, default_value_t)]
+        hash_algorithm: HashAlgorithm,
+
+        /// Also print a `fyeet://` share link for the server given with `--server-address`.
+        #[arg(long)]
+        link: bool,
     },
+
+    /// Check a file against the `hash[:ext]` it was shared with.
+    /// Exits with status 0 if the file matches, 1 if it doesn't, and 2 if it couldn't be checked.
+    Verify {
+        hash: String,
+        path: std::path::PathBuf,
+    },
+
+    /// Re-verify the hashes of every file in a directory tree.
+    /// Expected hashes are read from `.sha256` sidecar files, or from a manifest if one is given.
+    VerifyDir {
+        directory: String,
+
+        /// A file of `<sha256 hex>  <relative path>` lines, as written by `sha256sum`.
+        #[arg(short, long)]
+        manifest: Option<String>,
+    },
+
+    /// Keep publishes alive in the background without a display, controlled through a local socket.
+    /// Each request is a line of JSON, e.g., `{"command":"publish","path":"/srv/file.iso"}`.
+    /// The commands are `publish`, `unpublish`, `list`, `download`, and `status`.
+    Daemon {
+        /// The Unix socket, or named pipe on Windows, to accept control requests on.
+        /// Defaults to `file_yeet.sock` in the user's runtime directory, or `\\.\pipe\file_yeet` on Windows.
+        #[arg(long)]
+        control_socket: Option<std::path::PathBuf>,
+    },
+
+    /// Export the GUI settings to a portable profile file, to set up another machine the same way.
+    /// Written as TOML if the file ends in `.toml`, otherwise as JSON.
+    ExportSettings { path: std::path::PathBuf },
+
+    /// Import a profile file written by `export-settings` into the GUI settings.
+    ImportSettings { path: std::path::PathBuf },
 }
 
 #[tokio::main]
 async fn main() {
     // Parse command line arguments.
     use clap::Parser as _;
-    let args = Cli::parse();
+    let mut args = Cli::parse();
+    if let Some(size) = args.hash_buffer_size {
+        core::set_hash_read_buffer_size(usize::try_from(size).unwrap_or(usize::MAX));
+    }
+    file_yeet_shared::set_transport_tuning(args.transport_tuning());
+
+    // Export spans until the client exits, if a collector was given.
+    #[cfg(feature = "otel")]
+    let _export_guard = args.otlp_endpoint.as_deref().and_then(|endpoint| {
+        telemetry::export_spans(endpoint)
+            .map_err(|e| eprintln!("{} Failed to export spans: {e}", local_now_fmt()))
+            .ok()
+    });
 
     // If no subcommand was provided, run the GUI.
-    let Some(cmd) = args.cmd else {
+    let Some(cmd) = args.cmd.take() else {
         // If Windows, ensure we aren't displaying an unwanted console window.
         #[cfg(target_os = "windows")]
         {
@@ -87,46 +630,360 @@ async fn main() {
         return;
     };
 
+    // Pace the command's transfers.
+    throttle::set_bandwidth_limits(args.bandwidth_limits());
+    if args.json {
+        json_output::enable();
+    }
+
+    // A share link carries the server to connect to along with the hash.
+    let cmd = match args.resolve_share_uri(cmd) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            eprintln!("{} Invalid share link: {e}", local_now_fmt());
+            return;
+        }
+    };
+
+    // Updating doesn't require a server connection.
+    if let FileYeetCommand::SelfUpdate = cmd {
+        if let Err(e) = self_update_command().await {
+            eprintln!("{} Failed to update: {e}", local_now_fmt());
+        }
+        return;
+    }
+
+    // Neither does hashing a file ahead of time.
+    if let FileYeetCommand::Hash {
+        path,
+        hash_algorithm,
+        link,
+    } = &cmd
+    {
+        if let Err(e) = hash_command(&args, path, *hash_algorithm, *link).await {
+            eprintln!("{} Failed to hash the file: {e}", local_now_fmt());
+        }
+        return;
+    }
+
+    // Verifying files doesn't require a server connection either.
+    if let FileYeetCommand::Verify { hash, path } = &cmd {
+        match verify_command(hash, path).await {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{} Failed to verify the file: {e}", local_now_fmt());
+                std::process::exit(2);
+            }
+        }
+    }
+    if let FileYeetCommand::VerifyDir {
+        directory,
+        manifest,
+    } = &cmd
+    {
+        if let Err(e) = verify_dir_command(Path::new(directory), manifest.as_deref()).await {
+            eprintln!("{} Failed to verify the directory: {e}", local_now_fmt());
+        }
+        return;
+    }
+
+    // Settings profiles are local files as well.
+    match &cmd {
+        FileYeetCommand::ExportSettings { path } => {
+            match gui::export_settings_profile(path) {
+                Ok(()) => println!(
+                    "{} Exported settings to {}",
+                    local_now_fmt(),
+                    path.display()
+                ),
+                Err(e) => eprintln!("{} Failed to export settings: {e}", local_now_fmt()),
+            }
+            return;
+        }
+        FileYeetCommand::ImportSettings { path } => {
+            match gui::import_settings_profile(path) {
+                Ok(()) => println!(
+                    "{} Imported settings from {}",
+                    local_now_fmt(),
+                    path.display()
+                ),
+                Err(e) => eprintln!("{} Failed to import settings: {e}", local_now_fmt()),
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    // Open the download cache, if the user wants one.
+    let event_hooks = args.event_hooks();
+    let cache = match args.cache_dir.clone() {
+        Some(directory) => match cache::ContentCache::new(directory).await {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                eprintln!("{} {e}", local_now_fmt());
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // The daemon manages its own server connection so that it can reconnect.
+    if let FileYeetCommand::Daemon { control_socket } = cmd {
+        let control_socket = control_socket.unwrap_or_else(daemon::default_control_socket);
+        if let Err(e) = daemon::run(&args, control_socket, cache, event_hooks).await {
+            eprintln!("{} Daemon failed: {e}", local_now_fmt());
+        }
+        return;
+    }
+
+    // Files that are already cached don't need a server connection to download.
+    if let (
+        FileYeetCommand::Sub {
+            sha256_hex,
+            output,
+            directory: false,
+            ..
+        },
+        Some(cache),
+    ) = (&cmd, &cache)
+    {
+        match cached_download_command(cache, sha256_hex, output.as_deref(), &event_hooks).await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{} Failed to download the file: {e}", local_now_fmt());
+                return;
+            }
+        }
+    }
+
+    // Start looking on the local network too, when asked to.
+    let lan = args
+        .lan
+        .then(|| {
+            core::lan::LanDiscovery::new()
+                .map_err(|e| eprintln!("{} {e}", local_now_fmt()))
+                .ok()
+        })
+        .flatten();
+
+    // The experimental DHT mode finds peers without connecting to a server.
+    if args.dht {
+        if let Err(e) = dht_command(&args, cmd, lan.as_ref(), cache.as_ref(), &event_hooks).await {
+            eprintln!("{} Failed to use the DHT: {e}", local_now_fmt());
+            json_output::emit(&json_output::Event::Error {
+                message: e.to_string(),
+            });
+        }
+        return;
+    }
+
     // Create a buffer for sending and receiving data within the payload size for `file_yeet`.
-    let mut bb = bytes::BytesMut::with_capacity(MAX_SERVER_COMMUNICATION_SIZE);
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
 
     // Connect to the public file_yeet_server.
-    let prepared_connection = core::prepare_server_connection(
-        args.server_address.as_deref(),
-        args.server_port,
-        args.gateway.as_deref(),
-        if let Some(g) = args.port_override {
-            // Use the provided port override.
-            core::PortMappingConfig::PortForwarding(g)
-        } else if args.nat_map {
-            // Try to create a new port mapping using NAT-PMP or PCP.
-            core::PortMappingConfig::PcpNatPmp(None)
-        } else {
-            core::PortMappingConfig::None
-        },
-        &mut bb,
-    )
-    .await
-    .expect("Failed to perform basic connection setup");
+    let prepared_connection = args
+        .connect(&mut bb)
+        .await
+        .expect("Failed to perform basic connection setup");
+
+    // Also connect to any additional servers, sharing the endpoint so peers introduced by any of them reach the same port.
+    let mut prepared_connections = args.connect_additional(&prepared_connection, &mut bb).await;
+    prepared_connections.insert(0, prepared_connection);
+    let server_connections = prepared_connections
+        .iter()
+        .map(|c| c.server_connection.clone())
+        .collect::<Vec<_>>();
+
+    // Log any notifications the servers push to us while the command runs.
+    for prepared_connection in &prepared_connections {
+        let notifications = prepared_connection.server_notifications.clone();
+        tokio::task::spawn(async move {
+            let mut notifications = notifications.lock().await;
+            // The stream only fails once the server connection is closed.
+            while let Ok((kind, message)) = core::read_server_notification(&mut notifications).await
+            {
+                status!("{} {kind}: {message}", local_now_fmt());
+            }
+        });
+    }
 
     // Determine if we are going to make a publish or subscribe request.
     match cmd {
         // Try to hash and publish the file to the rendezvous server.
-        FileYeetCommand::Pub { file_path } => {
-            if let Err(e) = publish_command(&prepared_connection, bb, file_path).await {
+        FileYeetCommand::Pub {
+            file_paths,
+            upload_log,
+            hash_algorithm,
+            sign,
+            passphrase,
+            max_downloads,
+            once,
+            rehash,
+        } => {
+            let signing_key = sign.then(identity::PublisherKey::load_or_create);
+            if let Some(key) = &signing_key {
+                status!(
+                    "{} Signing publishes with the key fingerprint {}",
+                    local_now_fmt(),
+                    key.fingerprint()
+                );
+            }
+            if let Err(e) = publish_command(
+                &prepared_connections,
+                &file_paths,
+                hash_algorithm,
+                rehash,
+                cache.as_ref(),
+                args.relay,
+                PublishOptions {
+                    room: &args.room,
+                    upload_log: upload_log.as_deref(),
+                    signing_key: signing_key.as_ref(),
+                    passphrase: passphrase.as_deref(),
+                    lan: lan.as_ref(),
+                    max_downloads: if once {
+                        NonZeroUsize::new(1)
+                    } else {
+                        max_downloads
+                    },
+                },
+                &event_hooks,
+            )
+            .await
+            {
                 eprintln!("{} Failed to publish the file: {e}", local_now_fmt());
+                json_output::emit(&json_output::Event::Error {
+                    message: e.to_string(),
+                });
+                run_hook(
+                    &event_hooks,
+                    hooks::TransferEvent::PublishFailed,
+                    hooks::HookContext {
+                        // Several files have no single path to report.
+                        path: match file_paths.as_slice() {
+                            [file_path] => file_path.into(),
+                            _ => std::path::PathBuf::new(),
+                        },
+                        error: Some(e.to_string()),
+                        ..hooks::HookContext::default()
+                    },
+                )
+                .await;
             }
         }
 
         // Try to get the file hash from the rendezvous server and peers.
-        FileYeetCommand::Sub { sha256_hex, output } => {
-            if let Err(e) = subscribe_command(&prepared_connection, bb, sha256_hex, output).await {
-                eprintln!("{} Failed to download the file: {e}", local_now_fmt());
+        FileYeetCommand::Sub {
+            sha256_hex,
+            output,
+            directory,
+            wait,
+            max_peers,
+            passphrase,
+            seed,
+            resume,
+            streams,
+        } => {
+            let options = DownloadOptions {
+                max_download_size: args.max_download_size,
+                relay: args.relay,
+                ask_consent: true,
+                wait,
+                max_peers,
+                room: &args.room,
+                trusted_publishers: &args.trusted_publishers,
+                passphrase: passphrase.as_deref(),
+                resume,
+                streams,
+                lan: lan.as_ref(),
+                dht: None,
+            };
+            let result = if directory {
+                subscribe_directory_command(
+                    &prepared_connections[0].endpoint,
+                    &server_connections,
+                    sha256_hex,
+                    output,
+                    options,
+                    cache.as_ref(),
+                    &event_hooks,
+                )
+                .await
+                .map(|()| None)
+            } else {
+                subscribe_command(
+                    &prepared_connections[0].endpoint,
+                    &server_connections,
+                    bb,
+                    sha256_hex,
+                    output,
+                    options,
+                    cache.as_ref(),
+                    &event_hooks,
+                )
+                .await
+                .map(Some)
+            };
+            match result {
+                // Become another source for the file now that we have all of it.
+                Ok(Some(target)) if seed => {
+                    status!(
+                        "{} Seeding {} with hash {}",
+                        local_now_fmt(),
+                        target.path.display(),
+                        target.hash
+                    );
+                    let options = PublishOptions {
+                        room: &args.room,
+                        upload_log: None,
+                        signing_key: None,
+                        passphrase: None,
+                        lan: lan.as_ref(),
+                        max_downloads: None,
+                    };
+                    if let Err(e) = publish_targets(
+                        &prepared_connections,
+                        &[target],
+                        args.relay,
+                        options,
+                        &event_hooks,
+                    )
+                    .await
+                    {
+                        eprintln!("{} Failed to seed the file: {e}", local_now_fmt());
+                        json_output::emit(&json_output::Event::Error {
+                            message: e.to_string(),
+                        });
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("{} Failed to download the file: {e}", local_now_fmt());
+                    json_output::emit(&json_output::Event::Error {
+                        message: e.to_string(),
+                    });
+                }
             }
         }
+
+        // Report how peers see this client and whether they can reach it.
+        FileYeetCommand::Diagnose => diagnose_command(&prepared_connections[0]).await,
+
+        FileYeetCommand::SelfUpdate
+        | FileYeetCommand::Daemon { .. }
+        | FileYeetCommand::Hash { .. }
+        | FileYeetCommand::Verify { .. }
+        | FileYeetCommand::VerifyDir { .. }
+        | FileYeetCommand::ExportSettings { .. }
+        | FileYeetCommand::ImportSettings { .. } => {
+            unreachable!("Handled before connecting to the server")
+        }
     }
 
-    // Close our connection to the server. Send a goodbye to be polite.
+    // Close our connections to the servers, which all share the endpoint. Send a goodbye to be polite.
+    let prepared_connection = prepared_connections.swap_remove(0);
     prepared_connection
         .endpoint
         .close(GOODBYE_CODE, GOODBYE_MESSAGE.as_bytes());
@@ -140,7 +997,7 @@ async fn main() {
                 m.expiration()
             );
         } else {
-            println!(
+            status!(
                 "{} Successfully deleted the created port mapping",
                 local_now_fmt()
             );
@@ -148,223 +1005,2197 @@ async fn main() {
     }
 }
 
-/// Handle the CLI command to publish a file.
-async fn publish_command(
-    prepared_connection: &PreparedConnection,
-    bb: bytes::BytesMut,
-    file_path: String,
+/// Handle a publish or subscribe in the experimental DHT mode, which finds peers on a DHT instead of through a server.
+async fn dht_command(
+    args: &Cli,
+    cmd: FileYeetCommand,
+    lan: Option<&core::lan::LanDiscovery>,
+    cache: Option<&cache::ContentCache>,
+    event_hooks: &hooks::EventHooks,
 ) -> anyhow::Result<()> {
-    let file_path = std::path::Path::new(&file_path);
-    let (file_size, hash) = match core::file_size_and_hash(file_path, None).await {
-        Ok(t) => t,
-        Err(e) => anyhow::bail!("Failed to hash file: {e}"),
-    };
-    let mut hex_bytes = [0; 2 * file_yeet_shared::HASH_BYTE_COUNT];
-    println!(
-        "{} File {} has SHA-256 hash {} and size {} bytes",
+    let using_ipv4 = !args.dht_bootstrap.first().is_some_and(SocketAddr::is_ipv6);
+    let dht = core::dht::Dht::bind(args.dht_port, using_ipv4).await?;
+    status!(
+        "{} DHT node listening on {}",
         local_now_fmt(),
-        file_path.display(),
-        faster_hex::hex_encode(&hash, &mut hex_bytes).expect("Failed to use a valid hex buffer"),
-        humanize_bytes(file_size),
+        dht.local_addr()?
+    );
+    let known_nodes = dht.bootstrap(&args.dht_bootstrap).await;
+    status!(
+        "{} Joined the DHT with {known_nodes} known nodes",
+        local_now_fmt()
     );
 
-    let core::PreparedConnection {
-        endpoint,
-        server_connection,
-        ..
-    } = prepared_connection;
+    match cmd {
+        FileYeetCommand::Pub {
+            file_paths,
+            upload_log,
+            hash_algorithm,
+            sign,
+            passphrase,
+            max_downloads,
+            once,
+            rehash,
+        } => {
+            // Signatures are shared through the server, so there is nowhere to put them.
+            if sign {
+                anyhow::bail!("Signed publishes need a server");
+            }
+            let max_downloads = if once {
+                NonZeroUsize::new(1)
+            } else {
+                max_downloads
+            };
+            let (publishes, _stdin_spool) =
+                hash_publishes(&file_paths, hash_algorithm, rehash, cache, max_downloads).await?;
+            let options = PublishOptions {
+                room: "",
+                upload_log: upload_log.as_deref(),
+                signing_key: None,
+                passphrase: passphrase.as_deref(),
+                lan,
+                max_downloads,
+            };
+            dht_publish_targets(
+                &dht,
+                &publishes,
+                args.internal_port_range,
+                options,
+                event_hooks,
+            )
+            .await
+        }
+        FileYeetCommand::Sub {
+            sha256_hex,
+            output,
+            directory,
+            passphrase,
+            seed,
+            resume,
+            streams,
+            ..
+        } => {
+            let endpoint = core::bind_peer_endpoint(using_ipv4, args.internal_port_range)?;
+            let options = DownloadOptions {
+                max_download_size: args.max_download_size,
+                relay: false,
+                ask_consent: true,
+                wait: None,
+                max_peers: None,
+                room: "",
+                trusted_publishers: &args.trusted_publishers,
+                passphrase: passphrase.as_deref(),
+                resume,
+                streams,
+                lan,
+                dht: Some(&dht),
+            };
+            if directory {
+                return subscribe_directory_command(
+                    &endpoint,
+                    &[],
+                    sha256_hex,
+                    output,
+                    options,
+                    cache,
+                    event_hooks,
+                )
+                .await;
+            }
+            let target = subscribe_command(
+                &endpoint,
+                &[],
+                SERVER_MESSAGE_BUFFERS.take(),
+                sha256_hex,
+                output,
+                options,
+                cache,
+                event_hooks,
+            )
+            .await?;
 
-    // Allow the publish loop to be cancelled by a Ctrl-C signal.
-    let cancellation_token = CancellationToken::new();
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!("{} Ctrl-C detected, cancelling the publish", local_now_fmt());
-            cancellation_token.cancel();
+            // Become another source for the file now that we have all of it.
+            if seed {
+                let options = PublishOptions {
+                    room: "",
+                    upload_log: None,
+                    signing_key: None,
+                    passphrase: None,
+                    lan,
+                    max_downloads: None,
+                };
+                dht_publish_targets(
+                    &dht,
+                    &[target],
+                    args.internal_port_range,
+                    options,
+                    event_hooks,
+                )
+                .await?;
+            }
+            Ok(())
         }
-        r = publish_loop(endpoint, server_connection, bb, hash, file_size, file_path, cancellation_token.clone()) => return r
+        _ => anyhow::bail!("Only `pub` and `sub` can be used with `--dht`"),
     }
-
-    Ok(())
 }
 
-/// Handle the CLI command to subscribe to a file.
-async fn subscribe_command(
-    prepared_connection: &PreparedConnection,
-    mut bb: bytes::BytesMut,
-    sha256_hex: String,
-    output_path: Option<String>,
+/// Handle the CLI command to publish files, and directories as a manifest and every file it lists.
+/// Every file is hashed first, then all of them are published together over the same connection to each server.
+async fn publish_command(
+    prepared_connections: &[PreparedConnection],
+    file_paths: &[String],
+    hash_algorithm: HashAlgorithm,
+    rehash: bool,
+    cache: Option<&cache::ContentCache>,
+    relay: bool,
+    options: PublishOptions<'_>,
+    event_hooks: &hooks::EventHooks,
 ) -> anyhow::Result<()> {
-    let mut hash = HashBytes::default();
-    if let Err(e) = faster_hex::hex_decode(sha256_hex.as_bytes(), &mut hash) {
-        anyhow::bail!("Failed to parse hex hash: {e}");
-    };
-
-    // Determine the output file path to use.
-    let output = output_path.as_ref().filter(|s| !s.is_empty()).map_or_else(
-        || {
-            let mut output = std::env::temp_dir();
-            let mut hex_bytes = [0; 2 * file_yeet_shared::HASH_BYTE_COUNT];
-            output.push(
-                faster_hex::hex_encode(&hash, &mut hex_bytes)
-                    .expect("Failed to use a valid hex buffer"),
-            );
-            output
-        },
-        |o| o.clone().into(),
-    );
-
-    let core::PreparedConnection {
-        endpoint,
-        server_connection,
-        ..
-    } = prepared_connection;
-
-    // Request all available peers from the server.
-    let mut peers = match core::subscribe(server_connection, &mut bb, hash).await {
-        Err(e) => anyhow::bail!("Failed to subscribe to the file: {e}"),
-        Ok(c) => c,
-    };
-    bb.clear();
+    let (publishes, _stdin_spool) = hash_publishes(
+        file_paths,
+        hash_algorithm,
+        rehash,
+        cache,
+        options.max_downloads,
+    )
+    .await?;
+    publish_targets(
+        prepared_connections,
+        &publishes,
+        relay,
+        options,
+        event_hooks,
+    )
+    .await
+}
 
-    // If no peers are available, quickly return.
-    if peers.is_empty() {
-        anyhow::bail!("No peers are available for the file");
+/// Hash the files and directories to publish, skipping files given more than once.
+/// When several paths are given, those that fail are skipped and a table of what became of each file is printed.
+/// Returns the files to publish, and the data piped to standard input, which must be kept until publishing ends.
+async fn hash_publishes(
+    file_paths: &[String],
+    hash_algorithm: HashAlgorithm,
+    rehash: bool,
+    cache: Option<&cache::ContentCache>,
+    max_downloads: Option<NonZeroUsize>,
+) -> anyhow::Result<(Vec<PublishTarget>, Option<SpooledStdin>)> {
+    let mut publishes = Vec::new();
+    let mut failures = Vec::new();
+    let mut stdin_spool = None;
+    for file_path in file_paths {
+        match hash_publish(file_path, hash_algorithm, rehash, cache, &mut stdin_spool).await {
+            Ok(mut targets) => publishes.append(&mut targets),
+            Err(e) if file_paths.len() > 1 => {
+                eprintln!("{} {e}", local_now_fmt());
+                failures.push(table::FileRow::failed(file_path.clone(), e.to_string()));
+            }
+            Err(e) => return Err(e),
+        }
     }
 
-    // Try to connect to multiple peers concurrently with a list of connection futures.
-    let mut connection_attempts = FuturesUnordered::new();
-    for (peer_address, file_size) in peers.drain(..) {
-        connection_attempts.push(async move {
-            (
-                core::udp_holepunch(
-                    FileYeetCommandType::Sub,
-                    hash,
-                    endpoint.clone(),
-                    peer_address,
-                )
-                .await,
-                file_size,
-            )
+    // Identical files given more than once, or found in more than one directory, only need to be published once.
+    let mut published = std::collections::HashSet::new();
+    let mut rows = Vec::new();
+    publishes.retain(|target| {
+        let first = published.insert(target.hash);
+        rows.push(table::FileRow {
+            path: target.path.display().to_string(),
+            file_size: Some(target.file_size),
+            hash: Some(target.hash.to_string()),
+            state: if first {
+                table::FileState::Publishing
+            } else {
+                table::FileState::Duplicate
+            },
+            error: None,
         });
+        first
+    });
+    if file_paths.len() > 1 {
+        rows.append(&mut failures);
+        table::print_summary(&rows);
+        if publishes.is_empty() {
+            anyhow::bail!("None of the files could be published");
+        }
     }
 
-    // Iterate through the connection attempts as they resolve and use the first successful connection.
-    let peer_connection = loop {
-        match connection_attempts.next().await {
-            Some((Some((c, b)), file_size)) => {
-                let consent =
-                    file_consent_cli(file_size, &output).expect("Failed to read user input");
-                if consent {
-                    break Some((c, b, file_size));
-                }
-
-                println!("{} Download cancelled", local_now_fmt());
+    // Each file counts its own downloads toward the limit.
+    if let Some(max_downloads) = max_downloads {
+        for target in &mut publishes {
+            target.remaining_downloads = Some(Arc::new(AtomicUsize::new(max_downloads.get())));
+        }
+    }
+    Ok((publishes, stdin_spool))
+}
+
+/// Hash a file to publish, or every file in a directory and the manifest listing them.
+/// `-` reads standard input into `stdin_spool` and publishes that.
+async fn hash_publish(
+    file_path: &str,
+    hash_algorithm: HashAlgorithm,
+    rehash: bool,
+    cache: Option<&cache::ContentCache>,
+    stdin_spool: &mut Option<SpooledStdin>,
+) -> anyhow::Result<Vec<PublishTarget>> {
+    // Data piped to standard input is saved to a file first, then published like any other file.
+    let from_stdin = file_path == STDIN_PATH;
+    let mut file_path = if from_stdin {
+        if stdin_spool.is_some() {
+            anyhow::bail!("Standard input can only be published once");
+        }
+        stdin_spool.insert(SpooledStdin::read().await?).path.clone()
+    } else {
+        std::path::PathBuf::from(file_path)
+    };
+    if file_path.is_dir() {
+        return directory_publishes(&file_path, rehash).await;
+    }
+
+    // Files that came from the cache unchanged don't need to be hashed again.
+    let known_hash = match cache {
+        Some(cache) => cache
+            .known_hash(&file_path)
+            .await
+            .filter(|(_, hash)| hash.algorithm == hash_algorithm),
+        None => None,
+    };
+    let (file_size, hash, chunk_hashes) = match known_hash {
+        Some((file_size, hash)) => (file_size, hash, Vec::new()),
+        None => match hash_for_publish(&file_path, hash_algorithm, rehash).await {
+            Ok((file_size, hash, chunk_hashes)) => {
+                (file_size, FileHash::new(hash_algorithm, hash), chunk_hashes)
+            }
+            Err(e) => anyhow::bail!("Failed to hash {}: {e}", file_path.display()),
+        },
+    };
+    if let (true, Some(spool)) = (from_stdin, stdin_spool.as_mut()) {
+        spool.rename_to_hash(&hash).await?;
+        file_path.clone_from(&spool.path);
+    }
+    status!(
+        "{} File {} has {hash_algorithm} hash {hash} and size {} bytes",
+        local_now_fmt(),
+        file_path.display(),
+        humanize_bytes(file_size),
+    );
+    Ok(vec![PublishTarget {
+        path: file_path,
+        file_size,
+        hash,
+        chunk_hashes: Arc::new(chunk_hashes),
+        remaining_downloads: None,
+    }])
+}
+
+/// Data piped to standard input, saved to a temporary file so it can be hashed and served like any other file.
+/// The file is removed once it is no longer published.
+struct SpooledStdin {
+    path: std::path::PathBuf,
+}
+impl SpooledStdin {
+    /// Read standard input to its end into a temporary file.
+    async fn read() -> anyhow::Result<Self> {
+        let path = std::env::temp_dir().join(format!("file_yeet_stdin_{}", std::process::id()));
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create a file for standard input: {e}"))?;
+        let spool = Self { path };
+        let size = tokio::io::copy(&mut tokio::io::stdin(), &mut file)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read standard input: {e}"))?;
+        file.flush().await?;
+        if size == 0 {
+            anyhow::bail!("Nothing was piped to standard input");
+        }
+        status!(
+            "{} Read {} from standard input",
+            local_now_fmt(),
+            humanize_bytes(size)
+        );
+        Ok(spool)
+    }
+
+    /// Name the file after its hash, which is also the name subscribers save it as by default.
+    async fn rename_to_hash(&mut self, hash: &FileHash) -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(hash.to_string());
+        tokio::fs::rename(&self.path, &path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to rename the file for standard input: {e}"))?;
+        self.path = path;
+        Ok(())
+    }
+}
+impl Drop for SpooledStdin {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!(
+                "{} Failed to remove {}: {e}",
+                local_now_fmt(),
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Hash a file and each of its chunks to publish it, showing the progress as a bar or as JSON events.
+/// Unchanged files reuse their cached hashes unless `rehash` is set.
+async fn hash_for_publish(
+    file_path: &Path,
+    hash_algorithm: HashAlgorithm,
+    rehash: bool,
+) -> anyhow::Result<(u64, HashBytes, Vec<HashBytes>)> {
+    let (progress, progress_receiver) = tokio::sync::watch::channel(0.);
+    json_output::watch_progress(progress_receiver.clone(), {
+        let path = file_path.to_path_buf();
+        move |progress| json_output::Event::Hashing {
+            path: path.clone(),
+            progress,
+        }
+    });
+    show_progress(
+        "Hashing",
+        core::cached_file_size_hash_and_chunks(file_path, hash_algorithm, Some(progress), rehash),
+        &progress_receiver,
+    )
+    .await
+}
+
+/// Publish files that have already been hashed to every server until the server connections close or Ctrl-C is pressed.
+async fn publish_targets(
+    prepared_connections: &[PreparedConnection],
+    publishes: &[PublishTarget],
+    relay: bool,
+    options: PublishOptions<'_>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<()> {
+    let mut address_watches = Vec::with_capacity(prepared_connections.len());
+    let mut address_changes = Vec::with_capacity(prepared_connections.len());
+    for prepared_connection in prepared_connections {
+        let core::PreparedConnection {
+            server_connection,
+            port_override,
+            external_address,
+            ..
+        } = prepared_connection;
+
+        // Let subscribers that can't reach us directly ask for a relay instead.
+        if relay {
+            if let Err(e) = core::relay_opt_in(server_connection).await {
+                anyhow::bail!("Failed to enable relays: {e}");
+            }
+        }
+
+        // Tell the publishes to this server when our external address changes, so they can bring themselves up to date.
+        let server_connection = server_connection.clone();
+        let (external_address, address_change) =
+            tokio::sync::watch::channel(external_address.clone());
+        let port_override = *port_override;
+        address_watches.push(tokio::task::spawn(async move {
+            core::watch_external_address(&server_connection, &external_address, port_override)
+                .await;
+        }));
+        address_changes.push(address_change);
+    }
+
+    // Allow the publish loops to be cancelled by a Ctrl-C signal.
+    let cancellation_token = CancellationToken::new();
+    let result = tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            status!("{} Ctrl-C detected, cancelling the publish", local_now_fmt());
+            cancellation_token.cancel();
+            Ok(())
+        }
+        r = futures_util::future::try_join_all(publishes.iter().flat_map(|target| {
+            // A file is withdrawn from every server at once when it reaches its download limit.
+            let downloaded = CancellationToken::new();
+            let cancellation_token = &cancellation_token;
+            let address_changes = &address_changes;
+            prepared_connections.iter().enumerate().map(move |(i, prepared_connection)| {
+                // Each file is published on its own stream to each server,
+                // but only one of the publishes needs to answer the local network.
+                let options = if i == 0 {
+                    options
+                } else {
+                    PublishOptions { lan: None, ..options }
+                };
+                publish_loop(
+                    prepared_connection,
+                    target,
+                    options,
+                    event_hooks,
+                    cancellation_token.clone(),
+                    downloaded.clone(),
+                    address_changes[i].clone(),
+                )
+            })
+        })) => r.map(|_| ()),
+    };
+    for address_watch in address_watches {
+        address_watch.abort();
+    }
+
+    result
+}
+
+/// Announce files that have already been hashed on the DHT and serve them, until Ctrl-C is pressed
+/// or every file reaches its download limit.
+async fn dht_publish_targets(
+    dht: &core::dht::Dht,
+    publishes: &[PublishTarget],
+    port_range: Option<core::PortRange>,
+    options: PublishOptions<'_>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<()> {
+    // Each file gets an endpoint of its own, since peers only say which file they want once connected.
+    let using_ipv4 = dht.local_addr()?.is_ipv4();
+    let endpoints = publishes
+        .iter()
+        .map(|_| core::bind_peer_endpoint(using_ipv4, port_range))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Allow the publish loops to be cancelled by a Ctrl-C signal.
+    let cancellation_token = CancellationToken::new();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            status!("{} Ctrl-C detected, cancelling the publish", local_now_fmt());
+            cancellation_token.cancel();
+            Ok(())
+        }
+        r = futures_util::future::try_join_all(publishes.iter().zip(endpoints).map(|(target, endpoint)| {
+            dht_publish_loop(dht, target, endpoint, options, event_hooks, cancellation_token.clone())
+        })) => r.map(|_| ()),
+    }
+}
+
+/// Announce a file on the DHT, again every `REANNOUNCE_INTERVAL`, and serve the subscribers who connect to
+/// its endpoint until it reaches its download limit.
+async fn dht_publish_loop(
+    dht: &core::dht::Dht,
+    target: &PublishTarget,
+    endpoint: quinn::Endpoint,
+    options: PublishOptions<'_>,
+    event_hooks: &hooks::EventHooks,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let local_address = endpoint.local_addr()?;
+
+    // Cancelled once enough peers have downloaded the whole file.
+    let downloaded = CancellationToken::new();
+    let upload = SubscriberUpload::new(
+        target,
+        options,
+        event_hooks,
+        cancellation_token,
+        downloaded.clone(),
+    );
+    let _accepting = serve_direct_subscribers(endpoint, upload.clone());
+    let _lan_publish = options
+        .lan
+        .and_then(|lan| serve_lan_subscribers(lan, &upload, local_address.is_ipv4()));
+    json_output::emit(&json_output::Event::Published {
+        path: target.path.clone(),
+        hash: target.hash.to_string(),
+        file_size: target.file_size,
+    });
+
+    loop {
+        let nodes = dht
+            .announce(&target.hash.bytes, target.file_size, local_address.port())
+            .await;
+        if nodes == 0 {
+            eprintln!(
+                "{} No DHT nodes accepted the announcement of {}",
+                local_now_fmt(),
+                target.path.display()
+            );
+        } else {
+            status!(
+                "{} Announced {} to {nodes} DHT nodes, to be downloaded from port {}",
+                local_now_fmt(),
+                target.path.display(),
+                local_address.port()
+            );
+        }
+
+        tokio::select! {
+            () = downloaded.cancelled() => {
+                status!(
+                    "{} {} reached its download limit, so it is no longer published",
+                    local_now_fmt(),
+                    target.path.display()
+                );
+                return Ok(());
+            }
+            () = tokio::time::sleep(core::dht::REANNOUNCE_INTERVAL) => {}
+        }
+    }
+}
+
+/// A file to publish and what peers need to know to download it.
+struct PublishTarget {
+    path: std::path::PathBuf,
+    file_size: u64,
+    hash: FileHash,
+
+    /// The hash of each chunk of the file, shared with peers so they can verify ranges as they arrive.
+    /// Empty when the chunks were not hashed.
+    chunk_hashes: Arc<Vec<HashBytes>>,
+
+    /// The number of complete downloads left before the file is withdrawn, kept across reconnects. `None` for no limit.
+    remaining_downloads: Option<Arc<AtomicUsize>>,
+}
+
+/// Hash every file in a directory and save a manifest listing them.
+/// Returns the manifest and each distinct, non-empty file to publish, with their sizes and hashes.
+async fn directory_publishes(directory: &Path, rehash: bool) -> anyhow::Result<Vec<PublishTarget>> {
+    status!(
+        "{} Hashing every file in {}...",
+        local_now_fmt(),
+        directory.display()
+    );
+    json_output::emit(&json_output::Event::Hashing {
+        path: directory.to_path_buf(),
+        progress: 0.,
+    });
+    let (manifest, paths) = manifest::DirectoryManifest::build(directory, rehash).await?;
+    if manifest.files.is_empty() {
+        anyhow::bail!(
+            "The directory {} has no files to publish",
+            directory.display()
+        );
+    }
+
+    // Save the manifest so it can be served like any other file.
+    let (bytes, manifest_hash) = manifest.to_bytes()?;
+    let manifest_hex = faster_hex::hex_string(&manifest_hash);
+    let manifest_path =
+        std::env::temp_dir().join(format!("{manifest_hex}.{}", manifest::MANIFEST_EXTENSION));
+    tokio::fs::write(&manifest_path, &bytes)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to save the manifest: {e}"))?;
+    status!(
+        "{} Directory {} has {} files totalling {}. Download it with `sub --directory {manifest_hex}`",
+        local_now_fmt(),
+        directory.display(),
+        manifest.files.len(),
+        humanize_bytes(manifest.total_size()),
+    );
+
+    // Empty files are recreated without a download, and identical files only need to be published once.
+    let mut publishes = vec![PublishTarget {
+        path: manifest_path,
+        file_size: bytes.len() as u64,
+        hash: FileHash::new(HashAlgorithm::Sha256, manifest_hash),
+        chunk_hashes: Arc::default(),
+        remaining_downloads: None,
+    }];
+    let mut published = std::collections::HashSet::new();
+    for (entry, path) in manifest.files.iter().zip(paths) {
+        let hash = entry.hash()?;
+        if entry.size > 0 && published.insert(hash) {
+            publishes.push(PublishTarget {
+                path,
+                file_size: entry.size,
+                hash: FileHash::new(HashAlgorithm::Sha256, hash),
+                chunk_hashes: Arc::default(),
+                remaining_downloads: None,
+            });
+        }
+    }
+    Ok(publishes)
+}
+
+/// Options for how the CLI serves its publishes.
+#[derive(Clone, Copy, Debug)]
+struct PublishOptions<'a> {
+    /// The room to publish in.
+    room: &'a str,
+
+    /// A file to append a JSON line describing each upload attempt to.
+    upload_log: Option<&'a Path>,
+
+    /// The key to sign publishes with, if they should be signed.
+    signing_key: Option<&'a identity::PublisherKey>,
+
+    /// The passphrase peers must prove they know before we upload to them.
+    passphrase: Option<&'a str>,
+
+    /// Where to also announce the publish, if on the local network.
+    lan: Option<&'a core::lan::LanDiscovery>,
+
+    /// The most complete downloads to serve for each file `publish_command` publishes, if limited.
+    max_downloads: Option<NonZeroUsize>,
+}
+
+/// Why a publish loop stopped without an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PublishEnd {
+    /// The server closed the publish stream.
+    ServerClosed,
+
+    /// The publish reached its download limit and was withdrawn from the server.
+    Downloaded,
+}
+
+/// Options for how the CLI accepts downloads.
+#[derive(Clone, Copy, Debug)]
+struct DownloadOptions<'a> {
+    /// The largest download to accept.
+    max_download_size: Option<u64>,
+
+    /// Whether to ask the server for a relay when no peer can be reached directly.
+    relay: bool,
+
+    /// Whether to ask the user before downloading.
+    ask_consent: bool,
+
+    /// Whether to wait for a publisher when there are none, and the longest to wait if there is a deadline.
+    wait: Option<Option<Duration>>,
+
+    /// The most publishers to ask the server for, if more than fit in a single response.
+    max_peers: Option<NonZeroUsize>,
+
+    /// The room to look for publishers in.
+    room: &'a str,
+
+    /// The fingerprints of the publishers to accept downloads from. Any publisher is accepted if empty.
+    trusted_publishers: &'a [String],
+
+    /// The passphrase to prove to publishers that require one.
+    passphrase: Option<&'a str>,
+
+    /// Whether to continue from a partial file left by an earlier attempt.
+    resume: bool,
+
+    /// The number of streams to download over from each publisher at once.
+    streams: NonZeroUsize,
+
+    /// Where to also look for publishers, if on the local network.
+    lan: Option<&'a core::lan::LanDiscovery>,
+
+    /// Where to also look for publishers, if on the DHT.
+    dht: Option<&'a core::dht::Dht>,
+}
+
+/// Request the peers publishing a file from every server. If there are none and we are allowed to wait,
+/// keep asking with a backoff until a publisher appears or the deadline passes.
+/// Returns the peers along with the server that introduced each of them.
+async fn subscribe_or_wait(
+    server_connections: &[quinn::Connection],
+    bb: &mut bytes::BytesMut,
+    hash: FileHash,
+    options: DownloadOptions<'_>,
+) -> anyhow::Result<(
+    Vec<(std::net::SocketAddr, u64, core::FileMetadata)>,
+    HashMap<std::net::SocketAddr, quinn::Connection>,
+)> {
+    let DownloadOptions {
+        wait,
+        max_peers,
+        room,
+        ..
+    } = options;
+    let start = Instant::now();
+    let deadline = wait.flatten().map(|d| start + d);
+    let mut backoff = WAIT_POLL_INITIAL_BACKOFF;
+    let mut frame = 0;
+    loop {
+        let mut peers = Vec::new();
+        let mut introducers = HashMap::new();
+        let mut answered = false;
+        let mut error = None;
+        for server_connection in server_connections {
+            let server_peers = match max_peers {
+                Some(max_peers) => {
+                    core::subscribe_many(server_connection, bb, hash, room, max_peers).await
+                }
+                None => core::subscribe(server_connection, bb, hash, room).await,
+            };
+            bb.clear();
+            match server_peers {
+                Ok(server_peers) => {
+                    answered = true;
+                    for peer in server_peers {
+                        // A publisher known to several servers is only connected to once.
+                        if let std::collections::hash_map::Entry::Vacant(e) =
+                            introducers.entry(peer.0)
+                        {
+                            e.insert(server_connection.clone());
+                            peers.push(peer);
+                        }
+                    }
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        // Only give up when none of the servers could be asked.
+        match error {
+            Some(e) if !answered => anyhow::bail!("Failed to subscribe to the file: {e}"),
+            Some(e) => eprintln!(
+                "{} Failed to subscribe to the file on one of the servers: {e}",
+                local_now_fmt()
+            ),
+            None => {}
+        }
+
+        if !peers.is_empty() {
+            // End the spinner's line before the download flow prints.
+            if frame > 0 {
+                println!();
+            }
+            return Ok((peers, introducers));
+        }
+
+        // If no peers are available and we can't wait, quickly return.
+        if wait.is_none() {
+            anyhow::bail!("No peers are available for the file");
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            if frame > 0 {
+                println!();
+            }
+            anyhow::bail!("No publisher appeared before the deadline");
+        }
+
+        // Spin until the next poll, without sleeping past the deadline.
+        let next_poll = deadline.map_or(Instant::now() + backoff, |d| {
+            d.min(Instant::now() + backoff)
+        });
+        while let Some(remaining) = next_poll.checked_duration_since(Instant::now()) {
+            // Keep stdout free for JSON lines.
+            if json_output::enabled() {
+                tokio::time::sleep(remaining).await;
+                continue;
+            }
+            print!(
+                "\r{} {} Waiting for a publisher ({}s)",
+                local_now_fmt(),
+                SPINNER_FRAMES[frame % SPINNER_FRAMES.len()],
+                start.elapsed().as_secs()
+            );
+            std::io::stdout().flush()?;
+            frame += 1;
+            tokio::time::sleep(remaining.min(SPINNER_INTERVAL)).await;
+        }
+        backoff = (backoff * 2).min(WAIT_POLL_MAX_BACKOFF);
+    }
+}
+
+/// Handle the CLI command to subscribe to a file.
+/// Returns the downloaded file, ready to be published again.
+#[tracing::instrument(skip_all, fields(hash = %sha256_hex))]
+async fn subscribe_command(
+    endpoint: &quinn::Endpoint,
+    server_connections: &[quinn::Connection],
+    mut bb: PooledBuffer,
+    sha256_hex: String,
+    output_path: Option<String>,
+    options: DownloadOptions<'_>,
+    cache: Option<&cache::ContentCache>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<PublishTarget> {
+    let (hash, mut output) = subscribe_target(&sha256_hex, output_path.as_deref())?;
+
+    // Request all available peers from the servers, waiting for one to appear if asked to,
+    // while looking for publishers on the local network and the DHT too.
+    let server_lookup = async {
+        if server_connections.is_empty() {
+            Ok((Vec::new(), HashMap::new()))
+        } else {
+            subscribe_or_wait(server_connections, &mut bb, hash, options).await
+        }
+    };
+    let (peers, direct_peers) = tokio::join!(
+        server_lookup,
+        find_direct_publishers(endpoint, &hash.bytes, options)
+    );
+    let (mut peers, introducers) = match peers {
+        Ok(found) => found,
+        // Publishers found without a server are enough when the servers have none.
+        Err(_) if !direct_peers.is_empty() => (Vec::new(), HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    // Peers found without the server carry no signed metadata, so they are dropped when only trusted publishers are wanted.
+    let direct_addresses = direct_peers
+        .iter()
+        .map(|&(address, _)| address)
+        .collect::<std::collections::HashSet<_>>();
+    peers.extend(
+        direct_peers
+            .into_iter()
+            .map(|(address, file_size)| (address, file_size, core::FileMetadata::default())),
+    );
+    if peers.is_empty() {
+        anyhow::bail!("No peers are available for the file");
+    }
+    retain_trusted_publishers(&mut peers, &hash.bytes, options.trusted_publishers);
+    if peers.is_empty() {
+        anyhow::bail!("None of the publishers signed the file with a trusted key");
+    }
+
+    // Save the file under the name its publisher gave it, unless the user chose an output.
+    if output_path.as_deref().filter(|p| !p.is_empty()).is_none() {
+        if let Some(file_name) = peers
+            .iter()
+            .find_map(|(_, _, metadata)| metadata.safe_file_name())
+        {
+            output = std::env::temp_dir().join(file_name);
+        }
+    }
+
+    // Download into the cache first when there is one, and place the verified file afterwards.
+    let download_path = cache.map_or_else(|| output.clone(), |c| c.path_for(&hash));
+
+    // Try to connect to multiple peers concurrently with a list of connection futures.
+    let mut connection_attempts = FuturesUnordered::new();
+    // Peers found without the server are connected to directly, since they aren't expecting a hole punch.
+    let direct_addresses = &direct_addresses;
+    for (peer_address, file_size, _) in peers.drain(..) {
+        connection_attempts.push(async move {
+            let connection = if direct_addresses.contains(&peer_address) {
+                core::connect_to_publisher(
+                    endpoint.clone(),
+                    peer_address,
+                    hash.bytes,
+                    options.passphrase,
+                )
+                .await
+            } else {
+                core::udp_holepunch(
+                    FileYeetCommandType::Sub,
+                    hash.bytes,
+                    options.passphrase,
+                    endpoint.clone(),
+                    peer_address,
+                )
+                .await
+            };
+            (connection, file_size, peer_address)
+        });
+    }
+
+    // Iterate through the connection attempts as they resolve and use the first successful connection.
+    // Remember the peers that couldn't be reached to ask for relays to them instead.
+    let mut relay_candidates = Vec::new();
+    let peer_connection = loop {
+        match connection_attempts.next().await {
+            Some((Some((c, b)), file_size, peer_address)) => {
+                if accept_offer(file_size, options, &output) {
+                    break Some((c, b, file_size, peer_address));
+                }
+
+                // Close the connection since this command can't have multiple connections to a peer.
+                c.close(GOODBYE_CODE, &[]);
+            }
+            Some((None, file_size, peer_address)) => {
+                // The server can't relay from peers it never introduced.
+                if options.relay && !direct_addresses.contains(&peer_address) {
+                    relay_candidates.push((peer_address, file_size));
+                }
+            }
+            None => break None,
+        }
+    };
+
+    // Fall back to relaying through the servers when no peer could be reached directly.
+    let relayed = peer_connection.is_none();
+    let peer_connection = match peer_connection {
+        None if options.relay && !introducers.is_empty() => {
+            relay_from_any_peer(
+                &introducers,
+                &mut bb,
+                hash.bytes,
+                relay_candidates,
+                options,
+                &output,
+            )
+            .await
+        }
+        p => p,
+    };
+
+    // Try to get a successful peer connection.
+    if let Some((peer_connection, mut peer_streams, file_size, peer_address)) = peer_connection {
+        json_output::emit(&json_output::Event::Connected {
+            direction: json_output::Direction::Download,
+            hash: hash.to_string(),
+            peer: peer_address,
+            relayed,
+        });
+
+        // Continue a partial file from an earlier attempt instead of starting over, when asked to.
+        let partial_path = core::partial_download_path(&download_path);
+        let resume_from = if options.resume {
+            core::partial_download_progress(&download_path)
+                .await
+                .ok()
+                .filter(|&len| len > 0 && len <= file_size)
+        } else {
+            None
+        };
+
+        let (byte_progress, progress_receiver) = tokio::sync::watch::channel(0.);
+        let result = match (&peer_connection, resume_from) {
+            // A resumed download continues from the first peer alone.
+            (_, Some(existing)) => {
+                drop(connection_attempts);
+                status!(
+                    "{} Resuming the download after the {} already in {}",
+                    local_now_fmt(),
+                    humanize_bytes(existing),
+                    partial_path.display()
+                );
+                emit_download_progress(hash, Some(peer_address), progress_receiver.clone());
+                show_download_progress(
+                    Box::pin(core::resume_download_from_peer(
+                        hash,
+                        &mut peer_streams,
+                        peer_address,
+                        file_size,
+                        &download_path,
+                        Some(byte_progress),
+                    )),
+                    file_size,
+                    &progress_receiver,
+                    None,
+                )
+                .await
+            }
+            // Start downloading from the first peer, and add every other peer that connects and offers the same file size.
+            (connection, None) if !connection_attempts.is_empty() || options.streams.get() > 1 => {
+                if connection_attempts.is_empty() {
+                    status!(
+                        "{} Downloading over {} streams at once",
+                        local_now_fmt(),
+                        options.streams
+                    );
+                } else {
+                    status!(
+                        "{} Downloading from {peer_address}, adding other peers as they connect",
+                        local_now_fmt()
+                    );
+                }
+                let (swarm, swarm_receiver) =
+                    tokio::sync::mpsc::channel(connection_attempts.len() + 1);
+                swarm
+                    .try_send((connection.clone(), peer_streams))
+                    .expect("The swarm channel has room for the first peer");
+                let join_swarm = async move {
+                    while let Some(attempt) = connection_attempts.next().await {
+                        match attempt {
+                            (Some((c, b)), size, address) if size == file_size => {
+                                status!("{} Adding {address} to the download", local_now_fmt());
+                                if let Err(tokio::sync::mpsc::error::SendError((c, _))) =
+                                    swarm.send((c, b)).await
+                                {
+                                    c.close(GOODBYE_CODE, &[]);
+                                }
+                            }
+                            (Some((c, _)), _, _) => c.close(GOODBYE_CODE, &[]),
+                            (None, _, _) => {}
+                        }
+                    }
+
+                    // Let the download know that no more peers are coming, then leave it to finish.
+                    drop(swarm);
+                    std::future::pending::<()>().await;
+                };
+
+                emit_download_progress(hash, None, progress_receiver.clone());
+                let (peer_bytes, peer_bytes_receiver) = tokio::sync::watch::channel(HashMap::new());
+                let download = show_download_progress(
+                    Box::pin(core::download_from_peers(
+                        hash,
+                        options.passphrase,
+                        swarm_receiver,
+                        file_size,
+                        &download_path,
+                        options.streams,
+                        Some(byte_progress),
+                        Some(peer_bytes),
+                    )),
+                    file_size,
+                    &progress_receiver,
+                    Some(&peer_bytes_receiver),
+                );
+                tokio::select! {
+                    r = download => r,
+                    () = join_swarm => unreachable!("Peers join the swarm until the download ends"),
+                }
+            }
+
+            // Try to download the requested file using the peer connection.
+            // Pin the future to avoid a stack overflow. <https://rust-lang.github.io/rust-clippy/master/index.html#large_futures>
+            _ => {
+                emit_download_progress(hash, Some(peer_address), progress_receiver.clone());
+                show_download_progress(
+                    Box::pin(core::download_from_peer(
+                        hash,
+                        &mut peer_streams,
+                        file_size,
+                        &download_path,
+                        &mut bb,
+                        Some(byte_progress),
+                    )),
+                    file_size,
+                    &progress_receiver,
+                    None,
+                )
+                .await
+            }
+        };
+        if let Err(e) = result {
+            anyhow::bail!("Failed to download from peer: {e}");
+        }
+        if let Some(cache) = cache {
+            cache.insert(&hash).await?;
+            cache.place(&hash, &output).await?;
+        }
+        status!(
+            "{} Download complete: {}",
+            local_now_fmt(),
+            output.display()
+        );
+        json_output::emit(&json_output::Event::Completed {
+            direction: json_output::Direction::Download,
+            hash: hash.to_string(),
+            path: output.clone(),
+            file_size,
+            peer: Some(peer_address),
+        });
+
+        run_hook(
+            event_hooks,
+            hooks::TransferEvent::DownloadComplete,
+            hooks::HookContext {
+                hash_hex: sha256_hex,
+                path: output.clone(),
+                file_size: Some(file_size),
+                peer: Some(peer_address.to_string()),
+                error: None,
+            },
+        )
+        .await;
+        peer_connection.close(GOODBYE_CODE, "Thanks for sharing".as_bytes());
+
+        // The download was verified against the hash, so it can be published again without hashing it.
+        Ok(PublishTarget {
+            path: output,
+            file_size,
+            hash,
+            chunk_hashes: Arc::default(),
+            remaining_downloads: None,
+        })
+    } else {
+        anyhow::bail!("Failed to connect to any available peers");
+    }
+}
+
+/// Look for publishers on the local network and the DHT, when enabled, to connect to without the server.
+async fn find_direct_publishers(
+    endpoint: &quinn::Endpoint,
+    hash: &HashBytes,
+    options: DownloadOptions<'_>,
+) -> Vec<(SocketAddr, u64)> {
+    let lan_lookup = async {
+        match options.lan {
+            Some(lan) => {
+                let using_ipv4 = endpoint.local_addr().is_ok_and(|a| a.is_ipv4());
+                lan.find_publishers(hash, using_ipv4, LAN_LOOKUP_DURATION)
+                    .await
+            }
+            None => Vec::new(),
+        }
+    };
+    let dht_lookup = async {
+        match options.dht {
+            Some(dht) => dht.find_providers(hash).await,
+            None => Vec::new(),
+        }
+    };
+    let (mut peers, dht_peers) = tokio::join!(lan_lookup, dht_lookup);
+    for peer in dht_peers {
+        if !peers.iter().any(|&(address, _)| address == peer.0) {
+            peers.push(peer);
+        }
+    }
+    peers
+}
+
+/// Emit progress events for a download while it runs, if JSON output is enabled.
+/// Downloads from several peers at once have no single peer to report.
+fn emit_download_progress(
+    hash: FileHash,
+    peer: Option<SocketAddr>,
+    progress_receiver: tokio::sync::watch::Receiver<f32>,
+) {
+    json_output::watch_progress(progress_receiver, move |progress| {
+        json_output::Event::Progress {
+            direction: json_output::Direction::Download,
+            hash: hash.to_string(),
+            peer,
+            progress,
+        }
+    });
+}
+
+/// Report who signed each publish, and drop the publishers not signed by a trusted key when any keys are trusted.
+fn retain_trusted_publishers(
+    peers: &mut Vec<(std::net::SocketAddr, u64, core::FileMetadata)>,
+    hash: &HashBytes,
+    trusted_publishers: &[String],
+) {
+    peers.retain(|(peer_address, file_size, metadata)| {
+        let publisher = metadata.verified_publisher(hash, *file_size);
+        if let Some(fingerprint) = &publisher {
+            status!(
+                "{} Publisher {peer_address} signed the file with the key {}",
+                local_now_fmt(),
+                identity::short_fingerprint(fingerprint)
+            );
+        }
+        if trusted_publishers.is_empty() {
+            return true;
+        }
+
+        let trusted = publisher.is_some_and(|fingerprint| {
+            trusted_publishers
+                .iter()
+                .any(|trusted| identity::fingerprint_matches(&fingerprint, trusted))
+        });
+        if !trusted {
+            status!(
+                "{} Ignoring publisher {peer_address}, which did not sign the file with a trusted key",
+                local_now_fmt()
+            );
+        }
+        trusted
+    });
+}
+
+/// Check an offer against the maximum download size and ask the user whether to accept it.
+fn accept_offer(file_size: u64, options: DownloadOptions<'_>, output: &Path) -> bool {
+    // Reject offers larger than the user is willing to accept without prompting.
+    if let Some(max) = options.max_download_size.filter(|&max| file_size > max) {
+        status!(
+            "{} Rejecting offer of size {} which exceeds the maximum of {}",
+            local_now_fmt(),
+            humanize_bytes(file_size),
+            humanize_bytes(max),
+        );
+        return false;
+    }
+    if !options.ask_consent {
+        return true;
+    }
+
+    let consent = file_consent_cli(file_size, output).expect("Failed to read user input");
+    if !consent {
+        status!("{} Download cancelled", local_now_fmt());
+    }
+    consent
+}
+
+/// Ask the server that introduced each peer to relay a download from it in turn, using the first relay that is accepted.
+/// The peer connection runs end-to-end through the relay, so the server only forwards ciphertext.
+async fn relay_from_any_peer(
+    introducers: &HashMap<std::net::SocketAddr, quinn::Connection>,
+    bb: &mut bytes::BytesMut,
+    hash: HashBytes,
+    peers: Vec<(std::net::SocketAddr, u64)>,
+    options: DownloadOptions<'_>,
+    output: &Path,
+) -> Option<(quinn::Connection, BiStream, u64, std::net::SocketAddr)> {
+    for (peer_address, file_size) in peers {
+        let Some(server_connection) = introducers.get(&peer_address) else {
+            continue;
+        };
+        status!(
+            "{} Asking the server to relay from {peer_address}...",
+            local_now_fmt()
+        );
+        match core::relay_request(
+            server_connection,
+            bb,
+            hash,
+            peer_address,
+            options.room,
+            options.passphrase,
+        )
+        .await
+        {
+            Ok(Some((peer_connection, peer_streams))) => {
+                if accept_offer(file_size, options, output) {
+                    return Some((peer_connection, peer_streams, file_size, peer_address));
+                }
+                peer_connection.close(GOODBYE_CODE, &[]);
+            }
+            Ok(None) => eprintln!("{} Peer did not accept the relay", local_now_fmt()),
+            Err(e) => {
+                eprintln!("{} Failed to request a relay: {e}", local_now_fmt());
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Handle the CLI command to download a published directory from its manifest hash.
+async fn subscribe_directory_command(
+    endpoint: &quinn::Endpoint,
+    server_connections: &[quinn::Connection],
+    sha256_hex: String,
+    output_path: Option<String>,
+    options: DownloadOptions<'_>,
+    cache: Option<&cache::ContentCache>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<()> {
+    let (_, output) = subscribe_target(&sha256_hex, output_path.as_deref())?;
+
+    // The manifest is small and its contents are confirmed below, so download it without asking.
+    let manifest_path =
+        std::env::temp_dir().join(format!("{sha256_hex}.{}", manifest::MANIFEST_EXTENSION));
+    subscribe_command(
+        endpoint,
+        server_connections,
+        SERVER_MESSAGE_BUFFERS.take(),
+        sha256_hex,
+        Some(manifest_path.to_string_lossy().into_owned()),
+        DownloadOptions {
+            max_download_size: Some(manifest::MAX_MANIFEST_SIZE),
+            ask_consent: false,
+            ..options
+        },
+        None,
+        &hooks::EventHooks::default(),
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to download the manifest: {e}"))?;
+    let manifest_bytes = tokio::fs::read(&manifest_path).await?;
+    if let Err(e) = tokio::fs::remove_file(&manifest_path).await {
+        eprintln!("{} Failed to remove the manifest: {e}", local_now_fmt());
+    }
+    let manifest = manifest::DirectoryManifest::from_bytes(&manifest_bytes)?;
+
+    // Check every path before writing anything, so a malicious manifest can't escape the output directory.
+    let destinations = manifest
+        .files
+        .iter()
+        .map(|entry| entry.destination(&output))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Ask once for the whole directory instead of for every file.
+    let total_size = manifest.total_size();
+    if let Some(max) = options.max_download_size.filter(|&max| total_size > max) {
+        anyhow::bail!(
+            "The directory's size of {} exceeds the maximum of {}",
+            humanize_bytes(total_size),
+            humanize_bytes(max),
+        );
+    }
+    if options.ask_consent && !file_consent_cli(total_size, &output)? {
+        status!("{} Download cancelled", local_now_fmt());
+        return Ok(());
+    }
+
+    // A file that fails doesn't stop the others, and the table printed at the end shows which failed.
+    let mut rows = Vec::with_capacity(manifest.files.len());
+    for (entry, destination) in manifest.files.iter().zip(destinations) {
+        let state = match subscribe_directory_entry(
+            endpoint,
+            server_connections,
+            entry,
+            &destination,
+            options,
+            cache,
+            event_hooks,
+        )
+        .await
+        {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("{} Failed to download {}: {e}", local_now_fmt(), entry.path);
+                json_output::emit(&json_output::Event::Error {
+                    message: format!("Failed to download {}: {e}", entry.path),
+                });
+                rows.push(table::FileRow {
+                    file_size: Some(entry.size),
+                    hash: Some(entry.hash_hex.clone()),
+                    ..table::FileRow::failed(entry.path.clone(), e.to_string())
+                });
+                continue;
+            }
+        };
+        rows.push(table::FileRow {
+            path: entry.path.clone(),
+            file_size: Some(entry.size),
+            hash: Some(entry.hash_hex.clone()),
+            state,
+            error: None,
+        });
+    }
+
+    let failed = table::print_summary(&rows);
+    if failed > 0 {
+        anyhow::bail!(
+            "{failed} of {} files could not be downloaded",
+            manifest.files.len()
+        );
+    }
+    status!(
+        "{} Downloaded {} files to {}",
+        local_now_fmt(),
+        manifest.files.len(),
+        output.display()
+    );
+    Ok(())
+}
 
-                // Close the connection since this command can't have multiple connections to a peer.
-                c.close(GOODBYE_CODE, &[]);
-            }
-            Some((None, _)) => continue,
-            None => break None,
-        }
-    };
+/// Download one file listed in a directory manifest to its destination, from the cache if it is there.
+async fn subscribe_directory_entry(
+    endpoint: &quinn::Endpoint,
+    server_connections: &[quinn::Connection],
+    entry: &manifest::ManifestEntry,
+    destination: &Path,
+    options: DownloadOptions<'_>,
+    cache: Option<&cache::ContentCache>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<table::FileState> {
+    if let Some(parent) = destination.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
 
-    // Try to get a successful peer connection.
-    if let Some((peer_connection, mut peer_streams, file_size)) = peer_connection {
-        // Try to download the requested file using the peer connection.
-        // Pin the future to avoid a stack overflow. <https://rust-lang.github.io/rust-clippy/master/index.html#large_futures>
-        if let Err(e) = Box::pin(core::download_from_peer(
-            hash,
-            &mut peer_streams,
-            file_size,
-            &output,
-            &mut bb,
-            None,
-        ))
-        .await
+    // Empty files have nothing to download.
+    if entry.size == 0 {
+        tokio::fs::write(destination, []).await?;
+        return Ok(table::FileState::Empty);
+    }
+
+    let destination_str = destination.to_string_lossy().into_owned();
+    if let Some(cache) = cache {
+        if cached_download_command(cache, &entry.hash_hex, Some(&destination_str), event_hooks)
+            .await?
         {
-            anyhow::bail!("Failed to download from peer: {e}");
+            return Ok(table::FileState::Cached);
         }
+    }
 
-        peer_connection.close(GOODBYE_CODE, "Thanks for sharing".as_bytes());
-    } else {
-        anyhow::bail!("Failed to connect to any available peers");
+    status!(
+        "{} Downloading {} ({})",
+        local_now_fmt(),
+        entry.path,
+        humanize_bytes(entry.size)
+    );
+    subscribe_command(
+        endpoint,
+        server_connections,
+        SERVER_MESSAGE_BUFFERS.take(),
+        entry.hash_hex.clone(),
+        Some(destination_str),
+        DownloadOptions {
+            max_download_size: None,
+            ask_consent: false,
+            ..options
+        },
+        cache,
+        event_hooks,
+    )
+    .await?;
+    Ok(table::FileState::Downloaded)
+}
+
+/// Parse the hash to subscribe to and determine the output file path to use.
+fn subscribe_target(
+    sha256_hex: &str,
+    output_path: Option<&str>,
+) -> anyhow::Result<(FileHash, std::path::PathBuf)> {
+    let hash: FileHash = sha256_hex
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Failed to parse hex hash: {e}"))?;
+
+    let output = output_path.filter(|s| !s.is_empty()).map_or_else(
+        || std::env::temp_dir().join(hash.to_string()),
+        std::path::PathBuf::from,
+    );
+    Ok((hash, output))
+}
+
+/// Place a file from the cache at the output path if it is cached.
+/// Returns whether the file was found in the cache.
+async fn cached_download_command(
+    cache: &cache::ContentCache,
+    sha256_hex: &str,
+    output_path: Option<&str>,
+    event_hooks: &hooks::EventHooks,
+) -> anyhow::Result<bool> {
+    let (hash, output) = subscribe_target(sha256_hex, output_path)?;
+    let Some(file_size) = cache.lookup(&hash).await else {
+        return Ok(false);
     };
 
-    Ok(())
+    cache.place(&hash, &output).await?;
+    status!(
+        "{} Found {} in the cache: {}",
+        local_now_fmt(),
+        humanize_bytes(file_size),
+        output.display()
+    );
+    json_output::emit(&json_output::Event::Completed {
+        direction: json_output::Direction::Download,
+        hash: hash.to_string(),
+        path: output.clone(),
+        file_size,
+        peer: None,
+    });
+
+    run_hook(
+        event_hooks,
+        hooks::TransferEvent::DownloadComplete,
+        hooks::HookContext {
+            hash_hex: sha256_hex.to_owned(),
+            path: output,
+            file_size: Some(file_size),
+            peer: None,
+            error: None,
+        },
+    )
+    .await;
+    Ok(true)
 }
 
 /// Enter a loop to listen for the server to send peer socket addresses requesting our publish.
+/// `downloaded` is cancelled once enough peers have downloaded the whole file, through this server or any other.
+/// The publish is brought up to date whenever `address_changes` reports a new external address.
 async fn publish_loop(
-    endpoint: &quinn::Endpoint,
-    server_connection: &quinn::Connection,
-    bb: bytes::BytesMut,
-    hash: HashBytes,
-    file_size: u64,
-    file_path: &Path,
+    prepared_connection: &PreparedConnection,
+    target: &PublishTarget,
+    options: PublishOptions<'_>,
+    event_hooks: &hooks::EventHooks,
     cancellation_token: CancellationToken,
-) -> anyhow::Result<()> {
+    downloaded: CancellationToken,
+    mut address_changes: tokio::sync::watch::Receiver<String>,
+) -> anyhow::Result<PublishEnd> {
+    let core::PreparedConnection {
+        endpoint,
+        server_connection,
+        port_override,
+        ..
+    } = prepared_connection;
+    let PublishTarget {
+        path: file_path,
+        file_size,
+        hash: file_hash,
+        ..
+    } = target;
+    let (file_size, file_hash) = (*file_size, *file_hash);
+
     // Create a bi-directional stream to the server.
-    let mut server_streams: BiStream =
-        crate::core::publish(server_connection, bb, hash, file_size).await?;
+    let mut metadata = core::FileMetadata::from_path(file_path);
+    if let Some(key) = options.signing_key {
+        metadata = metadata.signed(key, &file_hash.bytes, file_size);
+    }
+    let mut server_streams: BiStream = crate::core::publish(
+        server_connection,
+        SERVER_MESSAGE_BUFFERS.take(),
+        file_hash,
+        file_size,
+        options.room,
+        &metadata,
+    )
+    .await?;
+    json_output::emit(&json_output::Event::Published {
+        path: file_path.clone(),
+        hash: file_hash.to_string(),
+        file_size,
+    });
+
+    // What each task serving a subscriber needs, whether the subscriber came through the server or the local network.
+    let upload = SubscriberUpload::new(
+        target,
+        options,
+        event_hooks,
+        cancellation_token,
+        downloaded.clone(),
+    );
+
+    // Also accept subscribers who find the file on the local network, until the publish ends.
+    let using_ipv4 = endpoint.local_addr()?.is_ipv4();
+    let _lan_publish = options
+        .lan
+        .and_then(|lan| serve_lan_subscribers(lan, &upload, using_ipv4));
 
     // Enter a loop to listen for the server to send peer connections.
     loop {
-        println!(
+        status!(
             "{} Waiting for the server to introduce a peer...",
             local_now_fmt()
         );
 
-        // Await the server to send a peer connection.
-        let Ok(peer_address) = crate::core::read_subscribing_peer(&mut server_streams.recv).await
-        else {
+        // Await the server to send a peer connection, or withdraw the publish once it reaches its download limit.
+        let subscriber = tokio::select! {
+            () = downloaded.cancelled() => {
+                if let Err(e) = server_streams.send.write_u8(PublishControl::Cancel as u8).await {
+                    eprintln!("{} Failed to cancel the publish: {e}", local_now_fmt());
+                }
+                status!(
+                    "{} {} reached its download limit, so it is no longer published",
+                    local_now_fmt(),
+                    file_path.display()
+                );
+                return Ok(PublishEnd::Downloaded);
+            }
+
+            // Redo the port override or the publish, so that subscribers are introduced to where we are now.
+            Ok(()) = address_changes.changed() => {
+                let address = address_changes.borrow_and_update().clone();
+                status!(
+                    "{} Updating the publish of {} for our new address {address}",
+                    local_now_fmt(),
+                    file_path.display()
+                );
+                if let Err(e) = core::refresh_publish(
+                    server_connection,
+                    &mut server_streams,
+                    *port_override,
+                    file_hash,
+                    file_size,
+                    options.room,
+                    &metadata,
+                )
+                .await
+                {
+                    eprintln!("{} Failed to update the publish: {e}", local_now_fmt());
+                }
+                continue;
+            }
+
+            r = crate::core::read_subscribing_peer(&mut server_streams) => r,
+        };
+        let Ok((subscriber, span)) = subscriber else {
             eprintln!("{} Failed to read the server's response", local_now_fmt());
             break;
         };
+        let (peer_address, connection) = match subscriber {
+            core::SubscribingPeer::Direct(address) => {
+                (address, SubscriberConnection::HolePunch(endpoint.clone()))
+            }
+            core::SubscribingPeer::Relay { token, address } => {
+                status!(
+                    "{} Relaying through the server to {address}",
+                    local_now_fmt()
+                );
+                (
+                    address,
+                    SubscriberConnection::Relay(server_connection.clone(), token),
+                )
+            }
+        };
+        tokio::task::spawn(
+            serve_subscriber(upload.clone(), peer_address, connection).instrument(span),
+        );
+    }
 
-        let cancellation_token = cancellation_token.clone();
-        let endpoint = endpoint.clone();
-        let file_path = file_path.to_path_buf();
-        tokio::task::spawn(async move {
-            tokio::select! {
-                // Ensure the publish tasks are cancellable.
-                () = cancellation_token.cancelled() => {}
-
-                // Try to connect to the peer and upload the file.
-                () = async move {
-                    // Attempt to connect to the peer using UDP hole punching.
-                    let Some((_peer_connection, mut peer_streams)) = core::udp_holepunch(
-                        FileYeetCommandType::Pub,
-                        hash,
-                        endpoint,
-                        peer_address,
-                    )
-                    .await
-                    else {
-                        eprintln!("{} Failed to connect to peer", local_now_fmt());
-                        return;
-                    };
+    status!("{} Server connection closed", local_now_fmt());
+    Ok(PublishEnd::ServerClosed)
+}
 
-                    let file = match tokio::fs::File::open(file_path).await {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("{} Failed to open the file: {e}", local_now_fmt());
-                            return;
-                        }
-                    };
+/// What a task serving a subscriber needs to know about the publish.
+#[derive(Clone)]
+struct SubscriberUpload {
+    cancellation_token: CancellationToken,
+    downloaded: CancellationToken,
+    file_path: std::path::PathBuf,
+    file_size: u64,
+    file_hash: FileHash,
+    chunk_hashes: Arc<Vec<HashBytes>>,
+    remaining_downloads: Option<Arc<AtomicUsize>>,
+    log_path: Option<std::path::PathBuf>,
+    event_hooks: hooks::EventHooks,
+    passphrase: Option<String>,
+}
+impl SubscriberUpload {
+    fn new(
+        target: &PublishTarget,
+        options: PublishOptions<'_>,
+        event_hooks: &hooks::EventHooks,
+        cancellation_token: CancellationToken,
+        downloaded: CancellationToken,
+    ) -> Self {
+        Self {
+            cancellation_token,
+            downloaded,
+            file_path: target.path.clone(),
+            file_size: target.file_size,
+            file_hash: target.hash,
+            chunk_hashes: target.chunk_hashes.clone(),
+            remaining_downloads: target.remaining_downloads.clone(),
+            log_path: options.upload_log.map(Path::to_path_buf),
+            event_hooks: event_hooks.clone(),
+            passphrase: options.passphrase.map(str::to_owned),
+        }
+    }
+}
+
+/// How a subscriber is to be connected to.
+enum SubscriberConnection {
+    /// Hole punch from this endpoint to the address the server introduced.
+    HolePunch(quinn::Endpoint),
 
-                    // Prepare a reader for the file to upload.
-                    let reader = tokio::io::BufReader::new(file);
+    /// Accept the relay the server offered with this token.
+    Relay(quinn::Connection, u64),
 
-                    // Try to upload the file to the peer connection.
-                    if let Err(e) = Box::pin(core::upload_to_peer(&mut peer_streams, file_size, reader, None)).await {
-                        eprintln!("{} Failed to upload to peer: {e}", local_now_fmt());
+    /// The subscriber already connected to us directly, such as from the local network.
+    Direct(quinn::Connection),
+}
+
+/// Connect to a subscriber and serve it every range of the file it requests.
+async fn serve_subscriber(
+    upload: SubscriberUpload,
+    peer_address: SocketAddr,
+    connection: SubscriberConnection,
+) {
+    let SubscriberUpload {
+        cancellation_token,
+        downloaded,
+        file_path,
+        file_size,
+        file_hash,
+        chunk_hashes,
+        remaining_downloads,
+        log_path,
+        event_hooks,
+        passphrase,
+    } = upload;
+    let hash = file_hash.bytes;
+
+    // Attempt to connect to the peer using UDP hole punching, accept the relay the server offered,
+    // or finish connecting to a peer that reached us directly.
+    let start = std::time::Instant::now();
+    let relayed = matches!(connection, SubscriberConnection::Relay(..));
+    let connected = tokio::select! {
+        // Ensure the publish tasks are cancellable, and stop connecting to peers once the download limit is reached.
+        () = cancellation_token.cancelled() => return,
+        () = downloaded.cancelled() => return,
+        c = async {
+            match connection {
+                SubscriberConnection::Relay(server_connection, token) => match core::relay_accept(&server_connection, token, peer_address, hash, passphrase.as_deref()).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("{} Failed to accept the relay: {e}", local_now_fmt());
+                        None
                     }
-                } => {}
+                },
+                SubscriberConnection::HolePunch(endpoint) => {
+                    core::udp_holepunch(FileYeetCommandType::Pub, hash, passphrase.as_deref(), endpoint, peer_address).await
+                }
+                SubscriberConnection::Direct(c) => {
+                    core::peer_connection_into_stream(&c, hash, passphrase.as_deref(), FileYeetCommandType::Pub)
+                        .await
+                        .map(|s| (c, s))
+                }
             }
+        } => c,
+    };
+    let Some((peer_connection, peer_streams)) = connected else {
+        eprintln!("{} Failed to connect to peer", local_now_fmt());
+        let record = upload_log::UploadRecord::new(
+            &hash,
+            peer_address,
+            core::UploadStats::default(),
+            start.elapsed(),
+            upload_log::UploadOutcome::ConnectFailed,
+            None,
+        );
+        log_upload(log_path.as_deref(), &record).await;
+        return;
+    };
+    json_output::emit(&json_output::Event::Connected {
+        direction: json_output::Direction::Upload,
+        hash: file_hash.to_string(),
+        peer: peer_address,
+        relayed,
+    });
+
+    // Serve the range requested on the first stream, then any further ranges requested by
+    // a subscriber downloading from several peers at once, until they close the connection.
+    let mut next_streams = Some(peer_streams);
+    let mut bytes_served = 0;
+    loop {
+        let peer_streams = match next_streams.take() {
+            Some(s) => Some(s),
+            None => tokio::select! {
+                () = cancellation_token.cancelled() => None,
+                () = downloaded.cancelled() => None,
+                s = core::peer_connection_into_stream(&peer_connection, hash, passphrase.as_deref(), FileYeetCommandType::Pub) => s,
+            },
+        };
+        let Some(peer_streams) = peer_streams else {
+            break;
+        };
+
+        let start = std::time::Instant::now();
+        let mut stats = core::UploadStats::default();
+        let byte_progress =
+            json_output::progress_events(move |progress| json_output::Event::Progress {
+                direction: json_output::Direction::Upload,
+                hash: file_hash.to_string(),
+                peer: Some(peer_address),
+                progress,
+            });
+        let (outcome, error) = tokio::select! {
+            () = cancellation_token.cancelled() => (upload_log::UploadOutcome::Cancelled, None),
+            r = upload_to_subscriber(peer_streams, file_size, &chunk_hashes, &file_path, byte_progress, &mut stats) => r,
+        };
+
+        // Record the attempt for later debugging, if the user asked for a log.
+        let record = upload_log::UploadRecord::new(
+            &hash,
+            peer_address,
+            stats,
+            start.elapsed(),
+            outcome,
+            error,
+        );
+        log_upload(log_path.as_deref(), &record).await;
+
+        if !matches!(outcome, upload_log::UploadOutcome::Success) {
+            break;
+        }
+        run_hook(
+            &event_hooks,
+            hooks::TransferEvent::UploadComplete,
+            hooks::HookContext {
+                hash_hex: file_hash.to_string(),
+                path: file_path.clone(),
+                file_size: Some(file_size),
+                peer: Some(peer_address.to_string()),
+                error: None,
+            },
+        )
+        .await;
+
+        // A subscriber may fetch the file over several streams, so count everything sent on this connection.
+        bytes_served += stats.bytes_sent;
+        if bytes_served < file_size {
+            continue;
+        }
+        bytes_served = 0;
+        json_output::emit(&json_output::Event::Completed {
+            direction: json_output::Direction::Upload,
+            hash: file_hash.to_string(),
+            path: file_path.clone(),
+            file_size,
+            peer: Some(peer_address),
         });
+        if let Some(remaining) = &remaining_downloads {
+            let Ok(left) =
+                remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            else {
+                break;
+            };
+            if left <= 1 {
+                downloaded.cancel();
+                break;
+            }
+            status!(
+                "{} {} has {} downloads left",
+                local_now_fmt(),
+                file_path.display(),
+                left - 1
+            );
+        }
+    }
+}
+
+/// Announce a publish on the local network and serve the subscribers who connect because of it.
+/// Returns the announcement and a guard that stops accepting subscribers, both to be held until the publish ends.
+fn serve_lan_subscribers(
+    lan: &core::lan::LanDiscovery,
+    upload: &SubscriberUpload,
+    using_ipv4: bool,
+) -> Option<(core::lan::LanAnnouncement, tokio_util::sync::DropGuard)> {
+    let announce = || -> anyhow::Result<_> {
+        let lan_endpoint = core::bind_peer_endpoint(using_ipv4, None)?;
+        let announcement = lan.announce(
+            &upload.file_hash.bytes,
+            upload.file_size,
+            lan_endpoint.local_addr()?.port(),
+        )?;
+        Ok((lan_endpoint, announcement))
+    };
+    let (lan_endpoint, announcement) = match announce() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!(
+                "{} Failed to publish on the local network: {e}",
+                local_now_fmt()
+            );
+            return None;
+        }
+    };
+    status!(
+        "{} Announcing {} on the local network",
+        local_now_fmt(),
+        upload.file_path.display()
+    );
+    Some((
+        announcement,
+        serve_direct_subscribers(lan_endpoint, upload.clone()),
+    ))
+}
+
+/// Serve every subscriber that connects to an endpoint from `core::bind_peer_endpoint`.
+/// Returns a guard that stops accepting subscribers when dropped.
+fn serve_direct_subscribers(
+    endpoint: quinn::Endpoint,
+    upload: SubscriberUpload,
+) -> tokio_util::sync::DropGuard {
+    let stop = CancellationToken::new();
+    let stop_accepting = stop.clone();
+    tokio::task::spawn(async move {
+        loop {
+            let connection = tokio::select! {
+                () = stop_accepting.cancelled() => break,
+                c = core::accept_direct_peer(&endpoint) => c,
+            };
+            let Some(connection) = connection else {
+                break;
+            };
+            tokio::task::spawn(serve_subscriber(
+                upload.clone(),
+                connection.remote_address(),
+                SubscriberConnection::Direct(connection),
+            ));
+        }
+    });
+    stop.drop_guard()
+}
+
+/// Upload the range of the file a subscribing peer requests, returning how the attempt ended.
+async fn upload_to_subscriber(
+    mut peer_streams: BiStream,
+    file_size: u64,
+    chunk_hashes: &[HashBytes],
+    file_path: &Path,
+    byte_progress: Option<tokio::sync::watch::Sender<f32>>,
+    stats: &mut core::UploadStats,
+) -> (upload_log::UploadOutcome, Option<String>) {
+    let file = match tokio::fs::File::open(file_path).await {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{} Failed to open the file: {e}", local_now_fmt());
+            return (upload_log::UploadOutcome::Failed, Some(e.to_string()));
+        }
+    };
+
+    // Prepare a reader for the file to upload.
+    let reader = tokio::io::BufReader::new(file);
+
+    // Try to upload the file to the peer connection.
+    if let Err(e) = Box::pin(core::upload_to_peer(
+        &mut peer_streams,
+        file_size,
+        chunk_hashes,
+        reader,
+        byte_progress,
+        Some(stats),
+    ))
+    .await
+    {
+        eprintln!("{} Failed to upload to peer: {e}", local_now_fmt());
+        return (upload_log::UploadOutcome::Failed, Some(e.to_string()));
+    }
+
+    (upload_log::UploadOutcome::Success, None)
+}
+
+/// Append a record to the upload log if the user asked for one.
+async fn log_upload(log_path: Option<&Path>, record: &upload_log::UploadRecord) {
+    if let Some(log_path) = log_path {
+        if let Err(e) = upload_log::append(log_path, record).await {
+            eprintln!("{} Failed to write to the upload log: {e}", local_now_fmt());
+        }
+    }
+}
+
+/// Run the hook configured for an event, if any, and report any failure.
+async fn run_hook(
+    event_hooks: &hooks::EventHooks,
+    event: hooks::TransferEvent,
+    context: hooks::HookContext,
+) {
+    if let Some(hook) = hooks::run(event_hooks, event, context) {
+        if let Err(e) = hook.await {
+            eprintln!("{} {e}", local_now_fmt());
+        }
+    }
+}
+
+/// Handle the CLI command to update the client to the latest release.
+async fn self_update_command() -> anyhow::Result<()> {
+    // Don't offer an update that can't be verified.
+    update::release_public_key()?;
+    let release = update::latest_release().await?;
+    if !release.is_newer() {
+        println!(
+            "{} Already up to date with version {}",
+            local_now_fmt(),
+            update::CURRENT_VERSION
+        );
+        return Ok(());
+    }
+
+    // Never update without explicit consent.
+    print!(
+        "{} Update from version {} to {}? <y/N>: ",
+        local_now_fmt(),
+        update::CURRENT_VERSION,
+        release.tag_name
+    );
+    std::io::stdout().flush()?;
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    if !(input.trim_start().starts_with('y') || input.trim_start().starts_with('Y')) {
+        println!("{} Update cancelled", local_now_fmt());
+        return Ok(());
+    }
+
+    update::install_release(&release).await
+}
+
+/// Print the connection details peers would use and test them with the server's echo peer.
+async fn diagnose_command(prepared_connection: &PreparedConnection) {
+    println!(
+        "{} Peers are introduced to this client as {}",
+        local_now_fmt(),
+        prepared_connection.external_address
+    );
+    if let Some(mapping) = &prepared_connection.port_mapping {
+        println!(
+            "{} Using a port mapping from external port {} to internal port {}",
+            local_now_fmt(),
+            mapping.external_port(),
+            mapping.internal_port()
+        );
+    } else if let Some(port) = prepared_connection.port_override {
+        println!("{} Using the port override {port}", local_now_fmt());
+    } else {
+        println!(
+            "{} No port forwarding or mapping is configured",
+            local_now_fmt()
+        );
+    }
+
+    match core::test_introduction(
+        &prepared_connection.server_connection,
+        prepared_connection.endpoint.clone(),
+    )
+    .await
+    {
+        Ok(round_trip) => println!(
+            "{} Test introduction succeeded with a round trip of {round_trip:?}",
+            local_now_fmt()
+        ),
+        Err(e) => eprintln!("{} Test introduction failed: {e}", local_now_fmt()),
+    }
+}
+
+/// Hash a file and print the `hash[:ext]` subscribers download it with, and a share link if asked for one.
+/// Only the identifiers are written to standard output, so they can be used in scripts.
+async fn hash_command(
+    args: &Cli,
+    path: &Path,
+    hash_algorithm: HashAlgorithm,
+    link: bool,
+) -> anyhow::Result<()> {
+    // Check that a share link can be made before spending time hashing.
+    let server_address = if link {
+        Some(
+            args.server_address
+                .as_deref()
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("A share link needs a server given with `--server-address`")
+                })?,
+        )
+    } else {
+        None
+    };
+
+    let (file_size, hash) = hash_with_progress_bar("Hashing", path, hash_algorithm).await?;
+    let hash = FileHash::new(hash_algorithm, hash);
+    eprintln!(
+        "{} File {} has {hash_algorithm} hash {hash} and size {} bytes",
+        local_now_fmt(),
+        path.display(),
+        humanize_bytes(file_size),
+    );
+
+    let extension = file_yeet_shared::share_extension(path);
+    match extension {
+        Some(extension) => println!("{hash}:{extension}"),
+        None => println!("{hash}"),
+    }
+    if let Some(server_address) = server_address {
+        println!(
+            "{}",
+            file_yeet_shared::format_share_uri(server_address, args.server_port, &hash, extension)
+        );
+    }
+    Ok(())
+}
+
+/// Hash a file and report whether it matches the given `hash[:ext]`. The extension hint is ignored.
+async fn verify_command(expected: &str, path: &Path) -> anyhow::Result<bool> {
+    let expected_hex = expected
+        .trim()
+        .split_once(':')
+        .map_or(expected.trim(), |(hash, _)| hash);
+    let expected = expected_hex
+        .parse::<FileHash>()
+        .map_err(|e| anyhow::anyhow!("Invalid hash: {e}"))?;
+
+    let (_, hash) = hash_with_progress_bar("Verifying", path, expected.algorithm).await?;
+    let actual = FileHash::new(expected.algorithm, hash);
+    if actual == expected {
+        println!("{} {} matches {expected}", local_now_fmt(), path.display());
+        Ok(true)
+    } else {
+        println!(
+            "{} {} does not match {expected}, its hash is {actual}",
+            local_now_fmt(),
+            path.display()
+        );
+        Ok(false)
+    }
+}
+
+/// Hash a file while drawing a progress bar on standard error.
+async fn hash_with_progress_bar(
+    label: &str,
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<(u64, HashBytes)> {
+    let (progress, progress_receiver) = tokio::sync::watch::channel(0.);
+    show_progress(
+        label,
+        core::file_size_and_hash(path, algorithm, Some(progress)),
+        &progress_receiver,
+    )
+    .await
+}
+
+/// Run a task while drawing a progress bar for it on standard error, when standard error is a terminal.
+async fn show_progress<T>(
+    label: &str,
+    task: impl std::future::Future<Output = T>,
+    progress: &tokio::sync::watch::Receiver<f32>,
+) -> T {
+    let Some(mut bars) = progress::MultiProgress::new() else {
+        return task.await;
+    };
+    let bar = bars.add(label);
+    let mut redraw = tokio::time::interval(progress::REDRAW_INTERVAL);
+    tokio::pin!(task);
+    loop {
+        let result = tokio::select! {
+            r = &mut task => Some(r),
+            _ = redraw.tick() => None,
+        };
+        bars.bar(bar).set_progress(*progress.borrow());
+        bars.draw();
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Run a download while drawing its progress on standard error, when standard error is a terminal.
+/// Downloads from several peers also show how much each peer has sent and how fast.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+async fn show_download_progress<T>(
+    download: impl std::future::Future<Output = T>,
+    file_size: u64,
+    progress: &tokio::sync::watch::Receiver<f32>,
+    peer_bytes: Option<&tokio::sync::watch::Receiver<HashMap<SocketAddr, u64>>>,
+) -> T {
+    let Some(mut bars) = progress::MultiProgress::new() else {
+        return download.await;
+    };
+    let total = bars.add("Downloading");
+    let mut peer_bars = HashMap::new();
+    let mut redraw = tokio::time::interval(progress::REDRAW_INTERVAL);
+    tokio::pin!(download);
+    loop {
+        let result = tokio::select! {
+            r = &mut download => Some(r),
+            _ = redraw.tick() => None,
+        };
+
+        let fraction = *progress.borrow();
+        let bar = bars.bar(total);
+        bar.set_progress(fraction);
+        bar.set_transferred((f64::from(fraction) * file_size as f64) as u64);
+        if let Some(peer_bytes) = peer_bytes {
+            for (peer, bytes) in peer_bytes.borrow().iter() {
+                let index = *peer_bars
+                    .entry(*peer)
+                    .or_insert_with(|| bars.add(format!("  {peer}")));
+                bars.bar(index).set_transferred(*bytes);
+            }
+        }
+        bars.draw();
+
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Re-hash every file in a directory tree and report any that don't match their expected hash.
+async fn verify_dir_command(directory: &Path, manifest: Option<&str>) -> anyhow::Result<()> {
+    let expected = if let Some(manifest) = manifest {
+        verify::read_manifest(Path::new(manifest), directory).await?
+    } else {
+        verify::find_sidecars(directory).await?
+    };
+    if expected.is_empty() {
+        anyhow::bail!("No expected hashes were found for {}", directory.display());
+    }
+
+    println!(
+        "{} Verifying {} files in {}...",
+        local_now_fmt(),
+        expected.len(),
+        directory.display()
+    );
+    let summary = verify::verify_files(expected).await;
+
+    for path in &summary.mismatched {
+        eprintln!("{} Hash mismatch: {}", local_now_fmt(), path.display());
     }
+    for (path, e) in &summary.failed {
+        eprintln!("{} Failed to hash {}: {e}", local_now_fmt(), path.display());
+    }
+    println!(
+        "{} Verified {}, mismatched {}, failed {}",
+        local_now_fmt(),
+        summary.verified,
+        summary.mismatched.len(),
+        summary.failed.len()
+    );
 
-    Ok(println!("{} Server connection closed", local_now_fmt()))
+    if summary.is_ok() {
+        Ok(())
+    } else {
+        anyhow::bail!("Some files did not match their expected hash")
+    }
 }
 
 /// Prompt the user for consent to download a file.
@@ -372,21 +3203,28 @@ fn file_consent_cli(file_size: u64, output: &Path) -> Result<bool, std::io::Erro
     let file_size = humanize_bytes(file_size);
 
     // Ensure the user consents to downloading the file.
-    if output.exists() {
-        print!(
+    let prompt = if output.exists() {
+        format!(
             "{} Download file of size {file_size} and overwrite {}? <y/N>: ",
             local_now_fmt(),
             output.display()
-        );
+        )
     } else {
-        print!(
+        format!(
             "{} Download file of size {file_size} to {}? <y/N>: ",
             local_now_fmt(),
             output.display()
-        );
+        )
+    };
+
+    // Ensure the prompt is printed before reading from stdin. Keep stdout free for JSON lines.
+    if json_output::enabled() {
+        eprint!("{prompt}");
+        std::io::stderr().flush()?;
+    } else {
+        print!("{prompt}");
+        std::io::stdout().flush()?;
     }
-    // Ensure the prompt is printed before reading from stdin.
-    std::io::stdout().flush()?;
 
     let mut input = String::new();
     std::io::stdin().read_line(&mut input)?;
@@ -0,0 +1,150 @@
+use std::path::{Component, Path, PathBuf};
+
+use file_yeet_shared::{HashAlgorithm, HashBytes, HASH_BYTE_COUNT};
+use futures_util::StreamExt as _;
+use sha2::Digest as _;
+
+/// The version of the manifest format written by this client.
+const MANIFEST_VERSION: u32 = 1;
+
+/// The extension of saved manifest files.
+pub const MANIFEST_EXTENSION: &str = "fyeet-manifest.json";
+
+/// The largest manifest a subscriber will download before knowing what it lists.
+pub const MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A file in a published directory.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ManifestEntry {
+    /// The path of the file relative to the directory, separated by `/` on every platform.
+    pub path: String,
+    pub size: u64,
+    pub hash_hex: String,
+}
+impl ManifestEntry {
+    /// The hash of the file.
+    pub fn hash(&self) -> anyhow::Result<HashBytes> {
+        let mut hash = HashBytes::default();
+        if self.hash_hex.len() != 2 * HASH_BYTE_COUNT
+            || faster_hex::hex_decode(self.hash_hex.as_bytes(), &mut hash).is_err()
+        {
+            anyhow::bail!("Invalid hash for {} in the manifest", self.path);
+        }
+        Ok(hash)
+    }
+
+    /// Where to recreate the file under the output directory.
+    /// Rejects paths that could escape the directory, such as absolute paths or `..` components.
+    pub fn destination(&self, root: &Path) -> anyhow::Result<PathBuf> {
+        let relative = Path::new(&self.path);
+        if self.path.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("Unsafe path in the manifest: {}", self.path);
+        }
+        Ok(root.join(relative))
+    }
+}
+
+/// A list of every file in a published directory, published under its own hash.
+/// Subscribers download the manifest first and then each file it lists.
+/// Manifests and their files are always hashed with SHA-256.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DirectoryManifest {
+    pub version: u32,
+    pub files: Vec<ManifestEntry>,
+}
+impl DirectoryManifest {
+    /// Hash every file in a directory tree in parallel, reusing the cached hashes of unchanged files unless `rehash` is set.
+    /// Returns the manifest along with the path of each file, in the same order as the manifest entries.
+    pub async fn build(directory: &Path, rehash: bool) -> anyhow::Result<(Self, Vec<PathBuf>)> {
+        let mut paths = Vec::new();
+        let mut directories = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory).await.map_err(|e| {
+                anyhow::anyhow!("Failed to read the directory {}: {e}", directory.display())
+            })?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    directories.push(entry.path());
+                } else if file_type.is_file() {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        paths.sort();
+
+        let parallelism =
+            std::thread::available_parallelism().map_or(4, std::num::NonZeroUsize::get);
+        let hashed: Vec<_> = futures_util::stream::iter(paths.iter())
+            .map(|path| async move {
+                crate::core::cached_file_size_and_hash(path, HashAlgorithm::Sha256, None, rehash)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to hash {}: {e}", path.display()))
+            })
+            .buffered(parallelism)
+            .collect()
+            .await;
+
+        let mut files = Vec::with_capacity(paths.len());
+        for (path, result) in paths.iter().zip(hashed) {
+            let (size, hash) = result?;
+            files.push(ManifestEntry {
+                path: relative_manifest_path(directory, path)?,
+                size,
+                hash_hex: faster_hex::hex_string(&hash),
+            });
+        }
+
+        Ok((
+            Self {
+                version: MANIFEST_VERSION,
+                files,
+            },
+            paths,
+        ))
+    }
+
+    /// Serialize the manifest and get the hash it is published under.
+    pub fn to_bytes(&self) -> anyhow::Result<(Vec<u8>, HashBytes)> {
+        let bytes = serde_json::to_vec_pretty(self)?;
+        let hash = sha2::Sha256::digest(&bytes).into();
+        Ok((bytes, hash))
+    }
+
+    /// Parse a downloaded manifest.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let manifest: Self = serde_json::from_slice(bytes)
+            .map_err(|e| anyhow::anyhow!("The download is not a directory manifest: {e}"))?;
+        if manifest.version > MANIFEST_VERSION {
+            anyhow::bail!(
+                "The manifest has version {}, but only version {MANIFEST_VERSION} is supported",
+                manifest.version
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// The combined size of every file in the manifest.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// Format a path relative to the published directory with `/` separators.
+fn relative_manifest_path(directory: &Path, path: &Path) -> anyhow::Result<String> {
+    let relative = path.strip_prefix(directory)?;
+    let parts = relative
+        .components()
+        .map(|c| match c {
+            Component::Normal(part) => part
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("{} is not valid UTF-8", path.display())),
+            _ => Err(anyhow::anyhow!("Unexpected path {}", path.display())),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
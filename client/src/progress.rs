@@ -0,0 +1,143 @@
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal as _, Write as _},
+    time::{Duration, Instant},
+};
+
+use crate::core::humanize_bytes;
+
+/// How often progress bars are redrawn.
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of characters in each progress bar.
+const BAR_WIDTH: usize = 30;
+
+/// How far back a bar's speed is measured.
+const SPEED_WINDOW: Duration = Duration::from_secs(3);
+
+/// Several progress bars drawn together on standard error and redrawn in place as they change.
+pub struct MultiProgress {
+    bars: Vec<ProgressBar>,
+
+    /// The number of lines drawn last time, which the next draw moves back over.
+    drawn_lines: usize,
+}
+impl MultiProgress {
+    /// Create an empty set of progress bars.
+    /// Returns `None` when standard error isn't a terminal, or is carrying messages for JSON output.
+    pub fn new() -> Option<Self> {
+        if !std::io::stderr().is_terminal() || crate::json_output::enabled() {
+            return None;
+        }
+        Some(Self {
+            bars: Vec::new(),
+            drawn_lines: 0,
+        })
+    }
+
+    /// Add a bar below the others, returning its index.
+    pub fn add(&mut self, label: impl Into<String>) -> usize {
+        self.bars.push(ProgressBar {
+            label: label.into(),
+            progress: None,
+            samples: VecDeque::new(),
+        });
+        self.bars.len() - 1
+    }
+
+    /// The bar at an index returned by `add`.
+    pub fn bar(&mut self, index: usize) -> &mut ProgressBar {
+        &mut self.bars[index]
+    }
+
+    /// Redraw every bar over the lines drawn last time.
+    /// Failing to draw shouldn't interrupt the work being shown, so errors are ignored.
+    pub fn draw(&mut self) {
+        let mut stderr = std::io::stderr().lock();
+        let mut lines = String::new();
+        if self.drawn_lines > 0 {
+            lines.push_str(&format!("\x1b[{}A", self.drawn_lines));
+        }
+        for bar in &self.bars {
+            lines.push_str("\r\x1b[2K");
+            lines.push_str(&bar.line());
+            lines.push('\n');
+        }
+        self.drawn_lines = self.bars.len();
+        let _ = stderr
+            .write_all(lines.as_bytes())
+            .and_then(|()| stderr.flush());
+    }
+}
+
+/// A single line of a `MultiProgress`, with a bar when the total is known and a speed when bytes are counted.
+pub struct ProgressBar {
+    label: String,
+
+    /// The fraction of the work done, if it is known.
+    progress: Option<f32>,
+
+    /// Recent byte totals with when they were seen, to measure the speed with.
+    samples: VecDeque<(Instant, u64)>,
+}
+impl ProgressBar {
+    /// Set the fraction of the work done, between zero and one.
+    pub fn set_progress(&mut self, progress: f32) {
+        self.progress = Some(progress.clamp(0., 1.));
+    }
+
+    /// Set the total bytes transferred so far, which the speed is measured from.
+    pub fn set_transferred(&mut self, bytes: u64) {
+        let now = Instant::now();
+        while self
+            .samples
+            .front()
+            .is_some_and(|(seen, _)| now.duration_since(*seen) > SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, bytes));
+    }
+
+    /// The bytes per second over the recent samples, if there are enough to tell.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn speed(&self) -> Option<u64> {
+        let ((first_seen, first_bytes), (last_seen, last_bytes)) =
+            (self.samples.front()?, self.samples.back()?);
+        let elapsed = last_seen.duration_since(*first_seen).as_secs_f64();
+        if elapsed <= 0. {
+            return None;
+        }
+        Some((last_bytes.saturating_sub(*first_bytes) as f64 / elapsed) as u64)
+    }
+
+    /// The text of the bar's line.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn line(&self) -> String {
+        let mut line = self.label.clone();
+        if let Some(progress) = self.progress {
+            let filled = (progress * BAR_WIDTH as f32).round() as usize;
+            line.push_str(&format!(
+                " [{}{}] {:>3.0}%",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                progress * 100.,
+            ));
+        }
+        if let Some((_, bytes)) = self.samples.back() {
+            line.push_str(&format!(" {}", humanize_bytes(*bytes)));
+        }
+        if let Some(speed) = self.speed() {
+            line.push_str(&format!(" at {}/s", humanize_bytes(speed)));
+        }
+        line
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Write as _;
+
+use file_yeet_shared::local_now_fmt;
+
+use crate::{
+    core::humanize_bytes,
+    json_output::{self, status},
+};
+
+/// What became of one file of a publish or subscribe of several files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    /// Hashed and published.
+    Publishing,
+
+    /// The same file as one published under another path, so only published once.
+    Duplicate,
+
+    /// Downloaded from peers and verified.
+    Downloaded,
+
+    /// Found in the local cache.
+    Cached,
+
+    /// An empty file, created without a download.
+    Empty,
+
+    /// Could not be published or downloaded.
+    Failed,
+}
+impl std::fmt::Display for FileState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Publishing => "publishing",
+            Self::Duplicate => "duplicate",
+            Self::Downloaded => "downloaded",
+            Self::Cached => "cached",
+            Self::Empty => "empty",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// One file of a publish or subscribe of several files, printed as a row of the table summarizing them.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FileRow {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub state: FileState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl FileRow {
+    /// A row for a file that could not be published or downloaded.
+    pub fn failed(path: String, error: String) -> Self {
+        Self {
+            path,
+            file_size: None,
+            hash: None,
+            state: FileState::Failed,
+            error: Some(error),
+        }
+    }
+}
+
+/// Print the files as a table with aligned columns, followed by how many succeeded and failed.
+/// With JSON output, a single summary event listing the files is emitted instead.
+/// Returns the number of files that failed.
+pub fn print_summary(rows: &[FileRow]) -> usize {
+    let failed = rows
+        .iter()
+        .filter(|row| row.state == FileState::Failed)
+        .count();
+    let succeeded = rows.len() - failed;
+    if json_output::enabled() {
+        json_output::emit(&json_output::Event::Summary {
+            succeeded,
+            failed,
+            files: rows.to_vec(),
+        });
+        return failed;
+    }
+
+    for line in render(rows) {
+        status!("{line}");
+    }
+    status!("{} {succeeded} succeeded, {failed} failed", local_now_fmt());
+    failed
+}
+
+/// Lay out the files as the lines of a table, under a header, with every column but the last padded to its widest cell.
+fn render(rows: &[FileRow]) -> Vec<String> {
+    let cells = rows
+        .iter()
+        .map(|row| {
+            [
+                row.path.clone(),
+                row.file_size.map_or_else(|| "-".to_owned(), humanize_bytes),
+                row.hash.clone().unwrap_or_else(|| "-".to_owned()),
+                match &row.error {
+                    Some(e) => format!("{}: {e}", row.state),
+                    None => row.state.to_string(),
+                },
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["PATH", "SIZE", "HASH", "STATE"].map(str::to_owned);
+
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&cells) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    std::iter::once(&header)
+        .chain(&cells)
+        .map(|row| {
+            let mut line = String::new();
+            for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+                if i + 1 == row.len() {
+                    line.push_str(cell);
+                } else {
+                    let _ = write!(line, "{cell:<width$}  ");
+                }
+            }
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_are_aligned() {
+        let rows = [
+            FileRow {
+                path: "a".to_owned(),
+                file_size: Some(2048),
+                hash: Some("0123".to_owned()),
+                state: FileState::Publishing,
+                error: None,
+            },
+            FileRow::failed("longer/path".to_owned(), "not found".to_owned()),
+        ];
+        let lines = render(&rows);
+        assert_eq!(lines.len(), 3);
+        let state_column = lines[0].find("STATE").unwrap();
+        assert_eq!(lines[1].find("publishing"), Some(state_column));
+        assert_eq!(lines[2].find("failed: not found"), Some(state_column));
+        assert_eq!(lines[2].find('-'), lines[0].find("SIZE"));
+    }
+}
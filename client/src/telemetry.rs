@@ -0,0 +1,35 @@
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::layer::SubscriberExt as _;
+
+/// The service name the client's spans are exported under.
+const SERVICE_NAME: &str = "file_yeet_client";
+
+/// Sends the spans still waiting in the batch to the collector when dropped, before the client exits.
+pub struct ExportGuard;
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// Export the client's publish, subscribe, hole punching, and transfer spans to an OpenTelemetry collector
+/// over OTLP/gRPC. Spans are sent in batches from a background task, so exporting never holds up a transfer.
+pub fn export_spans(endpoint: &str) -> anyhow::Result<ExportGuard> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)])),
+        )
+        .install_batch(runtime::Tokio)?;
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(ExportGuard)
+}
@@ -0,0 +1,209 @@
+use file_yeet_shared::{local_now_fmt, HashBytes};
+use sha2::Digest as _;
+
+/// The GitHub API endpoint describing the latest release of `file_yeet`.
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/ryco117/file_yeet/releases/latest";
+
+/// The version of this build of the client.
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The Ed25519 public key that release checksums are signed with, in hex, embedded when building a release.
+/// Builds without it can't verify a release, so they refuse to install one.
+const RELEASE_PUBLIC_KEY_HEX: Option<&str> = option_env!("FILE_YEET_RELEASE_PUBLIC_KEY");
+
+/// A release published on GitHub.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub html_url: String,
+    pub assets: Vec<ReleaseAsset>,
+}
+
+/// A file attached to a GitHub release.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// Whether this release is newer than the running client.
+    #[must_use]
+    pub fn is_newer(&self) -> bool {
+        match (
+            parse_version(&self.tag_name),
+            parse_version(CURRENT_VERSION),
+        ) {
+            (Some(latest), Some(current)) => latest > current,
+            _ => false,
+        }
+    }
+
+    /// Find the client binary for this platform, its checksum file, and the signature of the checksum file.
+    fn platform_assets(&self) -> Option<(&ReleaseAsset, &ReleaseAsset, &ReleaseAsset)> {
+        let binary_name = format!(
+            "file_yeet_client-{}-{}{}",
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            std::env::consts::EXE_SUFFIX,
+        );
+        let checksum_name = format!("{binary_name}.sha256");
+        let signature_name = format!("{checksum_name}.sig");
+
+        let binary = self.assets.iter().find(|a| a.name == binary_name)?;
+        let checksum = self.assets.iter().find(|a| a.name == checksum_name)?;
+        let signature = self.assets.iter().find(|a| a.name == signature_name)?;
+        Some((binary, checksum, signature))
+    }
+}
+
+/// Parse a version string like `v1.2.3` into its numeric components.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version
+        .trim()
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map(str::parse::<u64>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next()?.ok()?;
+    Some((major, minor, patch))
+}
+
+/// The key that release checksums must be signed with.
+/// Fails for builds made without one, since their updates can't be verified.
+pub fn release_public_key() -> anyhow::Result<ed25519_dalek::VerifyingKey> {
+    let Some(key_hex) = RELEASE_PUBLIC_KEY_HEX else {
+        anyhow::bail!("This build has no release signing key, so it can't verify updates. Update it manually instead");
+    };
+    let mut key = [0; ed25519_dalek::PUBLIC_KEY_LENGTH];
+    if faster_hex::hex_decode(key_hex.trim().as_bytes(), &mut key).is_err() {
+        anyhow::bail!("The embedded release signing key is not a valid Ed25519 public key");
+    }
+    ed25519_dalek::VerifyingKey::from_bytes(&key)
+        .map_err(|e| anyhow::anyhow!("The embedded release signing key is invalid: {e}"))
+}
+
+/// Check the detached, hex-encoded Ed25519 signature over a release's checksum file.
+fn verify_checksum_signature(
+    key: &ed25519_dalek::VerifyingKey,
+    checksum_text: &str,
+    signature_text: &str,
+) -> anyhow::Result<()> {
+    let mut signature = [0; ed25519_dalek::SIGNATURE_LENGTH];
+    if faster_hex::hex_decode(signature_text.trim().as_bytes(), &mut signature).is_err() {
+        anyhow::bail!("The release checksum signature is not a valid Ed25519 signature");
+    }
+    key.verify_strict(
+        checksum_text.as_bytes(),
+        &ed25519_dalek::Signature::from_bytes(&signature),
+    )
+    .map_err(|_| anyhow::anyhow!("The release checksum is not signed by the release key"))
+}
+
+/// Create an HTTP client that identifies itself to the GitHub API.
+fn http_client() -> anyhow::Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .user_agent(concat!("file_yeet_client/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// Fetch the latest release from GitHub.
+pub async fn latest_release() -> anyhow::Result<Release> {
+    let release = http_client()?
+        .get(LATEST_RELEASE_URL)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to request the latest release: {e}"))?
+        .error_for_status()?
+        .json::<Release>()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to parse the latest release: {e}"))?;
+    Ok(release)
+}
+
+/// Download the release binary for this platform, verify it against the published checksum
+/// after verifying the checksum's signature, and replace the running executable with it.
+pub async fn install_release(release: &Release) -> anyhow::Result<()> {
+    let key = release_public_key()?;
+    let Some((binary, checksum, signature)) = release.platform_assets() else {
+        anyhow::bail!(
+            "Release {} has no signed client binary for {}-{}",
+            release.tag_name,
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+        );
+    };
+    let client = http_client()?;
+
+    // Get the expected SHA-256 hash. The checksum file may also contain the file name after the hash.
+    let checksum_text = client
+        .get(&checksum.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    // Anyone able to change the release could change its checksum too, so only trust a checksum signed by the release key.
+    let signature_text = client
+        .get(&signature.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    verify_checksum_signature(&key, &checksum_text, &signature_text)?;
+
+    let mut expected_hash = HashBytes::default();
+    let checksum_hex = checksum_text.split_whitespace().next().unwrap_or_default();
+    if let Err(e) = faster_hex::hex_decode(checksum_hex.as_bytes(), &mut expected_hash) {
+        anyhow::bail!("Release checksum is not a valid SHA-256 hash: {e}");
+    }
+
+    println!(
+        "{} Downloading {} from release {}...",
+        local_now_fmt(),
+        binary.name,
+        release.tag_name
+    );
+    let bytes = client
+        .get(&binary.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    // Never install a binary that doesn't match the published checksum.
+    let downloaded_hash: HashBytes = sha2::Sha256::digest(&bytes).into();
+    if downloaded_hash != expected_hash {
+        anyhow::bail!("The downloaded binary does not match the release checksum");
+    }
+
+    // Write the new binary next to the current one before swapping them.
+    let current_exe = std::env::current_exe()?;
+    let staged_path = current_exe.with_extension("update");
+    tokio::fs::write(&staged_path, &bytes).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        tokio::fs::set_permissions(&staged_path, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+    let swap_result = self_replace::self_replace(&staged_path);
+    if let Err(e) = tokio::fs::remove_file(&staged_path).await {
+        eprintln!(
+            "{} Failed to remove the staged update {}: {e}",
+            local_now_fmt(),
+            staged_path.display()
+        );
+    }
+    swap_result.map_err(|e| anyhow::anyhow!("Failed to replace the client binary: {e}"))?;
+
+    println!(
+        "{} Updated from {CURRENT_VERSION} to {}",
+        local_now_fmt(),
+        release.tag_name
+    );
+    Ok(())
+}
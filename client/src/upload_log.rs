@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use file_yeet_shared::{local_now_fmt, HashBytes};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::core::UploadStats;
+
+/// How an upload attempt to a peer ended.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadOutcome {
+    Success,
+    ConnectFailed,
+    Failed,
+    Cancelled,
+}
+
+/// A record of a single upload attempt, written as one JSON line to the upload log.
+#[derive(Debug, serde::Serialize)]
+pub struct UploadRecord {
+    pub time: String,
+    pub hash: String,
+    pub peer: String,
+    pub range_start: u64,
+    pub range_length: u64,
+    pub bytes_sent: u64,
+    pub duration_ms: u128,
+    pub outcome: UploadOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl UploadRecord {
+    pub fn new(
+        hash: &HashBytes,
+        peer: SocketAddr,
+        stats: UploadStats,
+        duration: Duration,
+        outcome: UploadOutcome,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            time: local_now_fmt().to_string(),
+            hash: faster_hex::hex_string(hash),
+            peer: peer.to_string(),
+            range_start: stats.range_start,
+            range_length: stats.range_length,
+            bytes_sent: stats.bytes_sent,
+            duration_ms: duration.as_millis(),
+            outcome,
+            error,
+        }
+    }
+}
+
+/// Append a record to the upload log, creating the log if needed.
+pub async fn append(log_path: &Path, record: &UploadRecord) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open the upload log: {e}"))?;
+    file.write_all(&line).await?;
+    Ok(())
+}
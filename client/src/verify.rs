@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+
+use file_yeet_shared::{HashAlgorithm, HashBytes};
+use futures_util::StreamExt as _;
+
+/// The file extension of sidecar files holding the expected SHA-256 hash of a file.
+pub const SIDECAR_EXTENSION: &str = "sha256";
+
+/// A file and the hash it is expected to have.
+#[derive(Debug)]
+pub struct ExpectedHash {
+    pub path: PathBuf,
+    pub hash: HashBytes,
+}
+
+/// The results of verifying a set of files.
+#[derive(Debug, Default)]
+pub struct VerifySummary {
+    /// The number of files that matched their expected hash.
+    pub verified: usize,
+
+    /// Files whose contents do not match their expected hash.
+    pub mismatched: Vec<PathBuf>,
+
+    /// Files that could not be hashed, and why.
+    pub failed: Vec<(PathBuf, String)>,
+}
+impl VerifySummary {
+    /// Whether every file matched its expected hash.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// Parse a line in the format written by `sha256sum`, i.e., a hex hash optionally followed by a file name.
+fn parse_hash_line(line: &str) -> Option<(HashBytes, Option<&str>)> {
+    let line = line.trim();
+    let (hex, name) = match line.split_once(char::is_whitespace) {
+        Some((hex, name)) => {
+            // `sha256sum` marks files hashed in binary mode with a leading asterisk.
+            let name = name.trim_start();
+            let name = name.strip_prefix('*').unwrap_or(name);
+            (hex, Some(name).filter(|n| !n.is_empty()))
+        }
+        None => (line, None),
+    };
+
+    let mut hash = HashBytes::default();
+    faster_hex::hex_decode(hex.as_bytes(), &mut hash).ok()?;
+    Some((hash, name))
+}
+
+/// Read a manifest of hashes and file paths relative to `directory`.
+pub async fn read_manifest(manifest: &Path, directory: &Path) -> anyhow::Result<Vec<ExpectedHash>> {
+    let contents = tokio::fs::read_to_string(manifest)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the manifest {}: {e}", manifest.display()))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| match parse_hash_line(line) {
+            Some((hash, Some(name))) => Ok(ExpectedHash {
+                path: directory.join(name),
+                hash,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Invalid manifest entry on line {}: {line}",
+                i + 1
+            )),
+        })
+        .collect()
+}
+
+/// Find every sidecar hash file in a directory tree and the file each one describes.
+pub async fn find_sidecars(directory: &Path) -> anyhow::Result<Vec<ExpectedHash>> {
+    let mut expected = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(&directory).await.map_err(|e| {
+            anyhow::anyhow!("Failed to read the directory {}: {e}", directory.display())
+        })?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            if path.extension().and_then(std::ffi::OsStr::to_str) != Some(SIDECAR_EXTENSION) {
+                continue;
+            }
+
+            // The sidecar describes the file with the same name, minus the sidecar extension.
+            let contents = tokio::fs::read_to_string(&path).await?;
+            let Some((hash, _)) = contents.lines().next().and_then(parse_hash_line) else {
+                anyhow::bail!("Invalid sidecar file {}", path.display());
+            };
+            expected.push(ExpectedHash {
+                path: path.with_extension(""),
+                hash,
+            });
+        }
+    }
+
+    Ok(expected)
+}
+
+/// Hash every file in parallel and compare the results with the expected hashes.
+pub async fn verify_files(expected: Vec<ExpectedHash>) -> VerifySummary {
+    let parallelism = std::thread::available_parallelism().map_or(4, std::num::NonZeroUsize::get);
+
+    futures_util::stream::iter(expected)
+        .map(|ExpectedHash { path, hash }| async move {
+            let result = crate::core::file_size_and_hash(&path, HashAlgorithm::Sha256, None).await;
+            (path, hash, result)
+        })
+        .buffer_unordered(parallelism)
+        .fold(
+            VerifySummary::default(),
+            |mut summary, (path, expected_hash, result)| async move {
+                match result {
+                    Ok((_, hash)) if hash == expected_hash => summary.verified += 1,
+                    Ok(_) => summary.mismatched.push(path),
+                    Err(e) => summary.failed.push((path, e.to_string())),
+                }
+                summary
+            },
+        )
+        .await
+}
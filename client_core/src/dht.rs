@@ -0,0 +1,587 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf as _, BufMut as _};
+use file_yeet_shared::{local_now_fmt, HashBytes, HASH_BYTE_COUNT};
+use tokio::{net::UdpSocket, sync::oneshot};
+
+/// Node IDs share the keyspace of file hashes, so the nodes closest to a hash are the ones that store its providers.
+type NodeId = HashBytes;
+
+/// The most nodes kept in each bucket of the routing table, and returned in each response.
+const BUCKET_SIZE: usize = 8;
+
+/// The most nodes queried at once during a lookup.
+const LOOKUP_PARALLELISM: usize = 3;
+
+/// How long a node has to answer a request before it is considered gone.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long an announcement is kept before the provider must announce again.
+const PROVIDER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often publishers should announce their files again, well within `PROVIDER_TTL`.
+pub const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The most providers kept for each file hash, and returned in each response.
+const MAX_PROVIDERS: usize = 16;
+
+/// The largest message sent or accepted, small enough to avoid IP fragmentation.
+const MAX_MESSAGE_SIZE: usize = 1200;
+
+/// The type of each DHT message, sent as a `u8` after the transaction ID and the sender's node ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum MessageKind {
+    /// Ask for the nodes closest to a target ID. Followed by the target.
+    FindNode,
+
+    /// Ask for the providers of a file hash and the nodes closest to it. Followed by the hash.
+    GetProviders,
+
+    /// Announce that the sender provides a file. Followed by the hash, a `u16` QUIC port, and the `u64` file size.
+    Announce,
+
+    /// The answer to any request. Followed by a `u8` count of providers, each with their address and `u64` file size,
+    /// then a `u8` count of nodes, each with their ID and address.
+    Response,
+}
+impl MessageKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        [
+            Self::FindNode,
+            Self::GetProviders,
+            Self::Announce,
+            Self::Response,
+        ]
+        .into_iter()
+        .find(|k| *k as u8 == kind)
+    }
+}
+
+/// A request to a DHT node.
+#[derive(Clone, Copy, Debug)]
+enum Request {
+    FindNode(NodeId),
+    GetProviders(HashBytes),
+    Announce {
+        hash: HashBytes,
+        port: u16,
+        file_size: u64,
+    },
+}
+
+/// A DHT node's answer to a request.
+#[derive(Debug, Default)]
+struct Response {
+    providers: Vec<(SocketAddr, u64)>,
+    nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+/// The body of a DHT message.
+#[derive(Debug)]
+enum Body {
+    Request(Request),
+    Response(Response),
+}
+
+/// A message between DHT nodes, sent as a single UDP datagram.
+#[derive(Debug)]
+struct Message {
+    transaction: u64,
+    sender: NodeId,
+    body: Body,
+}
+impl Message {
+    fn encode(&self) -> bytes::BytesMut {
+        let mut bb = bytes::BytesMut::with_capacity(MAX_MESSAGE_SIZE);
+        bb.put_u64(self.transaction);
+        bb.put(&self.sender[..]);
+        match &self.body {
+            Body::Request(Request::FindNode(target)) => {
+                bb.put_u8(MessageKind::FindNode as u8);
+                bb.put(&target[..]);
+            }
+            Body::Request(Request::GetProviders(hash)) => {
+                bb.put_u8(MessageKind::GetProviders as u8);
+                bb.put(&hash[..]);
+            }
+            Body::Request(Request::Announce {
+                hash,
+                port,
+                file_size,
+            }) => {
+                bb.put_u8(MessageKind::Announce as u8);
+                bb.put(&hash[..]);
+                bb.put_u16(*port);
+                bb.put_u64(*file_size);
+            }
+            Body::Response(response) => {
+                bb.put_u8(MessageKind::Response as u8);
+                let providers = &response.providers[..response.providers.len().min(MAX_PROVIDERS)];
+                bb.put_u8(u8::try_from(providers.len()).expect("Provider count is bounded"));
+                for &(address, file_size) in providers {
+                    put_address(&mut bb, address);
+                    bb.put_u64(file_size);
+                }
+                let nodes = &response.nodes[..response.nodes.len().min(BUCKET_SIZE)];
+                bb.put_u8(u8::try_from(nodes.len()).expect("Node count is bounded"));
+                for (id, address) in nodes {
+                    bb.put(&id[..]);
+                    put_address(&mut bb, *address);
+                }
+            }
+        }
+        bb
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        let buf = &mut buf;
+        if buf.remaining() < size_of::<u64>() + HASH_BYTE_COUNT + size_of::<u8>() {
+            return None;
+        }
+        let transaction = buf.get_u64();
+        let sender = get_hash(buf)?;
+        let body = match MessageKind::from_u8(buf.get_u8())? {
+            MessageKind::FindNode => Body::Request(Request::FindNode(get_hash(buf)?)),
+            MessageKind::GetProviders => Body::Request(Request::GetProviders(get_hash(buf)?)),
+            MessageKind::Announce => {
+                let hash = get_hash(buf)?;
+                if buf.remaining() < size_of::<u16>() + size_of::<u64>() {
+                    return None;
+                }
+                Body::Request(Request::Announce {
+                    hash,
+                    port: buf.get_u16(),
+                    file_size: buf.get_u64(),
+                })
+            }
+            MessageKind::Response => {
+                let mut response = Response::default();
+                let providers = get_u8(buf)?;
+                for _ in 0..providers {
+                    let address = get_address(buf)?;
+                    if buf.remaining() < size_of::<u64>() {
+                        return None;
+                    }
+                    response.providers.push((address, buf.get_u64()));
+                }
+                let nodes = get_u8(buf)?;
+                for _ in 0..nodes {
+                    let id = get_hash(buf)?;
+                    response.nodes.push((id, get_address(buf)?));
+                }
+                Body::Response(response)
+            }
+        };
+        Some(Self {
+            transaction,
+            sender,
+            body,
+        })
+    }
+}
+
+fn get_u8(buf: &mut &[u8]) -> Option<u8> {
+    buf.has_remaining().then(|| buf.get_u8())
+}
+
+fn get_hash(buf: &mut &[u8]) -> Option<HashBytes> {
+    let mut hash = HashBytes::default();
+    if buf.remaining() < hash.len() {
+        return None;
+    }
+    buf.copy_to_slice(&mut hash);
+    Some(hash)
+}
+
+/// Write a socket address as a `u8` IP version, the IP's bytes, and the `u16` port.
+fn put_address(bb: &mut bytes::BytesMut, address: SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            bb.put_u8(4);
+            bb.put(&ip.octets()[..]);
+        }
+        IpAddr::V6(ip) => {
+            bb.put_u8(6);
+            bb.put(&ip.octets()[..]);
+        }
+    }
+    bb.put_u16(address.port());
+}
+
+fn get_address(buf: &mut &[u8]) -> Option<SocketAddr> {
+    let ip = match get_u8(buf)? {
+        4 if buf.remaining() >= 4 => {
+            let mut octets = [0; 4];
+            buf.copy_to_slice(&mut octets);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 if buf.remaining() >= 16 => {
+            let mut octets = [0; 16];
+            buf.copy_to_slice(&mut octets);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    if buf.remaining() < size_of::<u16>() {
+        return None;
+    }
+    Some(SocketAddr::new(ip, buf.get_u16()))
+}
+
+/// The XOR distance between two IDs, which compares like a big-endian number.
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// The nodes this node knows of, bucketed by how many leading bits of their ID match ours.
+struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<(NodeId, SocketAddr)>>,
+}
+impl RoutingTable {
+    fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); HASH_BYTE_COUNT * 8],
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+
+    /// Remember a node that was heard from. Full buckets keep their longest known nodes, since those tend to stay.
+    fn insert(&mut self, id: NodeId, address: SocketAddr) {
+        let Some(index) = self.bucket_index(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|(n, _)| *n == id) {
+            bucket.remove(position);
+            bucket.push((id, address));
+        } else if bucket.len() < BUCKET_SIZE {
+            bucket.push((id, address));
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|(n, _)| n != id);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes: Vec<_> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_unstable_by_key(|(id, _)| distance(id, target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+/// A peer that announced it provides a file.
+struct Provider {
+    address: SocketAddr,
+    file_size: u64,
+    announced: Instant,
+}
+
+struct DhtNode {
+    id: NodeId,
+    socket: UdpSocket,
+    routing: Mutex<RoutingTable>,
+    providers: Mutex<HashMap<HashBytes, Vec<Provider>>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<(NodeId, Response)>>>,
+}
+
+/// Lock a mutex, even if a panic poisoned it, since none of its users leave it in an inconsistent state.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// An experimental Kademlia-style DHT keyed by file hashes, for finding publishers without a server.
+/// Each publisher announces its files to the nodes whose IDs are closest to their hashes,
+/// where subscribers look for them.
+pub struct Dht {
+    node: Arc<DhtNode>,
+    receiver: tokio::task::AbortHandle,
+}
+impl Dht {
+    /// Bind a DHT node with a random ID to the given UDP port, or any port when zero.
+    /// Other nodes can bootstrap from this one while it runs.
+    pub async fn bind(port: u16, using_ipv4: bool) -> anyhow::Result<Self> {
+        let ip = if using_ipv4 {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(ip, port))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to bind the DHT socket: {e}"))?;
+        let id: NodeId = rand::random();
+        let node = Arc::new(DhtNode {
+            id,
+            socket,
+            routing: Mutex::new(RoutingTable::new(id)),
+            providers: Mutex::default(),
+            pending: Mutex::default(),
+        });
+        let receiver = tokio::task::spawn(receive_loop(node.clone())).abort_handle();
+        Ok(Self { node, receiver })
+    }
+
+    /// The local address of the DHT socket, for other nodes to bootstrap from.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.node.socket.local_addr()
+    }
+
+    /// Join the DHT through the given nodes, filling the routing table with the nodes near our ID.
+    /// Returns the number of nodes known afterwards.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> usize {
+        let own_id = self.node.id;
+        futures_util::future::join_all(
+            nodes
+                .iter()
+                .map(|&address| self.request(address, Request::FindNode(own_id))),
+        )
+        .await;
+        self.lookup(&own_id, false).await;
+        lock(&self.node.routing).len()
+    }
+
+    /// Announce that a file can be downloaded from our QUIC endpoint on the given port.
+    /// Announcements expire, so they should be repeated every `REANNOUNCE_INTERVAL`.
+    /// Returns the number of nodes that accepted the announcement.
+    pub async fn announce(&self, hash: &HashBytes, file_size: u64, port: u16) -> usize {
+        let (closest, _) = self.lookup(hash, false).await;
+        let request = Request::Announce {
+            hash: *hash,
+            port,
+            file_size,
+        };
+        futures_util::future::join_all(
+            closest
+                .iter()
+                .map(|&(_, address)| self.request(address, request)),
+        )
+        .await
+        .into_iter()
+        .filter(Option::is_some)
+        .count()
+    }
+
+    /// Find the address and file size of each provider of a file.
+    pub async fn find_providers(&self, hash: &HashBytes) -> Vec<(SocketAddr, u64)> {
+        let (_, mut providers) = self.lookup(hash, true).await;
+        for provider in self.node.live_providers(hash) {
+            if !providers.iter().any(|&(a, _)| a == provider.0) {
+                providers.push(provider);
+            }
+        }
+        providers
+    }
+
+    /// Send a request and wait for its response, forgetting the node if it doesn't answer.
+    async fn request(&self, address: SocketAddr, request: Request) -> Option<(NodeId, Response)> {
+        let transaction = rand::random();
+        let (tx, rx) = oneshot::channel();
+        lock(&self.node.pending).insert(transaction, tx);
+
+        let message = Message {
+            transaction,
+            sender: self.node.id,
+            body: Body::Request(request),
+        };
+        let response = match self.node.socket.send_to(&message.encode(), address).await {
+            Ok(_) => tokio::time::timeout(REQUEST_TIMEOUT, rx)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to send a DHT request to {address}: {e}",
+                    local_now_fmt()
+                );
+                None
+            }
+        };
+        lock(&self.node.pending).remove(&transaction);
+        response
+    }
+
+    /// Iteratively query the nodes closest to the target until no closer nodes are found.
+    /// Returns the closest nodes that answered, and any providers found when looking for them.
+    async fn lookup(
+        &self,
+        target: &HashBytes,
+        get_providers: bool,
+    ) -> (Vec<(NodeId, SocketAddr)>, Vec<(SocketAddr, u64)>) {
+        let request = if get_providers {
+            Request::GetProviders(*target)
+        } else {
+            Request::FindNode(*target)
+        };
+        let mut shortlist = lock(&self.node.routing).closest(target, BUCKET_SIZE);
+        let mut queried = HashSet::new();
+        let mut answered = Vec::new();
+        let mut providers: Vec<(SocketAddr, u64)> = Vec::new();
+        loop {
+            shortlist.sort_unstable_by_key(|(id, _)| distance(id, target));
+            shortlist.dedup_by_key(|(id, _)| *id);
+            let batch: Vec<_> = shortlist
+                .iter()
+                .take(BUCKET_SIZE)
+                .filter(|(id, _)| !queried.contains(id))
+                .take(LOOKUP_PARALLELISM)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let responses =
+                futures_util::future::join_all(batch.into_iter().map(|(id, address)| async move {
+                    (id, address, self.request(address, request).await)
+                }))
+                .await;
+            for (id, address, response) in responses {
+                queried.insert(id);
+                let Some((_, response)) = response else {
+                    shortlist.retain(|(n, _)| *n != id);
+                    lock(&self.node.routing).remove(&id);
+                    continue;
+                };
+                answered.push((id, address));
+                for provider in response.providers {
+                    if !providers.iter().any(|&(a, _)| a == provider.0) {
+                        providers.push(provider);
+                    }
+                }
+                shortlist.extend(
+                    response
+                        .nodes
+                        .into_iter()
+                        .filter(|(n, _)| *n != self.node.id),
+                );
+            }
+        }
+
+        answered.sort_unstable_by_key(|(id, _)| distance(id, target));
+        answered.truncate(BUCKET_SIZE);
+        (answered, providers)
+    }
+}
+impl std::fmt::Debug for Dht {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dht")
+            .field("id", &faster_hex::hex_string(&self.node.id))
+            .finish_non_exhaustive()
+    }
+}
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl DhtNode {
+    /// The providers of a file whose announcements haven't expired.
+    fn live_providers(&self, hash: &HashBytes) -> Vec<(SocketAddr, u64)> {
+        let mut providers = lock(&self.providers);
+        let Some(list) = providers.get_mut(hash) else {
+            return Vec::new();
+        };
+        list.retain(|p| p.announced.elapsed() < PROVIDER_TTL);
+        let live = list.iter().map(|p| (p.address, p.file_size)).collect();
+        if list.is_empty() {
+            providers.remove(hash);
+        }
+        live
+    }
+
+    /// Answer a request from another node.
+    fn answer(&self, request: Request, from: SocketAddr) -> Response {
+        match request {
+            Request::FindNode(target) => Response {
+                providers: Vec::new(),
+                nodes: lock(&self.routing).closest(&target, BUCKET_SIZE),
+            },
+            Request::GetProviders(hash) => Response {
+                providers: self.live_providers(&hash),
+                nodes: lock(&self.routing).closest(&hash, BUCKET_SIZE),
+            },
+            Request::Announce {
+                hash,
+                port,
+                file_size,
+            } => {
+                // Providers are reached at the address the announcement came from, so they can't announce others.
+                let address = SocketAddr::new(from.ip(), port);
+                let mut providers = lock(&self.providers);
+                let list = providers.entry(hash).or_default();
+                list.retain(|p| p.address != address && p.announced.elapsed() < PROVIDER_TTL);
+                if list.len() < MAX_PROVIDERS {
+                    list.push(Provider {
+                        address,
+                        file_size,
+                        announced: Instant::now(),
+                    });
+                }
+                Response::default()
+            }
+        }
+    }
+}
+
+/// Answer requests from other nodes and pass responses to the requests waiting for them.
+async fn receive_loop(node: Arc<DhtNode>) {
+    let mut buf = [0; MAX_MESSAGE_SIZE];
+    loop {
+        // Some platforms report unreachable nodes as errors on the next receive, which are safe to skip.
+        let Ok((len, from)) = node.socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(message) = Message::decode(&buf[..len]) else {
+            continue;
+        };
+        if message.sender == node.id {
+            continue;
+        }
+        lock(&node.routing).insert(message.sender, from);
+
+        match message.body {
+            Body::Response(response) => {
+                if let Some(tx) = lock(&node.pending).remove(&message.transaction) {
+                    let _ = tx.send((message.sender, response));
+                }
+            }
+            Body::Request(request) => {
+                let response = Message {
+                    transaction: message.transaction,
+                    sender: node.id,
+                    body: Body::Response(node.answer(request, from)),
+                };
+                if let Err(e) = node.socket.send_to(&response.encode(), from).await {
+                    eprintln!(
+                        "{} Failed to answer a DHT request from {from}: {e}",
+                        local_now_fmt()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use file_yeet_shared::{FileHash, HashAlgorithm, HashBytes, HASH_BYTE_COUNT};
+
+use crate::CHUNK_SIZE;
+
+/// The name of the directory the cache is kept in, within the client's data directory.
+const CACHE_DIRECTORY_NAME: &str = "hash_cache";
+
+/// The name of the file mapping each hashed path to its hash and the state it was hashed in.
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// The extension of the files holding the chunk hashes of a hash, as their raw bytes in order.
+const CHUNKS_EXTENSION: &str = "chunks";
+
+/// Keeps the index from being read and written by several tasks at once.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// The hash of a file and its state when it was hashed.
+/// A file whose size or modification time differ from its entry has changed and must be hashed again.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct IndexEntry {
+    hash: String,
+    file_size: u64,
+    modified: SystemTime,
+
+    /// Whether the chunk hashes were saved alongside the hash.
+    has_chunks: bool,
+}
+
+/// The size and modification time of a file, read before it is hashed.
+pub struct FileState {
+    key: String,
+    file_size: u64,
+    modified: SystemTime,
+}
+impl FileState {
+    /// Read the current state of a file. Returns `None` for files whose modification time is unavailable,
+    /// since their changes can't be noticed.
+    pub fn read(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path)
+            .ok()
+            .filter(std::fs::Metadata::is_file)?;
+        Some(Self {
+            key: std::fs::canonicalize(path)
+                .unwrap_or_else(|_| path.to_path_buf())
+                .to_string_lossy()
+                .into_owned(),
+            file_size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// The directory the cache is kept in, if the client has a data directory.
+fn cache_directory() -> Option<PathBuf> {
+    crate::identity::data_directory().map(|p| p.join(CACHE_DIRECTORY_NAME))
+}
+
+/// The path of the chunk hashes saved for a hash.
+fn chunks_path(directory: &Path, hash: &str) -> PathBuf {
+    directory.join(format!("{hash}.{CHUNKS_EXTENSION}"))
+}
+
+/// Get the size, hash, and chunk hashes saved for a file in the given state, if it was hashed with the algorithm.
+/// When `with_chunks` is set, only entries that saved the chunk hashes are used. Otherwise the chunk hashes are empty.
+pub fn lookup(
+    state: &FileState,
+    algorithm: HashAlgorithm,
+    with_chunks: bool,
+) -> Option<(u64, HashBytes, Vec<HashBytes>)> {
+    let directory = cache_directory()?;
+    let entry = {
+        let _lock = INDEX_LOCK
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        read_index(&directory).remove(&state.key)?
+    };
+    if entry.file_size != state.file_size || entry.modified != state.modified {
+        return None;
+    }
+    let hash: FileHash = entry.hash.parse().ok()?;
+    if hash.algorithm != algorithm {
+        return None;
+    }
+    if !with_chunks {
+        return Some((entry.file_size, hash.bytes, Vec::new()));
+    }
+    if !entry.has_chunks {
+        return None;
+    }
+
+    // Ignore chunk hashes that don't fit the file, such as from a write that was cut short.
+    let bytes = std::fs::read(chunks_path(&directory, &entry.hash)).ok()?;
+    if bytes.len() % HASH_BYTE_COUNT != 0
+        || (bytes.len() / HASH_BYTE_COUNT) as u64 != entry.file_size.div_ceil(CHUNK_SIZE)
+    {
+        return None;
+    }
+    let chunk_hashes = bytes
+        .chunks_exact(HASH_BYTE_COUNT)
+        .map(|chunk| chunk.try_into().expect("Chunks have the length of a hash"))
+        .collect();
+    Some((entry.file_size, hash.bytes, chunk_hashes))
+}
+
+/// Save the hash of a file in the state it was in before it was hashed, replacing any earlier entry for the file.
+pub fn store(
+    state: &FileState,
+    hash: &FileHash,
+    chunk_hashes: Option<&[HashBytes]>,
+) -> std::io::Result<()> {
+    let Some(directory) = cache_directory() else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(&directory)?;
+    let hash = hash.to_string();
+    if let Some(chunk_hashes) = chunk_hashes {
+        std::fs::write(chunks_path(&directory, &hash), chunk_hashes.concat())?;
+    }
+
+    let _lock = INDEX_LOCK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    let mut index = read_index(&directory);
+
+    // The same contents have the same chunks, so keep any chunk hashes saved for them before.
+    let had_chunks = index
+        .get(&state.key)
+        .is_some_and(|entry| entry.hash == hash && entry.has_chunks);
+    let replaced = index.insert(
+        state.key.clone(),
+        IndexEntry {
+            hash: hash.clone(),
+            file_size: state.file_size,
+            modified: state.modified,
+            has_chunks: chunk_hashes.is_some() || had_chunks,
+        },
+    );
+
+    // Remove chunk hashes that no entry refers to anymore.
+    if let Some(replaced) = replaced.filter(|replaced| replaced.hash != hash) {
+        if !index.values().any(|entry| entry.hash == replaced.hash) {
+            let _ = std::fs::remove_file(chunks_path(&directory, &replaced.hash));
+        }
+    }
+
+    // Write the whole index before replacing the old one, so that an interrupted write can't lose it.
+    let index_path = directory.join(INDEX_FILE_NAME);
+    let temporary_path = index_path.with_extension("json.tmp");
+    std::fs::write(&temporary_path, serde_json::to_vec(&index)?)?;
+    std::fs::rename(temporary_path, index_path)
+}
+
+/// Read the index, treating a missing or unreadable index as empty.
+fn read_index(directory: &Path) -> HashMap<String, IndexEntry> {
+    std::fs::read(directory.join(INDEX_FILE_NAME))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use file_yeet_shared::{HashBytes, PUBLISH_SIGNATURE_LENGTH};
+use sha2::Digest as _;
+
+/// The file name of our saved certificate, in DER format.
+const CERTIFICATE_FILE_NAME: &str = "identity.der";
+
+/// The file name of our saved private key, in DER format.
+const PRIVATE_KEY_FILE_NAME: &str = "identity.key";
+
+/// The file name of our saved publisher signing key, as the raw 32 byte Ed25519 secret key.
+const SIGNING_KEY_FILE_NAME: &str = "signing.key";
+
+/// Prefixed to every signed publish so that the signatures can't be mistaken for anything else.
+const PUBLISH_SIGNATURE_CONTEXT: &[u8] = b"file_yeet publish v1";
+
+/// The oldest a publish signature may be, so that an old signature can't be replayed indefinitely.
+const MAX_SIGNATURE_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How far in the future a publish signature may be, allowing for the publisher's clock to be ahead of ours.
+const MAX_SIGNATURE_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// The file name of the peers we remember.
+const KNOWN_PEERS_FILE_NAME: &str = "known_peers.json";
+
+/// The file name of the server certificates we remember.
+const KNOWN_SERVERS_FILE_NAME: &str = "known_servers.json";
+
+/// The number of hex characters of a fingerprint shown to users.
+const SHORT_FINGERPRINT_LENGTH: usize = 16;
+
+/// The directory our identity, the peers we remember, and other saved state are kept in.
+pub(crate) fn data_directory() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|mut p| {
+        p.push("file_yeet_client");
+        p
+    })
+}
+
+/// The certificate and private key this client presents to every peer.
+/// Generated on first run and reused afterwards so that peers can recognize us across sessions.
+pub struct PeerIdentity {
+    pub cert: rustls::Certificate,
+    pub key: rustls::PrivateKey,
+}
+impl PeerIdentity {
+    /// Load the identity saved by a previous run, or create and save a new one.
+    /// If the identity cannot be saved, a temporary identity is used for this session only.
+    pub fn load_or_create() -> anyhow::Result<Self> {
+        let Some(directory) = data_directory() else {
+            eprintln!("No data directory is available, using a temporary peer identity");
+            return Self::generate();
+        };
+        let cert_path = directory.join(CERTIFICATE_FILE_NAME);
+        let key_path = directory.join(PRIVATE_KEY_FILE_NAME);
+
+        if let (Ok(cert), Ok(key)) = (std::fs::read(&cert_path), std::fs::read(&key_path)) {
+            return Ok(Self {
+                cert: rustls::Certificate(cert),
+                key: rustls::PrivateKey(key),
+            });
+        }
+
+        let identity = Self::generate()?;
+        if let Err(e) = identity.save(&directory, &cert_path, &key_path) {
+            eprintln!("Failed to save the peer identity, using a temporary one instead: {e}");
+        }
+        Ok(identity)
+    }
+
+    /// Generate a new self-signed identity.
+    fn generate() -> anyhow::Result<Self> {
+        let (cert, key) = file_yeet_shared::generate_self_signed_cert()?;
+        Ok(Self { cert, key })
+    }
+
+    /// Write the identity to disk, keeping the private key readable only by the current user where supported.
+    fn save(
+        &self,
+        directory: &std::path::Path,
+        cert_path: &std::path::Path,
+        key_path: &std::path::Path,
+    ) -> std::io::Result<()> {
+        std::fs::create_dir_all(directory)?;
+        std::fs::write(cert_path, &self.cert.0)?;
+        write_private(key_path, &self.key.0)
+    }
+
+    /// The fingerprint peers know us by.
+    pub fn fingerprint(&self) -> String {
+        certificate_fingerprint(&self.cert)
+    }
+}
+
+/// Write a secret to disk, keeping it readable only by the current user where supported.
+fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+/// The hex encoded SHA-256 hash of a certificate.
+fn certificate_fingerprint(cert: &rustls::Certificate) -> String {
+    faster_hex::hex_string(&sha2::Sha256::digest(&cert.0))
+}
+
+/// The fingerprint of the certificate a peer presented on this connection, if any.
+pub fn peer_fingerprint(connection: &quinn::Connection) -> Option<String> {
+    let identity = connection.peer_identity()?;
+    let certs = identity.downcast::<Vec<rustls::Certificate>>().ok()?;
+    certs.first().map(certificate_fingerprint)
+}
+
+/// A shortened fingerprint that is easier to read and compare.
+pub fn short_fingerprint(fingerprint: &str) -> &str {
+    &fingerprint[..fingerprint.len().min(SHORT_FINGERPRINT_LENGTH)]
+}
+
+/// Whether a fingerprint matches one the user trusts.
+/// The whole fingerprint must be given, since a prefix is much easier for someone else's key to match.
+pub fn fingerprint_matches(fingerprint: &str, trusted: &str) -> bool {
+    trusted.trim().eq_ignore_ascii_case(fingerprint)
+}
+
+/// The Ed25519 key this client signs its publishes with, so that subscribers can verify who published a file.
+/// Generated on first use and reused afterwards so that the fingerprint stays the same across sessions.
+pub struct PublisherKey(ed25519_dalek::SigningKey);
+impl std::fmt::Debug for PublisherKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PublisherKey")
+            .field(&self.fingerprint())
+            .finish()
+    }
+}
+impl PublisherKey {
+    /// Load the key saved by a previous run, or create and save a new one.
+    /// If the key cannot be saved, a temporary key is used for this session only.
+    pub fn load_or_create() -> Self {
+        let Some(directory) = data_directory() else {
+            eprintln!("No data directory is available, using a temporary signing key");
+            return Self::generate();
+        };
+        let key_path = directory.join(SIGNING_KEY_FILE_NAME);
+
+        if let Some(secret) = std::fs::read(&key_path)
+            .ok()
+            .and_then(|bytes| <[u8; ed25519_dalek::SECRET_KEY_LENGTH]>::try_from(bytes).ok())
+        {
+            return Self(ed25519_dalek::SigningKey::from_bytes(&secret));
+        }
+
+        let key = Self::generate();
+        if let Err(e) = std::fs::create_dir_all(&directory)
+            .and_then(|()| write_private(&key_path, key.0.as_bytes()))
+        {
+            eprintln!("Failed to save the signing key, using a temporary one instead: {e}");
+        }
+        key
+    }
+
+    /// Generate a new random key.
+    fn generate() -> Self {
+        Self(ed25519_dalek::SigningKey::from_bytes(&rand::random()))
+    }
+
+    /// The fingerprint subscribers know our publishes by.
+    pub fn fingerprint(&self) -> String {
+        public_key_fingerprint(self.0.verifying_key().as_bytes())
+    }
+
+    /// Sign a publish of a file hash and size at the current time.
+    pub fn sign(&self, hash: &HashBytes, file_size: u64) -> PublishSignature {
+        use ed25519_dalek::Signer as _;
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let signature = self.0.sign(&publish_message(hash, file_size, timestamp));
+        PublishSignature {
+            public_key: self.0.verifying_key().to_bytes(),
+            signature: signature.to_bytes(),
+            timestamp,
+        }
+    }
+}
+
+/// A publisher's signature over a file hash and size, relayed to subscribers by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublishSignature {
+    pub public_key: [u8; ed25519_dalek::PUBLIC_KEY_LENGTH],
+    pub signature: [u8; ed25519_dalek::SIGNATURE_LENGTH],
+
+    /// The Unix time, in seconds, the publish was signed at.
+    pub timestamp: u64,
+}
+impl PublishSignature {
+    /// The fingerprint of the key that made the signature.
+    pub fn fingerprint(&self) -> String {
+        public_key_fingerprint(&self.public_key)
+    }
+
+    /// Whether this is a valid signature over the file hash and size, made recently enough to not be a replay.
+    /// Says nothing about who made it; compare the fingerprint against a trusted one for that.
+    pub fn verify(&self, hash: &HashBytes, file_size: u64) -> bool {
+        if !self.is_recent() {
+            return false;
+        }
+        let Ok(key) = ed25519_dalek::VerifyingKey::from_bytes(&self.public_key) else {
+            return false;
+        };
+        let signature = ed25519_dalek::Signature::from_bytes(&self.signature);
+        key.verify_strict(
+            &publish_message(hash, file_size, self.timestamp),
+            &signature,
+        )
+        .is_ok()
+    }
+
+    /// Whether the signature was made within `MAX_SIGNATURE_AGE`, and not too far in the future.
+    fn is_recent(&self) -> bool {
+        let signed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(self.timestamp);
+        match SystemTime::now().duration_since(signed_at) {
+            Ok(age) => age <= MAX_SIGNATURE_AGE,
+            Err(e) => e.duration() <= MAX_SIGNATURE_CLOCK_SKEW,
+        }
+    }
+
+    /// The signature as it is sent to the server.
+    pub fn to_bytes(&self) -> [u8; PUBLISH_SIGNATURE_LENGTH] {
+        let mut bytes = [0; PUBLISH_SIGNATURE_LENGTH];
+        let (public_key, rest) = bytes.split_at_mut(ed25519_dalek::PUBLIC_KEY_LENGTH);
+        let (signature, timestamp) = rest.split_at_mut(ed25519_dalek::SIGNATURE_LENGTH);
+        public_key.copy_from_slice(&self.public_key);
+        signature.copy_from_slice(&self.signature);
+        timestamp.copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Read a signature as it is relayed by the server.
+    pub fn from_bytes(bytes: &[u8; PUBLISH_SIGNATURE_LENGTH]) -> Self {
+        let (public_key, rest) = bytes.split_at(ed25519_dalek::PUBLIC_KEY_LENGTH);
+        let (signature, timestamp) = rest.split_at(ed25519_dalek::SIGNATURE_LENGTH);
+        Self {
+            public_key: public_key.try_into().expect("Public key length is fixed"),
+            signature: signature.try_into().expect("Signature length is fixed"),
+            timestamp: u64::from_be_bytes(timestamp.try_into().expect("Timestamp length is fixed")),
+        }
+    }
+}
+
+/// The hex encoded SHA-256 hash of an Ed25519 public key.
+fn public_key_fingerprint(public_key: &[u8; ed25519_dalek::PUBLIC_KEY_LENGTH]) -> String {
+    faster_hex::hex_string(&sha2::Sha256::digest(public_key))
+}
+
+/// The message a publisher signs, binding the file hash and size to the time of signing.
+fn publish_message(hash: &HashBytes, file_size: u64, timestamp: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(
+        PUBLISH_SIGNATURE_CONTEXT.len() + hash.len() + 2 * std::mem::size_of::<u64>(),
+    );
+    message.extend_from_slice(PUBLISH_SIGNATURE_CONTEXT);
+    message.extend_from_slice(hash);
+    message.extend_from_slice(&file_size.to_be_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message
+}
+
+/// What we remember about a peer we have transferred with.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct KnownPeer {
+    /// A name the user chose for the peer.
+    pub name: Option<String>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+
+    /// The number of transfers with this peer, including the current ones.
+    pub times_seen: u64,
+}
+
+/// The peers we have transferred with, by the fingerprint of their certificate.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct KnownPeers(HashMap<String, KnownPeer>);
+impl KnownPeers {
+    /// Load the peers remembered by previous runs, or an empty list.
+    pub fn load() -> Self {
+        load_json(KNOWN_PEERS_FILE_NAME)
+    }
+
+    /// Save the remembered peers for future runs.
+    pub fn save(&self) -> anyhow::Result<()> {
+        save_json(KNOWN_PEERS_FILE_NAME, self)
+    }
+
+    /// Get what we remember about a peer.
+    pub fn get(&self, fingerprint: &str) -> Option<&KnownPeer> {
+        self.0.get(fingerprint)
+    }
+
+    /// Record a new transfer with a peer.
+    pub fn record_seen(&mut self, fingerprint: &str) {
+        let now = SystemTime::now();
+        let peer = self
+            .0
+            .entry(fingerprint.to_owned())
+            .or_insert_with(|| KnownPeer {
+                name: None,
+                first_seen: now,
+                last_seen: now,
+                times_seen: 0,
+            });
+        peer.last_seen = now;
+        peer.times_seen += 1;
+    }
+
+    /// Name a peer, or clear its name if the given name is blank.
+    pub fn set_name(&mut self, fingerprint: &str, name: &str) {
+        if let Some(peer) = self.0.get_mut(fingerprint) {
+            peer.name = (!name.trim().is_empty()).then(|| name.to_owned());
+        }
+    }
+
+    /// A short description of whether we have seen a peer before, for consent prompts.
+    pub fn describe(&self, fingerprint: &str) -> String {
+        match self.get(fingerprint) {
+            Some(KnownPeer {
+                name: Some(name), ..
+            }) => format!("Seen before as '{name}'"),
+            Some(peer) if peer.times_seen > 1 => format!(
+                "Seen {} times before as {}",
+                peer.times_seen - 1,
+                short_fingerprint(fingerprint)
+            ),
+            _ => format!("New peer {}", short_fingerprint(fingerprint)),
+        }
+    }
+}
+
+/// The certificate fingerprints of the servers we have connected to, by server address.
+/// A server's certificate is trusted the first time we connect, and refused if it later changes.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct KnownServers(HashMap<String, String>);
+impl KnownServers {
+    /// Load the server certificates remembered by previous runs, or an empty list.
+    pub fn load() -> Self {
+        load_json(KNOWN_SERVERS_FILE_NAME)
+    }
+
+    /// Save the remembered server certificates for future runs.
+    pub fn save(&self) -> anyhow::Result<()> {
+        save_json(KNOWN_SERVERS_FILE_NAME, self)
+    }
+
+    /// The fingerprint of the certificate we trust for a server, if we have connected to it before.
+    pub fn get(&self, server: &str) -> Option<&str> {
+        self.0.get(server).map(String::as_str)
+    }
+
+    /// Trust a certificate for a server, replacing any we trusted before.
+    pub fn pin(&mut self, server: &str, fingerprint: &str) {
+        self.0.insert(server.to_owned(), fingerprint.to_owned());
+    }
+}
+
+/// A server presented a different certificate than the one we trusted on an earlier connection.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error(
+    "The server {server} presented a different certificate ({}) than before ({}). It may have been reinstalled, or the connection may be intercepted",
+    short_fingerprint(.presented),
+    short_fingerprint(.expected)
+)]
+pub struct ServerIdentityChanged {
+    pub server: String,
+    pub expected: String,
+    pub presented: String,
+}
+
+/// Load a JSON file from the identity directory, or the default value if it is missing or invalid.
+fn load_json<T: serde::de::DeserializeOwned + Default>(file_name: &str) -> T {
+    data_directory()
+        .and_then(|p| std::fs::read_to_string(p.join(file_name)).ok())
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Save a value as a JSON file in the identity directory.
+fn save_json<T: serde::Serialize>(file_name: &str, value: &T) -> anyhow::Result<()> {
+    let directory =
+        data_directory().ok_or_else(|| anyhow::anyhow!("No data directory is available"))?;
+    std::fs::create_dir_all(&directory)?;
+    std::fs::write(
+        directory.join(file_name),
+        serde_json::to_string_pretty(value)?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::{net::SocketAddr, time::Duration};
+
+use file_yeet_shared::{local_now_fmt, HashBytes};
+
+/// The mDNS service type that publishers announce their files under.
+const SERVICE_TYPE: &str = "_file-yeet._udp.local.";
+
+/// The TXT record property holding the hex hash of an announced file.
+const HASH_PROPERTY: &str = "hash";
+
+/// The TXT record property holding the size of an announced file, in bytes.
+const SIZE_PROPERTY: &str = "size";
+
+/// Announces published files to, and finds the publishers of files on, the local network with mDNS.
+/// Peers found this way are connected to directly, without the server or hole punching.
+#[derive(Clone)]
+pub struct LanDiscovery {
+    daemon: mdns_sd::ServiceDaemon,
+}
+impl LanDiscovery {
+    /// Start the mDNS responder used to announce and find files.
+    pub fn new() -> anyhow::Result<Self> {
+        let daemon = mdns_sd::ServiceDaemon::new()
+            .map_err(|e| anyhow::anyhow!("Failed to start mDNS discovery: {e}"))?;
+        Ok(Self { daemon })
+    }
+
+    /// Announce that a file can be downloaded from the QUIC endpoint on the given port.
+    /// The announcement is withdrawn when the returned value is dropped.
+    pub fn announce(
+        &self,
+        hash: &HashBytes,
+        file_size: u64,
+        port: u16,
+    ) -> anyhow::Result<LanAnnouncement> {
+        let hash_hex = faster_hex::hex_string(hash);
+
+        // Instance names must be unique on the network, even when several clients announce the same file.
+        let instance = format!("{}-{:08x}", &hash_hex[..16], rand::random::<u32>());
+        let host_name = format!("file-yeet-{:08x}.local.", rand::random::<u32>());
+        let size = file_size.to_string();
+        let properties = [
+            (HASH_PROPERTY, hash_hex.as_str()),
+            (SIZE_PROPERTY, size.as_str()),
+        ];
+        let service = mdns_sd::ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host_name,
+            "",
+            port,
+            &properties[..],
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to describe the mDNS announcement: {e}"))?
+        .enable_addr_auto();
+
+        let fullname = service.get_fullname().to_owned();
+        self.daemon.register(service).map_err(|e| {
+            anyhow::anyhow!("Failed to announce the file on the local network: {e}")
+        })?;
+        Ok(LanAnnouncement {
+            daemon: self.daemon.clone(),
+            fullname,
+        })
+    }
+
+    /// Look for publishers of a file on the local network for the given duration.
+    /// Returns the address and file size of each publisher reachable from an endpoint of the given IP version.
+    pub async fn find_publishers(
+        &self,
+        hash: &HashBytes,
+        using_ipv4: bool,
+        duration: Duration,
+    ) -> Vec<(SocketAddr, u64)> {
+        let receiver = match self.daemon.browse(SERVICE_TYPE) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to search the local network: {e}",
+                    local_now_fmt()
+                );
+                return Vec::new();
+            }
+        };
+        let hash_hex = faster_hex::hex_string(hash);
+
+        let mut publishers = Vec::new();
+        let deadline = tokio::time::sleep(duration);
+        tokio::pin!(deadline);
+        loop {
+            let event = tokio::select! {
+                () = &mut deadline => break,
+                event = receiver.recv_async() => event,
+            };
+            let Ok(event) = event else {
+                break;
+            };
+            let mdns_sd::ServiceEvent::ServiceResolved(service) = event else {
+                continue;
+            };
+            if service.get_property_val_str(HASH_PROPERTY) != Some(hash_hex.as_str()) {
+                continue;
+            }
+            let Some(file_size) = service
+                .get_property_val_str(SIZE_PROPERTY)
+                .and_then(|s| s.parse().ok())
+            else {
+                continue;
+            };
+
+            // Prefer a single address per publisher, so it isn't connected to more than once.
+            let address = service
+                .get_addresses()
+                .iter()
+                .copied()
+                .find(|ip| ip.is_ipv4() == using_ipv4);
+            if let Some(ip) = address {
+                let address = SocketAddr::new(ip, service.get_port());
+                if !publishers.iter().any(|&(a, _)| a == address) {
+                    publishers.push((address, file_size));
+                }
+            }
+        }
+
+        if let Err(e) = self.daemon.stop_browse(SERVICE_TYPE) {
+            eprintln!(
+                "{} Failed to stop searching the local network: {e}",
+                local_now_fmt()
+            );
+        }
+        publishers
+    }
+}
+
+impl std::fmt::Debug for LanDiscovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LanDiscovery").finish_non_exhaustive()
+    }
+}
+
+/// A file announced on the local network, withdrawn when dropped.
+pub struct LanAnnouncement {
+    daemon: mdns_sd::ServiceDaemon,
+    fullname: String,
+}
+impl Drop for LanAnnouncement {
+    fn drop(&mut self) {
+        // The responder says goodbye to the network in the background, so the result isn't awaited.
+        let _ = self.daemon.unregister(&self.fullname);
+    }
+}
@@ -0,0 +1,3676 @@
+//! The connection, hole punching, and transfer logic of the `file_yeet` client, for embedding file transfers in other applications.
+//!
+//! Connect to a rendezvous server with [`prepare_server_connection`]. Then either [`publish`] a file hash and
+//! serve each peer the server introduces with [`upload_to_peer`], or [`subscribe`] to a hash and download it
+//! from the peers publishing it with [`download_from_peer`] or [`download_from_peers`].
+//! Peers are reached directly with [`udp_holepunch`], or through the server with [`relay_request`].
+//!
+//! ```no_run
+//! use file_yeet_client_core::{FileYeetCommandType, PortMappingConfig};
+//! use file_yeet_shared::{FileHash, DEFAULT_PORT, MAX_SERVER_COMMUNICATION_SIZE};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let hash: FileHash = "b3-0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef".parse()?;
+//! let mut bb = bytes::BytesMut::with_capacity(MAX_SERVER_COMMUNICATION_SIZE);
+//! let connection = file_yeet_client_core::prepare_server_connection(
+//!     Some("example.com"),
+//!     DEFAULT_PORT,
+//!     None,
+//!     PortMappingConfig::None,
+//!     None,
+//!     None,
+//!     None,
+//!     &file_yeet_client_core::ServerTrust::FirstUse,
+//!     None,
+//!     &mut bb,
+//! )
+//! .await?;
+//!
+//! // Download the file from the first publisher that can be reached.
+//! let peers = file_yeet_client_core::subscribe(&connection.server_connection, &mut bb, hash, "").await?;
+//! for (peer_address, file_size, _) in peers {
+//!     let Some((_, mut peer_streams)) = file_yeet_client_core::udp_holepunch(
+//!         FileYeetCommandType::Sub,
+//!         hash.bytes,
+//!         None,
+//!         connection.endpoint.clone(),
+//!         peer_address,
+//!     )
+//!     .await
+//!     else {
+//!         continue;
+//!     };
+//!     let output = std::path::Path::new("downloaded_file");
+//!     file_yeet_client_core::download_from_peer(hash, &mut peer_streams, file_size, output, &mut bb, None)
+//!         .await?;
+//!     break;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    num::{NonZeroU16, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::BufMut as _;
+use file_yeet_shared::{
+    local_now_fmt, BiStream, BufferPool, FileHash, FileHasher, HashAlgorithm, HashBytes,
+    LookupStatus, PeerAddr, PooledBuffer, PublishControl, RelayRole, ServerCapabilities,
+    ServerNotification, SocketAddrHelper, MAX_SERVER_COMMUNICATION_SIZE, PUBLISH_HEARTBEAT,
+    PUBLISH_REFRESH, PUBLISH_SIGNATURE_LENGTH, PUBLISH_TRACE_CONTEXT, RELAY_OFFER,
+    SERVER_MESSAGE_BUFFERS,
+};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+    sync::watch,
+};
+
+pub mod dht;
+mod hash_cache;
+pub mod identity;
+pub mod lan;
+mod relay;
+#[cfg(feature = "otel")]
+mod telemetry;
+pub mod throttle;
+
+use crate::throttle::{Direction, Pacer};
+
+/// Use a sane default timeout for server connections.
+pub const SERVER_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Sane default timeout for listening for a peer.
+pub const PEER_LISTEN_TIMEOUT: Duration = Duration::from_secs(3);
+/// Sane default timeout for peer connection attempts. Should try to connect for a longer time than listening.
+pub const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// How long to wait for an existing peer connection to acknowledge a probe before considering it dead.
+pub const PEER_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Define a sane number of maximum retries.
+pub const MAX_PEER_CONNECTION_RETRIES: usize = 3;
+
+/// How often to re-ping the server to detect changes to our external address.
+pub const SOCKET_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to poll the default network route for changes, such as a VPN going up or down.
+pub const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The wait before the first background retry of a port mapping that failed at connect time.
+/// Doubled after each failed retry.
+pub const PORT_MAPPING_RETRY_BACKOFF: Duration = Duration::from_secs(15);
+
+/// The longest wait between background retries of a port mapping.
+pub const MAX_PORT_MAPPING_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
+/// The maximum time to wait for a stale port mapping to be removed.
+pub const PORT_MAPPING_DROP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the peers returned by a subscribe request are reused before asking the server again.
+pub const SUBSCRIBE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// The most subscribe requests made to list a file's publishers, so that a misbehaving server can't keep us paging forever.
+pub const MAX_SUBSCRIBE_PAGES: usize = 64;
+
+/// Recent subscribe results keyed by the server connection's stable ID, the room, and the file hash.
+type SubscribeCache =
+    HashMap<(usize, String, FileHash), (Instant, Vec<(SocketAddr, u64, FileMetadata)>)>;
+static SUBSCRIBE_CACHE: once_cell::sync::Lazy<Mutex<SubscribeCache>> =
+    once_cell::sync::Lazy::new(Mutex::default);
+
+/// Define the maximum size of a payload for peer communication.
+/// QUIC may choose to fragment the payload when sending raw packets, but this isn't a concern.
+/// The limit is mainly meant to set reasonable memory usage for a stream.
+pub const MAX_PEER_COMMUNICATION_SIZE: usize = 16 * 1024;
+
+/// The number of full frames an upload reads from the file and hands to the stream at once.
+const UPLOAD_BATCH_FRAMES: usize = 16;
+
+/// Buffers for the batches of file data read by uploads, recycled between concurrent uploads.
+static UPLOAD_BUFFERS: BufferPool =
+    BufferPool::new(UPLOAD_BATCH_FRAMES * MAX_PEER_COMMUNICATION_SIZE, 64);
+
+/// The default size of the reads made while hashing a file. Large reads let BLAKE3 hash on several threads
+/// and keep the number of reads low on fast storage.
+const DEFAULT_HASH_READ_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// The smallest read size allowed while hashing a file, below which the cost of each read dominates.
+const MIN_HASH_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The size of the reads made while hashing a file, set with `set_hash_read_buffer_size`.
+static HASH_READ_BUFFER_SIZE: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(DEFAULT_HASH_READ_BUFFER_SIZE);
+
+/// The size of the chunks a file is hashed in so that downloaded ranges can be verified as they arrive.
+pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// The extension added to a download's path while it is incomplete or unverified.
+pub const PARTIAL_DOWNLOAD_EXTENSION: &str = "fyeet-part";
+
+/// The extension added to a download's path for the record of how much of its partial file has been written.
+pub const PARTIAL_PROGRESS_EXTENSION: &str = "fyeet-progress";
+
+/// Chunks are hashed with BLAKE3 whatever the file's algorithm, to keep the extra hashing cheap.
+/// Chunk hashes only catch corrupt ranges early; the whole file is still verified against its own hash.
+const CHUNK_HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+/// A range start no file can have, sent to ask a publishing peer for its chunk hashes before requesting a range.
+const CHUNK_HASHES_REQUEST: u64 = u64::MAX;
+
+/// The stream error code a publisher resets a peer's stream with when the peer doesn't prove it knows the passphrase.
+const PASSPHRASE_REJECTED_CODE: quinn::VarInt = quinn::VarInt::from_u32(1);
+
+/// The label of the keying material a passphrase proof is bound to, so that a proof can't be replayed on another connection.
+const PASSPHRASE_PROOF_LABEL: &[u8] = b"file_yeet publish passphrase";
+
+/// The BLAKE3 key derivation context passphrases are turned into proof keys with.
+const PASSPHRASE_KEY_CONTEXT: &str = "file_yeet 2024-06 publish passphrase proof key";
+
+/// How long an uploader waits on a stalled disk read before telling the peer it is still alive.
+pub const PEER_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a downloader waits for any frame, data or keep-alive, before assuming the peer is gone.
+pub const PEER_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest a transfer may go without progress, even while the peer reports that it is alive.
+pub const MAX_PEER_STALL_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Specify whether any existing port forwarding can be used or if a new mapping should be attempted.
+pub enum PortMappingConfig {
+    None,
+    PortForwarding(NonZeroU16),
+    PcpNatPmp(Option<crab_nat::PortMapping>),
+}
+
+/// An inclusive range of local UDP ports to bind our QUIC endpoint to, e.g., `50000-50100`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PortRange {
+    pub start: NonZeroU16,
+    pub end: NonZeroU16,
+}
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Every port in the internal port range was already bound by another socket.
+#[derive(Debug, thiserror::Error)]
+#[error("Every port in the internal port range {0} is already in use, possibly by another file_yeet instance or application")]
+pub struct PortRangeInUse(pub PortRange);
+
+/// Parse a port range like `50000-50100`. A single port is a range of one.
+pub fn parse_port_range(s: &str) -> Result<PortRange, String> {
+    let parse_port = |p: &str| {
+        p.trim()
+            .parse::<NonZeroU16>()
+            .map_err(|e| format!("Invalid port {p:?}: {e}"))
+    };
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+        None => {
+            let port = parse_port(s)?;
+            (port, port)
+        }
+    };
+    if start > end {
+        return Err(format!("The port range {start}-{end} is empty"));
+    }
+    Ok(PortRange { start, end })
+}
+
+/// Parse a room name, which must fit in the `u8` length it is sent with.
+pub fn parse_room(s: &str) -> Result<String, String> {
+    if s.len() > file_yeet_shared::MAX_ROOM_NAME_LENGTH {
+        return Err(format!(
+            "Room names can be at most {} bytes",
+            file_yeet_shared::MAX_ROOM_NAME_LENGTH
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+/// Append a room to a request as a `u8` length and UTF-8 string.
+fn put_room(bb: &mut bytes::BytesMut, room: &str) -> anyhow::Result<()> {
+    let room_len = u8::try_from(room.len())
+        .map_err(|_| anyhow::anyhow!("The room name {room:?} is too long"))?;
+    bb.put_u8(room_len);
+    bb.put(room.as_bytes());
+    Ok(())
+}
+
+/// Start a request with the trace context of the current span, if we export spans and the server accepts it.
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn put_trace_context(
+    bb: &mut bytes::BytesMut,
+    server_connection: &quinn::Connection,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "otel")]
+    if telemetry::is_traced(server_connection) {
+        let trace_parent = telemetry::current_trace_parent();
+        bb.put_u16(file_yeet_shared::ClientApiRequest::Traced as u16);
+        bb.put_u8(u8::try_from(trace_parent.len())?);
+        bb.put(trace_parent.as_bytes());
+    }
+    Ok(())
+}
+
+/// Hints a publisher attaches to a file about how subscribers should save it. Empty strings mean no hint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub file_name: String,
+    pub mime_type: String,
+
+    /// The publisher's signature over the file hash and size, if they signed the publish.
+    pub signature: Option<identity::PublishSignature>,
+}
+impl FileMetadata {
+    /// Describe a file by its name, guessing its MIME type from the extension.
+    pub fn from_path(path: &Path) -> Self {
+        Self {
+            file_name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            mime_type: mime_guess::from_path(path)
+                .first_raw()
+                .unwrap_or_default()
+                .to_owned(),
+            signature: None,
+        }
+    }
+
+    /// Sign the publish of a file hash and size so that subscribers can verify it came from us.
+    #[must_use]
+    pub fn signed(
+        mut self,
+        key: &identity::PublisherKey,
+        hash: &HashBytes,
+        file_size: u64,
+    ) -> Self {
+        self.signature = Some(key.sign(hash, file_size));
+        self
+    }
+
+    /// The fingerprint of the publisher's key, if they signed this file hash and size with it.
+    pub fn verified_publisher(&self, hash: &HashBytes, file_size: u64) -> Option<String> {
+        self.signature
+            .filter(|signature| signature.verify(hash, file_size))
+            .map(|signature| signature.fingerprint())
+    }
+
+    /// The file name to save a download as, if the publisher gave one that is safe to use.
+    /// Names that could point outside of the chosen directory are ignored.
+    pub fn safe_file_name(&self) -> Option<&str> {
+        let name = self.file_name.trim();
+        let unsafe_name = name.is_empty()
+            || name == "."
+            || name == ".."
+            || name
+                .chars()
+                .any(|c| matches!(c, '/' | '\\' | ':') || c.is_control());
+        (!unsafe_name).then_some(name)
+    }
+}
+
+/// Append a file metadata hint, cutting off each string at the longest length that can be sent,
+/// followed by the signature if there is one.
+fn put_file_metadata(bb: &mut bytes::BytesMut, metadata: &FileMetadata) {
+    for text in [&metadata.file_name, &metadata.mime_type] {
+        let mut len = text.len().min(file_yeet_shared::MAX_FILE_METADATA_LENGTH);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        bb.put_u8(u8::try_from(len).expect("Metadata length is bounded"));
+        bb.put(&text.as_bytes()[..len]);
+    }
+    match &metadata.signature {
+        Some(signature) => {
+            bb.put_u8(1);
+            bb.put(&signature.to_bytes()[..]);
+        }
+        None => bb.put_u8(0),
+    }
+}
+
+/// The command relationship between the two peers. Useful for asserting synchronization roles based on the command type.
+#[derive(Clone, Copy, Debug)]
+pub enum FileYeetCommandType {
+    Pub,
+    Sub,
+}
+
+/// How the server's certificate is verified when connecting.
+#[derive(Clone, Debug, Default)]
+pub enum ServerTrust {
+    /// Trust the server's certificate the first time we connect, and refuse it if it changes later.
+    #[default]
+    FirstUse,
+
+    /// Require a certificate issued for the server's name by one of the CA certificates in this file.
+    Ca(PathBuf),
+
+    /// Require the certificate with this SHA-256 fingerprint.
+    Fingerprint([u8; 32]),
+}
+
+/// The certificate and private key files to present to servers that require clients to authenticate with one.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A prepared server connection with relevant server connection info.
+#[derive(Clone, Debug)]
+pub struct PreparedConnection {
+    pub endpoint: quinn::Endpoint,
+    pub server_connection: quinn::Connection,
+    pub port_mapping: Option<crab_nat::PortMapping>,
+    pub port_override: Option<NonZeroU16>,
+    pub external_address: String,
+    pub server_notifications: Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+    pub capabilities: ServerCapabilities,
+}
+
+/// Create a QUIC endpoint connected to the server and perform basic setup.
+pub async fn prepare_server_connection(
+    server_address: Option<&str>,
+    server_port: NonZeroU16,
+    suggested_gateway: Option<&str>,
+    port_config: PortMappingConfig,
+    internal_port_range: Option<PortRange>,
+    local_socket_dir: Option<&Path>,
+    auth_token: Option<&str>,
+    server_trust: &ServerTrust,
+    client_certificate: Option<&ClientCertificate>,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<PreparedConnection> {
+    // Use our persistent certificate for the peer communications so that peers can recognize us.
+    let identity = crate::identity::PeerIdentity::load_or_create()?;
+    println!(
+        "{} Using peer identity {}",
+        local_now_fmt(),
+        identity.fingerprint()
+    );
+    let mut server_config =
+        file_yeet_shared::configure_peer_server(identity.cert.clone(), identity.key.clone())?;
+
+    // Set custom keep alive policies.
+    server_config.transport_config(file_yeet_shared::server_transport_config());
+
+    // Get the server address info. Local sockets stand in for loopback addresses.
+    let mut server_socket = file_yeet_shared::get_server_or_default(server_address, server_port)?;
+    if local_socket_dir.is_some() {
+        server_socket.address.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    println!(
+        "{} Connecting to server {} at socket address: {}",
+        local_now_fmt(),
+        server_socket.hostname,
+        server_socket.address,
+    );
+
+    let using_ipv4 = server_socket.address.is_ipv4();
+
+    // Create our QUIC endpoint. Use an unspecified address, and any port unless the user restricted the range.
+    let mut endpoint = match local_socket_dir {
+        #[cfg(all(unix, feature = "unix-socket"))]
+        Some(directory) => {
+            file_yeet_shared::unix_socket::bind_endpoint(directory, None, Some(server_config))?
+        }
+        _ => bind_endpoint(server_config, using_ipv4, internal_port_range)?,
+    };
+
+    // Use an insecure client configuration when connecting to peers, presenting our identity to them.
+    endpoint.set_default_client_config(
+        file_yeet_shared::configure_peer_verification_with_identity(identity.cert, identity.key)?,
+    );
+
+    // Connect to the public file_yeet_server.
+    let connection =
+        connect_to_trusted_server(&endpoint, server_socket, server_trust, client_certificate)
+            .await?;
+
+    // Share debug information about the QUIC endpoints.
+    let local_address = routable_local_address(&endpoint, using_ipv4)?;
+    println!(
+        "{} QUIC endpoint created with local address: {local_address}",
+        local_now_fmt()
+    );
+
+    // Attempt to get a port forwarding, starting with user's override and then attempting NAT-PMP and PCP.
+    let (port_mapping, port_override) = match port_config {
+        // Use a port that is explicitly set by the user without PCP/NAT-PMP.
+        PortMappingConfig::PortForwarding(p) => (None, Some(p)),
+
+        // Attempt PCP and NAT-PMP port mappings to the gateway.
+        // The gateway is only looked up here so that machines without a network route can still connect locally.
+        PortMappingConfig::PcpNatPmp(None) => {
+            let gateway = if let Some(g) = suggested_gateway {
+                g.parse()?
+            } else {
+                default_net::get_default_gateway()
+                    .map_err(|s| anyhow::anyhow!(s))?
+                    .ip_addr
+            };
+            match try_port_mapping(gateway, local_address).await {
+                Ok(m) => {
+                    let p = m.external_port();
+                    println!(
+                        "{} Success mapping external port {p} -> internal {}",
+                        local_now_fmt(),
+                        m.internal_port(),
+                    );
+                    (Some(m), Some(p))
+                }
+                Err(e) => {
+                    eprintln!("{} Failed to create a port mapping: {e}", local_now_fmt());
+                    (None, None)
+                }
+            }
+        }
+
+        // Re-using existing port mapping.
+        PortMappingConfig::PcpNatPmp(Some(m)) => {
+            let p = m.external_port();
+            (Some(m), Some(p))
+        }
+
+        PortMappingConfig::None => (None, None),
+    };
+
+    let (external_address, server_notifications, capabilities) =
+        start_server_session(&connection, local_address, port_override, auth_token, bb).await?;
+    Ok(PreparedConnection {
+        endpoint,
+        server_connection: connection,
+        port_mapping,
+        port_override,
+        external_address,
+        server_notifications,
+        capabilities,
+    })
+}
+
+/// Connect to another server from the endpoint of an existing connection, so that peers introduced by
+/// either server reach the same port. The port mapping stays with the existing connection.
+pub async fn prepare_additional_server_connection(
+    prepared_connection: &PreparedConnection,
+    server_address: &str,
+    server_port: NonZeroU16,
+    auth_token: Option<&str>,
+    server_trust: &ServerTrust,
+    client_certificate: Option<&ClientCertificate>,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<PreparedConnection> {
+    let PreparedConnection {
+        endpoint,
+        port_override,
+        ..
+    } = prepared_connection;
+    let using_ipv4 = endpoint.local_addr()?.is_ipv4();
+    let server_socket = file_yeet_shared::get_server_or_default(Some(server_address), server_port)?;
+    if server_socket.address.is_ipv4() != using_ipv4 {
+        anyhow::bail!(
+            "The server at {} uses a different IP version than the first server",
+            server_socket.address
+        );
+    }
+    println!(
+        "{} Connecting to server {} at socket address: {}",
+        local_now_fmt(),
+        server_socket.hostname,
+        server_socket.address,
+    );
+
+    let connection =
+        connect_to_trusted_server(endpoint, server_socket, server_trust, client_certificate)
+            .await?;
+    let local_address = routable_local_address(endpoint, using_ipv4)?;
+    let (external_address, server_notifications, capabilities) =
+        start_server_session(&connection, local_address, *port_override, auth_token, bb).await?;
+    Ok(PreparedConnection {
+        endpoint: endpoint.clone(),
+        server_connection: connection,
+        port_mapping: None,
+        port_override: *port_override,
+        external_address,
+        server_notifications,
+        capabilities,
+    })
+}
+
+/// Connect to the server, verifying its certificate the way the user asked.
+async fn connect_to_trusted_server(
+    endpoint: &quinn::Endpoint,
+    server_socket: SocketAddrHelper,
+    server_trust: &ServerTrust,
+    client_certificate: Option<&ClientCertificate>,
+) -> anyhow::Result<quinn::Connection> {
+    // Without a CA or fingerprint, the server's certificate is checked against the one we trusted on first use once connected.
+    // Present the client certificate, if given, to servers that only accept authenticated clients.
+    let client_certificate = client_certificate
+        .map(|c| file_yeet_shared::certificates::CertificateAndKey::load(&c.cert, &c.key))
+        .transpose()?;
+    let server_client_config = match server_trust {
+        ServerTrust::FirstUse => {
+            file_yeet_shared::configure_server_verification_on_first_use(client_certificate)?
+        }
+        ServerTrust::Ca(path) => file_yeet_shared::configure_server_verification_with_roots(
+            file_yeet_shared::certificates::load_root_store(path)?,
+            client_certificate,
+        )?,
+        ServerTrust::Fingerprint(fingerprint) => {
+            file_yeet_shared::configure_server_verification_with_fingerprint(
+                *fingerprint,
+                client_certificate,
+            )?
+        }
+    };
+
+    let server_name = format!(
+        "{}:{}",
+        server_socket.hostname.to_ascii_lowercase(),
+        server_socket.address.port()
+    );
+    let connection = connect_to_server(server_socket, endpoint, server_client_config).await?;
+    if let ServerTrust::FirstUse = server_trust {
+        verify_server_identity(&connection, &server_name)?;
+    }
+    Ok(connection)
+}
+
+/// The address of the endpoint on our local network, in place of the unspecified address it is bound to.
+fn routable_local_address(
+    endpoint: &quinn::Endpoint,
+    using_ipv4: bool,
+) -> anyhow::Result<SocketAddr> {
+    let mut local_address = endpoint
+        .local_addr()
+        .expect("Failed to get the local address of our QUIC endpoint");
+    if local_address.ip().is_unspecified() {
+        local_address.set_ip(probe_local_address(using_ipv4)?);
+    }
+    Ok(local_address)
+}
+
+/// Learn how a newly connected server sees us, confirm our port with it, and tell it what it needs to introduce us.
+/// Returns our external address, the server's notification stream, and its capabilities.
+async fn start_server_session(
+    connection: &quinn::Connection,
+    local_address: SocketAddr,
+    port_override: Option<NonZeroU16>,
+    auth_token: Option<&str>,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<(
+    String,
+    Arc<tokio::sync::Mutex<quinn::RecvStream>>,
+    ServerCapabilities,
+)> {
+    // Read the server's response to the sanity check.
+    let (mut sanity_check_addr, sanity_check) = socket_ping_request(connection).await?;
+    println!("{} Server sees us as {sanity_check}", local_now_fmt());
+
+    if let Some(port) = port_override {
+        // Always confirm the port with the server, even if it already sees us through it,
+        // since servers may require a port override before accepting publishes.
+        port_override_request(connection, port, bb).await?;
+        sanity_check_addr.set_port(port.get());
+    }
+
+    // Let the server push notifications to us for the rest of the session.
+    let server_notifications = open_notification_stream(connection).await?;
+
+    // Learn which optional features the server has, such as whether it keeps any trace of us.
+    let capabilities = server_capabilities_request(connection).await?;
+    println!("{} Server capabilities: {capabilities}", local_now_fmt());
+    #[cfg(feature = "otel")]
+    telemetry::note_capabilities(connection, capabilities);
+
+    // Let peers behind the same public IP reach us over our local network instead of through our router.
+    if capabilities.contains(ServerCapabilities::PRIVATE_ADDRESSES)
+        && local_address.ip() != sanity_check_addr.ip()
+    {
+        private_address_request(connection, local_address, bb).await?;
+    }
+
+    // Present our access token before making any requests the server may restrict.
+    if let Some(token) = auth_token {
+        authenticate(connection, token, bb).await?;
+    } else if capabilities.contains(ServerCapabilities::AUTH_REQUIRED) {
+        eprintln!(
+            "{} The server requires an access token to publish or download",
+            local_now_fmt()
+        );
+    }
+
+    Ok((
+        sanity_check_addr.to_string(),
+        Arc::new(tokio::sync::Mutex::new(server_notifications)),
+        capabilities,
+    ))
+}
+
+/// Bind a QUIC endpoint to the unspecified address, trying each port in the range in order until one succeeds.
+fn bind_endpoint(
+    server_config: quinn::ServerConfig,
+    using_ipv4: bool,
+    port_range: Option<PortRange>,
+) -> anyhow::Result<quinn::Endpoint> {
+    let bind_address = |port| {
+        if using_ipv4 {
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+        } else {
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
+        }
+    };
+
+    let Some(range) = port_range else {
+        return Ok(quinn::Endpoint::server(server_config, bind_address(0))?);
+    };
+    let mut all_in_use = true;
+    for port in range.start.get()..=range.end.get() {
+        match quinn::Endpoint::server(server_config.clone(), bind_address(port)) {
+            Ok(endpoint) => return Ok(endpoint),
+            Err(e) => {
+                eprintln!("{} Failed to bind to port {port}: {e}", local_now_fmt());
+                all_in_use &= e.kind() == std::io::ErrorKind::AddrInUse;
+            }
+        }
+    }
+
+    // Distinguish port conflicts so that the user can be offered a way around them.
+    if all_in_use {
+        return Err(PortRangeInUse(range).into());
+    }
+    anyhow::bail!("Failed to bind to any port in the range {range}")
+}
+
+/// Move an endpoint to a fresh UDP socket after the network route changes, such as when a VPN goes up or down.
+/// Its connections migrate to the new socket, which the server follows, instead of waiting on a path that may be dead.
+/// The endpoint returns to its original port when it can, so that port mappings and overrides stay valid.
+/// Returns the local port the endpoint is bound to afterwards.
+pub fn rebind_endpoint(endpoint: &quinn::Endpoint) -> anyhow::Result<u16> {
+    let original_address = endpoint.local_addr()?;
+    let unspecified_address = SocketAddr::new(original_address.ip(), 0);
+
+    // The old socket holds our port until it is replaced, so move through a temporary socket first.
+    let temporary_socket = std::net::UdpSocket::bind(unspecified_address)?;
+    let temporary_port = temporary_socket.local_addr()?.port();
+    endpoint.rebind(temporary_socket)?;
+
+    match std::net::UdpSocket::bind(original_address) {
+        Ok(socket) => {
+            endpoint.rebind(socket)?;
+            Ok(original_address.port())
+        }
+        Err(e) => {
+            eprintln!(
+                "{} Failed to rebind to port {}, using port {temporary_port} instead: {e}",
+                local_now_fmt(),
+                original_address.port(),
+            );
+            Ok(temporary_port)
+        }
+    }
+}
+
+/// Bind a QUIC endpoint for peers that connect directly, without the server introducing them,
+/// such as peers found on the local network or the DHT.
+/// It is kept apart from the endpoint used with a server, so that these peers never race a hole punch.
+pub fn bind_peer_endpoint(
+    using_ipv4: bool,
+    port_range: Option<PortRange>,
+) -> anyhow::Result<quinn::Endpoint> {
+    let identity = crate::identity::PeerIdentity::load_or_create()?;
+    let mut server_config =
+        file_yeet_shared::configure_peer_server(identity.cert.clone(), identity.key.clone())?;
+    server_config.transport_config(file_yeet_shared::server_transport_config());
+
+    let mut endpoint = bind_endpoint(server_config, using_ipv4, port_range)?;
+    endpoint.set_default_client_config(
+        file_yeet_shared::configure_peer_verification_with_identity(identity.cert, identity.key)?,
+    );
+    Ok(endpoint)
+}
+
+/// Accept the next peer that connects to an endpoint from `bind_peer_endpoint`.
+/// Returns `None` once the endpoint is closed.
+pub async fn accept_direct_peer(endpoint: &quinn::Endpoint) -> Option<quinn::Connection> {
+    loop {
+        match endpoint.accept().await?.await {
+            Ok(connection) => {
+                println!(
+                    "{} Accepted a peer at {}",
+                    local_now_fmt(),
+                    connection.remote_address()
+                );
+                return Some(connection);
+            }
+            Err(e) => eprintln!("{} Failed to accept a peer: {e}", local_now_fmt()),
+        }
+    }
+}
+
+/// Connect directly to a publisher that can be reached without hole punching, and ask it for the file.
+pub async fn connect_to_publisher(
+    endpoint: quinn::Endpoint,
+    peer_address: SocketAddr,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+) -> Option<(quinn::Connection, BiStream)> {
+    let connection = tokio::time::timeout(
+        PEER_CONNECT_TIMEOUT,
+        connect_to_peer(endpoint, peer_address),
+    )
+    .await
+    .ok()
+    .flatten()?;
+    let peer_streams =
+        peer_connection_into_stream(&connection, hash, passphrase, FileYeetCommandType::Sub)
+            .await?;
+    Some((connection, peer_streams))
+}
+
+/// The local network route used to reach the server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetworkRoute {
+    pub local_ip: IpAddr,
+    pub gateway: IpAddr,
+}
+
+/// Probe the default interface and gateway so that route changes can be detected.
+pub fn probe_network_route(using_ipv4: bool) -> anyhow::Result<NetworkRoute> {
+    let local_ip = probe_local_address(using_ipv4)?;
+    let gateway = default_net::get_default_gateway()
+        .map_err(|s| anyhow::anyhow!(s))?
+        .ip_addr;
+    Ok(NetworkRoute { local_ip, gateway })
+}
+
+/// Replace a port mapping with a new one against the given gateway and tell the server about our new external port.
+/// The endpoint is bound to an unspecified address, so it does not need to be rebound when the route changes.
+pub async fn renew_port_mapping(
+    server_connection: &quinn::Connection,
+    local_address: SocketAddr,
+    gateway: IpAddr,
+    stale_mapping: Option<crab_nat::PortMapping>,
+) -> anyhow::Result<crab_nat::PortMapping> {
+    // Try to remove the stale mapping, but the old gateway may no longer be reachable.
+    if let Some(mapping) = stale_mapping {
+        match tokio::time::timeout(PORT_MAPPING_DROP_TIMEOUT, mapping.try_drop()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err((e, _))) => eprintln!(
+                "{} Could not remove the stale port mapping: {e}",
+                local_now_fmt()
+            ),
+            Err(_) => eprintln!(
+                "{} Timed out removing the stale port mapping",
+                local_now_fmt()
+            ),
+        }
+    }
+
+    let mapping = try_port_mapping(gateway, local_address)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to create a port mapping: {e}"))?;
+    println!(
+        "{} Success mapping external port {} -> internal {}",
+        local_now_fmt(),
+        mapping.external_port(),
+        mapping.internal_port(),
+    );
+
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+    port_override_request(server_connection, mapping.external_port(), &mut bb).await?;
+
+    Ok(mapping)
+}
+
+/// Helper to determine the default interface's IP address.
+fn probe_local_address(using_ipv4: bool) -> anyhow::Result<IpAddr> {
+    let interface = default_net::get_default_interface()
+        .map_err(|e| anyhow::anyhow!("Failed to get a default interface: {e}"))?;
+    let ip = if using_ipv4 {
+        IpAddr::V4(
+            interface
+                .ipv4
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get a default IPv4 address"))?
+                .addr,
+        )
+    } else {
+        IpAddr::V6(
+            interface
+                .ipv6
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get a default IPv6 address"))?
+                .addr,
+        )
+    };
+
+    Ok(ip)
+}
+
+/// Attempt to create a port mapping using NAT-PMP or PCP.
+async fn try_port_mapping(
+    gateway: IpAddr,
+    local_address: SocketAddr,
+) -> Result<crab_nat::PortMapping, crab_nat::MappingFailure> {
+    crab_nat::PortMapping::new(
+        gateway,
+        local_address.ip(),
+        crab_nat::InternetProtocol::Udp,
+        std::num::NonZeroU16::new(local_address.port()).expect("Socket address has no port"),
+        crab_nat::PortMappingOptions {
+            timeout_config: Some(crab_nat::natpmp::TIMEOUT_CONFIG_DEFAULT),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Connect to the server using QUIC, verifying it with the given client config.
+async fn connect_to_server(
+    server_socket: SocketAddrHelper,
+    endpoint: &quinn::Endpoint,
+    client_config: quinn::ClientConfig,
+) -> anyhow::Result<quinn::Connection> {
+    // Reused error message string.
+    const SERVER_CONNECTION_ERR: &str = "Failed to establish a QUIC connection to the server";
+
+    // Attempt to connect to the server using QUIC.
+    let connection = match tokio::time::timeout(
+        SERVER_CONNECTION_TIMEOUT,
+        endpoint.connect_with(
+            client_config,
+            server_socket.address,
+            server_socket.hostname.as_str(),
+        )?,
+    )
+    .await
+    {
+        Ok(Ok(c)) => c,
+        Ok(Err(e)) => {
+            anyhow::bail!("{SERVER_CONNECTION_ERR}: {e}");
+        }
+        Err(e) => {
+            anyhow::bail!("{SERVER_CONNECTION_ERR}: Timeout {e:#}");
+        }
+    };
+    println!("{} QUIC connection made to the server", local_now_fmt());
+    Ok(connection)
+}
+
+/// Trust the server's certificate the first time we connect to it, and refuse the connection if the server
+/// later presents a different one.
+fn verify_server_identity(connection: &quinn::Connection, server_name: &str) -> anyhow::Result<()> {
+    let fingerprint = crate::identity::peer_fingerprint(connection)
+        .ok_or_else(|| anyhow::anyhow!("The server did not present a certificate"))?;
+    let mut known_servers = crate::identity::KnownServers::load();
+    match known_servers.get(server_name) {
+        Some(expected) if expected == fingerprint => Ok(()),
+        Some(expected) => Err(crate::identity::ServerIdentityChanged {
+            server: server_name.to_owned(),
+            expected: expected.to_owned(),
+            presented: fingerprint,
+        }
+        .into()),
+        None => {
+            println!(
+                "{} Trusting the certificate {fingerprint} of new server {server_name}",
+                local_now_fmt()
+            );
+            known_servers.pin(server_name, &fingerprint);
+            if let Err(e) = known_servers.save() {
+                eprintln!(
+                    "{} Failed to remember the server's certificate: {e}",
+                    local_now_fmt()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Perform a socket ping request to the server and sanity chech the response.
+/// Returns the server's address and the string encoding it was sent as.
+pub async fn socket_ping_request(
+    server_connection: &quinn::Connection,
+) -> anyhow::Result<(SocketAddr, String)> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    // Perform a sanity check by sending the server a socket ping request.
+    // This allows us to verify that the server can determine our public address.
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::SocketPing as u16)
+        .await?;
+
+    // Read the server's response to the sanity check.
+    let string_len = server_streams.recv.read_u16().await?;
+    let sanity_check = expect_server_text(&mut server_streams.recv, string_len).await?;
+    let sanity_check_addr: SocketAddr = sanity_check.parse()?;
+
+    Ok((sanity_check_addr, sanity_check))
+}
+
+/// Re-ping the server and compare the result against the cached external address.
+/// Returns `Some(..)` new external address if it has changed since the last ping.
+pub async fn refresh_external_address(
+    server_connection: &quinn::Connection,
+    cached_address: &str,
+    port_override: Option<NonZeroU16>,
+) -> anyhow::Result<Option<String>> {
+    let (mut address, _) = socket_ping_request(server_connection).await?;
+
+    // Apply the same port override the server was told about.
+    if let Some(port) = port_override {
+        address.set_port(port.get());
+    }
+
+    let address = address.to_string();
+    Ok(if address == cached_address {
+        None
+    } else {
+        Some(address)
+    })
+}
+
+/// Periodically re-ping the server and send our external address to the watching publishes whenever it changes,
+/// so they can bring themselves up to date with [`refresh_publish`]. Runs until the server connection fails.
+pub async fn watch_external_address(
+    server_connection: &quinn::Connection,
+    external_address: &watch::Sender<String>,
+    port_override: Option<NonZeroU16>,
+) {
+    let mut interval = tokio::time::interval(SOCKET_PING_INTERVAL);
+
+    // The first tick completes immediately, skip it since we just pinged the server.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let cached_address = external_address.borrow().clone();
+        match refresh_external_address(server_connection, &cached_address, port_override).await {
+            Ok(Some(new_address)) => {
+                eprintln!(
+                    "{} External address changed from {cached_address} to {new_address}",
+                    local_now_fmt()
+                );
+                external_address.send_replace(new_address);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("{} Failed to re-ping the server: {e}", local_now_fmt());
+                return;
+            }
+        }
+    }
+}
+
+/// Perform a port override request to the server.
+pub async fn port_override_request(
+    server_connection: &quinn::Connection,
+    port: NonZeroU16,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<()> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    // Format a port override request.
+    bb.clear();
+    bb.put_u16(file_yeet_shared::ClientApiRequest::PortOverride as u16);
+    bb.put_u16(port.get());
+
+    // Send the port override request to the server and clear the buffer.
+    server_streams.send.write_all(bb).await?;
+
+    Ok(())
+}
+
+/// Tell the server our address on the local network, for peers behind the same public IP to connect to.
+pub async fn private_address_request(
+    server_connection: &quinn::Connection,
+    local_address: SocketAddr,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<()> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    // Format a private address request.
+    let address = local_address.to_string();
+    bb.clear();
+    bb.put_u16(file_yeet_shared::ClientApiRequest::PrivateAddress as u16);
+    bb.put_u8(u8::try_from(address.len())?);
+    bb.put(address.as_bytes());
+
+    // Send the private address request to the server.
+    server_streams.send.write_all(bb).await?;
+
+    Ok(())
+}
+
+/// Open a stream for the server to push notifications to us.
+pub async fn open_notification_stream(
+    server_connection: &quinn::Connection,
+) -> anyhow::Result<quinn::RecvStream> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection
+        .open_bi()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open a bi-directional QUIC stream for server notifications: {e}"
+            )
+        })?
+        .into();
+
+    // Send the server a notifications request. Only the receiving half is needed afterwards.
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::Notifications as u16)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send a notifications request: {e}"))?;
+
+    Ok(server_streams.recv)
+}
+
+/// Ask the server which optional features and policies it has.
+pub async fn server_capabilities_request(
+    server_connection: &quinn::Connection,
+) -> anyhow::Result<ServerCapabilities> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::Capabilities as u16)
+        .await?;
+
+    let flags = server_streams
+        .recv
+        .read_u32()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the server's capabilities: {e}"))?;
+    Ok(ServerCapabilities(flags))
+}
+
+/// Authenticate with the server using an access token, allowing us to publish and subscribe on servers that require one.
+pub async fn authenticate(
+    server_connection: &quinn::Connection,
+    token: &str,
+    bb: &mut bytes::BytesMut,
+) -> anyhow::Result<()> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    bb.clear();
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Authenticate as u16);
+    bb.put_u16(
+        u16::try_from(token.len())
+            .ok()
+            .filter(|&len| usize::from(len) <= MAX_SERVER_COMMUNICATION_SIZE)
+            .ok_or_else(|| anyhow::anyhow!("The access token is too long"))?,
+    );
+    bb.put(token.as_bytes());
+    server_streams.send.write_all(bb).await?;
+    bb.clear();
+
+    read_lookup_status(&mut server_streams.recv)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to authenticate: {e}"))?;
+    Ok(())
+}
+
+/// Whether the server connection was lost in a way that reconnecting may recover from,
+/// such as the server restarting or the network dropping, rather than being closed by us.
+pub fn is_server_connection_lost(error: &quinn::ConnectionError) -> bool {
+    matches!(
+        error,
+        quinn::ConnectionError::ApplicationClosed(_)
+            | quinn::ConnectionError::ConnectionClosed(_)
+            | quinn::ConnectionError::Reset
+            | quinn::ConnectionError::TimedOut
+    )
+}
+
+/// Whether a failed attempt to connect to a server should move on to the next server in a fallback list.
+/// Local ports already being in use, or a server presenting a changed certificate, need the user's attention instead.
+pub fn should_try_next_server(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<PortRangeInUse>().is_none()
+        && error
+            .downcast_ref::<crate::identity::ServerIdentityChanged>()
+            .is_none()
+}
+
+/// Read the next notification pushed by the server.
+pub async fn read_server_notification(
+    server_recv: &mut quinn::RecvStream,
+) -> anyhow::Result<(ServerNotification, String)> {
+    let kind = server_recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read a notification from the server: {e}"))?;
+    let message_len = server_recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read a notification from the server: {e}"))?;
+    if message_len as usize > MAX_SERVER_COMMUNICATION_SIZE {
+        anyhow::bail!("Server notification length is invalid");
+    }
+
+    // Read the whole message before validating the kind so that the stream stays aligned.
+    let message = expect_server_text(server_recv, message_len).await?;
+    let kind = ServerNotification::try_from(kind)
+        .map_err(|e| anyhow::anyhow!("Unknown server notification kind: {}", e.number))?;
+
+    Ok((kind, message))
+}
+
+/// Perform a publish request to the server, with hints for subscribers about how to save the file.
+/// Only subscribers in the same room will be introduced. The default room has an empty name.
+#[tracing::instrument(skip_all, fields(%hash, room))]
+pub async fn publish(
+    server_connection: &quinn::Connection,
+    mut bb: PooledBuffer,
+    hash: FileHash,
+    file_size: u64,
+    room: &str,
+    metadata: &FileMetadata,
+) -> anyhow::Result<BiStream> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection
+        .open_bi()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open a bi-directional QUIC stream for a socket ping request {e}"
+            )
+        })?
+        .into();
+
+    // Format a publish request.
+    bb.clear();
+    put_trace_context(&mut bb, server_connection)?;
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Publish as u16);
+    bb.put(&hash.bytes[..]);
+    bb.put_u8(hash.algorithm as u8);
+    bb.put_u64(file_size);
+    put_room(&mut bb, room)?;
+    put_file_metadata(&mut bb, metadata);
+
+    // Send the server a publish request.
+    server_streams
+        .send
+        .write_all(&bb)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send a publish request to the server {e}"))?;
+
+    Ok(server_streams)
+}
+
+/// Bring a publish up to date after our external address changed, such as when our ISP renumbered us.
+/// Redoes our port override if we have one, so that the server keeps introducing us by the mapped port,
+/// and otherwise makes the publish again on a new stream in place of the old one.
+pub async fn refresh_publish(
+    server_connection: &quinn::Connection,
+    server_streams: &mut BiStream,
+    port_override: Option<NonZeroU16>,
+    hash: FileHash,
+    file_size: u64,
+    room: &str,
+    metadata: &FileMetadata,
+) -> anyhow::Result<()> {
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+    if let Some(port) = port_override {
+        return port_override_request(server_connection, port, &mut bb).await;
+    }
+
+    let new_streams = publish(server_connection, bb, hash, file_size, room, metadata).await?;
+    let mut old_streams = std::mem::replace(server_streams, new_streams);
+    if let Err(e) = old_streams
+        .send
+        .write_u8(PublishControl::Cancel as u8)
+        .await
+    {
+        eprintln!(
+            "{} Failed to cancel the publish being replaced: {e}",
+            local_now_fmt()
+        );
+    }
+    Ok(())
+}
+
+/// A file hash the server has us publishing, as returned by [`list_publishes`].
+#[derive(Clone, Debug)]
+pub struct RegisteredPublish {
+    pub hash: HashBytes,
+    pub file_size: u64,
+
+    /// The room the hash was published in. Empty for the default room.
+    pub room: String,
+}
+
+/// Ask the server which file hashes it has us publishing, e.g., to check that our publishes survived a reconnect.
+pub async fn list_publishes(
+    server_connection: &quinn::Connection,
+) -> anyhow::Result<Vec<RegisteredPublish>> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::ListPublishes as u16)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send a list publishes request: {e}"))?;
+
+    let server_recv = &mut server_streams.recv;
+    let count = server_recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the number of publishes: {e}"))?;
+    let mut publishes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut hash = HashBytes::default();
+        server_recv.read_exact(&mut hash).await?;
+        let file_size = server_recv.read_u64().await?;
+        let room_len = server_recv.read_u8().await?;
+        let room = expect_server_text(server_recv, u16::from(room_len)).await?;
+        publishes.push(RegisteredPublish {
+            hash,
+            file_size,
+            room,
+        });
+    }
+    Ok(publishes)
+}
+
+/// A subscriber the server introduced to one of our publishes.
+#[derive(Clone, Copy, Debug)]
+pub enum SubscribingPeer {
+    /// A subscriber to connect to directly.
+    Direct(SocketAddr),
+
+    /// A subscriber that couldn't connect directly and asks to be relayed through the server.
+    /// Only offered to clients that opted in to relays.
+    Relay { token: u64, address: SocketAddr },
+}
+
+/// Read a response to a publish request from the server.
+/// Requests to refresh the publish and heartbeats are answered here, so callers only see subscribers.
+/// Each subscriber comes with a span to connect to and serve it in, which continues the subscriber's trace
+/// when the server forwarded it.
+pub async fn read_subscribing_peer(
+    server_streams: &mut BiStream,
+) -> anyhow::Result<(SubscribingPeer, tracing::Span)> {
+    let server_recv = &mut server_streams.recv;
+    let mut data_len = server_recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read response from the server: {e}"))?;
+
+    // Keep the publish alive for as long as the server asks, and show that we can still be reached.
+    // Note the subscriber's trace context if the server sends it ahead of their introduction.
+    let mut trace_parent = String::new();
+    loop {
+        let control = match data_len {
+            PUBLISH_REFRESH => PublishControl::Refresh,
+            PUBLISH_HEARTBEAT => PublishControl::Heartbeat,
+            PUBLISH_TRACE_CONTEXT => {
+                let trace_parent_len = server_recv.read_u8().await?;
+                trace_parent = expect_server_text(server_recv, u16::from(trace_parent_len)).await?;
+                data_len = server_recv
+                    .read_u16()
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to read response from the server: {e}"))?;
+                continue;
+            }
+            _ => break,
+        };
+        server_streams
+            .send
+            .write_u8(control as u8)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to answer the server's {control:?}: {e}"))?;
+        data_len = server_recv
+            .read_u16()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read response from the server: {e}"))?;
+    }
+
+    // A relay offer carries a token ahead of the subscriber's address.
+    let peer = if data_len == RELAY_OFFER {
+        let token = server_recv
+            .read_u64()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read a relay offer from the server: {e}"))?;
+        let address = read_peer_address(server_recv).await?;
+        SubscribingPeer::Relay { token, address }
+    } else {
+        SubscribingPeer::Direct(read_peer_address_of_len(server_recv, data_len).await?)
+    };
+
+    Ok((peer, subscriber_span(peer, &trace_parent)))
+}
+
+/// Start a span to connect to and serve a subscriber in, which continues their trace if the server forwarded it.
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn subscriber_span(peer: SubscribingPeer, trace_parent: &str) -> tracing::Span {
+    let span = tracing::info_span!("subscriber", ?peer);
+    #[cfg(feature = "otel")]
+    telemetry::continue_trace(&span, trace_parent);
+    span
+}
+
+/// Read a peer address as a `u16` length and a UTF-8 string.
+async fn read_peer_address(server_recv: &mut quinn::RecvStream) -> anyhow::Result<SocketAddr> {
+    let data_len = server_recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read response from the server: {e}"))?;
+    read_peer_address_of_len(server_recv, data_len).await
+}
+
+/// Read a peer address of a known length, treating a zero length as the server refusing the request.
+async fn read_peer_address_of_len(
+    server_recv: &mut quinn::RecvStream,
+    data_len: u16,
+) -> anyhow::Result<SocketAddr> {
+    let data_len = data_len as usize;
+    if data_len == 0 {
+        // The server may follow the error with an explanation.
+        match read_server_reason(server_recv).await {
+            Some(reason) => anyhow::bail!("Server refused the request: {reason}"),
+            None => anyhow::bail!("Server encountered and error"),
+        }
+    }
+    if data_len > MAX_SERVER_COMMUNICATION_SIZE {
+        anyhow::bail!("Server response length is invalid");
+    }
+
+    let mut scratch_space = [0; MAX_SERVER_COMMUNICATION_SIZE];
+    let peer_string_bytes = &mut scratch_space[..data_len];
+    if let Err(e) = server_recv.read_exact(peer_string_bytes).await {
+        anyhow::bail!("Failed to read a response from the server: {e}");
+    }
+
+    // Parse the response as a peer socket address or skip this message.
+    let peer_string = match std::str::from_utf8(peer_string_bytes) {
+        Ok(s) => s,
+        Err(e) => anyhow::bail!("Server did not send a valid UTF-8 response: {e}"),
+    };
+    let peer_address = match peer_string.parse() {
+        Ok(addr) => addr,
+        Err(e) => anyhow::bail!("Failed to parse peer address: {e}"),
+    };
+
+    Ok(peer_address)
+}
+
+/// Let the server offer relays to publishes made after this request.
+/// Fails if the server does not relay transfers.
+pub async fn relay_opt_in(server_connection: &quinn::Connection) -> anyhow::Result<()> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::Relay as u16)
+        .await?;
+    server_streams.send.write_u8(RelayRole::OptIn as u8).await?;
+    read_lookup_status(&mut server_streams.recv).await?;
+    Ok(())
+}
+
+/// Ask the server to relay a stream to a publisher we could not connect to directly,
+/// and connect to the publisher through it as we would directly.
+/// Returns the peer connection and a stream ready to download from,
+/// or `None` if the publisher is gone, never accepted, or could not be connected to through the relay.
+pub async fn relay_request(
+    server_connection: &quinn::Connection,
+    bb: &mut bytes::BytesMut,
+    hash: HashBytes,
+    peer: SocketAddr,
+    room: &str,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<(quinn::Connection, BiStream)>> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    let peer_string = peer.to_string();
+    bb.clear();
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Relay as u16);
+    bb.put_u8(RelayRole::Subscribe as u8);
+    bb.put(&hash[..]);
+    bb.put_u8(u8::try_from(peer_string.len())?);
+    bb.put(peer_string.as_bytes());
+    put_room(bb, room)?;
+    server_streams.send.write_all(bb).await?;
+
+    // The server responds once the publisher accepts, or gives up waiting.
+    match read_lookup_status(&mut server_streams.recv).await? {
+        LookupStatus::Found => {
+            relay::connect_through_relay(server_streams, peer, hash, passphrase).await
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Accept a relay the server offered on one of our publishes, and the subscriber's connection through it.
+/// Returns the peer connection and the subscriber's request stream to upload on,
+/// or `None` if the subscriber stopped waiting or could not connect through the relay.
+#[tracing::instrument(skip(server_connection, hash, passphrase))]
+pub async fn relay_accept(
+    server_connection: &quinn::Connection,
+    token: u64,
+    peer: SocketAddr,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<(quinn::Connection, BiStream)>> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    let mut bb = bytes::BytesMut::with_capacity(16);
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Relay as u16);
+    bb.put_u8(RelayRole::Publish as u8);
+    bb.put_u64(token);
+    server_streams.send.write_all(&bb).await?;
+
+    match read_lookup_status(&mut server_streams.recv).await? {
+        LookupStatus::Found => {
+            relay::accept_through_relay(server_streams, peer, hash, passphrase).await
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Try to read the reason the server gave for refusing a request.
+async fn read_server_reason(server_recv: &mut quinn::RecvStream) -> Option<String> {
+    let reason_len = server_recv.read_u16().await.ok()? as usize;
+    if reason_len == 0 || reason_len > MAX_SERVER_COMMUNICATION_SIZE {
+        return None;
+    }
+
+    let mut scratch_space = [0; MAX_SERVER_COMMUNICATION_SIZE];
+    let reason_bytes = &mut scratch_space[..reason_len];
+    server_recv.read_exact(reason_bytes).await.ok()?;
+    std::str::from_utf8(reason_bytes).ok().map(str::to_owned)
+}
+
+/// Perform a subscribe request to the server, reusing recent results for the same hash when possible.
+/// Returns a list of peers that are sharing the file in the given room, the file size they promise to send,
+/// and their hints about how to save it.
+#[tracing::instrument(skip_all, fields(%hash, room))]
+pub async fn subscribe(
+    server_connection: &quinn::Connection,
+    bb: &mut bytes::BytesMut,
+    hash: FileHash,
+    room: &str,
+) -> anyhow::Result<Vec<(SocketAddr, u64, FileMetadata)>> {
+    let key = (server_connection.stable_id(), room.to_owned(), hash);
+    let cached = {
+        let cache = SUBSCRIBE_CACHE
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Subscribe cache lock was poisoned: {e}"))?;
+        cache
+            .get(&key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < SUBSCRIBE_CACHE_TTL)
+            .map(|(_, peers)| peers.clone())
+    };
+
+    // The publishers still need to learn our address to punch through to us,
+    // so ask for a lightweight introduction to each cached peer instead of a full subscribe.
+    if let Some(peers) = cached {
+        let mut introduced = Vec::with_capacity(peers.len());
+        for (peer, file_size, metadata) in peers {
+            if let Ok(true) =
+                introduction_request(server_connection, bb, hash.bytes, peer, room).await
+            {
+                introduced.push((peer, file_size, metadata));
+            }
+        }
+        if !introduced.is_empty() {
+            return Ok(introduced);
+        }
+    }
+
+    let (peers, _) = subscribe_request(server_connection, bb, hash, room, 0, 0).await?;
+
+    // Cache the non-empty results and drop any that have expired.
+    let mut cache = SUBSCRIBE_CACHE
+        .lock()
+        .map_err(|e| anyhow::anyhow!("Subscribe cache lock was poisoned: {e}"))?;
+    cache.retain(|_, (cached_at, _)| cached_at.elapsed() < SUBSCRIBE_CACHE_TTL);
+    if peers.is_empty() {
+        cache.remove(&key);
+    } else {
+        cache.insert(key, (Instant::now(), peers.clone()));
+    }
+
+    Ok(peers)
+}
+
+/// Perform subscribe requests until `max_peers` publishers are found or the server has no more,
+/// for clients that want to download from more publishers than fit in a single response.
+/// Stops early after [`MAX_SUBSCRIBE_PAGES`] requests, or if the server sends an empty page or repeats a cursor.
+/// Unlike [`subscribe`], the results are never cached.
+#[tracing::instrument(skip_all, fields(%hash, room))]
+pub async fn subscribe_many(
+    server_connection: &quinn::Connection,
+    bb: &mut bytes::BytesMut,
+    hash: FileHash,
+    room: &str,
+    max_peers: NonZeroUsize,
+) -> anyhow::Result<Vec<(SocketAddr, u64, FileMetadata)>> {
+    let mut peers = Vec::new();
+    let mut cursor = 0;
+    for _ in 0..MAX_SUBSCRIBE_PAGES {
+        let requested = u16::try_from(max_peers.get() - peers.len()).unwrap_or(u16::MAX);
+        let (page, next_cursor) =
+            subscribe_request(server_connection, bb, hash, room, requested, cursor).await?;
+        let page_is_empty = page.is_empty();
+        peers.extend(page);
+
+        // The server sends a zero cursor once every publisher has been listed.
+        if next_cursor == 0 || peers.len() >= max_peers.get() {
+            return Ok(peers);
+        }
+
+        // A page without publishers, or a cursor that doesn't move, would only repeat itself.
+        if page_is_empty || next_cursor == cursor {
+            eprintln!(
+                "{} The server stopped making progress listing publishers, using the {} found",
+                local_now_fmt(),
+                peers.len()
+            );
+            return Ok(peers);
+        }
+        cursor = next_cursor;
+    }
+
+    eprintln!(
+        "{} Stopped listing publishers after {MAX_SUBSCRIBE_PAGES} pages, using the {} found",
+        local_now_fmt(),
+        peers.len()
+    );
+    Ok(peers)
+}
+
+/// Forget any cached subscribe results for a file hash, e.g., after a download from those peers failed.
+pub fn invalidate_subscribe_cache(hash: &FileHash) {
+    if let Ok(mut cache) = SUBSCRIBE_CACHE.lock() {
+        cache.retain(|(_, _, cached_hash), _| cached_hash != hash);
+    }
+}
+
+/// Ask the server to introduce us to a specific peer publishing a file hash.
+/// Returns whether the peer is still publishing and was introduced.
+async fn introduction_request(
+    server_connection: &quinn::Connection,
+    bb: &mut bytes::BytesMut,
+    hash: HashBytes,
+    peer: SocketAddr,
+    room: &str,
+) -> anyhow::Result<bool> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+
+    let peer_string = peer.to_string();
+    bb.clear();
+    put_trace_context(bb, server_connection)?;
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Introduction as u16);
+    bb.put(&hash[..]);
+    bb.put_u8(u8::try_from(peer_string.len())?);
+    bb.put(peer_string.as_bytes());
+    put_room(bb, room)?;
+    server_streams.send.write_all(bb).await?;
+
+    // The server responds with whether the peer is still publishing and was introduced.
+    Ok(read_lookup_status(&mut server_streams.recv).await? == LookupStatus::Found)
+}
+
+/// Read the status of a subscribe or introduction response.
+/// Throttled and denied requests are returned as errors with the server's reason.
+async fn read_lookup_status(server_recv: &mut quinn::RecvStream) -> anyhow::Result<LookupStatus> {
+    let status = server_recv
+        .read_u8()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the server's response: {e}"))?;
+    let status = LookupStatus::try_from(status)
+        .map_err(|_| anyhow::anyhow!("Server sent an unknown response status {status}"))?;
+    match status {
+        LookupStatus::Found | LookupStatus::NotFound => Ok(status),
+        LookupStatus::Throttled | LookupStatus::Denied => {
+            match read_server_reason(server_recv).await {
+                Some(reason) => anyhow::bail!("{status}: {reason}"),
+                None => anyhow::bail!("{status}"),
+            }
+        }
+    }
+}
+
+/// Ask the server's echo peer to connect to us, validating that peers can reach this client end-to-end.
+/// Returns the round trip time of a payload echoed over the resulting peer connection.
+pub async fn test_introduction(
+    server_connection: &quinn::Connection,
+    endpoint: quinn::Endpoint,
+) -> anyhow::Result<Duration> {
+    let mut server_streams: BiStream = server_connection.open_bi().await?.into();
+    server_streams
+        .send
+        .write_u16(file_yeet_shared::ClientApiRequest::TestIntroduction as u16)
+        .await?;
+
+    // The server responds with the port of its echo peer, or zero if it doesn't host one.
+    let echo_port = server_streams.recv.read_u16().await?;
+    if echo_port == 0 {
+        anyhow::bail!("The server does not host an echo peer");
+    }
+    let echo_address = SocketAddr::new(server_connection.remote_address().ip(), echo_port);
+
+    // Act as the publishing peer, since that is the role that relies on being reachable.
+    let Some((connection, mut peer_streams)) = udp_holepunch(
+        FileYeetCommandType::Pub,
+        file_yeet_shared::ECHO_HASH,
+        None,
+        endpoint,
+        echo_address,
+    )
+    .await
+    else {
+        anyhow::bail!("Could not connect with the server's echo peer at {echo_address}");
+    };
+
+    // Ensure a random payload makes the round trip intact.
+    let payload: [u8; 32] = rand::random();
+    let start = Instant::now();
+    peer_streams.send.write_all(&payload).await?;
+    peer_streams.send.finish().await?;
+    let echo = peer_streams
+        .recv
+        .read_to_end(file_yeet_shared::MAX_ECHO_PAYLOAD_SIZE)
+        .await?;
+    let round_trip = start.elapsed();
+    connection.close(file_yeet_shared::GOODBYE_CODE, &[]);
+
+    if echo != payload {
+        anyhow::bail!("The echo peer responded with an unexpected payload");
+    }
+    Ok(round_trip)
+}
+
+/// Send a subscribe request to the server and read the list of peers in the response,
+/// and the cursor to continue the listing from, which is zero if there are no more peers.
+/// A requested count of zero asks for as many peers as fit in the response.
+async fn subscribe_request(
+    server_connection: &quinn::Connection,
+    bb: &mut bytes::BytesMut,
+    hash: FileHash,
+    room: &str,
+    requested: u16,
+    cursor: u64,
+) -> anyhow::Result<(Vec<(SocketAddr, u64, FileMetadata)>, u64)> {
+    // Create a bi-directional stream to the server.
+    let mut server_streams: BiStream = server_connection
+        .open_bi()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open a bi-directional QUIC stream for a socket ping request: {e}"
+            )
+        })?
+        .into();
+
+    // Send the server a subscribe request.
+    bb.clear();
+    put_trace_context(bb, server_connection)?;
+    bb.put_u16(file_yeet_shared::ClientApiRequest::Subscribe as u16);
+    bb.put(&hash.bytes[..]);
+    bb.put_u8(hash.algorithm as u8);
+    put_room(bb, room)?;
+    bb.put_u16(requested);
+    bb.put_u64(cursor);
+    server_streams
+        .send
+        .write_all(bb)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send a subscribe request to the server: {e}"))?;
+
+    println!(
+        "{} Requesting file with hash from the server...",
+        local_now_fmt()
+    );
+
+    // Determine if the server is responding with a success or failure.
+    if read_lookup_status(&mut server_streams.recv).await? == LookupStatus::NotFound {
+        return Ok((Vec::with_capacity(0), 0));
+    }
+    let response_count = server_streams
+        .recv
+        .read_u16()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read a u16 response from the server: {e}"))?;
+
+    let mut peers = Vec::new();
+
+    // Parse each peer socket address, file size, and file metadata.
+    for _ in 0..response_count {
+        // Read the incoming peer address length.
+        let address_len = server_streams
+            .recv
+            .read_u8()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read response from server: {e}"))?;
+
+        // Read the incoming peer address to memory.
+        let peer_address_str =
+            expect_server_text(&mut server_streams.recv, u16::from(address_len)).await?;
+
+        // Read the incoming file size and the publisher's hints about the file.
+        let file_size = server_streams.recv.read_u64().await?;
+        let file_name_len = server_streams.recv.read_u8().await?;
+        let file_name =
+            expect_server_text(&mut server_streams.recv, u16::from(file_name_len)).await?;
+        let mime_type_len = server_streams.recv.read_u8().await?;
+        let mime_type =
+            expect_server_text(&mut server_streams.recv, u16::from(mime_type_len)).await?;
+        let signature = if server_streams.recv.read_u8().await? == 0 {
+            None
+        } else {
+            let mut signature = [0; PUBLISH_SIGNATURE_LENGTH];
+            server_streams.recv.read_exact(&mut signature).await?;
+            Some(identity::PublishSignature::from_bytes(&signature))
+        };
+
+        // Parse the peer address into a socket address.
+        let peer_address = match peer_address_str.parse() {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to parse peer address {peer_address_str}: {e}",
+                    local_now_fmt()
+                );
+                continue;
+            }
+        };
+
+        peers.push((
+            peer_address,
+            file_size,
+            FileMetadata {
+                file_name,
+                mime_type,
+                signature,
+            },
+        ));
+    }
+    let next_cursor = server_streams.recv.read_u64().await?;
+
+    Ok((peers, next_cursor))
+}
+
+/// Try to read a valid UTF-8 from the server until the expected length is reached.
+async fn expect_server_text(stream: &mut quinn::RecvStream, len: u16) -> anyhow::Result<String> {
+    let mut raw_bytes = [0; MAX_SERVER_COMMUNICATION_SIZE];
+    let expected_slice = &mut raw_bytes[..len as usize];
+    stream.read_exact(expected_slice).await?;
+    Ok(std::str::from_utf8(expected_slice)?.to_owned())
+}
+
+/// Attempt to connect to peer using UDP hole punching.
+/// Publishers with a passphrase only accept subscribers that prove they know it, which subscribers do if given one.
+#[tracing::instrument(skip(hash, passphrase, endpoint))]
+pub async fn udp_holepunch(
+    cmd: FileYeetCommandType,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+    endpoint: quinn::Endpoint,
+    peer_address: SocketAddr,
+) -> Option<(quinn::Connection, BiStream)> {
+    // Spawn a thread that listens for a peer and will set the boolean lock when connected.
+    let endpoint_listen = endpoint.clone();
+    let listen_future = tokio::time::timeout(
+        PEER_LISTEN_TIMEOUT,
+        listen_for_peer(endpoint_listen, peer_address),
+    );
+
+    // Attempt to connect to the peer's public address.
+    let connect_future = tokio::time::timeout(
+        PEER_CONNECT_TIMEOUT,
+        connect_to_peer(endpoint, peer_address),
+    );
+
+    // Return the peer stream if we have one.
+    let (listen_stream, connect_stream) = futures_util::join!(listen_future, connect_future);
+    let listen_stream = listen_stream.ok().flatten();
+    let connect_stream = connect_stream.ok().flatten();
+    let connections =
+        // TODO: It could be interesting and possible to create a more general stream negotiation.
+        //       For example, if each peer sent a random nonce over each stream, and the nonces were XOR'd per stream,
+        //       the result could be used to determine which stream to use (highest/lowest resulting nonce after XOR).
+        match cmd {
+            FileYeetCommandType::Pub => listen_stream.into_iter().chain(connect_stream.into_iter()),
+            FileYeetCommandType::Sub => connect_stream.into_iter().chain(listen_stream.into_iter()),
+        };
+
+    for connection in connections {
+        if let Some(peer_streams) =
+            peer_connection_into_stream(&connection, hash, passphrase, cmd).await
+        {
+            // Let the user know that a connection is established. A bi-directional stream is ready to use.
+            println!("{} Peer connection established", local_now_fmt());
+            return Some((connection, peer_streams));
+        }
+    }
+    None
+}
+
+/// Try to finalize a peer connection attempt by turning it into a bi-directional stream.
+/// The subscriber names the file it wants, followed by its proof of the passphrase if it has one.
+/// A publisher with a passphrase resets the streams of peers whose proof doesn't match.
+pub async fn peer_connection_into_stream(
+    connection: &quinn::Connection,
+    expected_hash: HashBytes,
+    passphrase: Option<&str>,
+    cmd: FileYeetCommandType,
+) -> Option<BiStream> {
+    let streams = match cmd {
+        FileYeetCommandType::Pub => {
+            // Let the downloading peer initiate a bi-directional stream.
+            let mut r = connection.accept_bi().await;
+            if let Ok(s) = &mut r {
+                let mut requested_hash = HashBytes::default();
+                s.1.read_exact(&mut requested_hash).await.ok()?;
+
+                // Ensure the requested hash matches the expected file hash.
+                if requested_hash != expected_hash {
+                    eprintln!(
+                        "{} Peer requested a file with an unexpected hash",
+                        local_now_fmt(),
+                    );
+                    return None;
+                }
+
+                // Read the peer's passphrase proof, if it sent one.
+                let proof = if s.1.read_u8().await.ok()? == 0 {
+                    None
+                } else {
+                    let mut proof = [0; blake3::OUT_LEN];
+                    s.1.read_exact(&mut proof).await.ok()?;
+                    Some(blake3::Hash::from(proof))
+                };
+
+                // Refuse peers that don't prove they know our passphrase. The hash comparison is constant-time.
+                if let Some(passphrase) = passphrase {
+                    let expected = passphrase_proof(connection, &expected_hash, passphrase);
+                    if expected.is_none() || proof != expected {
+                        eprintln!(
+                            "{} Peer did not give the publish passphrase",
+                            local_now_fmt(),
+                        );
+                        s.0.reset(PASSPHRASE_REJECTED_CODE).ok();
+                        s.1.stop(PASSPHRASE_REJECTED_CODE).ok();
+                        return None;
+                    }
+                }
+
+                println!("{} New peer stream accepted", local_now_fmt());
+            }
+            r
+        }
+        FileYeetCommandType::Sub => {
+            // Open a bi-directional stream to the publishing peer.
+            let mut r = connection.open_bi().await;
+            if let Ok(s) = &mut r {
+                let mut request = bytes::BytesMut::with_capacity(
+                    expected_hash.len() + std::mem::size_of::<u8>() + blake3::OUT_LEN,
+                );
+                request.put(&expected_hash[..]);
+                match passphrase {
+                    Some(passphrase) => {
+                        let proof = passphrase_proof(connection, &expected_hash, passphrase)?;
+                        request.put_u8(1);
+                        request.put(&proof.as_bytes()[..]);
+                    }
+                    None => request.put_u8(0),
+                }
+                s.0.write_all(&request).await.ok()?;
+
+                println!("{} New peer stream opened", local_now_fmt());
+            }
+            r
+        }
+    };
+    if let Ok(streams) = streams {
+        Some(streams.into())
+    } else {
+        None
+    }
+}
+
+/// Prove knowledge of a publish passphrase without revealing it: a MAC of the file hash keyed by the passphrase,
+/// bound to this connection's TLS session so that the publisher can't replay it to other publishers.
+fn passphrase_proof(
+    connection: &quinn::Connection,
+    hash: &HashBytes,
+    passphrase: &str,
+) -> Option<blake3::Hash> {
+    let mut binding = [0; blake3::OUT_LEN];
+    connection
+        .export_keying_material(&mut binding, PASSPHRASE_PROOF_LABEL, hash)
+        .ok()?;
+    let key = blake3::derive_key(PASSPHRASE_KEY_CONTEXT, passphrase.as_bytes());
+    let mut mac = blake3::Hasher::new_keyed(&key);
+    mac.update(&binding);
+    mac.update(hash);
+    Some(mac.finalize())
+}
+
+/// Check that an existing peer connection is still responsive before reusing it for another transfer.
+/// Sends an ACK-eliciting datagram and waits briefly for the peer to acknowledge it.
+pub async fn peer_connection_is_healthy(connection: &quinn::Connection) -> bool {
+    if connection.close_reason().is_some() {
+        return false;
+    }
+
+    // Without datagram support there is no way to probe the peer, so trust the connection state.
+    let acks = connection.stats().frame_rx.acks;
+    if connection.max_datagram_size().is_none()
+        || connection
+            .send_datagram(bytes::Bytes::from_static(&[0]))
+            .is_err()
+    {
+        return true;
+    }
+
+    tokio::time::timeout(PEER_HEALTH_CHECK_TIMEOUT, async {
+        while connection.stats().frame_rx.acks <= acks {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Try to reuse an existing peer connection if it is healthy, otherwise establish a new one.
+pub async fn reuse_or_holepunch(
+    existing: Option<quinn::Connection>,
+    cmd: FileYeetCommandType,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+    endpoint: quinn::Endpoint,
+    peer_address: SocketAddr,
+) -> Option<(quinn::Connection, BiStream)> {
+    if let Some(connection) = existing {
+        if peer_connection_is_healthy(&connection).await {
+            return peer_connection_into_stream(&connection, hash, passphrase, cmd)
+                .await
+                .map(|s| (connection, s));
+        }
+        eprintln!(
+            "{} Existing connection to {peer_address} is unresponsive, reconnecting",
+            local_now_fmt()
+        );
+    }
+    udp_holepunch(cmd, hash, passphrase, endpoint, peer_address).await
+}
+
+/// Spawn a thread that listens for a peer and will assign the peer `Connection` lock when connected.
+async fn listen_for_peer(
+    endpoint: quinn::Endpoint,
+    expected_peer: SocketAddr,
+) -> Option<quinn::Connection> {
+    // Print and create binding from the local address.
+    println!(
+        "{} Listening for peer on the QUIC endpoint",
+        local_now_fmt()
+    );
+
+    // Accept a peer connection.
+    let connecting = endpoint.accept().await?;
+
+    // Ensure we are connecting to the expected peer.
+    // TODO: Allow returning an unexpected peer connection to the caller to be routed appropriately.
+    if PeerAddr::from(connecting.remote_address()) != PeerAddr::from(expected_peer) {
+        eprintln!(
+            "{} Peer connection from unexpected address: {}",
+            local_now_fmt(),
+            connecting.remote_address(),
+        );
+        return None;
+    }
+
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!(
+                "{} Failed to accept a peer connection: {e}",
+                local_now_fmt()
+            );
+            return None;
+        }
+    };
+
+    // Connected to a peer on the listening stream, print their address.
+    let peer_addr = connection.remote_address();
+    println!(
+        "{} New connection accepted from peer at: {peer_addr:?}",
+        local_now_fmt()
+    );
+
+    Some(connection)
+}
+
+/// Try to connect to a peer at the given address.
+async fn connect_to_peer(
+    endpoint: quinn::Endpoint,
+    peer_address: SocketAddr,
+) -> Option<quinn::Connection> {
+    // Set a sane number of connection retries.
+    let mut retries = MAX_PEER_CONNECTION_RETRIES;
+
+    // Ensure we have retries left and there isn't already a peer `Connection` to use.
+    while retries > 0 {
+        println!("{} Connecting to peer at {peer_address}", local_now_fmt());
+        match endpoint.connect(peer_address, "peer") {
+            Ok(connecting) => {
+                let connection = match connecting.await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("{} Failed to connect to peer: {e}", local_now_fmt());
+                        retries -= 1;
+                        continue;
+                    }
+                };
+
+                println!("{} Connected to peer at {peer_address}", local_now_fmt());
+                return Some(connection);
+            }
+            Err(e) => {
+                eprintln!("{} Failed to connect to peer: {e}", local_now_fmt());
+                retries -= 1;
+            }
+        }
+    }
+
+    None
+}
+
+/// Errors that may occur when downloading a file from a peer.
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("An I/O error occurred: {0}")]
+    IoError(std::io::Error),
+    #[error("A write error occurred: {0}")]
+    WriteError(quinn::WriteError),
+    #[error("The downloaded file hash does not match the expected hash")]
+    HashMismatch,
+    #[error("The peer stopped sending data for too long")]
+    Stalled,
+    #[error("No peers were left to download the remaining {0} bytes from")]
+    PeersExhausted(u64),
+    #[error("Chunk {0} of the download does not match the hash the peer shared")]
+    ChunkMismatch(u64),
+    #[error("The publisher requires a passphrase, and the one given was missing or wrong")]
+    PassphraseRejected,
+}
+impl DownloadError {
+    /// Report a failed read from the peer, recognizing a publisher that refused our passphrase.
+    fn from_read(e: std::io::Error) -> Self {
+        let rejected = e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<quinn::ReadError>())
+            .is_some_and(
+                |e| matches!(e, quinn::ReadError::Reset(code) if *code == PASSPHRASE_REJECTED_CODE),
+            );
+        if rejected {
+            Self::PassphraseRejected
+        } else {
+            Self::IoError(e)
+        }
+    }
+}
+
+/// Download a file from the peer. Initiates the download by consenting to the peer to receive the file.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(skip_all, fields(%hash, file_size))]
+pub async fn download_from_peer(
+    hash: FileHash,
+    peer_streams: &mut BiStream,
+    file_size: u64,
+    output_path: &Path,
+    bb: &mut bytes::BytesMut,
+    byte_progress: Option<watch::Sender<f32>>,
+) -> Result<(), DownloadError> {
+    // Open the partial file for writing, leaving any file at the output path alone until the download is verified.
+    let partial_path = partial_download_path(output_path);
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&partial_path)
+        .await
+        .map_err(DownloadError::IoError)?;
+
+    // Record that nothing is written yet before the file takes its full length,
+    // so that an interrupted download is never mistaken for a complete one.
+    let progress = ProgressRecord::create(output_path, 0)
+        .await
+        .map_err(DownloadError::IoError)?;
+
+    // Reserve the whole file up front so that a full disk is reported before any data is transferred.
+    preallocate(&file, file_size)
+        .await
+        .map_err(DownloadError::IoError)?;
+    let mut file = PartialFile {
+        file,
+        progress: Some(progress),
+    };
+
+    // Here we want the entire file, and hash it as it arrives.
+    let file_size_f = file_size as f32;
+    let mut hasher = FileHasher::new(hash.algorithm);
+    let range = DownloadRange::new(0, file_size);
+    let result = receive_range(
+        peer_streams,
+        &mut file,
+        &range,
+        bb,
+        |data, bytes_written| {
+            hasher.update(data);
+
+            // Update the caller with the number of bytes written.
+            if let Some(progress) = byte_progress.as_ref() {
+                progress.send_replace(bytes_written as f32 / file_size_f);
+            }
+            Ok(())
+        },
+    )
+    .await;
+    if let Err(e) = result {
+        // Record exactly where the download stopped, ready to be resumed.
+        file.record_stop(range.received()).await;
+        return Err(e);
+    }
+    file.file.flush().await.map_err(DownloadError::IoError)?;
+    drop(file);
+
+    // Ensure the file hash is correct before moving it into place.
+    finish_download(&partial_path, output_path, hash.bytes == hasher.finalize()).await
+}
+
+/// Request a range of the file from the peer and write the framed data they send to the file's current position.
+/// `on_data` sees each chunk of data before it is written, along with the range's total bytes received after it.
+/// The range tracks the bytes written so far, so callers know where a failed range left off.
+/// Receiving stops early if the end of the range moves closer, once every byte up to the new end is written.
+async fn receive_range(
+    peer_streams: &mut BiStream,
+    PartialFile { file, progress }: &mut PartialFile,
+    range: &DownloadRange,
+    bb: &mut bytes::BytesMut,
+    mut on_data: impl FnMut(&[u8], u64) -> Result<(), DownloadError>,
+) -> Result<(), DownloadError> {
+    // Let the peer know which range we want to download using this QUIC stream.
+    let range_length = range.length();
+    bb.clear();
+    bb.put_u64(range.start);
+    bb.put_u64(range_length);
+    peer_streams
+        .send
+        .write(bb)
+        .await
+        .map_err(DownloadError::WriteError)?;
+    peer_streams
+        .send
+        .finish()
+        .await
+        .map_err(|e| DownloadError::IoError(e.into()))?;
+
+    // Create a scratch space for reading data from the stream.
+    let mut buf = [0; MAX_PEER_COMMUNICATION_SIZE];
+    // Read from the peer and write to the file, no faster than the bandwidth limits allow.
+    let pacer = Pacer::new(Direction::Download);
+    let mut last_progress = Instant::now();
+    let mut received = range.received();
+    while received < range.length() {
+        // Each frame starts with the length of its data. A zero length is a keep-alive frame.
+        let size =
+            match tokio::time::timeout(PEER_FRAME_TIMEOUT, peer_streams.recv.read_u16()).await {
+                Ok(Ok(size)) => usize::from(size),
+                Ok(Err(e)) => {
+                    return Err(match DownloadError::from_read(e) {
+                        DownloadError::IoError(e) => DownloadError::IoError(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!("Peer closed the upload early: {e}"),
+                        )),
+                        e => e,
+                    })
+                }
+                Err(_) => return Err(DownloadError::Stalled),
+            };
+        if size > MAX_PEER_COMMUNICATION_SIZE || (size as u64) > range_length - received {
+            return Err(DownloadError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Peer sent an oversized frame",
+            )));
+        }
+
+        if size == 0 {
+            // The peer is alive but waiting on its disk. Give up if it has been waiting too long.
+            if last_progress.elapsed() > MAX_PEER_STALL_DURATION {
+                return Err(DownloadError::Stalled);
+            }
+        } else {
+            // Read the frame's data from the peer. Waiting to read slows the peer down through flow control.
+            pacer.pace(size).await;
+            tokio::time::timeout(
+                PEER_FRAME_TIMEOUT,
+                peer_streams.recv.read_exact(&mut buf[..size]),
+            )
+            .await
+            .map_err(|_| DownloadError::Stalled)?
+            .map_err(|e| {
+                DownloadError::IoError(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e))
+            })?;
+            last_progress = Instant::now();
+
+            // Only keep the bytes still in the range, in case another peer took over its end.
+            let kept = usize::try_from(range.length().saturating_sub(received))
+                .map_or(size, |remaining| remaining.min(size));
+
+            // Write the bytes to the file, processing them while the write may be pending.
+            let data = &buf[..kept];
+            let f = file.write_all(data);
+            on_data(data, received + kept as u64)?;
+            f.await.map_err(DownloadError::IoError)?;
+
+            // Update the number of bytes written.
+            received += kept as u64;
+            range
+                .received
+                .store(received, std::sync::atomic::Ordering::SeqCst);
+            if let Some(progress) = progress.as_mut() {
+                progress
+                    .update(file, range.start + received)
+                    .await
+                    .map_err(DownloadError::IoError)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A range of the file being received from a peer.
+/// Its end moves closer while it is received when another peer takes over the rest of it.
+struct DownloadRange {
+    start: u64,
+    end: std::sync::atomic::AtomicU64,
+    received: std::sync::atomic::AtomicU64,
+}
+impl DownloadRange {
+    fn new(start: u64, length: u64) -> Self {
+        Self {
+            start,
+            end: std::sync::atomic::AtomicU64::new(start + length),
+            received: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    /// The length of the range, which shrinks if another peer takes over its end.
+    fn length(&self) -> u64 {
+        self.end.load(std::sync::atomic::Ordering::SeqCst) - self.start
+    }
+
+    /// The bytes of the range written so far.
+    fn received(&self) -> u64 {
+        self.received.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+/// A partial download's file, along with the record of its progress when it is written in order from the start.
+struct PartialFile {
+    file: tokio::fs::File,
+    progress: Option<ProgressRecord>,
+}
+impl PartialFile {
+    /// Record where an interrupted download stopped, if its progress is recorded.
+    async fn record_stop(&mut self, written: u64) {
+        if let Some(progress) = self.progress.as_mut() {
+            if let Err(e) = progress.record(&mut self.file, written).await {
+                eprintln!(
+                    "{} Failed to record the download's progress: {e}",
+                    local_now_fmt()
+                );
+            }
+        }
+    }
+}
+
+/// Records how many bytes from the start of a partial download are written, in a file beside the partial file.
+/// Partial files are preallocated to the full size of the download, so their length can't say where a download stopped.
+struct ProgressRecord {
+    path: PathBuf,
+    recorded: u64,
+}
+impl ProgressRecord {
+    /// Start recording the progress of the download to `output_path`, which has `written` bytes so far.
+    async fn create(output_path: &Path, written: u64) -> std::io::Result<Self> {
+        let path = partial_progress_path(output_path);
+        tokio::fs::write(&path, written.to_be_bytes()).await?;
+        Ok(Self {
+            path,
+            recorded: written,
+        })
+    }
+
+    /// Record the bytes written to the file, at most once per chunk so that the record is cheap to keep.
+    async fn update(&mut self, file: &mut tokio::fs::File, written: u64) -> std::io::Result<()> {
+        if written < self.recorded + CHUNK_SIZE {
+            return Ok(());
+        }
+        self.record(file, written).await
+    }
+
+    /// Record the bytes written to the file. The file is flushed first so that the record never runs ahead of its data.
+    async fn record(&mut self, file: &mut tokio::fs::File, written: u64) -> std::io::Result<()> {
+        file.flush().await?;
+        tokio::fs::write(&self.path, written.to_be_bytes()).await?;
+        self.recorded = written;
+        Ok(())
+    }
+}
+
+/// Download a range of a file from the peer into the output file at the range's offset. The file must already exist,
+/// sized by `preallocate_ranges` so that ranges may be written in any order.
+/// The file's hash is not verified since the range is only part of the file. If the hash of each chunk
+/// is known, the range must start on a chunk boundary and each chunk is verified as it arrives.
+/// Returns the number of bytes of the range received, even when the download fails part way.
+/// When verifying chunks, only the bytes of verified chunks count as received.
+async fn download_partial_from_peer(
+    peer_streams: &mut BiStream,
+    output_path: &Path,
+    range: &DownloadRange,
+    chunk_hashes: Option<&[HashBytes]>,
+    peer: SocketAddr,
+    progress: &SwarmProgress,
+) -> (u64, Result<(), DownloadError>) {
+    let first_chunk = range.start / CHUNK_SIZE;
+    let mut next_chunk = first_chunk;
+    let mut chunks = ChunkHasher::new();
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&output_path)
+            .await
+            .map_err(DownloadError::IoError)?;
+        file.seek(std::io::SeekFrom::Start(range.start))
+            .await
+            .map_err(DownloadError::IoError)?;
+
+        // Ranges from many peers land out of order, so there is no single point to resume from to record.
+        let mut file = PartialFile {
+            file,
+            progress: None,
+        };
+
+        let mut bb = bytes::BytesMut::with_capacity(2 * std::mem::size_of::<u64>());
+        receive_range(peer_streams, &mut file, range, &mut bb, |data, _| {
+            progress.add(peer, data.len() as u64);
+            if let Some(expected) = chunk_hashes {
+                chunks.update(data);
+                verify_chunks(&mut chunks, expected, &mut next_chunk)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        // The range may end with the last, shorter chunk of the file.
+        // Any other unfinished chunk was cut short by another peer taking over the range, and is left to them.
+        if let Some(expected) = chunk_hashes {
+            if next_chunk + 1 == expected.len() as u64 {
+                chunks.flush();
+                verify_chunks(&mut chunks, expected, &mut next_chunk)?;
+            }
+        }
+        file.file.flush().await.map_err(DownloadError::IoError)
+    }
+    .await;
+
+    let received = range.received();
+    if chunk_hashes.is_none() {
+        return (received, result);
+    }
+
+    // The bytes of a chunk that wasn't verified must be downloaded again.
+    let verified = ((next_chunk - first_chunk) * CHUNK_SIZE).min(received);
+    progress.remove(received - verified);
+    (verified, result)
+}
+
+/// Compare the chunks completed so far with their expected hashes, advancing to the next chunk to verify.
+fn verify_chunks(
+    chunks: &mut ChunkHasher,
+    expected: &[HashBytes],
+    next_chunk: &mut u64,
+) -> Result<(), DownloadError> {
+    for hash in chunks.completed.drain(..) {
+        let index = *next_chunk;
+        if usize::try_from(index).ok().and_then(|i| expected.get(i)) != Some(&hash) {
+            return Err(DownloadError::ChunkMismatch(index));
+        }
+        *next_chunk += 1;
+    }
+    Ok(())
+}
+
+/// Ask a publishing peer for the hash of each chunk of the file. A range may be requested on the same stream after.
+/// Returns `None` if the peer has no chunk hashes or shares a list that doesn't fit the file.
+async fn request_chunk_hashes(
+    peer_streams: &mut BiStream,
+    file_size: u64,
+) -> Result<Option<Vec<HashBytes>>, DownloadError> {
+    let mut request = bytes::BytesMut::with_capacity(2 * std::mem::size_of::<u64>());
+    request.put_u64(CHUNK_HASHES_REQUEST);
+    request.put_u64(0);
+    peer_streams
+        .send
+        .write_all(&request)
+        .await
+        .map_err(DownloadError::WriteError)?;
+
+    let count = tokio::time::timeout(PEER_FRAME_TIMEOUT, peer_streams.recv.read_u32())
+        .await
+        .map_err(|_| DownloadError::Stalled)?
+        .map_err(DownloadError::from_read)?;
+    let expected_count = file_size.div_ceil(CHUNK_SIZE);
+    if u64::from(count) > expected_count {
+        return Err(DownloadError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Peer sent too many chunk hashes",
+        )));
+    }
+
+    // Read the whole list, even if it is unusable, so that the stream stays aligned.
+    let mut hashes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut hash = HashBytes::default();
+        tokio::time::timeout(PEER_FRAME_TIMEOUT, peer_streams.recv.read_exact(&mut hash))
+            .await
+            .map_err(|_| DownloadError::Stalled)?
+            .map_err(DownloadError::IoError)?;
+        hashes.push(hash);
+    }
+    if hashes.is_empty() || u64::from(count) != expected_count {
+        return Ok(None);
+    }
+    Ok(Some(hashes))
+}
+
+/// The smallest range worth splitting off for another peer when downloading from several peers.
+const MIN_SWARM_RANGE_SIZE: u64 = 1024 * 1024;
+
+/// The size of the first range each of a peer's streams takes when downloading from several peers.
+/// Small enough that peers joining late still get a share, large enough to be worth a new stream.
+const SWARM_RANGE_SIZE: u64 = 2 * CHUNK_SIZE;
+
+/// The largest range a peer's stream takes at once, however fast the peer is, so that the rest stays shared.
+const MAX_SWARM_RANGE_SIZE: u64 = 16 * CHUNK_SIZE;
+
+/// How long each range should take a peer's stream, which sizes the next range it takes by how fast the last arrived.
+const SWARM_RANGE_DURATION: Duration = Duration::from_secs(4);
+
+/// The combined progress of a download from several peers.
+pub struct SwarmProgress {
+    pub file_size: u64,
+    pub bytes_received: std::sync::atomic::AtomicU64,
+    pub byte_progress: Option<watch::Sender<f32>>,
+
+    /// The bytes received from each peer, including any that were discarded, so callers can show each peer's speed.
+    pub peer_bytes: Option<watch::Sender<HashMap<SocketAddr, u64>>>,
+}
+impl SwarmProgress {
+    /// Count bytes received from a peer and update the caller's progress.
+    #[allow(clippy::cast_precision_loss)]
+    fn add(&self, peer: SocketAddr, bytes: u64) {
+        let total = self
+            .bytes_received
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed)
+            + bytes;
+        if let Some(progress) = self.byte_progress.as_ref() {
+            progress.send_replace(total as f32 / self.file_size as f32);
+        }
+        if let Some(peer_bytes) = self.peer_bytes.as_ref() {
+            peer_bytes.send_modify(|peer_bytes| *peer_bytes.entry(peer).or_default() += bytes);
+        }
+    }
+
+    /// Stop counting bytes that were discarded and must be received again.
+    #[allow(clippy::cast_precision_loss)]
+    fn remove(&self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let total = self
+            .bytes_received
+            .fetch_sub(bytes, std::sync::atomic::Ordering::Relaxed)
+            - bytes;
+        if let Some(progress) = self.byte_progress.as_ref() {
+            progress.send_replace(total as f32 / self.file_size as f32);
+        }
+    }
+}
+
+/// Hands out the ranges of a download from several peers. Each of a peer's streams takes a range sized
+/// to how fast its last range arrived, so that fast peers take more of the file at once.
+/// Once every range is taken, idle streams take over the unfinished end of the range with the most left,
+/// so that a slow peer doesn't hold up the end of the download.
+struct RangeScheduler {
+    /// Ranges start on a multiple of this from the start of the file, so that their chunks can be verified.
+    alignment: u64,
+
+    ranges: Mutex<ScheduledRanges>,
+}
+
+/// The ranges of a download that no stream has taken, and those being received.
+struct ScheduledRanges {
+    unassigned: VecDeque<(u64, u64)>,
+    active: Vec<Arc<DownloadRange>>,
+}
+
+impl RangeScheduler {
+    fn new(file_size: u64, alignment: u64) -> Self {
+        Self {
+            alignment,
+            ranges: Mutex::new(ScheduledRanges {
+                unassigned: std::iter::once((0, file_size))
+                    .filter(|&(_, length)| length > 0)
+                    .collect(),
+                active: Vec::new(),
+            }),
+        }
+    }
+
+    /// Take the next range for a stream that can receive about `size` bytes in `SWARM_RANGE_DURATION`,
+    /// or the end of the busiest range once every range is taken. `None` once nothing is left worth taking.
+    fn take(&self, size: u64) -> Option<Arc<DownloadRange>> {
+        let mut ranges = self
+            .ranges
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let range = match ranges.unassigned.pop_front() {
+            Some((start, length)) => {
+                // Leave the rest of a larger range for the next stream, unless the rest would be too small to bother with.
+                let size = size.next_multiple_of(self.alignment);
+                if length > size.saturating_add(MIN_SWARM_RANGE_SIZE) {
+                    ranges.unassigned.push_front((start + size, length - size));
+                    DownloadRange::new(start, size)
+                } else {
+                    DownloadRange::new(start, length)
+                }
+            }
+            None => self.steal(&ranges.active)?,
+        };
+        let range = Arc::new(range);
+        ranges.active.push(range.clone());
+        Some(range)
+    }
+
+    /// Take over the second half of what is left of the range with the most left, if it is worth splitting.
+    fn steal(&self, active: &[Arc<DownloadRange>]) -> Option<DownloadRange> {
+        let (range, split, end) = active
+            .iter()
+            .filter_map(|range| {
+                // Split past the frame the stream may be writing, so that two streams never write the same bytes.
+                let position = range.start + range.received() + MAX_PEER_COMMUNICATION_SIZE as u64;
+                let end = range.end.load(std::sync::atomic::Ordering::SeqCst);
+                let split =
+                    (position + end.saturating_sub(position) / 2).next_multiple_of(self.alignment);
+                (end > split && end - split >= MIN_SWARM_RANGE_SIZE).then_some((range, split, end))
+            })
+            .max_by_key(|&(_, split, end)| end - split)?;
+        range.end.store(split, std::sync::atomic::Ordering::SeqCst);
+        Some(DownloadRange::new(split, end - split))
+    }
+
+    /// Stop tracking a range once its stream is done with it, leaving the part after the `kept` bytes to be taken again.
+    fn finish(&self, range: &Arc<DownloadRange>, kept: u64) {
+        let mut ranges = self
+            .ranges
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        ranges.active.retain(|active| !Arc::ptr_eq(active, range));
+        let start = range.start + kept;
+        let end = range.end.load(std::sync::atomic::Ordering::SeqCst);
+        if end > start {
+            ranges.unassigned.push_back((start, end - start));
+        }
+    }
+
+    /// The bytes of the download that no stream has taken.
+    fn unassigned_bytes(&self) -> u64 {
+        self.ranges
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .unassigned
+            .iter()
+            .map(|(_, length)| length)
+            .sum()
+    }
+}
+
+/// The size of the next range for a stream that received `received` bytes of its last range in `elapsed`.
+fn next_swarm_range_size(received: u64, elapsed: Duration) -> u64 {
+    let size = u128::from(received) * SWARM_RANGE_DURATION.as_millis() / elapsed.as_millis().max(1);
+    u64::try_from(size)
+        .unwrap_or(u64::MAX)
+        .clamp(MIN_SWARM_RANGE_SIZE, MAX_SWARM_RANGE_SIZE)
+}
+
+/// Download a file from several peers at once, each sending disjoint ranges of the file
+/// over up to `streams_per_peer` streams at a time. Several streams keep a lossy connection busy,
+/// since a lost packet only stalls the stream it belongs to.
+/// Peers are received until the channel closes, so the download starts with the first peer to connect
+/// and the peers that connect later take the ranges no one has started yet.
+/// Faster peers take larger ranges, and near the end idle peers take over the rest of slower peers' ranges.
+/// Ranges left unfinished by failing peers are reassigned to the remaining peers,
+/// and the hash of the whole file is verified once every range has arrived.
+/// Like `download_from_peer`, the file is assembled at its partial path and renamed to the output path once verified.
+/// If the first peer shares the hash of each chunk, chunks are verified as they arrive and a peer that sends
+/// a corrupt chunk is dropped, so that only the chunks from the corrupt one onward are downloaded again.
+#[tracing::instrument(skip_all, fields(%hash, file_size))]
+pub async fn download_from_peers(
+    hash: FileHash,
+    passphrase: Option<&str>,
+    mut peers: tokio::sync::mpsc::Receiver<(quinn::Connection, BiStream)>,
+    file_size: u64,
+    output_path: &Path,
+    streams_per_peer: NonZeroUsize,
+    byte_progress: Option<watch::Sender<f32>>,
+    peer_bytes: Option<watch::Sender<HashMap<SocketAddr, u64>>>,
+) -> Result<(), DownloadError> {
+    let Some((first_connection, first_streams)) = peers.recv().await else {
+        return Err(DownloadError::PeersExhausted(file_size));
+    };
+
+    // Create the file at its full size so each peer can write its ranges at their offsets, in whatever order they finish.
+    let partial_path = partial_download_path(output_path);
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&partial_path)
+        .await
+        .map_err(DownloadError::IoError)?;
+    ProgressRecord::create(output_path, 0)
+        .await
+        .map_err(DownloadError::IoError)?;
+    preallocate_ranges(&file, file_size)
+        .await
+        .map_err(DownloadError::IoError)?;
+    drop(file);
+
+    let progress = SwarmProgress {
+        file_size,
+        bytes_received: std::sync::atomic::AtomicU64::new(0),
+        byte_progress,
+        peer_bytes,
+    };
+
+    // Ask for the chunk hashes on the first peer's initial stream, which then carries that peer's first range.
+    let mut first_streams = Some(first_streams);
+    let mut chunk_hashes = None;
+    if let Some(peer_streams) = first_streams.as_mut() {
+        match request_chunk_hashes(peer_streams, file_size).await {
+            Ok(hashes) => chunk_hashes = hashes,
+            Err(e) => {
+                eprintln!(
+                    "{} Failed to get chunk hashes from {}: {e}",
+                    local_now_fmt(),
+                    first_connection.remote_address(),
+                );
+                first_streams = None;
+            }
+        }
+    }
+    let chunk_hashes = chunk_hashes.as_deref();
+    let alignment = if chunk_hashes.is_some() {
+        CHUNK_SIZE
+    } else {
+        1
+    };
+
+    // The peers' streams take ranges of the file one at a time, so that peers joining later get a share.
+    let scheduler = RangeScheduler::new(file_size, alignment);
+
+    // Work through the ranges on each of a peer's streams until there are none left or the peer fails.
+    let work = |connection: quinn::Connection, streams: Option<BiStream>| {
+        let (scheduler, progress, partial_path) = (&scheduler, &progress, &partial_path);
+        async move {
+            let mut streams = streams;
+            let lanes = (0..streams_per_peer.get()).map(|_| {
+                download_ranges_from_peer(
+                    &connection,
+                    streams.take(),
+                    scheduler,
+                    hash,
+                    passphrase,
+                    partial_path,
+                    chunk_hashes,
+                    progress,
+                )
+            });
+            let healthy = futures_util::future::join_all(lanes)
+                .await
+                .into_iter()
+                .all(|healthy| healthy);
+            (connection, healthy)
+        }
+    };
+    let mut workers = futures_util::stream::FuturesUnordered::new();
+    workers.push(work(first_connection, first_streams));
+
+    // Peers that finished their ranges while others were still downloading, kept for ranges that fail.
+    let mut idle = Vec::new();
+    let mut accepting_peers = true;
+    loop {
+        // Put idle peers back to work on the ranges that failing peers left unfinished.
+        let remaining = scheduler.unassigned_bytes();
+        if remaining > 0 {
+            for connection in idle.drain(..) {
+                workers.push(work(connection, None));
+            }
+        }
+        if workers.is_empty() {
+            if remaining == 0 {
+                break;
+            }
+            if !accepting_peers {
+                return Err(DownloadError::PeersExhausted(remaining));
+            }
+        }
+
+        tokio::select! {
+            peer = peers.recv(), if accepting_peers => match peer {
+                Some((connection, streams)) => workers.push(work(connection, Some(streams))),
+                None => accepting_peers = false,
+            },
+            Some((connection, healthy)) = futures_util::StreamExt::next(&mut workers) => {
+                if healthy {
+                    idle.push(connection);
+                } else {
+                    connection.close(file_yeet_shared::GOODBYE_CODE, &[]);
+                }
+            }
+        }
+    }
+    drop(workers);
+    for connection in idle {
+        connection.close(
+            file_yeet_shared::GOODBYE_CODE,
+            file_yeet_shared::GOODBYE_MESSAGE.as_bytes(),
+        );
+    }
+
+    // Ensure the assembled file has the expected hash.
+    verify_downloaded_file(hash, &partial_path, output_path).await
+}
+
+/// Download ranges of a file from a peer one after another on a single stream at a time,
+/// starting with `streams` if given and opening a new stream for each following range.
+/// Returns whether the peer stayed healthy. The unfinished part of a range the peer fails on is left for other peers.
+async fn download_ranges_from_peer(
+    connection: &quinn::Connection,
+    mut streams: Option<BiStream>,
+    scheduler: &RangeScheduler,
+    hash: FileHash,
+    passphrase: Option<&str>,
+    partial_path: &Path,
+    chunk_hashes: Option<&[HashBytes]>,
+    progress: &SwarmProgress,
+) -> bool {
+    let mut range_size = SWARM_RANGE_SIZE;
+    while let Some(range) = scheduler.take(range_size) {
+        // Use the stream the peer connection was established with first, then ask for more.
+        let peer_streams = match streams.take() {
+            Some(s) => Some(s),
+            None => {
+                peer_connection_into_stream(connection, hash, passphrase, FileYeetCommandType::Sub)
+                    .await
+            }
+        };
+        let Some(mut peer_streams) = peer_streams else {
+            scheduler.finish(&range, 0);
+            return false;
+        };
+
+        let started = Instant::now();
+        let (received, result) = download_partial_from_peer(
+            &mut peer_streams,
+            partial_path,
+            &range,
+            chunk_hashes,
+            connection.remote_address(),
+            progress,
+        )
+        .await;
+        scheduler.finish(&range, received);
+        if let Err(e) = result {
+            eprintln!(
+                "{} Failed to download a range from {}: {e}",
+                local_now_fmt(),
+                connection.remote_address(),
+            );
+            return false;
+        }
+
+        // Size the next range by how fast this one arrived.
+        range_size = next_swarm_range_size(received, started.elapsed());
+    }
+    true
+}
+
+/// Continue a download from where an earlier attempt stopped in the partial file left at `partial_download_path(output_path)`,
+/// as told by `partial_download_progress`, then verify the hash of the whole file and move it to the output path.
+/// The partial file must not hold more of the download than its size.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(skip_all, fields(%hash, %peer, file_size))]
+pub async fn resume_download_from_peer(
+    hash: FileHash,
+    peer_streams: &mut BiStream,
+    peer: SocketAddr,
+    file_size: u64,
+    output_path: &Path,
+    byte_progress: Option<watch::Sender<f32>>,
+) -> Result<(), DownloadError> {
+    let partial_path = partial_download_path(output_path);
+    let existing = partial_download_progress(output_path)
+        .await
+        .map_err(DownloadError::IoError)?;
+    if existing > file_size {
+        return Err(DownloadError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "The partial file is larger than the download",
+        )));
+    }
+
+    if existing < file_size {
+        // Reserve the rest of the file up front, recording our progress since its length no longer shows it.
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&partial_path)
+            .await
+            .map_err(DownloadError::IoError)?;
+        let progress = ProgressRecord::create(output_path, existing)
+            .await
+            .map_err(DownloadError::IoError)?;
+        preallocate(&file, file_size)
+            .await
+            .map_err(DownloadError::IoError)?;
+        file.seek(std::io::SeekFrom::Start(existing))
+            .await
+            .map_err(DownloadError::IoError)?;
+        let mut file = PartialFile {
+            file,
+            progress: Some(progress),
+        };
+
+        // Count the bytes already on disk toward the progress.
+        let file_size_f = file_size as f32;
+        let mut bb = bytes::BytesMut::with_capacity(2 * std::mem::size_of::<u64>());
+        let range = DownloadRange::new(existing, file_size - existing);
+        let result = receive_range(peer_streams, &mut file, &range, &mut bb, |_, received| {
+            if let Some(progress) = byte_progress.as_ref() {
+                progress.send_replace((existing + received) as f32 / file_size_f);
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            file.record_stop(existing + range.received()).await;
+            return Err(e);
+        }
+        file.file.flush().await.map_err(DownloadError::IoError)?;
+    }
+
+    // The earlier attempt's bytes were never verified, so check the whole file.
+    verify_downloaded_file(hash, &partial_path, output_path).await
+}
+
+/// The path a download to `output_path` is written to until its hash is verified.
+/// An interrupted download is left here, where a resumed download continues from.
+pub fn partial_download_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(PARTIAL_DOWNLOAD_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// The path of the record of how much of the partial file for a download to `output_path` has been written.
+fn partial_progress_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(PARTIAL_PROGRESS_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// The number of bytes from the start of the partial file for a download to `output_path` that a resumed download can keep.
+/// Partial files take their full size before any data arrives, so their progress is read from the record kept beside them.
+pub async fn partial_download_progress(output_path: &Path) -> std::io::Result<u64> {
+    let length = tokio::fs::metadata(partial_download_path(output_path))
+        .await?
+        .len();
+    match tokio::fs::read(partial_progress_path(output_path)).await {
+        Ok(record) => {
+            let written = <[u8; 8]>::try_from(record.as_slice())
+                .map(u64::from_be_bytes)
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "The download's progress record is corrupt",
+                    )
+                })?;
+            Ok(written.min(length))
+        }
+
+        // Partial files without a record were left by versions that didn't preallocate, and end where they stopped.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(length),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reserve space for a whole download before it starts, so the filesystem can keep the file contiguous
+/// and a full disk is reported up front instead of part way through the transfer. Also sets the file's length.
+async fn preallocate(file: &tokio::fs::File, file_size: u64) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if file_size > 0 {
+        use std::os::fd::AsRawFd as _;
+        let length = libc::off_t::try_from(file_size).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The file is too large to allocate",
+            )
+        })?;
+
+        // SAFETY: The descriptor belongs to `file`, which stays open for the duration of the call.
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length) } != 0 {
+            let e = std::io::Error::last_os_error();
+
+            // Some filesystems can't reserve space ahead of time, which leaves only setting the length.
+            if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(e);
+            }
+        }
+    }
+    file.set_len(file_size).await
+}
+
+/// Reserve space for a download whose ranges are written out of order, such as by several peers at once.
+/// Windows would otherwise fill the gap before each write past the furthest written byte with zeros,
+/// blocking the write until the gap is filled, so the file is made sparse first where the filesystem allows it.
+/// Elsewhere the reserved space is never written before the ranges arrive, so ranges can already land in any order.
+async fn preallocate_ranges(file: &tokio::fs::File, file_size: u64) -> std::io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawHandle as _;
+        use windows::Win32::{
+            Foundation::HANDLE, System::Ioctl::FSCTL_SET_SPARSE, System::IO::DeviceIoControl,
+        };
+
+        let mut bytes_returned = 0;
+
+        // SAFETY: The handle belongs to `file`, which stays open for the duration of the call,
+        // and the request takes no input or output buffers.
+        let sparse = unsafe {
+            DeviceIoControl(
+                HANDLE(file.as_raw_handle() as isize),
+                FSCTL_SET_SPARSE,
+                None,
+                0,
+                None,
+                0,
+                Some(&mut bytes_returned),
+                None,
+            )
+        };
+
+        // Filesystems without sparse files, like FAT, fall back to an ordinary reservation.
+        if sparse.is_ok() {
+            return file.set_len(file_size).await;
+        }
+    }
+    preallocate(file, file_size).await
+}
+
+/// Hash a downloaded partial file and move it to the output path if it matches the expected hash.
+async fn verify_downloaded_file(
+    hash: FileHash,
+    partial_path: &Path,
+    output_path: &Path,
+) -> Result<(), DownloadError> {
+    let (_, downloaded_hash) = file_size_and_hash(partial_path, hash.algorithm, None)
+        .await
+        .map_err(|e| {
+            DownloadError::IoError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ))
+        })?;
+    finish_download(partial_path, output_path, hash.bytes == downloaded_hash).await
+}
+
+/// Replace the output path with a complete partial file once its hash is known to be correct.
+/// A partial file with the wrong hash is removed, since resuming it could only fail the same way.
+async fn finish_download(
+    partial_path: &Path,
+    output_path: &Path,
+    hash_matches: bool,
+) -> Result<(), DownloadError> {
+    // The record of the partial file's progress is done with either way.
+    let _ = tokio::fs::remove_file(partial_progress_path(output_path)).await;
+    if !hash_matches {
+        let _ = tokio::fs::remove_file(partial_path).await;
+        return Err(DownloadError::HashMismatch);
+    }
+    tokio::fs::rename(partial_path, output_path)
+        .await
+        .map_err(DownloadError::IoError)
+}
+
+/// Hashes data in `CHUNK_SIZE` chunks as it arrives in order.
+struct ChunkHasher {
+    hasher: FileHasher,
+    filled: u64,
+
+    /// The hashes of the chunks completed so far.
+    completed: Vec<HashBytes>,
+}
+impl ChunkHasher {
+    fn new() -> Self {
+        Self {
+            hasher: FileHasher::new(CHUNK_HASH_ALGORITHM),
+            filled: 0,
+            completed: Vec::new(),
+        }
+    }
+
+    /// Add the next data, completing chunks as they fill.
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = usize::try_from(CHUNK_SIZE - self.filled)
+                .map_or(data.len(), |remaining| remaining.min(data.len()));
+            self.hasher.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == CHUNK_SIZE {
+                self.flush();
+            }
+        }
+    }
+
+    /// Complete the current chunk, even if it is shorter than a full chunk.
+    fn flush(&mut self) {
+        if self.filled > 0 {
+            let hasher = std::mem::replace(&mut self.hasher, FileHasher::new(CHUNK_HASH_ALGORITHM));
+            self.completed.push(hasher.finalize());
+            self.filled = 0;
+        }
+    }
+}
+
+/// Get a file's size and its hash with the given algorithm, reusing the hash saved by an earlier call
+/// if the file's size and modification time haven't changed since. Set `rehash` to hash the file again regardless.
+/// Files are only checked by their metadata, so use `file_size_and_hash` to verify a file's contents.
+pub async fn cached_file_size_and_hash(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+    rehash: bool,
+) -> anyhow::Result<(u64, HashBytes)> {
+    cached_hash_file(file_path, algorithm, progress, rehash, false)
+        .await
+        .map(|(file_size, hash, _)| (file_size, hash))
+}
+
+/// Get a file's size, its hash, and the hash of each of its chunks like `file_size_hash_and_chunks`,
+/// reusing the hashes saved by an earlier call if the file's size and modification time haven't changed since.
+/// Set `rehash` to hash the file again regardless.
+pub async fn cached_file_size_hash_and_chunks(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+    rehash: bool,
+) -> anyhow::Result<(u64, HashBytes, Vec<HashBytes>)> {
+    cached_hash_file(file_path, algorithm, progress, rehash, true).await
+}
+
+/// Hash a file and its chunks if asked to, unless the hash cache already has them for the file's current state.
+/// Newly computed hashes are saved to the cache for next time.
+async fn cached_hash_file(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+    rehash: bool,
+    with_chunks: bool,
+) -> anyhow::Result<(u64, HashBytes, Vec<HashBytes>)> {
+    // Read the state before hashing, so a file changed while it is hashed won't match its entry.
+    let state = hash_cache::FileState::read(file_path);
+    if let Some(cached) = state
+        .as_ref()
+        .filter(|_| !rehash)
+        .and_then(|state| hash_cache::lookup(state, algorithm, with_chunks))
+    {
+        if let Some(progress) = progress.as_ref() {
+            progress.send_replace(1.);
+        }
+        return Ok(cached);
+    }
+
+    let (file_size, hash, chunk_hashes) = if with_chunks {
+        file_size_hash_and_chunks(file_path, algorithm, progress).await?
+    } else {
+        let (file_size, hash) = file_size_and_hash(file_path, algorithm, progress).await?;
+        (file_size, hash, Vec::new())
+    };
+    if let Some(state) = state {
+        let chunks = with_chunks.then_some(chunk_hashes.as_slice());
+        if let Err(e) = hash_cache::store(&state, &FileHash::new(algorithm, hash), chunks) {
+            eprintln!("{} Failed to save the file's hash: {e}", local_now_fmt());
+        }
+    }
+    Ok((file_size, hash, chunk_hashes))
+}
+
+/// Get a file's size and its hash with the given algorithm.
+pub async fn file_size_and_hash(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+) -> anyhow::Result<(u64, HashBytes)> {
+    hash_file(file_path, algorithm, progress, None)
+        .await
+        .map(|(file_size, hash, _)| (file_size, hash))
+}
+
+/// Get a file's size, its hash with the given algorithm, and the hash of each of its chunks.
+/// Publishers share the chunk hashes so that subscribers can verify ranges as they arrive.
+pub async fn file_size_hash_and_chunks(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+) -> anyhow::Result<(u64, HashBytes, Vec<HashBytes>)> {
+    let (file_size, hash, chunks) =
+        hash_file(file_path, algorithm, progress, Some(ChunkHasher::new())).await?;
+    let mut chunks = chunks.expect("The chunk hasher is returned when given");
+    chunks.flush();
+    Ok((file_size, hash, chunks.completed))
+}
+
+/// Set the size of the reads made while hashing files. Sizes below 64 KiB are raised to it.
+pub fn set_hash_read_buffer_size(bytes: usize) {
+    HASH_READ_BUFFER_SIZE.store(
+        bytes.max(MIN_HASH_READ_BUFFER_SIZE),
+        std::sync::atomic::Ordering::Relaxed,
+    );
+}
+
+/// Hash a file, and its chunks if a chunk hasher is given, returning the chunk hasher once it has seen the whole file.
+/// The file is read and hashed on a blocking thread so that hashing large files doesn't hold up transfers
+/// running on the async runtime. Dropping the returned future stops the hashing at the next buffer.
+async fn hash_file(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<watch::Sender<f32>>,
+    chunks: Option<ChunkHasher>,
+) -> anyhow::Result<(u64, HashBytes, Option<ChunkHasher>)> {
+    let file_path = file_path.to_path_buf();
+    let cancel_on_drop = CancelOnDrop::default();
+    let cancelled = cancel_on_drop.0.clone();
+    tokio::task::spawn_blocking(move || {
+        hash_file_blocking(&file_path, algorithm, progress.as_ref(), chunks, &cancelled)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!("The hashing task failed: {e}"))?
+}
+
+/// Tells a blocking hash to stop when the future waiting on it is dropped.
+#[derive(Default)]
+struct CancelOnDrop(Arc<std::sync::atomic::AtomicBool>);
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Hash a file on the current thread, reporting progress as each buffer is hashed and stopping once `cancelled` is set.
+/// On 64-bit targets the file is memory mapped and hashed in place, avoiding a copy of every byte into a buffer.
+/// Files that can't be mapped are read in buffers of the size given to `set_hash_read_buffer_size`.
+#[allow(clippy::cast_precision_loss)]
+fn hash_file_blocking(
+    file_path: &Path,
+    algorithm: HashAlgorithm,
+    progress: Option<&watch::Sender<f32>>,
+    mut chunks: Option<ChunkHasher>,
+    cancelled: &std::sync::atomic::AtomicBool,
+) -> anyhow::Result<(u64, HashBytes, Option<ChunkHasher>)> {
+    use std::io::{Read as _, Seek as _};
+
+    let mut hasher = FileHasher::new(algorithm);
+    let mut file = std::fs::File::open(file_path)
+        .map_err(|e| anyhow::anyhow!("Failed to open the file: {e}"))?;
+
+    // Get the file size so we can report progress.
+    let file_size = file
+        .seek(std::io::SeekFrom::End(0))
+        .map_err(|e| anyhow::anyhow!("Failed to seek in file: {e}"))?;
+
+    // Reset the reader to the start of the file.
+    file.seek(std::io::SeekFrom::Start(0))
+        .map_err(|e| anyhow::anyhow!("Failed to seek in file: {e}"))?;
+
+    let buffer_size = HASH_READ_BUFFER_SIZE.load(std::sync::atomic::Ordering::Relaxed);
+    let size_float = file_size as f32;
+    let mut bytes_hashed = 0;
+    let mut hash_data = |data: &[u8]| {
+        if cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+            anyhow::bail!("Hashing was cancelled");
+        }
+        hasher.update(data);
+        if let Some(chunks) = chunks.as_mut() {
+            chunks.update(data);
+        }
+        bytes_hashed += data.len();
+
+        // Update the caller with the number of bytes hashed.
+        if let Some(progress) = progress {
+            progress.send_replace(bytes_hashed as f32 / size_float);
+        }
+        Ok(())
+    };
+
+    #[cfg(target_pointer_width = "64")]
+    if let Some(map) = map_file(&file, file_size) {
+        for data in map.chunks(buffer_size) {
+            hash_data(data)?;
+        }
+        return Ok((file_size, hasher.finalize(), chunks));
+    }
+
+    let mut hash_byte_buffer = vec![0; buffer_size];
+    loop {
+        let n = match file.read(&mut hash_byte_buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => anyhow::bail!("Failed to read from the file: {e}"),
+        };
+        hash_data(&hash_byte_buffer[..n])?;
+    }
+    Ok((file_size, hasher.finalize(), chunks))
+}
+
+/// Memory map a file to hash it, if it is a non-empty regular file the platform can map.
+/// Only used on 64-bit targets, where even the largest files fit in the address space.
+#[cfg(target_pointer_width = "64")]
+fn map_file(file: &std::fs::File, file_size: u64) -> Option<memmap2::Mmap> {
+    if file_size == 0 || !file.metadata().ok()?.is_file() {
+        return None;
+    }
+
+    // SAFETY: The map is only read while hashing. A file truncated by another program meanwhile could fault
+    // the read, which is the same risk every memory-mapped hasher accepts, and a file changed meanwhile
+    // only yields a hash that doesn't match its contents, as reading it would.
+    let map = unsafe { memmap2::Mmap::map(file) }.ok()?;
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Some(map)
+}
+
+/// The range a peer requested during an upload and how much of it was sent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UploadStats {
+    pub range_start: u64,
+    pub range_length: u64,
+    pub bytes_sent: u64,
+}
+
+/// Upload the file to the peer. Ensure they consent to the file size before sending the file.
+/// The peer may first ask for the hash of each chunk of the file, which is answered with `chunk_hashes`.
+/// If `stats` are given, they are kept up to date as the upload progresses, even if it fails.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(skip_all, fields(file_size))]
+pub async fn upload_to_peer(
+    peer_streams: &mut BiStream,
+    file_size: u64,
+    chunk_hashes: &[HashBytes],
+    mut reader: tokio::io::BufReader<tokio::fs::File>,
+    byte_progress: Option<watch::Sender<f32>>,
+    mut stats: Option<&mut UploadStats>,
+) -> anyhow::Result<()> {
+    // Read the peer's desired upload range.
+    let mut start_index = peer_streams.recv.read_u64().await?;
+    let mut upload_length = peer_streams.recv.read_u64().await?;
+
+    // Share the chunk hashes if the peer asks for them, then read the range it wants on the same stream.
+    // An empty list tells the peer to verify only the whole file.
+    if start_index == CHUNK_HASHES_REQUEST {
+        peer_streams
+            .send
+            .write_u32(u32::try_from(chunk_hashes.len())?)
+            .await?;
+        for hash in chunk_hashes {
+            peer_streams.send.write_all(hash).await?;
+        }
+        start_index = peer_streams.recv.read_u64().await?;
+        upload_length = peer_streams.recv.read_u64().await?;
+    }
+    if let Some(stats) = stats.as_deref_mut() {
+        stats.range_start = start_index;
+        stats.range_length = upload_length;
+    }
+    // Sanity check the upload range.
+    match start_index.checked_add(upload_length) {
+        Some(end) if end > file_size => anyhow::bail!("Invalid range requested, exceeds file size"),
+        None => anyhow::bail!("Invalid range requested, 64-bit overflow 🫠"),
+        Some(_) => {}
+    }
+
+    // Ensure that the file reader is at the starting index for the upload.
+    reader.seek(std::io::SeekFrom::Start(start_index)).await?;
+
+    // Read several frames' worth of the file at a time, then hand the frames to the stream without copying them.
+    // Each buffer reclaims its allocation once the stream is done with the frames split from it.
+    let batch_size = UPLOAD_BATCH_FRAMES * MAX_PEER_COMMUNICATION_SIZE;
+    let mut buf = UPLOAD_BUFFERS.take();
+    let mut headers = bytes::BytesMut::with_capacity(UPLOAD_BATCH_FRAMES * 2);
+    let mut frames = Vec::with_capacity(UPLOAD_BATCH_FRAMES * 2);
+    let mut bytes_read = 0;
+    let upload_length_f = upload_length as f32;
+    let pacer = Pacer::new(Direction::Upload);
+
+    // Read from the file and write to the peer.
+    while bytes_read < upload_length {
+        // Read a natural amount of bytes from the file, without reading past the requested range.
+        // While the disk is stalled, periodically send keep-alive frames so the peer knows we are still here.
+        buf.reserve(batch_size);
+        let remaining = upload_length - bytes_read;
+        let mut range_reader = (&mut reader).take(remaining.min(batch_size as u64));
+        let n = {
+            let read = range_reader.read_buf(&mut *buf);
+            tokio::pin!(read);
+            let stall_start = Instant::now();
+            loop {
+                tokio::select! {
+                    r = &mut read => break r?,
+                    () = tokio::time::sleep(PEER_KEEP_ALIVE_INTERVAL) => {
+                        if stall_start.elapsed() > MAX_PEER_STALL_DURATION {
+                            anyhow::bail!("Reading the file stalled for too long");
+                        }
+                        peer_streams.send.write_u16(0).await?;
+                    }
+                }
+            }
+        };
+        if n == 0 {
+            break;
+        }
+
+        // Write the bytes to the peer as frames prefixed by their length, no faster than the bandwidth limits allow.
+        let mut data = buf.split().freeze();
+        headers.reserve(UPLOAD_BATCH_FRAMES * 2);
+        while !data.is_empty() {
+            let frame = data.split_to(data.len().min(MAX_PEER_COMMUNICATION_SIZE));
+            headers.put_u16(u16::try_from(frame.len())?);
+            frames.push(headers.split().freeze());
+            frames.push(frame);
+        }
+        pacer.pace(n).await;
+        peer_streams.send.write_all_chunks(&mut frames).await?;
+        frames.clear();
+
+        // Update the number of bytes read.
+        bytes_read += n as u64;
+        if let Some(stats) = stats.as_deref_mut() {
+            stats.bytes_sent = bytes_read;
+        }
+
+        // Update the caller with the number of bytes sent to the peer.
+        if let Some(progress) = byte_progress.as_ref() {
+            progress.send_replace(bytes_read as f32 / upload_length_f);
+        }
+    }
+
+    // The stream has released every frame, so reclaim the whole batch allocation before it returns to the pool.
+    buf.reserve(batch_size);
+
+    // Gracefully close our connection after all data has been sent.
+    if let Err(e) = peer_streams.send.finish().await {
+        eprintln!(
+            "{} Failed to close the peer stream gracefully: {e}",
+            local_now_fmt()
+        );
+    }
+
+    // Let the user know that the upload is complete.
+    println!("{} Upload complete!", local_now_fmt());
+    Ok(())
+}
+
+/// Parse a byte count with an optional unit suffix, e.g., `500MB`, `2 GiB`, or `1024`.
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("Invalid byte count {number:?}: {e}"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        u => return Err(format!("Unknown byte unit {u:?}")),
+    };
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a duration with an optional unit suffix, e.g., `90`, `30s`, `10m`, or `2h`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("Invalid duration {number:?}: {e}"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        u => return Err(format!("Unknown duration unit {u:?}")),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(multiplier)))
+}
+
+/// Turn a byte count into a human readable string.
+#[allow(clippy::cast_precision_loss)]
+pub fn humanize_bytes(bytes: u64) -> String {
+    human_bytes::human_bytes(bytes as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore as _;
+
+    /// A new directory for a test's files under the system's temporary directory.
+    fn test_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("file_yeet_{name}_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Connect a downloading peer to an uploading peer over loopback, returning each side's connection.
+    async fn connected_peers() -> (quinn::Connection, quinn::Connection) {
+        let (cert, key) = file_yeet_shared::generate_self_signed_cert().unwrap();
+        let uploader = quinn::Endpoint::server(
+            file_yeet_shared::configure_peer_server(cert, key).unwrap(),
+            (Ipv4Addr::LOCALHOST, 0).into(),
+        )
+        .unwrap();
+        let mut downloader = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        downloader.set_default_client_config(file_yeet_shared::configure_peer_verification());
+
+        let connecting = downloader
+            .connect(uploader.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (downloading, uploading) =
+            tokio::join!(connecting, async { uploader.accept().await.unwrap().await });
+        (downloading.unwrap(), uploading.unwrap())
+    }
+
+    #[tokio::test]
+    #[allow(clippy::cast_precision_loss)]
+    async fn interrupted_download_resumes_where_it_stopped() {
+        const SENT: usize = 6 * 1024 * 1024;
+        let directory = test_directory("resume");
+        let source_path = directory.join("source");
+        let output_path = directory.join("output");
+        let mut data = vec![0; 10 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut data);
+        std::fs::write(&source_path, &data).unwrap();
+        let file_size = data.len() as u64;
+        let (_, hash_bytes) = file_size_and_hash(&source_path, HashAlgorithm::Blake3, None)
+            .await
+            .unwrap();
+        let hash = FileHash::new(HashAlgorithm::Blake3, hash_bytes);
+        let (downloading, uploading) = connected_peers().await;
+
+        // A peer that sends the start of the file and then goes quiet.
+        let stalled_uploading = uploading.clone();
+        let sent_data = data[..SENT].to_vec();
+        let stalled_upload = tokio::spawn(async move {
+            let mut streams = BiStream::from(stalled_uploading.accept_bi().await.unwrap());
+            assert_eq!(streams.recv.read_u64().await.unwrap(), 0);
+            assert_eq!(streams.recv.read_u64().await.unwrap(), file_size);
+            for frame in sent_data.chunks(MAX_PEER_COMMUNICATION_SIZE) {
+                streams
+                    .send
+                    .write_u16(u16::try_from(frame.len()).unwrap())
+                    .await
+                    .unwrap();
+                streams.send.write_all(frame).await.unwrap();
+            }
+            std::future::pending::<()>().await;
+        });
+
+        // Drop the download once everything sent has arrived, as if the client were closed part way through.
+        let mut streams = BiStream::from(downloading.open_bi().await.unwrap());
+        let mut bb = bytes::BytesMut::new();
+        let (byte_progress, mut progress) = watch::channel(0.);
+        tokio::select! {
+            _ = download_from_peer(hash, &mut streams, file_size, &output_path, &mut bb, Some(byte_progress)) => {
+                panic!("The download finished with only part of the file");
+            }
+            _ = progress.wait_for(|&p| p >= SENT as f32 / file_size as f32) => {}
+        }
+        stalled_upload.abort();
+        drop(streams);
+
+        // The partial file is already at its full size, but only the recorded bytes count toward resuming.
+        let partial_path = partial_download_path(&output_path);
+        assert_eq!(std::fs::metadata(&partial_path).unwrap().len(), file_size);
+        let existing = partial_download_progress(&output_path).await.unwrap();
+        assert!((CHUNK_SIZE..=SENT as u64).contains(&existing));
+        let partial_data = std::fs::read(&partial_path).unwrap();
+        assert_eq!(
+            partial_data[..usize::try_from(existing).unwrap()],
+            data[..usize::try_from(existing).unwrap()]
+        );
+
+        // Resume from a peer that serves the whole file.
+        let upload = tokio::spawn(async move {
+            let mut streams = BiStream::from(uploading.accept_bi().await.unwrap());
+            let reader =
+                tokio::io::BufReader::new(tokio::fs::File::open(source_path).await.unwrap());
+            upload_to_peer(&mut streams, file_size, &[], reader, None, None)
+                .await
+                .unwrap();
+        });
+        let mut streams = BiStream::from(downloading.open_bi().await.unwrap());
+        resume_download_from_peer(
+            hash,
+            &mut streams,
+            downloading.remote_address(),
+            file_size,
+            &output_path,
+            None,
+        )
+        .await
+        .unwrap();
+        upload.await.unwrap();
+
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        assert!(!partial_path.exists());
+        assert!(!partial_progress_path(&output_path).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn scheduler_takes_aligned_ranges_then_steals_the_busiest() {
+        let file_size = 10 * CHUNK_SIZE + 123;
+        let scheduler = RangeScheduler::new(file_size, CHUNK_SIZE);
+
+        // Ranges are carved from the front on chunk boundaries, and the last one keeps a remainder too small to leave.
+        let first = scheduler.take(CHUNK_SIZE + 1).unwrap();
+        assert_eq!((first.start, first.length()), (0, 2 * CHUNK_SIZE));
+        let second = scheduler.take(MAX_SWARM_RANGE_SIZE).unwrap();
+        assert_eq!(
+            (second.start, second.length()),
+            (2 * CHUNK_SIZE, 8 * CHUNK_SIZE + 123)
+        );
+        assert_eq!(scheduler.unassigned_bytes(), 0);
+
+        // With nothing unassigned, the busiest range gives up the second half of what it has left.
+        second
+            .received
+            .store(2 * CHUNK_SIZE, std::sync::atomic::Ordering::SeqCst);
+        let stolen = scheduler.take(SWARM_RANGE_SIZE).unwrap();
+        assert_eq!(stolen.start % CHUNK_SIZE, 0);
+        assert_eq!(second.start + second.length(), stolen.start);
+        assert_eq!(stolen.start + stolen.length(), file_size);
+        assert!(
+            stolen.start > second.start + second.received() + MAX_PEER_COMMUNICATION_SIZE as u64
+        );
+
+        // A stream that stops part way leaves the rest of its range to be taken again.
+        scheduler.finish(&first, CHUNK_SIZE);
+        assert_eq!(scheduler.unassigned_bytes(), CHUNK_SIZE);
+        let retried = scheduler.take(SWARM_RANGE_SIZE).unwrap();
+        assert_eq!((retried.start, retried.length()), (CHUNK_SIZE, CHUNK_SIZE));
+    }
+
+    #[test]
+    fn faster_streams_take_larger_ranges() {
+        let slow = next_swarm_range_size(CHUNK_SIZE, 4 * SWARM_RANGE_DURATION);
+        let fast = next_swarm_range_size(CHUNK_SIZE, SWARM_RANGE_DURATION / 4);
+        assert!(slow < fast);
+        assert_eq!(
+            next_swarm_range_size(0, SWARM_RANGE_DURATION),
+            MIN_SWARM_RANGE_SIZE
+        );
+        assert_eq!(
+            next_swarm_range_size(u64::MAX, Duration::ZERO),
+            MAX_SWARM_RANGE_SIZE
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Peer connections tunnelled through a server relay.
+//!
+//! The relayed stream carries the datagrams of an ordinary QUIC connection between the two peers,
+//! so the peers authenticate each other as they would directly and the server only ever forwards ciphertext.
+
+use std::{
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use file_yeet_shared::{BiStream, HashBytes};
+use quinn::udp::{RecvMeta, UdpState};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    sync::mpsc,
+};
+
+use crate::{FileYeetCommandType, PEER_CONNECT_TIMEOUT};
+
+/// The most datagrams queued in each direction of a tunnel. Like a congested UDP link, more are dropped.
+const TUNNEL_QUEUE_LENGTH: usize = 256;
+
+/// The address a tunnel endpoint reports as its own, since a relayed stream has no local address of its own.
+const TUNNEL_LOCAL_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Carries QUIC datagrams to a single peer over a relayed stream instead of UDP.
+/// Each datagram is written to the stream after its length as a `u16`.
+#[derive(Debug)]
+struct TunnelSocket {
+    outgoing: mpsc::Sender<Bytes>,
+    incoming: Mutex<mpsc::Receiver<Bytes>>,
+    peer_address: SocketAddr,
+}
+impl TunnelSocket {
+    /// Start moving datagrams between the relayed stream and the socket in background tasks.
+    fn new(streams: BiStream, peer_address: SocketAddr) -> Self {
+        let BiStream { mut send, mut recv } = streams;
+        let (outgoing, mut unsent) = mpsc::channel::<Bytes>(TUNNEL_QUEUE_LENGTH);
+        let (received, incoming) = mpsc::channel(TUNNEL_QUEUE_LENGTH);
+
+        tokio::task::spawn(async move {
+            while let Some(datagram) = unsent.recv().await {
+                let Ok(len) = u16::try_from(datagram.len()) else {
+                    continue;
+                };
+                if send.write_u16(len).await.is_err() || send.write_all(&datagram).await.is_err() {
+                    return;
+                }
+            }
+            let _ = send.finish().await;
+        });
+        tokio::task::spawn(async move {
+            let mut buffer = vec![0; usize::from(u16::MAX)];
+            while let Ok(len) = recv.read_u16().await {
+                let datagram = &mut buffer[..usize::from(len)];
+                if recv.read_exact(datagram).await.is_err() {
+                    return;
+                }
+
+                // Drop datagrams the endpoint isn't keeping up with, as a congested UDP link would.
+                if let Err(mpsc::error::TrySendError::Closed(_)) =
+                    received.try_send(Bytes::copy_from_slice(datagram))
+                {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            outgoing,
+            incoming: Mutex::new(incoming),
+            peer_address,
+        }
+    }
+}
+impl quinn::AsyncUdpSocket for TunnelSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[quinn::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        for transmit in transmits {
+            // Send segmented transmits as separate datagrams, since there is no segmentation offload to do it for us.
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                // Like UDP, datagrams are lost when the tunnel is congested or closed.
+                let _ = self.outgoing.try_send(Bytes::copy_from_slice(segment));
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let (Some(buf), Some(meta)) = (bufs.first_mut(), meta.first_mut()) else {
+            return Poll::Ready(Ok(0));
+        };
+        let mut incoming = self.incoming.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Nothing more arrives once the relay closes. The connection is left to close or time out on its own.
+        let Some(datagram) = std::task::ready!(incoming.poll_recv(cx)) else {
+            return Poll::Pending;
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        *meta = RecvMeta {
+            addr: self.peer_address,
+            len,
+            stride: len,
+            ecn: None,
+            dst_ip: None,
+        };
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(TUNNEL_LOCAL_ADDRESS)
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+/// Create a QUIC endpoint with our peer identity that communicates with a single peer over a relayed stream.
+fn bind_tunnel_endpoint(
+    streams: BiStream,
+    peer_address: SocketAddr,
+    accept_peers: bool,
+) -> anyhow::Result<quinn::Endpoint> {
+    let identity = crate::identity::PeerIdentity::load_or_create()?;
+    let server_config = if accept_peers {
+        let mut server_config =
+            file_yeet_shared::configure_peer_server(identity.cert.clone(), identity.key.clone())?;
+        server_config.transport_config(file_yeet_shared::server_transport_config());
+        Some(server_config)
+    } else {
+        None
+    };
+
+    let mut endpoint = quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        server_config,
+        TunnelSocket::new(streams, peer_address),
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    endpoint.set_default_client_config(
+        file_yeet_shared::configure_peer_verification_with_identity(identity.cert, identity.key)?,
+    );
+    Ok(endpoint)
+}
+
+/// Connect to a publisher through a relayed stream, and ask it for the file as we would directly.
+/// Returns `None` if the peer connection could not be established through the relay.
+pub(crate) async fn connect_through_relay(
+    streams: BiStream,
+    peer_address: SocketAddr,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<(quinn::Connection, BiStream)>> {
+    let endpoint = bind_tunnel_endpoint(streams, peer_address, false)?;
+    Ok(crate::connect_to_publisher(endpoint, peer_address, hash, passphrase).await)
+}
+
+/// Accept a subscriber's connection through a relayed stream, and its request for the file.
+/// Returns `None` if the peer connection could not be established through the relay.
+pub(crate) async fn accept_through_relay(
+    streams: BiStream,
+    peer_address: SocketAddr,
+    hash: HashBytes,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<(quinn::Connection, BiStream)>> {
+    let endpoint = bind_tunnel_endpoint(streams, peer_address, true)?;
+    let Ok(Some(connection)) =
+        tokio::time::timeout(PEER_CONNECT_TIMEOUT, crate::accept_direct_peer(&endpoint)).await
+    else {
+        return Ok(None);
+    };
+    Ok(
+        crate::peer_connection_into_stream(&connection, hash, passphrase, FileYeetCommandType::Pub)
+            .await
+            .map(|streams| (connection, streams)),
+    )
+}
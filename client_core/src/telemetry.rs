@@ -0,0 +1,55 @@
+//! Carrying trace context through the server, so that a collector can follow a transfer across processes:
+//! from a subscriber's request, through the server's introduction, to the publisher's hole punch and upload.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, PoisonError},
+};
+
+use file_yeet_shared::ServerCapabilities;
+use once_cell::sync::Lazy;
+use opentelemetry::propagation::TextMapPropagator as _;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+/// The W3C header that trace context is carried in.
+const TRACE_PARENT: &str = "traceparent";
+
+/// The stable IDs of server connections that accept trace context ahead of requests.
+static TRACED_SERVERS: Lazy<Mutex<HashSet<usize>>> = Lazy::new(Mutex::default);
+
+/// Remember whether a newly connected server accepts trace context ahead of requests.
+pub(crate) fn note_capabilities(connection: &quinn::Connection, capabilities: ServerCapabilities) {
+    let mut servers = TRACED_SERVERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if capabilities.contains(ServerCapabilities::TRACE_CONTEXT) {
+        servers.insert(connection.stable_id());
+    } else {
+        servers.remove(&connection.stable_id());
+    }
+}
+
+/// Whether requests to the server should carry trace context.
+pub(crate) fn is_traced(connection: &quinn::Connection) -> bool {
+    TRACED_SERVERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains(&connection.stable_id())
+}
+
+/// The W3C `traceparent` of the current span, or an empty string if its trace isn't being exported.
+pub(crate) fn current_trace_parent() -> String {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&tracing::Span::current().context(), &mut carrier);
+    carrier.remove(TRACE_PARENT).unwrap_or_default()
+}
+
+/// Make a span continue the trace of a peer's request, given the W3C `traceparent` the server forwarded.
+pub(crate) fn continue_trace(span: &tracing::Span, trace_parent: &str) {
+    if trace_parent.is_empty() {
+        return;
+    }
+    let carrier = HashMap::from([(TRACE_PARENT.to_owned(), trace_parent.to_owned())]);
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
@@ -0,0 +1,140 @@
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Bandwidth limits in bytes per second. Transfers are not throttled in directions without a limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct BandwidthLimits {
+    /// The combined rate of every upload.
+    pub upload: Option<NonZeroU64>,
+
+    /// The combined rate of every download.
+    pub download: Option<NonZeroU64>,
+
+    /// The rate of each stream of a transfer, in either direction.
+    pub per_transfer: Option<NonZeroU64>,
+}
+
+/// The direction of a transfer being paced.
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// The limits in effect and the buckets every transfer in each direction shares.
+#[derive(Debug, Default)]
+struct SharedLimits {
+    limits: BandwidthLimits,
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+}
+static SHARED_LIMITS: once_cell::sync::Lazy<Mutex<SharedLimits>> =
+    once_cell::sync::Lazy::new(Mutex::default);
+
+/// Set the bandwidth limits. The combined limits apply to running transfers immediately,
+/// while the per-transfer limit applies to transfers started afterwards.
+pub fn set_bandwidth_limits(limits: BandwidthLimits) {
+    let mut shared = SHARED_LIMITS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    if shared.limits == limits {
+        return;
+    }
+    *shared = SharedLimits {
+        limits,
+        upload: limits.upload.map(RateLimiter::new),
+        download: limits.download.map(RateLimiter::new),
+    };
+}
+
+/// A token bucket limiting how fast bytes are transferred. Clones share the same bucket.
+#[derive(Clone, Debug)]
+pub struct RateLimiter(Arc<Mutex<TokenBucket>>);
+
+#[derive(Debug)]
+struct TokenBucket {
+    bytes_per_second: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a bucket that refills at the given rate and holds at most a second's worth of bytes.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(bytes_per_second: NonZeroU64) -> Self {
+        let bytes_per_second = bytes_per_second.get() as f64;
+        Self(Arc::new(Mutex::new(TokenBucket {
+            bytes_per_second,
+            available: bytes_per_second,
+            last_refill: Instant::now(),
+        })))
+    }
+
+    /// Take the bytes about to be transferred from the bucket, waiting until it has refilled enough to cover them.
+    /// The bucket may go into debt, so transfers of any size make progress and concurrent transfers share fairly.
+    #[allow(clippy::cast_precision_loss)]
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self
+                .0
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let now = Instant::now();
+            let refilled =
+                now.duration_since(bucket.last_refill).as_secs_f64() * bucket.bytes_per_second;
+            bucket.available = (bucket.available + refilled).min(bucket.bytes_per_second);
+            bucket.last_refill = now;
+            bucket.available -= bytes as f64;
+
+            if bucket.available < 0. {
+                Duration::from_secs_f64(-bucket.available / bucket.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Paces a single transfer stream by the combined limit of its direction and the per-transfer limit.
+#[derive(Debug)]
+pub struct Pacer {
+    direction: Direction,
+    own: Option<RateLimiter>,
+}
+impl Pacer {
+    /// Create a pacer for a new transfer stream with the limits currently in effect.
+    pub fn new(direction: Direction) -> Self {
+        let shared = SHARED_LIMITS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Self {
+            direction,
+            own: shared.limits.per_transfer.map(RateLimiter::new),
+        }
+    }
+
+    /// Wait until the bytes may be transferred without exceeding any limit.
+    pub async fn pace(&self, bytes: usize) {
+        let combined = {
+            let shared = SHARED_LIMITS
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            match self.direction {
+                Direction::Upload => shared.upload.clone(),
+                Direction::Download => shared.download.clone(),
+            }
+        };
+        if let Some(limiter) = combined {
+            limiter.acquire(bytes).await;
+        }
+        if let Some(limiter) = &self.own {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! An in-process `file_yeet` server and clients, for testing whole transfers end to end on localhost.
+//!
+//! Start a [`TestServer`], connect clients to it with [`TestServer::connect`], then [`publish`] a file
+//! from one client and [`download`] it from another.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    num::NonZeroU16,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser as _;
+use file_yeet_client_core::{
+    self as core, FileYeetCommandType, PortMappingConfig, PreparedConnection,
+};
+use file_yeet_shared::{
+    BiStream, FileHash, HashAlgorithm, PublishControl, GOODBYE_CODE, SERVER_MESSAGE_BUFFERS,
+};
+use rand::RngCore as _;
+use sha2::Digest as _;
+use tokio::{
+    io::AsyncWriteExt as _,
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+
+/// The longest to wait for the server to reflect a change a client asked for.
+pub const SERVER_UPDATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The time between checks for the server to reflect a change.
+const SERVER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A server running in-process on a loopback port, shut down when dropped.
+pub struct TestServer {
+    address: SocketAddr,
+    fingerprint: [u8; 32],
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+impl TestServer {
+    /// Start a server on a free loopback port with the given command line flags in addition to its address.
+    pub async fn start(flags: &[&str]) -> anyhow::Result<Self> {
+        // Quinn can't bind to port zero through the server's flags, so find a free port first.
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        let port = port.to_string();
+        let args = file_yeet_server::Cli::try_parse_from(
+            [
+                "file_yeet_server",
+                "--bind-ip",
+                "127.0.0.1",
+                "--bind-port",
+                &port,
+            ]
+            .into_iter()
+            .chain(flags.iter().copied()),
+        )?;
+
+        let server = file_yeet_server::Server::bind(args);
+        let address = server.local_addr()?;
+        let fingerprint = sha2::Sha256::digest(&server.certificate().0).into();
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let task = tokio::spawn(server.run(async move {
+            shutdown_receiver.await.ok();
+        }));
+        Ok(Self {
+            address,
+            fingerprint,
+            shutdown: Some(shutdown),
+            task,
+        })
+    }
+
+    /// Connect a new client to the server, pinning the server's certificate.
+    /// `port_config` lets the client ask to be introduced to peers by another port.
+    pub async fn connect(
+        &self,
+        port_config: PortMappingConfig,
+    ) -> anyhow::Result<PreparedConnection> {
+        isolate_client_data();
+        let mut bb = bytes::BytesMut::new();
+        core::prepare_server_connection(
+            Some(&self.address.ip().to_string()),
+            NonZeroU16::new(self.address.port()).expect("The server is bound to a port"),
+            None,
+            port_config,
+            None,
+            None,
+            None,
+            &core::ServerTrust::Fingerprint(self.fingerprint),
+            None,
+            &mut bb,
+        )
+        .await
+    }
+
+    /// Shut the server down and wait for it to finish.
+    pub async fn stop(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        (&mut self.task).await.ok();
+    }
+}
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Keep the identity and remembered peers of test clients out of the user's own data directory.
+/// Only takes effect where the data directory follows `XDG_DATA_HOME`.
+fn isolate_client_data() {
+    static ISOLATE: std::sync::Once = std::sync::Once::new();
+    ISOLATE.call_once(|| {
+        let directory = test_directory("client_data");
+        std::env::set_var("XDG_DATA_HOME", directory);
+    });
+}
+
+/// A new directory for a test's files under the system's temporary directory.
+pub fn test_directory(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("file_yeet_it_{name}_{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&path).expect("Failed to create a test directory");
+    path
+}
+
+/// Write a file of random data to publish.
+pub fn random_file(path: &Path, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; size];
+    rand::thread_rng().fill_bytes(&mut data);
+    std::fs::write(path, &data)?;
+    Ok(data)
+}
+
+/// A file published by a client, served to every subscriber the server introduces until it is cancelled.
+pub struct Publish {
+    pub hash: FileHash,
+    pub file_size: u64,
+    cancel: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+impl Publish {
+    /// Withdraw the publish from the server and stop serving new subscribers.
+    pub async fn cancel(mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.send(()).ok();
+        }
+        (&mut self.task).await.ok();
+    }
+}
+impl Drop for Publish {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Publish a file and wait for the server to register it.
+pub async fn publish(connection: &PreparedConnection, path: &Path) -> anyhow::Result<Publish> {
+    let (file_size, hash) = core::file_size_and_hash(path, HashAlgorithm::Sha256, None).await?;
+    let hash = FileHash::new(HashAlgorithm::Sha256, hash);
+    let server_streams = core::publish(
+        &connection.server_connection,
+        SERVER_MESSAGE_BUFFERS.take(),
+        hash,
+        file_size,
+        "",
+        &core::FileMetadata::from_path(path),
+    )
+    .await?;
+    wait_for_server(move || async move {
+        Ok(core::list_publishes(&connection.server_connection)
+            .await?
+            .iter()
+            .any(|publish| publish.hash == hash.bytes))
+    })
+    .await?;
+
+    let (cancel, cancelled) = oneshot::channel();
+    let task = tokio::spawn(serve_publish(
+        connection.endpoint.clone(),
+        server_streams,
+        hash,
+        file_size,
+        path.to_path_buf(),
+        cancelled,
+    ));
+    Ok(Publish {
+        hash,
+        file_size,
+        cancel: Some(cancel),
+        task,
+    })
+}
+
+/// Hole punch to each subscriber the server introduces and upload the file to them, until cancelled.
+async fn serve_publish(
+    endpoint: quinn::Endpoint,
+    mut server_streams: BiStream,
+    hash: FileHash,
+    file_size: u64,
+    path: PathBuf,
+    mut cancelled: oneshot::Receiver<()>,
+) {
+    loop {
+        let peer = tokio::select! {
+            _ = &mut cancelled => {
+                server_streams
+                    .send
+                    .write_u8(PublishControl::Cancel as u8)
+                    .await
+                    .ok();
+                return;
+            }
+            peer = core::read_subscribing_peer(&mut server_streams) => peer,
+        };
+        let Ok((core::SubscribingPeer::Direct(peer_address), _)) = peer else {
+            return;
+        };
+        tokio::spawn(upload_to_subscriber(
+            endpoint.clone(),
+            peer_address,
+            hash,
+            file_size,
+            path.clone(),
+        ));
+    }
+}
+
+/// Serve every range a subscriber asks for, until they close the connection.
+async fn upload_to_subscriber(
+    endpoint: quinn::Endpoint,
+    peer_address: SocketAddr,
+    hash: FileHash,
+    file_size: u64,
+    path: PathBuf,
+) {
+    let Some((connection, peer_streams)) = core::udp_holepunch(
+        FileYeetCommandType::Pub,
+        hash.bytes,
+        None,
+        endpoint,
+        peer_address,
+    )
+    .await
+    else {
+        return;
+    };
+
+    let mut next_streams = Some(peer_streams);
+    loop {
+        let peer_streams = match next_streams.take() {
+            Some(s) => Some(s),
+            None => {
+                core::peer_connection_into_stream(
+                    &connection,
+                    hash.bytes,
+                    None,
+                    FileYeetCommandType::Pub,
+                )
+                .await
+            }
+        };
+        let Some(mut peer_streams) = peer_streams else {
+            return;
+        };
+        let Ok(file) = tokio::fs::File::open(&path).await else {
+            return;
+        };
+        let reader = tokio::io::BufReader::new(file);
+        if core::upload_to_peer(&mut peer_streams, file_size, &[], reader, None, None)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Ask the server for the publishers of a file until it has at least one.
+pub async fn find_publishers(
+    connection: &PreparedConnection,
+    hash: FileHash,
+) -> anyhow::Result<Vec<(SocketAddr, u64)>> {
+    tokio::time::timeout(SERVER_UPDATE_TIMEOUT, async {
+        loop {
+            let peers = subscribe_uncached(connection, hash).await?;
+            if !peers.is_empty() {
+                return Ok(peers);
+            }
+            tokio::time::sleep(SERVER_POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("The server found no publishers of {hash} in time"))?
+}
+
+/// Ask the server for the publishers of a file, without reusing an earlier answer.
+pub async fn subscribe_uncached(
+    connection: &PreparedConnection,
+    hash: FileHash,
+) -> anyhow::Result<Vec<(SocketAddr, u64)>> {
+    core::invalidate_subscribe_cache(&hash);
+    let mut bb = bytes::BytesMut::new();
+    let peers = core::subscribe(&connection.server_connection, &mut bb, hash, "").await?;
+    Ok(peers
+        .into_iter()
+        .map(|(address, file_size, _)| (address, file_size))
+        .collect())
+}
+
+/// Hole punch to the first publisher the server knows of for a file.
+async fn connect_to_publisher(
+    connection: &PreparedConnection,
+    hash: FileHash,
+) -> anyhow::Result<(quinn::Connection, BiStream, SocketAddr, u64)> {
+    let (peer_address, file_size) = find_publishers(connection, hash).await?[0];
+    let (peer_connection, peer_streams) = core::udp_holepunch(
+        FileYeetCommandType::Sub,
+        hash.bytes,
+        None,
+        connection.endpoint.clone(),
+        peer_address,
+    )
+    .await
+    .ok_or_else(|| anyhow::anyhow!("Failed to connect to the publisher at {peer_address}"))?;
+    Ok((peer_connection, peer_streams, peer_address, file_size))
+}
+
+/// Download a file from the first publisher the server knows of, reporting the fraction downloaded to `progress`.
+pub async fn download(
+    connection: &PreparedConnection,
+    hash: FileHash,
+    output_path: &Path,
+    progress: Option<watch::Sender<f32>>,
+) -> anyhow::Result<()> {
+    let (peer_connection, mut peer_streams, _, file_size) =
+        connect_to_publisher(connection, hash).await?;
+    let mut bb = bytes::BytesMut::new();
+    core::download_from_peer(
+        hash,
+        &mut peer_streams,
+        file_size,
+        output_path,
+        &mut bb,
+        progress,
+    )
+    .await?;
+    peer_connection.close(GOODBYE_CODE, &[]);
+    Ok(())
+}
+
+/// Continue an interrupted download from the first publisher the server knows of.
+pub async fn resume_download(
+    connection: &PreparedConnection,
+    hash: FileHash,
+    output_path: &Path,
+) -> anyhow::Result<()> {
+    let (peer_connection, mut peer_streams, peer_address, file_size) =
+        connect_to_publisher(connection, hash).await?;
+    core::resume_download_from_peer(
+        hash,
+        &mut peer_streams,
+        peer_address,
+        file_size,
+        output_path,
+        None,
+    )
+    .await?;
+    peer_connection.close(GOODBYE_CODE, &[]);
+    Ok(())
+}
+
+/// Check with the server until `check` passes, since requests like publishing aren't answered once they take effect.
+/// Fails if it doesn't pass within [`SERVER_UPDATE_TIMEOUT`].
+pub async fn wait_for_server<F>(mut check: impl FnMut() -> F) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<bool>>,
+{
+    tokio::time::timeout(SERVER_UPDATE_TIMEOUT, async {
+        while !check().await? {
+            tokio::time::sleep(SERVER_POLL_INTERVAL).await;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("The server did not reflect the change in time"))?
+}
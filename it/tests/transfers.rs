@@ -0,0 +1,133 @@
+use std::num::NonZeroU16;
+
+use file_yeet_client_core::{self as core, PortMappingConfig};
+use file_yeet_it::{
+    download, find_publishers, publish, random_file, resume_download, subscribe_uncached,
+    test_directory, wait_for_server, TestServer,
+};
+
+#[tokio::test]
+async fn published_file_downloads_to_subscriber() {
+    let directory = test_directory("download");
+    let source_path = directory.join("source");
+    let output_path = directory.join("output");
+    let data = random_file(&source_path, 3 * 1024 * 1024 + 17).unwrap();
+
+    let server = TestServer::start(&[]).await.unwrap();
+    let publisher = server.connect(PortMappingConfig::None).await.unwrap();
+    let subscriber = server.connect(PortMappingConfig::None).await.unwrap();
+    let publish = publish(&publisher, &source_path).await.unwrap();
+
+    let peers = find_publishers(&subscriber, publish.hash).await.unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].1, data.len() as u64);
+
+    download(&subscriber, publish.hash, &output_path, None)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+    assert!(!core::partial_download_path(&output_path).exists());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn interrupted_download_resumes_from_publisher() {
+    let directory = test_directory("resume");
+    let source_path = directory.join("source");
+    let output_path = directory.join("output");
+    let data = random_file(&source_path, 24 * 1024 * 1024).unwrap();
+
+    let server = TestServer::start(&[]).await.unwrap();
+    let publisher = server.connect(PortMappingConfig::None).await.unwrap();
+    let subscriber = server.connect(PortMappingConfig::None).await.unwrap();
+    let publish = publish(&publisher, &source_path).await.unwrap();
+
+    // Stop the download part way through, as if the subscriber were closed.
+    let (progress, mut progress_receiver) = tokio::sync::watch::channel(0.);
+    tokio::select! {
+        r = download(&subscriber, publish.hash, &output_path, Some(progress)) => {
+            panic!("The download finished before it could be interrupted: {r:?}");
+        }
+        _ = progress_receiver.wait_for(|&p| p >= 0.5) => {}
+    }
+    let existing = core::partial_download_progress(&output_path).await.unwrap();
+    assert!((core::CHUNK_SIZE..publish.file_size).contains(&existing));
+
+    resume_download(&subscriber, publish.hash, &output_path)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn cancelled_publish_is_withdrawn() {
+    let directory = test_directory("cancel");
+    let source_path = directory.join("source");
+    random_file(&source_path, 64 * 1024).unwrap();
+
+    let server = TestServer::start(&[]).await.unwrap();
+    let publisher = server.connect(PortMappingConfig::None).await.unwrap();
+    let subscriber = server.connect(PortMappingConfig::None).await.unwrap();
+    let publish = publish(&publisher, &source_path).await.unwrap();
+    let hash = publish.hash;
+    find_publishers(&subscriber, hash).await.unwrap();
+
+    publish.cancel().await;
+    let subscriber = &subscriber;
+    wait_for_server(
+        move || async move { Ok(subscribe_uncached(subscriber, hash).await?.is_empty()) },
+    )
+    .await
+    .unwrap();
+    assert!(core::list_publishes(&publisher.server_connection)
+        .await
+        .unwrap()
+        .is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn port_override_changes_introduced_port() {
+    let directory = test_directory("port_override");
+    let source_path = directory.join("source");
+    let output_path = directory.join("output");
+    let data = random_file(&source_path, 256 * 1024).unwrap();
+
+    // Ephemeral servers never introduce peers by their private address, so the public port is what peers see.
+    let server = TestServer::start(&["--ephemeral"]).await.unwrap();
+    let forwarded_port = NonZeroU16::new(40_404).unwrap();
+    let publisher = server
+        .connect(PortMappingConfig::PortForwarding(forwarded_port))
+        .await
+        .unwrap();
+    let subscriber = server.connect(PortMappingConfig::None).await.unwrap();
+    let publish = publish(&publisher, &source_path).await.unwrap();
+    let hash = publish.hash;
+
+    let peers = find_publishers(&subscriber, hash).await.unwrap();
+    assert_eq!(peers[0].0.port(), forwarded_port.get());
+
+    // Overriding the port with the one the publisher is really bound to lets the subscriber reach it.
+    let local_port = publisher.endpoint.local_addr().unwrap().port();
+    let mut bb = bytes::BytesMut::new();
+    core::port_override_request(
+        &publisher.server_connection,
+        NonZeroU16::new(local_port).unwrap(),
+        &mut bb,
+    )
+    .await
+    .unwrap();
+    let subscriber = &subscriber;
+    wait_for_server(move || async move {
+        Ok(subscribe_uncached(subscriber, hash).await?[0].0.port() == local_port)
+    })
+    .await
+    .unwrap();
+
+    download(subscriber, hash, &output_path, None)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&output_path).unwrap(), data);
+    server.stop().await;
+}
@@ -0,0 +1,619 @@
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use zeroize::Zeroize as _;
+
+use crate::{
+    auth::constant_time_eq, ban_list, config::AdminTokenConfig, disconnect_banned, lock_sessions,
+    rate_limit, session_id, Nonce, PublishersRef, RuntimeSettings, ServerStats, SessionsRef,
+};
+
+/// The close code sent to clients the operator disconnects.
+const KICKED_CODE: quinn::VarInt = quinn::VarInt::from_u32(2);
+
+/// The reason sent to clients the operator disconnects.
+const KICKED_MESSAGE: &[u8] = b"Disconnected by the server operator";
+
+/// The longest request line the admin interface reads, so a stuck writer can't grow the buffer forever.
+const MAX_REQUEST_LINE: usize = 4096;
+
+/// The name logged for requests made without a token, when the server has no admin tokens.
+const SOCKET_OPERATOR: &str = "socket";
+
+/// The kinds of request an admin token may be allowed to make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminScope {
+    /// Read the server's stats, sessions, publishes, limits, and bans.
+    Read,
+
+    /// Disconnect clients.
+    Kick,
+
+    /// Change the connection and request limits.
+    Limits,
+
+    /// Ban and unban addresses.
+    Ban,
+}
+
+/// Requests the operator can make of a running server. Sent as one line of JSON per request.
+#[derive(Clone, Debug, clap::Subcommand, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Show counters describing the server's activity.
+    Stats,
+
+    /// List the connected clients.
+    Sessions,
+
+    /// List the published file hashes and how many clients publish each.
+    Publishes,
+
+    /// Disconnect a client, ending their publishes.
+    Kick {
+        /// The session ID of the client, as listed by `sessions`.
+        session: String,
+    },
+
+    /// Show the connection and request limits.
+    Limits,
+
+    /// Change connection and request limits without restarting.
+    ///
+    /// Limits that are not given are left as they are. Giving zero removes a limit.
+    SetLimits {
+        /// The most clients that may be connected at once.
+        #[arg(long)]
+        max_connections: Option<usize>,
+
+        /// The most clients that may be connected at once from a single IP address.
+        #[arg(long)]
+        max_connections_per_ip: Option<usize>,
+
+        /// The number of publish, subscribe, and introduction requests a single IP address may make per minute.
+        #[arg(long)]
+        requests_per_minute: Option<u32>,
+
+        /// The most requests a single IP address may make in a burst before being held to the rate.
+        #[arg(long)]
+        request_burst: Option<u32>,
+    },
+
+    /// List the banned IP addresses and ranges.
+    Bans,
+
+    /// Refuse connections from an IP address or CIDR range, disconnecting any clients within it.
+    Ban {
+        /// The address or range to ban, such as `203.0.113.7` or `203.0.113.0/24`.
+        range: String,
+    },
+
+    /// Lift a ban on an IP address or CIDR range.
+    Unban {
+        /// The address or range to unban, as listed by `bans`.
+        range: String,
+    },
+}
+
+impl AdminRequest {
+    /// The scope a token needs to make this request.
+    fn scope(&self) -> AdminScope {
+        match self {
+            Self::Stats | Self::Sessions | Self::Publishes | Self::Limits | Self::Bans => {
+                AdminScope::Read
+            }
+            Self::Kick { .. } => AdminScope::Kick,
+            Self::SetLimits { .. } => AdminScope::Limits,
+            Self::Ban { .. } | Self::Unban { .. } => AdminScope::Ban,
+        }
+    }
+}
+
+/// A request as sent over the admin socket, with the token it is made with.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AdminMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+
+    #[serde(flatten)]
+    request: AdminRequest,
+}
+
+/// The tokens the admin interface accepts, by name, and the scopes each is allowed.
+/// Tokens are only required when at least one is configured, since the socket is already limited to the server's user.
+#[derive(Default)]
+pub struct AdminTokens {
+    tokens: Vec<(String, String, Vec<AdminScope>)>,
+}
+impl AdminTokens {
+    /// Accept the tokens from the configuration file. Empty tokens are ignored so that a blank setting can't let everyone in.
+    pub fn new(tokens: HashMap<String, AdminTokenConfig>) -> Self {
+        let tokens = tokens
+            .into_iter()
+            .filter(|(_, config)| !config.token.is_empty())
+            .map(|(name, config)| (name, config.token, config.scopes))
+            .collect();
+        Self { tokens }
+    }
+
+    /// Find the name of the token a request is made with, if it is allowed the scope the request needs.
+    /// Every token is compared in full so that timing reveals nothing about them.
+    pub(crate) fn authorize(&self, token: Option<&str>, scope: AdminScope) -> Result<&str, String> {
+        if self.tokens.is_empty() {
+            return Ok(SOCKET_OPERATOR);
+        }
+        let token = token.ok_or_else(|| "An admin token is required".to_owned())?;
+        let (name, scopes) = self
+            .tokens
+            .iter()
+            .fold(None, |found, (name, expected, scopes)| {
+                if constant_time_eq(expected.as_bytes(), token.as_bytes()) {
+                    Some((name.as_str(), scopes))
+                } else {
+                    found
+                }
+            })
+            .ok_or_else(|| "The admin token was not accepted".to_owned())?;
+        if scopes.contains(&scope) {
+            Ok(name)
+        } else {
+            Err(format!(
+                "The admin token {name:?} is not allowed the {scope:?} scope"
+            ))
+        }
+    }
+}
+impl std::fmt::Debug for AdminTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.tokens.iter().map(|(name, _, scopes)| (name, scopes)))
+            .finish()
+    }
+}
+impl Drop for AdminTokens {
+    fn drop(&mut self) {
+        for (_, token, _) in &mut self.tokens {
+            token.zeroize();
+        }
+    }
+}
+
+/// The server's answer to an admin request. Sent as one line of JSON.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminResponse {
+    Stats(StatsInfo),
+    Sessions(Vec<SessionInfo>),
+    Publishes(Vec<PublishInfo>),
+    Kicked {
+        session: String,
+    },
+    Limits(LimitsInfo),
+    Bans {
+        ranges: Vec<String>,
+        persisted: bool,
+    },
+    Banned {
+        range: String,
+        disconnected: usize,
+    },
+    Unbanned {
+        range: String,
+    },
+    Error(String),
+}
+
+/// Counters describing the server's activity so far.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct StatsInfo {
+    uptime_seconds: u64,
+    active_connections: usize,
+    peak_connections: usize,
+    total_connections: u64,
+    total_introductions: u64,
+    total_relays: u64,
+    relayed_bytes: u64,
+    publishes: usize,
+    hashes: usize,
+}
+
+/// A connected client. Addresses are left out when the server is ephemeral.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SessionInfo {
+    id: String,
+    address: Option<String>,
+    connected_seconds: u64,
+    publishes: usize,
+}
+
+/// A published file hash and the number of clients publishing it.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PublishInfo {
+    room: String,
+    hash: String,
+    file_size: u64,
+    publishers: usize,
+}
+
+/// The connection and request limits in effect.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct LimitsInfo {
+    max_connections: Option<NonZeroUsize>,
+    max_connections_per_ip: Option<NonZeroUsize>,
+    requests_per_minute: Option<NonZeroU32>,
+    request_burst: Option<NonZeroU32>,
+}
+
+/// Errors that can occur when opening the admin socket.
+#[derive(Debug, thiserror::Error)]
+pub enum AdminSocketError {
+    /// Another process, or a server that didn't shut down cleanly, left a file at the socket path.
+    #[error("{0} already exists. Remove it if no server is running")]
+    InUse(PathBuf),
+
+    /// Failed to create the socket or restrict its permissions.
+    #[error("Failed to open the admin socket {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+/// Errors that can occur when sending a request to the admin socket.
+#[derive(Debug, thiserror::Error)]
+pub enum AdminClientError {
+    /// Failed to connect to the admin socket.
+    #[error("Failed to connect to {0}: {1}")]
+    Connect(PathBuf, std::io::Error),
+
+    /// Failed to send the request or read the response.
+    #[error("Admin socket I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The server's response could not be understood.
+    #[error("Invalid response from the server: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+
+    /// The server refused the request.
+    #[error("{0}")]
+    Refused(String),
+}
+
+/// The server state the admin interface can inspect and change.
+#[derive(Clone, Debug)]
+pub struct AdminContext {
+    pub publishers: PublishersRef,
+    pub sessions: SessionsRef,
+    pub stats: Arc<ServerStats>,
+    pub ip_limiter: Arc<rate_limit::IpLimiter>,
+    pub settings: Arc<RuntimeSettings>,
+    pub start_time: Instant,
+    pub ephemeral: bool,
+    pub tokens: Arc<AdminTokens>,
+}
+
+/// Create the admin socket. Only the user running the server may connect to it.
+pub fn bind(path: &Path) -> Result<UnixListener, AdminSocketError> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let listener = UnixListener::bind(path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AddrInUse {
+            AdminSocketError::InUse(path.to_path_buf())
+        } else {
+            AdminSocketError::Io(path.to_path_buf(), e)
+        }
+    })?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| AdminSocketError::Io(path.to_path_buf(), e))?;
+    Ok(listener)
+}
+
+/// Answer admin requests until the server shuts down.
+pub async fn serve(
+    listener: UnixListener,
+    context: AdminContext,
+    cancellation_token: CancellationToken,
+    task_master: TaskTracker,
+) {
+    loop {
+        let stream = tokio::select! {
+            () = cancellation_token.cancelled() => return,
+            r = listener.accept() => match r {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept an admin connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let context = context.clone();
+        let cancellation_token = cancellation_token.clone();
+        task_master.spawn(async move {
+            tokio::select! {
+                () = cancellation_token.cancelled() => {}
+                r = handle_admin_connection(stream, &context) => {
+                    if let Err(e) = r {
+                        tracing::warn!("Failed to handle admin connection: {e}");
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Answer each request line on an admin connection with a response line.
+async fn handle_admin_connection(
+    stream: UnixStream,
+    context: &AdminContext,
+) -> std::io::Result<()> {
+    let (recv, mut send) = stream.into_split();
+    let mut lines = BufReader::new(recv).take(MAX_REQUEST_LINE as u64);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        lines.set_limit(MAX_REQUEST_LINE as u64);
+        if lines.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.len() >= MAX_REQUEST_LINE && !line.ends_with('\n') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Admin request is too long",
+            ));
+        }
+
+        let response = match serde_json::from_str::<AdminMessage>(&line) {
+            Ok(AdminMessage { mut token, request }) => {
+                let authorized = context.tokens.authorize(token.as_deref(), request.scope());
+                token.zeroize();
+                match authorized {
+                    Ok(operator) => {
+                        tracing::info!("Admin request by {operator}: {request:?}");
+                        handle_admin_request(request, operator, context).await
+                    }
+                    Err(e) => {
+                        tracing::warn!("Refused admin request {request:?}: {e}");
+                        AdminResponse::Error(e)
+                    }
+                }
+            }
+            Err(e) => AdminResponse::Error(format!("Invalid request: {e}")),
+        };
+        line.zeroize();
+        let mut response = serde_json::to_vec(&response).expect("Failed to serialize a response");
+        response.push(b'\n');
+        send.write_all(&response).await?;
+    }
+}
+
+/// Carry out an admin request made with the token named `operator`, naming it in the log of any change.
+async fn handle_admin_request(
+    request: AdminRequest,
+    operator: &str,
+    context: &AdminContext,
+) -> AdminResponse {
+    match request {
+        AdminRequest::Stats => {
+            let publishers = context.publishers.read().await;
+            let stats = &context.stats;
+            AdminResponse::Stats(StatsInfo {
+                uptime_seconds: context.start_time.elapsed().as_secs(),
+                active_connections: stats.active_connections.load(Ordering::Relaxed),
+                peak_connections: stats.peak_connections.load(Ordering::Relaxed),
+                total_connections: stats.total_connections.load(Ordering::Relaxed),
+                total_introductions: stats.total_introductions.load(Ordering::Relaxed),
+                total_relays: stats.total_relays.load(Ordering::Relaxed),
+                relayed_bytes: stats.relayed_bytes.load(Ordering::Relaxed),
+                publishes: publishers.values().map(HashMap::len).sum(),
+                hashes: publishers.len(),
+            })
+        }
+
+        AdminRequest::Sessions => {
+            // Count the publishes of each session before locking the sessions, so neither lock waits on the other.
+            let mut publish_counts = HashMap::<Nonce, usize>::new();
+            for nonce in context
+                .publishers
+                .read()
+                .await
+                .values()
+                .flat_map(HashMap::keys)
+            {
+                *publish_counts.entry(*nonce).or_default() += 1;
+            }
+            let handles = lock_sessions(&context.sessions)
+                .iter()
+                .map(|(nonce, handle)| (*nonce, handle.peer_addr.clone(), handle.connected_at))
+                .collect::<Vec<_>>();
+
+            let mut sessions = Vec::with_capacity(handles.len());
+            for (nonce, peer_addr, connected_at) in handles {
+                let address = if context.ephemeral {
+                    None
+                } else {
+                    Some(peer_addr.read().await.to_string())
+                };
+                sessions.push(SessionInfo {
+                    id: session_id(nonce),
+                    address,
+                    connected_seconds: connected_at.elapsed().as_secs(),
+                    publishes: publish_counts.get(&nonce).copied().unwrap_or_default(),
+                });
+            }
+            AdminResponse::Sessions(sessions)
+        }
+
+        AdminRequest::Publishes => AdminResponse::Publishes(
+            context
+                .publishers
+                .read()
+                .await
+                .iter()
+                .map(|((room, hash), publishes)| PublishInfo {
+                    room: room.clone(),
+                    hash: faster_hex::hex_string(hash),
+                    file_size: publishes.values().next().map_or(0, |p| p.file_size),
+                    publishers: publishes.len(),
+                })
+                .collect(),
+        ),
+
+        AdminRequest::Kick { session } => {
+            let Some(nonce) = parse_session_id(&session) else {
+                return AdminResponse::Error(format!("Invalid session ID {session:?}"));
+            };
+            let Some(connection) = lock_sessions(&context.sessions)
+                .get(&nonce)
+                .map(|handle| handle.connection.clone())
+            else {
+                return AdminResponse::Error(format!("No client has the session ID {session}"));
+            };
+
+            // Closing the connection ends the client's session, which removes their publishes.
+            connection.close(KICKED_CODE, KICKED_MESSAGE);
+            tracing::info!("Disconnected session {session} by admin request from {operator}");
+            AdminResponse::Kicked { session }
+        }
+
+        AdminRequest::Limits => AdminResponse::Limits(limits_info(context)),
+
+        AdminRequest::SetLimits {
+            max_connections,
+            max_connections_per_ip,
+            requests_per_minute,
+            request_burst,
+        } => {
+            if let Some(max) = max_connections {
+                context
+                    .settings
+                    .max_connections
+                    .store(max, Ordering::Relaxed);
+            }
+            let mut ip_limits = context.ip_limiter.limits();
+            if let Some(max) = max_connections_per_ip {
+                ip_limits.max_connections = NonZeroUsize::new(max);
+            }
+            if let Some(rate) = requests_per_minute {
+                ip_limits.requests_per_minute = NonZeroU32::new(rate);
+            }
+            if let Some(burst) = request_burst {
+                ip_limits.request_burst = NonZeroU32::new(burst);
+            }
+            context.ip_limiter.set_limits(ip_limits);
+
+            let limits = limits_info(context);
+            tracing::info!("Limits changed by admin request from {operator}: {limits:?}");
+            AdminResponse::Limits(limits)
+        }
+
+        AdminRequest::Bans => AdminResponse::Bans {
+            ranges: context
+                .settings
+                .bans
+                .ranges()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            persisted: context.settings.bans.is_persisted(),
+        },
+
+        AdminRequest::Ban { range } => {
+            let bans = &context.settings.bans;
+            let range = match range.parse::<ban_list::IpRange>().and_then(|range| {
+                bans.add(range)?;
+                Ok(range)
+            }) {
+                Ok(range) => range,
+                Err(e) => return AdminResponse::Error(e.to_string()),
+            };
+            let disconnected = disconnect_banned(&context.sessions, bans);
+            tracing::info!(
+                "Banned {range} by admin request from {operator}, disconnecting {disconnected} clients"
+            );
+            AdminResponse::Banned {
+                range: range.to_string(),
+                disconnected,
+            }
+        }
+
+        AdminRequest::Unban { range } => {
+            let range = match range.parse::<ban_list::IpRange>() {
+                Ok(range) => range,
+                Err(e) => return AdminResponse::Error(e.to_string()),
+            };
+            match context.settings.bans.remove(range) {
+                Ok(true) => {
+                    tracing::info!("Unbanned {range} by admin request from {operator}");
+                    AdminResponse::Unbanned {
+                        range: range.to_string(),
+                    }
+                }
+                Ok(false) => AdminResponse::Error(format!("{range} is not banned")),
+                Err(e) => AdminResponse::Error(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Describe the limits currently in effect.
+fn limits_info(context: &AdminContext) -> LimitsInfo {
+    let ip_limits = context.ip_limiter.limits();
+    LimitsInfo {
+        max_connections: NonZeroUsize::new(
+            context.settings.max_connections.load(Ordering::Relaxed),
+        ),
+        max_connections_per_ip: ip_limits.max_connections,
+        requests_per_minute: ip_limits.requests_per_minute,
+        request_burst: ip_limits.request_burst,
+    }
+}
+
+/// Parse a session ID shown to the operator back into its nonce.
+fn parse_session_id(id: &str) -> Option<Nonce> {
+    if id.len() != 32 || !id.is_ascii() {
+        return None;
+    }
+    let (high, low) = id.split_at(16);
+    Some([
+        u64::from_str_radix(high, 16).ok()?,
+        u64::from_str_radix(low, 16).ok()?,
+    ])
+}
+
+/// Send a request to a running server's admin socket, with a token if it needs one, and print its response.
+pub async fn run_client(
+    socket: &Path,
+    token: Option<&str>,
+    request: &AdminRequest,
+) -> Result<(), AdminClientError> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| AdminClientError::Connect(socket.to_path_buf(), e))?;
+    let (recv, mut send) = stream.into_split();
+
+    let mut line = serde_json::to_vec(&AdminMessage {
+        token: token.map(str::to_owned),
+        request: request.clone(),
+    })?;
+    line.push(b'\n');
+    send.write_all(&line).await?;
+
+    let mut response = String::new();
+    BufReader::new(recv).read_line(&mut response).await?;
+    match serde_json::from_str::<AdminResponse>(&response)? {
+        AdminResponse::Error(e) => Err(AdminClientError::Refused(e)),
+        response => {
+            println!("{}", serde_json::to_string_pretty(&response)?);
+            Ok(())
+        }
+    }
+}
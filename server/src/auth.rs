@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use zeroize::Zeroize as _;
+
+/// The name given to clients that authenticate with the shared token.
+const SHARED_TOKEN_USER: &str = "shared";
+
+/// The access tokens clients may authenticate with, and the users they belong to.
+/// Authentication is only required when at least one token is configured.
+#[derive(Debug, Default)]
+pub struct AuthTokens {
+    tokens: Vec<(String, String)>,
+}
+impl AuthTokens {
+    /// Accept a token shared by every client and tokens for individual users, by user name.
+    /// Empty tokens are ignored so that a blank setting can't let everyone in.
+    pub fn new(shared_token: Option<String>, user_tokens: HashMap<String, String>) -> Self {
+        let tokens = shared_token
+            .map(|token| (SHARED_TOKEN_USER.to_owned(), token))
+            .into_iter()
+            .chain(user_tokens)
+            .filter(|(_, token)| !token.is_empty())
+            .collect();
+        Self { tokens }
+    }
+
+    /// Whether clients must authenticate before publishing or subscribing.
+    pub fn is_required(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Find the user a token belongs to. Every token is compared in full so that timing reveals nothing about them.
+    pub fn user_of(&self, token: &[u8]) -> Option<&str> {
+        self.tokens.iter().fold(None, |user, (name, expected)| {
+            if constant_time_eq(expected.as_bytes(), token) {
+                Some(name.as_str())
+            } else {
+                user
+            }
+        })
+    }
+}
+impl Drop for AuthTokens {
+    fn drop(&mut self) {
+        for (_, token) in &mut self.tokens {
+            token.zeroize();
+        }
+    }
+}
+
+/// Compare two byte strings in time that depends only on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,277 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+/// How often the ban list file is checked for changes made outside the server.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A range of IP addresses given in CIDR notation, such as `203.0.113.0/24`. A bare address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+impl IpRange {
+    /// Whether the address is within the range. IPv4 addresses mapped into IPv6 match IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix) == self.network
+    }
+}
+
+/// Clear the bits of an address beyond the prefix length.
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+impl FromStr for IpRange {
+    type Err = BanListError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BanListError::InvalidRange(s.to_owned());
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = IpAddr::from_str(address.trim())
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .map(|p| p.trim().parse::<u8>().map_err(|_| invalid()))
+            .transpose()?
+            .unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network: mask(network, prefix),
+            prefix,
+        })
+    }
+}
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Errors that can occur when reading, changing, or saving the ban list.
+#[derive(Debug, thiserror::Error)]
+pub enum BanListError {
+    /// A line of the ban list, or a requested change, is not an IP address or CIDR range.
+    #[error("Invalid IP range {0:?}")]
+    InvalidRange(String),
+
+    /// Failed to read or write the ban list file.
+    #[error("Failed to access the ban list {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+/// The ranges of IP addresses the server refuses to serve.
+/// Kept in a file with one range per line when a path is given, so that bans survive restarts
+/// and can be edited by hand. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    state: RwLock<BanListState>,
+}
+
+/// The banned ranges and the modification time of the file they match.
+#[derive(Debug, Default)]
+struct BanListState {
+    ranges: Vec<IpRange>,
+    modified: Option<SystemTime>,
+}
+impl BanList {
+    /// Load the ban list from a file. A missing file is an empty list, and is created when a ban is added.
+    pub fn load(path: &Path) -> Result<Self, BanListError> {
+        let list = Self {
+            path: Some(path.to_path_buf()),
+            ..Self::default()
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Whether connections from the address are refused.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.read().ranges.iter().any(|range| range.contains(ip))
+    }
+
+    /// The banned ranges, in the order they were added.
+    pub fn ranges(&self) -> Vec<IpRange> {
+        self.read().ranges.clone()
+    }
+
+    /// Whether changes to the list are saved to a file.
+    pub fn is_persisted(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Ban a range of addresses, saving the list if it has a file. Returns false if the range was already banned.
+    pub fn add(&self, range: IpRange) -> Result<bool, BanListError> {
+        let mut state = self.write();
+        if state.ranges.contains(&range) {
+            return Ok(false);
+        }
+        state.ranges.push(range);
+        self.save(&mut state)?;
+        Ok(true)
+    }
+
+    /// Lift the ban on a range of addresses, saving the list if it has a file.
+    /// Returns false if the range was not banned.
+    pub fn remove(&self, range: IpRange) -> Result<bool, BanListError> {
+        let mut state = self.write();
+        let count = state.ranges.len();
+        state.ranges.retain(|r| *r != range);
+        if state.ranges.len() == count {
+            return Ok(false);
+        }
+        self.save(&mut state)?;
+        Ok(true)
+    }
+
+    /// Read the file again if it changed since it was last read or written.
+    /// Returns true if the list was replaced. The list is left as it was if the file is invalid.
+    pub fn reload(&self) -> Result<bool, BanListError> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => Some(modified),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(BanListError::Io(path.clone(), e)),
+        };
+        if self.read().modified == modified {
+            return Ok(false);
+        }
+
+        let ranges = match modified {
+            Some(_) => std::fs::read_to_string(path)
+                .map_err(|e| BanListError::Io(path.clone(), e))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(IpRange::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        *self.write() = BanListState { ranges, modified };
+        Ok(true)
+    }
+
+    /// Write the list to its file, if it has one, and remember the file's new modification time.
+    fn save(&self, state: &mut BanListState) -> Result<(), BanListError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = state
+            .ranges
+            .iter()
+            .fold(String::new(), |mut contents, range| {
+                contents.push_str(&range.to_string());
+                contents.push('\n');
+                contents
+            });
+        std::fs::write(path, contents).map_err(|e| BanListError::Io(path.clone(), e))?;
+
+        // Avoid reading back the file we just wrote.
+        state.modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Ok(())
+    }
+
+    /// Lock the list for reading. The list stays consistent even if a holder panicked, so poisoning is ignored.
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, BanListState> {
+        self.state
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Lock the list for changes.
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, BanListState> {
+        self.state
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_match_addresses_within_their_prefix() {
+        let range = IpRange::from_str("203.0.113.9/24").unwrap();
+        assert_eq!(range.to_string(), "203.0.113.0/24");
+        assert!(range.contains(ip("203.0.113.77")));
+        assert!(!range.contains(ip("203.0.114.1")));
+
+        let range = IpRange::from_str("2001:db8:1:2::/64").unwrap();
+        assert!(range.contains(ip("2001:db8:1:2:abcd::1")));
+        assert!(!range.contains(ip("2001:db8:1:3::1")));
+        assert!(!range.contains(ip("203.0.113.77")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_ranges() {
+        let range = IpRange::from_str("203.0.113.0/24").unwrap();
+        assert!(range.contains(ip("::ffff:203.0.113.5")));
+
+        // A mapped range is stored as the IPv4 range it stands for.
+        let mapped = IpRange::from_str("::ffff:203.0.113.5").unwrap();
+        assert_eq!(mapped.to_string(), "203.0.113.5/32");
+        assert!(mapped.contains(ip("203.0.113.5")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert_eq!(
+            IpRange::from_str("198.51.100.7").unwrap().to_string(),
+            "198.51.100.7/32"
+        );
+        for s in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "nonsense", ""] {
+            assert!(
+                matches!(IpRange::from_str(s), Err(BanListError::InvalidRange(_))),
+                "{s:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn bans_are_saved_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("file_yeet_ban_list_{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+
+        let list = BanList::load(&path).unwrap();
+        let range = IpRange::from_str("198.51.100.0/24").unwrap();
+        assert!(list.add(range).unwrap());
+        assert!(!list.add(range).unwrap());
+        assert!(list.is_banned(ip("198.51.100.20")));
+
+        let reloaded = BanList::load(&path).unwrap();
+        assert_eq!(reloaded.ranges(), vec![range]);
+        assert!(reloaded.remove(range).unwrap());
+        assert!(!reloaded.remove(range).unwrap());
+        assert!(!reloaded.is_banned(ip("198.51.100.20")));
+        std::fs::remove_file(&path).ok();
+    }
+}
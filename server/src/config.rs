@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+};
+
+/// Server settings loaded from a TOML file. Every setting is optional, and command line flags take precedence.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The IP address the server will bind to.
+    pub bind_ip: Option<String>,
+
+    /// The port the server will bind to.
+    pub bind_port: Option<NonZeroU16>,
+
+    /// The port to host an echo peer on.
+    pub echo_port: Option<NonZeroU16>,
+
+    /// The most clients that may be connected at once.
+    pub max_connections: Option<NonZeroUsize>,
+
+    /// The most clients that may be connected at once from a single IP address.
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+
+    /// The number of lookup requests a single IP address may make per minute.
+    pub requests_per_minute: Option<NonZeroU32>,
+
+    /// The most lookup requests a single IP address may make in a burst.
+    pub request_burst: Option<NonZeroU32>,
+
+    /// Require clients to override their port before publishing.
+    pub require_port_override: Option<bool>,
+
+    /// Forward peer-to-peer streams through the server.
+    pub allow_relay: Option<bool>,
+
+    /// Run without leaving traces of clients behind.
+    pub ephemeral: Option<bool>,
+
+    /// The number of seconds a publish lasts unless the publisher refreshes it.
+    pub publish_ttl: Option<NonZeroU64>,
+
+    /// The number of seconds between checks that each publisher can still be reached.
+    pub publisher_heartbeat: Option<NonZeroU64>,
+
+    /// The number of seconds a publisher has to answer a heartbeat.
+    pub heartbeat_timeout: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes a client may send on a single QUIC stream.
+    pub stream_receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes a client may send across every stream of a QUIC connection.
+    pub receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes to send across every stream of a QUIC connection.
+    pub send_window: Option<NonZeroU64>,
+
+    /// The size of the UDP datagrams to send before path MTU discovery finds a larger size.
+    pub initial_mtu: Option<u16>,
+
+    /// The number of seconds of inactivity before a QUIC connection is closed.
+    pub idle_timeout: Option<NonZeroU64>,
+
+    /// The number of seconds between keep-alive packets on idle QUIC connections.
+    pub keep_alive_interval: Option<NonZeroU64>,
+
+    /// A file to write a JSON summary of the server's run to when it shuts down.
+    pub shutdown_report: Option<PathBuf>,
+
+    /// A URL to post JSON events about publishes, introductions, and refused connections to.
+    pub webhook_url: Option<String>,
+
+    /// A certificate chain file to present to clients.
+    pub cert: Option<PathBuf>,
+
+    /// The private key file for the certificate.
+    pub key: Option<PathBuf>,
+
+    /// A file of CA certificates that clients must present a certificate from.
+    pub client_ca: Option<PathBuf>,
+
+    /// A token every client must present before publishing or subscribing.
+    pub auth_token: Option<String>,
+
+    /// Tokens for individual users, by user name. Clients may present any of them, or the shared token.
+    pub user_tokens: HashMap<String, String>,
+
+    /// The most verbose level of logs to print, such as `info` or `debug`.
+    pub log_level: Option<String>,
+
+    /// The format to print logs in, `text` or `json`.
+    pub log_format: Option<String>,
+
+    /// An OpenTelemetry collector to export request spans to over OTLP/gRPC.
+    #[cfg(feature = "otel")]
+    pub otlp_endpoint: Option<String>,
+
+    /// A file of IP addresses and CIDR ranges to refuse connections from.
+    pub ban_list: Option<PathBuf>,
+
+    /// A Unix socket to serve the admin interface on.
+    #[cfg(unix)]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Tokens for the admin interface, by name, each allowed only the requests its scopes cover.
+    /// Without any, every request on the admin socket is allowed.
+    #[cfg(unix)]
+    pub admin_tokens: HashMap<String, AdminTokenConfig>,
+
+    /// Serve over Unix domain sockets in this directory instead of UDP.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub unix_socket_dir: Option<PathBuf>,
+}
+
+/// A token for the admin interface and the requests it may make.
+#[cfg(unix)]
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminTokenConfig {
+    pub token: String,
+    pub scopes: Vec<crate::admin::AdminScope>,
+}
+
+/// Errors that can occur when loading a configuration file.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// Failed to read the configuration file.
+    #[error("Failed to read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    /// The configuration file is not valid TOML or contains unknown settings.
+    #[error("Invalid configuration in {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl ConfigFile {
+    /// Read and parse a TOML configuration file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+}
@@ -0,0 +1,3225 @@
+use std::{
+    collections::HashMap,
+    mem::size_of,
+    net::SocketAddr,
+    num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::BufMut as _;
+use clap::{CommandFactory as _, Parser};
+use file_yeet_shared::{
+    BiStream, ClientApiRequest, HashAlgorithm, HashBytes, LookupStatus, PeerAddr, PooledBuffer,
+    PublishControl, RelayRole, ServerCapabilities, ServerNotification, SocketAddrHelper,
+    GOODBYE_CODE, MAX_SERVER_COMMUNICATION_SIZE, PUBLISH_HEARTBEAT, PUBLISH_REFRESH,
+    PUBLISH_SIGNATURE_LENGTH, PUBLISH_TRACE_CONTEXT, RELAY_OFFER, SERVER_MESSAGE_BUFFERS,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{broadcast, mpsc, oneshot, Mutex, Notify, RwLock},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::Instrument as _;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+};
+use zeroize::Zeroize as _;
+
+#[cfg(unix)]
+mod admin;
+mod auth;
+mod ban_list;
+mod config;
+mod rate_limit;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod webhook;
+
+/// A client stream that is handling a publish request.
+#[derive(Debug)]
+struct Publisher {
+    // A reference to the client's socket address.
+    pub address: Arc<RwLock<PeerAddr>>,
+
+    // A channel to send messages to the task handling this client's publish request.
+    pub stream: mpsc::Sender<PublisherMessage>,
+
+    // Whether the client opted in to relays before publishing.
+    pub accepts_relay: bool,
+
+    // The client's address on its local network, introduced to peers sharing its public IP.
+    pub private_address: Option<PeerAddr>,
+}
+impl Publisher {
+    /// Whether the publisher can be reached at the address, publicly or on its local network.
+    async fn is_at(&self, address: &PeerAddr) -> bool {
+        *self.address.read().await == *address || self.private_address.as_ref() == Some(address)
+    }
+}
+type PublisherRef = Arc<RwLock<Publisher>>;
+
+/// A message for the task handling a client's publish request.
+#[derive(Debug)]
+enum PublisherMessage {
+    /// Introduce the subscriber at this address to the publisher, with the trace context of their request if it had one.
+    Introduce(String, Option<String>),
+
+    /// Offer the publisher a relay to the subscriber at this address, accepted with the token.
+    Relay(u64, String),
+
+    /// Ask the publisher to refresh their publish before it expires.
+    Refresh,
+
+    /// Check that the publisher can still be reached.
+    Heartbeat,
+
+    /// Tell the publisher their publish expired and end it.
+    Expired,
+}
+
+/// A client and the file size they are publishing.
+#[derive(Debug)]
+struct PublishedFile {
+    pub publisher: PublisherRef,
+    pub file_size: u64,
+
+    // The algorithm the publisher hashed the file with. Subscribers are only introduced to publishers that agree.
+    pub algorithm: HashAlgorithm,
+
+    // What the publisher tells subscribers about the file.
+    pub hints: PublishHints,
+
+    // When the publish is removed unless the publisher refreshes it, if the server has a publish TTL.
+    pub expires_at: Option<Instant>,
+
+    // Whether the publisher has been asked to refresh since their last refresh.
+    pub refresh_requested: bool,
+}
+impl PublishedFile {
+    pub fn new(
+        publisher: PublisherRef,
+        file_size: u64,
+        algorithm: HashAlgorithm,
+        hints: PublishHints,
+        ttl: Option<Duration>,
+    ) -> Self {
+        Self {
+            publisher,
+            file_size,
+            algorithm,
+            hints,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            refresh_requested: false,
+        }
+    }
+}
+
+/// A client's request to publish a file, as read from their stream.
+#[derive(Debug)]
+struct PublishRequest {
+    pub key: RoomHash,
+    pub algorithm: HashAlgorithm,
+    pub file_size: u64,
+    pub hints: PublishHints,
+}
+
+/// The hints a publisher attaches to a file for subscribers.
+/// The server passes them along as given and never interprets them.
+#[derive(Clone, Debug, Default)]
+struct PublishHints {
+    // The file name and MIME type the publisher suggests saving the file with. Empty if not given.
+    pub file_name: String,
+    pub mime_type: String,
+
+    // The publisher's signature over the hash and file size, for subscribers to verify.
+    pub signature: Option<[u8; PUBLISH_SIGNATURE_LENGTH]>,
+}
+impl PublishHints {
+    /// Read the hints that follow a publish request.
+    /// Hints that are too long are dropped rather than crowding other publishers out of subscribe responses.
+    async fn read(quic_recv: &mut quinn::RecvStream) -> Result<Self, ClientRequestError> {
+        let mut file_name = read_short_string(quic_recv).await?;
+        let mut mime_type = read_short_string(quic_recv).await?;
+        for text in [&mut file_name, &mut mime_type] {
+            if text.len() > file_yeet_shared::MAX_FILE_METADATA_LENGTH {
+                text.clear();
+            }
+        }
+
+        let signed = quic_recv.read_u8().await.map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?;
+        let signature = if signed == 0 {
+            None
+        } else {
+            let mut signature = [0; PUBLISH_SIGNATURE_LENGTH];
+            quic_recv.read_exact(&mut signature).await.map_err(|_| {
+                ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            })?;
+            Some(signature)
+        };
+
+        Ok(Self {
+            file_name,
+            mime_type,
+            signature,
+        })
+    }
+
+    /// The number of bytes the hints take in a subscribe response.
+    fn encoded_len(&self) -> usize {
+        3 * size_of::<u8>()
+            + self.file_name.len()
+            + self.mime_type.len()
+            + self
+                .signature
+                .as_ref()
+                .map_or(0, |_| PUBLISH_SIGNATURE_LENGTH)
+    }
+
+    /// Append the hints to a subscribe response.
+    fn put(&self, bb: &mut bytes::BytesMut) {
+        for text in [&self.file_name, &self.mime_type] {
+            bb.put_u8(u8::try_from(text.len()).expect("File metadata length is invalid"));
+            bb.put(text.as_bytes());
+        }
+        match &self.signature {
+            Some(signature) => {
+                bb.put_u8(1);
+                bb.put(&signature[..]);
+            }
+            None => bb.put_u8(0),
+        }
+    }
+}
+
+/// Where a paginated subscribe continues from: the seed that orders the publishers, and the key of the last one visited.
+/// Sent to clients as an opaque `u64`, where zero starts a new listing and ends a finished one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SubscribeCursor {
+    seed: u16,
+    last_key: u64,
+}
+impl SubscribeCursor {
+    /// The bits of the wire cursor holding the key of the last publisher visited.
+    const KEY_BITS: u32 = 48;
+
+    /// Read a cursor sent by a client, or `None` for a new listing.
+    fn from_wire(cursor: u64) -> Option<Self> {
+        (cursor != 0).then_some(Self {
+            seed: (cursor >> Self::KEY_BITS) as u16,
+            last_key: cursor & ((1 << Self::KEY_BITS) - 1),
+        })
+    }
+
+    /// The cursor to send to the client. Never zero, since the seed is never zero.
+    fn to_wire(self) -> u64 {
+        (u64::from(self.seed) << Self::KEY_BITS) | self.last_key
+    }
+
+    /// A random seed for a new listing, never zero so that its cursors aren't either.
+    fn new_seed() -> u16 {
+        rand::random::<u16>().max(1)
+    }
+
+    /// The key that orders a publisher in the listing with the given seed.
+    /// Keys don't change as other publishers come and go, so continuing after the last key visited
+    /// neither repeats nor skips the publishers that remain. Two publishers only share a key by a 48-bit collision.
+    fn key(seed: u16, nonce: &Nonce) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&(seed, nonce), &mut hasher);
+        std::hash::Hasher::finish(&hasher) >> (u64::BITS - Self::KEY_BITS)
+    }
+}
+
+/// The most publishers that can be introduced in one subscribe response, each taking at least
+/// the length of a short address, a file size, and empty hints.
+const MAX_INTRODUCTIONS_SENT: usize = MAX_SERVER_COMMUNICATION_SIZE
+    / (size_of::<u8>() + "0.0.0.0:0".len() + size_of::<u64>() + 3 * size_of::<u8>());
+
+/// The keys and nonces of the next publishers in the listing with `seed`, in order, after the one with `last_key`.
+/// At most `page_size` are returned, along with how many publishers come after `last_key` in all.
+fn next_subscribe_page<'a>(
+    nonces: impl Iterator<Item = &'a Nonce>,
+    seed: u16,
+    last_key: Option<u64>,
+    page_size: usize,
+) -> (Vec<(u64, Nonce)>, usize) {
+    // Only keep the lowest keys seen so far, instead of ordering every publisher.
+    let mut remaining = 0;
+    let mut page = std::collections::BinaryHeap::new();
+    for nonce in nonces {
+        let key = SubscribeCursor::key(seed, nonce);
+        if last_key >= Some(key) {
+            continue;
+        }
+        remaining += 1;
+        page.push((key, *nonce));
+        if page.len() > page_size {
+            page.pop();
+        }
+    }
+    (page.into_sorted_vec(), remaining)
+}
+
+/// A nonce for the server to use in its communications with clients.
+type Nonce = [u64; 2];
+
+/// Format a session nonce as the ID shown to the operator and in logs.
+fn session_id(nonce: Nonce) -> String {
+    format!("{:016x}{:016x}", nonce[0], nonce[1])
+}
+
+/// A notification to push to every client with an open notification stream.
+type NotificationMessage = (ServerNotification, Arc<str>);
+
+/// The maximum time the echo peer spends on a single test introduction.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum time a subscriber waits for a publisher to accept a relay.
+const RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shortest time between sweeps for expired publishes.
+const MIN_PUBLISH_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The time a publisher has to answer a heartbeat when the operator doesn't choose one.
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The reason sent to publishers whose publish expired without being refreshed.
+const PUBLISH_EXPIRED_MESSAGE: &str = "The publish expired without being refreshed";
+
+/// Code sent when refusing a client because the server is at its connection limit.
+const SERVER_FULL_CODE: quinn::VarInt = quinn::VarInt::from_u32(1);
+
+/// The reason sent to clients refused because the server is at its connection limit.
+const SERVER_FULL_MESSAGE: &[u8] = b"The server is full";
+
+/// The time to allow shutdown notices to reach clients before closing connections.
+const SHUTDOWN_NOTICE_GRACE: Duration = Duration::from_millis(250);
+
+/// The command line interface for `file_yeet_server`.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Act on a running server instead of starting one.
+    #[cfg(unix)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A TOML file to load settings from. Command line flags override the file's values.
+    #[arg(short = 'c', long)]
+    config: Option<std::path::PathBuf>,
+
+    /// The IP address the server will bind to. The default is local for testing.
+    #[arg(short = 'b', long)]
+    bind_ip: Option<String>,
+
+    /// The port the server will bind to. The default is 7828.
+    #[arg(short = 'p', long)]
+    bind_port: Option<NonZeroU16>,
+
+    /// The most clients that may be connected at once. Further clients are refused until others leave.
+    #[arg(long)]
+    max_connections: Option<NonZeroUsize>,
+
+    /// The most clients that may be connected at once from a single IP address.
+    #[arg(long)]
+    max_connections_per_ip: Option<NonZeroUsize>,
+
+    /// The number of publish, subscribe, and introduction requests a single IP address may make per minute.
+    ///
+    /// Requests beyond the limit are refused with a reason asking the client to try again later.
+    #[arg(long)]
+    requests_per_minute: Option<NonZeroU32>,
+
+    /// The most requests a single IP address may make in a burst before being held to `--requests-per-minute`.
+    /// Defaults to a minute's worth of requests.
+    #[arg(long, requires = "requests_per_minute")]
+    request_burst: Option<NonZeroU32>,
+
+    /// The most verbose level of logs to print, such as `info` or `debug`.
+    #[arg(long)]
+    log_level: Option<tracing::Level>,
+
+    /// The format to print logs in, `text` or `json`. The default is `text`.
+    ///
+    /// JSON logs are printed one object per line, with the request type, file hash, client session,
+    /// and latency of each request as fields.
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+
+    /// An OpenTelemetry collector to export request spans to over OTLP/gRPC, such as `http://localhost:4317`.
+    ///
+    /// Spans are exported at the level given by `--log-level`.
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Require clients to tell the server which port to introduce them as before they may publish.
+    ///
+    /// Helps avoid publishes on NAT ephemeral ports that will soon expire.
+    #[arg(long)]
+    require_port_override: bool,
+
+    /// The port to host an echo peer on, which clients can use to test their peer-to-peer reachability.
+    ///
+    /// The echo peer is disabled unless a port is given.
+    #[arg(long)]
+    echo_port: Option<NonZeroU16>,
+
+    /// Forward peer-to-peer streams through the server for clients that cannot connect directly.
+    ///
+    /// Both peers must opt in. Relayed data passes through the server, which costs bandwidth.
+    #[arg(long)]
+    allow_relay: bool,
+
+    /// A file to write a JSON summary of the server's run to when it shuts down.
+    #[arg(long)]
+    shutdown_report: Option<std::path::PathBuf>,
+
+    /// Run without leaving traces of clients behind, for privacy-focused deployments.
+    ///
+    /// Nothing is written to disk, client activity is not logged, maps are shrunk as entries are removed,
+    /// and buffers that held client addresses are zeroed before reuse. Clients are told the server is ephemeral.
+    #[arg(long, conflicts_with_all = ["shutdown_report", "webhook_url"])]
+    ephemeral: bool,
+
+    /// A URL to post JSON events to when publishes are added or removed, subscribers are introduced,
+    /// or connections are refused, for chat notifications or audit pipelines.
+    ///
+    /// Events are posted one at a time in the order they happen. Events are dropped if the webhook falls far behind.
+    #[arg(long)]
+    webhook_url: Option<reqwest::Url>,
+
+    /// The number of seconds a publish lasts unless the publisher refreshes it.
+    ///
+    /// Publishers are asked to refresh halfway through, so publishers that crashed without closing their
+    /// connection stop being introduced to subscribers. Publishes never expire unless a TTL is given.
+    #[arg(long)]
+    publish_ttl: Option<NonZeroU64>,
+
+    /// The number of seconds between checks that each publisher can still be reached.
+    ///
+    /// Publishers that don't answer within `--heartbeat-timeout` are dropped, so subscribers aren't introduced
+    /// to publishers whose NAT binding has expired. Publishers are not checked unless an interval is given.
+    #[arg(long)]
+    publisher_heartbeat: Option<NonZeroU64>,
+
+    /// The number of seconds a publisher has to answer a heartbeat. The default is 10.
+    #[arg(long, requires = "publisher_heartbeat")]
+    heartbeat_timeout: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes a client may send on a single QUIC stream.
+    ///
+    /// Raise it, with `--receive-window`, when relaying over fast links with high latency.
+    #[arg(long)]
+    stream_receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes a client may send across every stream of a QUIC connection.
+    #[arg(long)]
+    receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes to send across every stream of a QUIC connection.
+    #[arg(long)]
+    send_window: Option<NonZeroU64>,
+
+    /// The size of the UDP datagrams to send before path MTU discovery finds a larger size, in bytes.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1200..))]
+    initial_mtu: Option<u16>,
+
+    /// The number of seconds of inactivity before a QUIC connection is closed. The default is 120.
+    #[arg(long)]
+    idle_timeout: Option<NonZeroU64>,
+
+    /// The number of seconds between keep-alive packets on idle QUIC connections. The default is 30.
+    #[arg(long)]
+    keep_alive_interval: Option<NonZeroU64>,
+
+    /// A certificate chain file, in PEM or DER format, to present to clients instead of a self-signed certificate.
+    #[arg(long, requires = "key")]
+    cert: Option<std::path::PathBuf>,
+
+    /// The private key file, in PEM or DER format, for the certificate given with `--cert`.
+    #[arg(long, requires = "cert")]
+    key: Option<std::path::PathBuf>,
+
+    /// A file of CA certificates, in PEM or DER format, that clients must present a certificate from.
+    ///
+    /// Clients without a certificate issued by one of these CAs are refused during the handshake,
+    /// before any of their requests are read. The echo peer still accepts any peer.
+    #[arg(long)]
+    client_ca: Option<std::path::PathBuf>,
+
+    /// A token clients must present before they may publish or subscribe.
+    ///
+    /// Tokens for individual users can be given as `user_tokens` in the configuration file,
+    /// which also keeps them out of the process list.
+    #[arg(long)]
+    auth_token: Option<String>,
+
+    /// Tokens for individual users, only read from the configuration file.
+    #[arg(skip)]
+    user_tokens: HashMap<String, String>,
+
+    /// A file of IP addresses and CIDR ranges to refuse connections from, one per line.
+    ///
+    /// The file is reloaded when it changes, and bans added through the admin interface are saved to it.
+    #[arg(long)]
+    ban_list: Option<std::path::PathBuf>,
+
+    /// A Unix socket to serve the admin interface on, for `file_yeet_server admin` to connect to.
+    ///
+    /// Only the user running the server may connect to it.
+    #[cfg(unix)]
+    #[arg(long)]
+    admin_socket: Option<std::path::PathBuf>,
+
+    /// Tokens for the admin interface and their scopes, only read from the configuration file.
+    #[cfg(unix)]
+    #[arg(skip)]
+    admin_tokens: HashMap<String, config::AdminTokenConfig>,
+
+    /// Serve over Unix domain sockets in this directory instead of UDP, for local testing.
+    ///
+    /// Clients must be given the same directory. The echo peer still uses UDP.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    #[arg(long)]
+    unix_socket_dir: Option<std::path::PathBuf>,
+}
+impl Cli {
+    /// Fill in the settings that were not given on the command line from a configuration file.
+    /// Flags can only enable boolean settings, not disable ones the file enables.
+    fn apply_config(&mut self, config: config::ConfigFile) -> Result<(), String> {
+        self.bind_ip = self.bind_ip.take().or(config.bind_ip);
+        self.bind_port = self.bind_port.or(config.bind_port);
+        self.echo_port = self.echo_port.or(config.echo_port);
+        self.max_connections = self.max_connections.or(config.max_connections);
+        self.max_connections_per_ip = self
+            .max_connections_per_ip
+            .or(config.max_connections_per_ip);
+        self.requests_per_minute = self.requests_per_minute.or(config.requests_per_minute);
+        self.request_burst = self.request_burst.or(config.request_burst);
+        self.require_port_override |= config.require_port_override.unwrap_or_default();
+        self.allow_relay |= config.allow_relay.unwrap_or_default();
+        self.ephemeral |= config.ephemeral.unwrap_or_default();
+        self.publish_ttl = self.publish_ttl.or(config.publish_ttl);
+        self.publisher_heartbeat = self.publisher_heartbeat.or(config.publisher_heartbeat);
+        self.heartbeat_timeout = self.heartbeat_timeout.or(config.heartbeat_timeout);
+        self.stream_receive_window = self.stream_receive_window.or(config.stream_receive_window);
+        self.receive_window = self.receive_window.or(config.receive_window);
+        self.send_window = self.send_window.or(config.send_window);
+        self.initial_mtu = self.initial_mtu.or(config.initial_mtu);
+        self.idle_timeout = self.idle_timeout.or(config.idle_timeout);
+        self.keep_alive_interval = self.keep_alive_interval.or(config.keep_alive_interval);
+        self.shutdown_report = self.shutdown_report.take().or(config.shutdown_report);
+        if self.webhook_url.is_none() {
+            self.webhook_url = config
+                .webhook_url
+                .map(|u| {
+                    u.parse()
+                        .map_err(|e| format!("Invalid webhook URL {u:?}: {e}"))
+                })
+                .transpose()?;
+        }
+        self.cert = self.cert.take().or(config.cert);
+        self.key = self.key.take().or(config.key);
+        self.client_ca = self.client_ca.take().or(config.client_ca);
+        self.auth_token = self.auth_token.take().or(config.auth_token);
+        self.user_tokens = config.user_tokens;
+        self.ban_list = self.ban_list.take().or(config.ban_list);
+        #[cfg(unix)]
+        {
+            self.admin_socket = self.admin_socket.take().or(config.admin_socket);
+            self.admin_tokens = config.admin_tokens;
+        }
+        #[cfg(all(unix, feature = "unix-socket"))]
+        {
+            self.unix_socket_dir = self.unix_socket_dir.take().or(config.unix_socket_dir);
+        }
+        #[cfg(feature = "otel")]
+        {
+            self.otlp_endpoint = self.otlp_endpoint.take().or(config.otlp_endpoint);
+        }
+        if self.log_level.is_none() {
+            self.log_level = config
+                .log_level
+                .map(|l| {
+                    l.parse()
+                        .map_err(|e| format!("Invalid log level {l:?}: {e}"))
+                })
+                .transpose()?;
+        }
+        if self.log_format.is_none() {
+            self.log_format = config.log_format.map(|f| f.parse()).transpose()?;
+        }
+
+        // The file bypasses the argument parser, so check the relationships between settings again.
+        if self.ephemeral && self.shutdown_report.is_some() {
+            return Err("An ephemeral server cannot write a shutdown report".to_owned());
+        }
+        if self.ephemeral && self.webhook_url.is_some() {
+            return Err("An ephemeral server cannot report events to a webhook".to_owned());
+        }
+        if self.cert.is_some() != self.key.is_some() {
+            return Err("A certificate and its key must be given together".to_owned());
+        }
+        if self.heartbeat_timeout.is_some() && self.publisher_heartbeat.is_none() {
+            return Err(
+                "A heartbeat timeout can only be given with a heartbeat interval".to_owned(),
+            );
+        }
+        if self.request_burst.is_some() && self.requests_per_minute.is_none() {
+            return Err("A request burst can only be given with a request rate".to_owned());
+        }
+        if self.initial_mtu.is_some_and(|mtu| mtu < 1200) {
+            return Err("The initial MTU must be at least 1200 bytes".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// The formats the server can print its logs in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+
+    /// One JSON object per line, for log collectors to ingest without parsing text.
+    Json,
+}
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown log format {s:?}, expected `text` or `json`"
+            )),
+        }
+    }
+}
+
+/// Commands that act on a running server instead of starting one.
+#[cfg(unix)]
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Inspect or adjust a running server through its admin socket.
+    Admin {
+        /// The admin socket of the running server, as given to it with `--admin-socket`.
+        #[arg(short, long)]
+        socket: std::path::PathBuf,
+
+        /// A token from the server's `admin_tokens`, required when the server has any.
+        ///
+        /// Read from the `FILE_YEET_ADMIN_TOKEN` environment variable if not given, which keeps it out of the process list.
+        #[arg(short, long)]
+        token: Option<String>,
+
+        #[command(subcommand)]
+        request: admin::AdminRequest,
+    },
+}
+
+/// Settings the operator can change while the server runs.
+#[derive(Debug, Default)]
+struct RuntimeSettings {
+    /// The most clients that may be connected at once, or zero if unlimited.
+    pub max_connections: AtomicUsize,
+
+    /// The addresses the server refuses to serve.
+    pub bans: ban_list::BanList,
+}
+
+/// A connected client, as listed to the operator.
+#[derive(Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+struct SessionHandle {
+    pub connection: quinn::Connection,
+    pub peer_addr: Arc<RwLock<PeerAddr>>,
+    pub connected_at: Instant,
+}
+
+/// The connected clients, by session nonce, so the operator can inspect and disconnect them.
+type SessionsRef = Arc<std::sync::Mutex<HashMap<Nonce, SessionHandle>>>;
+
+/// Lock the session list. The list stays consistent even if a holder panicked, so poisoning is ignored.
+fn lock_sessions(
+    sessions: &SessionsRef,
+) -> std::sync::MutexGuard<'_, HashMap<Nonce, SessionHandle>> {
+    sessions
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Disconnect every client whose address is banned. Returns the number of clients disconnected.
+fn disconnect_banned(sessions: &SessionsRef, bans: &ban_list::BanList) -> usize {
+    let sessions = lock_sessions(sessions);
+    let mut disconnected = 0;
+    for handle in sessions.values() {
+        if bans.is_banned(handle.connection.remote_address().ip()) {
+            handle.connection.close(BANNED_CODE, BANNED_MESSAGE);
+            disconnected += 1;
+        }
+    }
+    disconnected
+}
+
+/// Counters describing the server's activity over its run.
+#[derive(Debug, Default)]
+struct ServerStats {
+    pub active_connections: AtomicUsize,
+    pub peak_connections: AtomicUsize,
+    pub total_connections: AtomicU64,
+    pub total_introductions: AtomicU64,
+    pub total_relays: AtomicU64,
+    pub relayed_bytes: AtomicU64,
+}
+impl ServerStats {
+    /// Count a newly accepted client connection.
+    fn connection_opened(&self) {
+        let active = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_connections.fetch_max(active, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a client connection that has ended.
+    fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A summary of the server's run, reported on shutdown.
+#[derive(Debug, serde::Serialize)]
+struct ShutdownReport {
+    uptime_seconds: u64,
+    peak_connections: usize,
+    total_connections: u64,
+    total_introductions: u64,
+    total_relays: u64,
+    relayed_bytes: u64,
+    publishes_at_exit: usize,
+    hashes_at_exit: usize,
+    connections_at_exit: usize,
+}
+
+/// Operator policies applied to every client session.
+#[derive(Clone, Copy, Debug)]
+struct ServerPolicy {
+    /// Whether a port override request must succeed before a publish is accepted.
+    pub require_port_override: bool,
+
+    /// Whether the server forwards streams between peers that cannot connect directly.
+    pub allow_relay: bool,
+
+    /// Whether the server avoids keeping any trace of its clients.
+    pub ephemeral: bool,
+
+    /// How long a publish lasts without being refreshed, if publishes expire.
+    pub publish_ttl: Option<Duration>,
+
+    /// How often to check that publishers can still be reached, if they are checked.
+    pub publisher_heartbeat: Option<Duration>,
+
+    /// How long a publisher has to answer a heartbeat.
+    pub heartbeat_timeout: Duration,
+
+    /// Whether clients must authenticate with a token before publishing or subscribing.
+    pub require_auth: bool,
+}
+impl ServerPolicy {
+    /// The capabilities advertised to clients that ask for them.
+    fn capabilities(self, echo_peer: bool) -> ServerCapabilities {
+        let flags = [
+            (self.allow_relay, ServerCapabilities::RELAY),
+            (echo_peer, ServerCapabilities::ECHO_PEER),
+            (
+                self.require_port_override,
+                ServerCapabilities::PORT_OVERRIDE_REQUIRED,
+            ),
+            (self.ephemeral, ServerCapabilities::EPHEMERAL),
+            (self.require_auth, ServerCapabilities::AUTH_REQUIRED),
+            // Ephemeral servers don't keep more of a client's addresses than they need.
+            (!self.ephemeral, ServerCapabilities::PRIVATE_ADDRESSES),
+            (true, ServerCapabilities::TRACE_CONTEXT),
+        ];
+        ServerCapabilities(
+            flags
+                .into_iter()
+                .filter_map(|(enabled, flag)| enabled.then_some(flag))
+                .fold(0, |a, b| a | b),
+        )
+    }
+}
+
+/// The reason sent to clients that ask for a relay when the server does not offer them.
+const RELAY_DISABLED_MESSAGE: &str = "This server does not relay transfers";
+
+/// The reason sent to clients that publish before overriding their port when the server requires it.
+const PORT_OVERRIDE_REQUIRED_MESSAGE: &str =
+    "This server requires a port forward or port mapping to be configured before publishing";
+
+/// The reason sent to clients that publish or subscribe before authenticating when the server requires it.
+const AUTH_REQUIRED_MESSAGE: &str = "This server requires an access token";
+
+/// The reason sent to clients that make more requests than their address is allowed.
+const RATE_LIMITED_MESSAGE: &str = "Too many requests from this address, try again later";
+
+/// The close code sent to connected clients whose address is banned.
+const BANNED_CODE: quinn::VarInt = quinn::VarInt::from_u32(3);
+
+/// The reason sent to connected clients whose address is banned.
+const BANNED_MESSAGE: &[u8] = b"This address is banned from the server";
+
+/// The reason sent to clients refused because their address has too many connections open.
+const TOO_MANY_CONNECTIONS_MESSAGE: &[u8] = b"Too many connections from this address";
+
+/// The reason sent to clients that present a token the server does not accept.
+const INVALID_TOKEN_MESSAGE: &str = "The access token was not accepted";
+
+/// The time to wait before refusing a token, to slow down guessing.
+const INVALID_TOKEN_DELAY: Duration = Duration::from_secs(1);
+
+/// A file hash and the room it was published in. The default room has an empty name.
+type RoomHash = (String, HashBytes);
+
+/// A mapping between file hashes and the addresses of connected peers that are publishing the file.
+/// Publishes are only visible to subscribers in the same room.
+type PublishersRef = Arc<RwLock<HashMap<RoomHash, HashMap<Nonce, PublishedFile>>>>;
+
+/// Subscriber streams waiting for a publisher to accept their relay, by the token of the offer.
+type RelaysRef = Arc<Mutex<HashMap<u64, oneshot::Sender<BiStream>>>>;
+
+/// Run the server with the parsed command line arguments until Ctrl-C is pressed,
+/// or send a request to a running server's admin interface if that was asked for instead.
+pub async fn run(mut args: Cli) {
+    // Send the request to a running server instead of starting one.
+    #[cfg(unix)]
+    if let Some(Command::Admin {
+        socket,
+        token,
+        request,
+    }) = &args.command
+    {
+        let token = token
+            .clone()
+            .or_else(|| std::env::var("FILE_YEET_ADMIN_TOKEN").ok());
+        if let Err(e) = admin::run_client(socket, token.as_deref(), request).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Fall back to the configuration file for anything not given on the command line.
+    if let Some(path) = args.config.clone() {
+        let config = config::ConfigFile::load(&path)
+            .unwrap_or_else(|e| Cli::command().error(clap::error::ErrorKind::Io, e).exit());
+        if let Err(e) = args.apply_config(config) {
+            Cli::command()
+                .error(clap::error::ErrorKind::ArgumentConflict, e)
+                .exit();
+        }
+    }
+
+    // Initialize logging. Ephemeral servers only log problems, never the activity of their clients.
+    let log_level = if args.ephemeral {
+        Some(
+            args.log_level
+                .map_or(tracing::Level::WARN, |l| l.min(tracing::Level::WARN)),
+        )
+    } else {
+        args.log_level
+    };
+    let fmt_layer = match args.log_format.unwrap_or_default() {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+
+        // Put each event's fields at the top level, next to the fields of the spans it happened in.
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .boxed(),
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::from_level(
+            log_level.unwrap_or(tracing::Level::INFO),
+        ))
+        .with(fmt_layer);
+
+    // Export request spans to the collector as well, if one was given.
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().and_then(|endpoint| {
+        telemetry::otlp_layer(endpoint)
+            .map_err(|e| eprintln!("Failed to start exporting spans to {endpoint}: {e}"))
+            .ok()
+    }));
+    subscriber.init();
+
+    // Tune the QUIC transport before any endpoint is configured.
+    file_yeet_shared::set_transport_tuning(file_yeet_shared::TransportTuning {
+        stream_receive_window: args.stream_receive_window,
+        receive_window: args.receive_window,
+        send_window: args.send_window,
+        initial_mtu: args.initial_mtu,
+        idle_timeout: args.idle_timeout,
+        keep_alive_interval: args.keep_alive_interval,
+        ..file_yeet_shared::TransportTuning::default()
+    });
+
+    Server::bind(args)
+        .run(async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                tracing::error!("Failed to handle SIGINT, aborting: {e}");
+            }
+        })
+        .await;
+    #[cfg(feature = "otel")]
+    telemetry::shutdown();
+}
+
+/// A server bound to its address, with its state prepared, ready to serve clients.
+/// Lets the server run in-process alongside its clients, such as in tests.
+pub struct Server {
+    args: Cli,
+    certificate: rustls::Certificate,
+    local_end: quinn::Endpoint,
+    context: Arc<ServerContext>,
+    start_time: Instant,
+    cancellation_token: CancellationToken,
+    task_master: TaskTracker,
+}
+impl Server {
+    /// Bind the server's endpoints and start its background tasks, such as the admin interface.
+    /// Must be called from within a Tokio runtime.
+    /// # Panics
+    /// Panics if the server's certificates, ban list, or sockets can't be loaded or bound.
+    pub fn bind(mut args: Cli) -> Self {
+        // Determine which address to bind to.
+        let SocketAddrHelper {
+            address: bind_address,
+            hostname: _,
+        } = file_yeet_shared::get_server_or_default(
+            args.bind_ip.as_deref(),
+            args.bind_port.unwrap_or(file_yeet_shared::DEFAULT_PORT),
+        )
+        .expect("Failed to parse server address");
+
+        // Print out the address we're going to bind to.
+        tracing::info!("Using bind address: {bind_address:?}");
+
+        // Use the given certificate so clients can authenticate the server, otherwise generate a self-signed one.
+        let (cert_chain, server_key) =
+            if let (Some(cert_path), Some(key_path)) = (&args.cert, &args.key) {
+                let cert_chain = file_yeet_shared::certificates::load_certificates(cert_path)
+                    .expect("Failed to load the server certificate");
+                let key = file_yeet_shared::certificates::load_private_key(key_path)
+                    .expect("Failed to load the server's private key");
+                tracing::info!("Using the certificate from {}", cert_path.display());
+                (cert_chain, key)
+            } else {
+                let (server_cert, server_key) = file_yeet_shared::generate_self_signed_cert()
+                    .expect("Failed to generate self-signed certificate");
+                (vec![server_cert], server_key)
+            };
+        let certificate = cert_chain
+            .first()
+            .cloned()
+            .expect("The server certificate chain is empty");
+        let mut peer_config =
+            quinn::ServerConfig::with_single_cert(cert_chain.clone(), server_key.clone())
+                .expect("Quinn failed to accept the server certificates");
+        apply_transport_policies(&mut peer_config);
+
+        // Only accept clients with a certificate from the operator's CA, if one was given.
+        // Other clients fail the handshake, so none of their requests are ever handled.
+        let server_config = if let Some(ca_path) = &args.client_ca {
+            let client_roots = file_yeet_shared::certificates::load_root_store(ca_path)
+                .expect("Failed to load the client CA certificates");
+            let mut server_config = file_yeet_shared::configure_server_with_client_auth(
+                file_yeet_shared::certificates::CertificateAndKey {
+                    chain: cert_chain,
+                    key: server_key,
+                },
+                client_roots,
+            )
+            .expect("Failed to configure client certificate authentication");
+            apply_transport_policies(&mut server_config);
+            tracing::info!(
+                "Requiring client certificates issued by the CAs in {}",
+                ca_path.display()
+            );
+            server_config
+        } else {
+            peer_config.clone()
+        };
+
+        // Create an endpoint for the echo peer, if enabled. It connects to clients like any other peer would.
+        let echo_end = args.echo_port.map(|port| {
+            let mut echo_address = bind_address;
+            echo_address.set_port(port.get());
+            let mut echo_end = quinn::Endpoint::server(peer_config, echo_address)
+                .expect("Failed to bind to the echo peer QUIC endpoint");
+            echo_end.set_default_client_config(file_yeet_shared::configure_peer_verification());
+            tracing::info!("Hosting an echo peer on port {port}");
+            echo_end
+        });
+
+        // Create a new QUIC endpoint, over local sockets if requested.
+        #[cfg(all(unix, feature = "unix-socket"))]
+        let local_end = if let Some(directory) = &args.unix_socket_dir {
+            tracing::info!(
+                "Serving over Unix domain sockets in {}",
+                directory.display()
+            );
+            file_yeet_shared::unix_socket::bind_endpoint(
+                directory,
+                Some(bind_address.port()),
+                Some(server_config),
+            )
+            .expect("Failed to bind to the local socket endpoint")
+        } else {
+            quinn::Endpoint::server(server_config, bind_address)
+                .expect("Failed to bind to local QUIC endpoint")
+        };
+        #[cfg(not(all(unix, feature = "unix-socket")))]
+        let local_end = quinn::Endpoint::server(server_config, bind_address)
+            .expect("Failed to bind to local QUIC endpoint");
+
+        // Create a map between file hashes and the addresses of peers that have the file.
+        let publishers: PublishersRef = PublishersRef::default();
+
+        // Create a map of relays waiting for a publisher to accept them.
+        let relays = RelaysRef::default();
+
+        // Keep a list of connected clients for the admin interface.
+        let sessions = SessionsRef::default();
+
+        // Track the server's activity for the shutdown report.
+        let start_time = std::time::Instant::now();
+        let stats = Arc::new(ServerStats::default());
+
+        // Report the server's events to the webhook, if one was given.
+        let webhook = args
+            .webhook_url
+            .take()
+            .map(webhook::Webhook::start)
+            .unwrap_or_default();
+
+        // Load the tokens clients may authenticate with, if the server requires any.
+        let auth = Arc::new(auth::AuthTokens::new(
+            args.auth_token.take(),
+            std::mem::take(&mut args.user_tokens),
+        ));
+        if auth.is_required() {
+            tracing::info!("Requiring clients to authenticate with a token");
+        }
+
+        // Limit the connections and requests of each source address, if the server is configured to.
+        let ip_limiter = Arc::new(rate_limit::IpLimiter::new(
+            rate_limit::IpLimits {
+                max_connections: args.max_connections_per_ip,
+                requests_per_minute: args.requests_per_minute,
+                request_burst: args.request_burst,
+            },
+            args.ephemeral,
+        ));
+
+        // Determine the policies that clients must follow.
+        let policy = ServerPolicy {
+            require_port_override: args.require_port_override,
+            allow_relay: args.allow_relay,
+            ephemeral: args.ephemeral,
+            publish_ttl: args.publish_ttl.map(|s| Duration::from_secs(s.get())),
+            publisher_heartbeat: args
+                .publisher_heartbeat
+                .map(|s| Duration::from_secs(s.get())),
+            heartbeat_timeout: args
+                .heartbeat_timeout
+                .map_or(DEFAULT_HEARTBEAT_TIMEOUT, |s| Duration::from_secs(s.get())),
+            require_auth: auth.is_required(),
+        };
+
+        // Load the addresses the server refuses to serve, if the operator keeps a ban list.
+        let bans = match &args.ban_list {
+            Some(path) => {
+                let bans = ban_list::BanList::load(path).expect("Failed to load the ban list");
+                tracing::info!("Refusing {} banned address ranges", bans.ranges().len());
+                bans
+            }
+            None => ban_list::BanList::default(),
+        };
+
+        // Keep the settings that can change while the server runs where every task can see them.
+        let settings = Arc::new(RuntimeSettings {
+            max_connections: AtomicUsize::new(args.max_connections.map_or(0, NonZeroUsize::get)),
+            bans,
+        });
+
+        // Create a channel for pushing notifications to all connected clients.
+        let (notifier, _) = broadcast::channel::<NotificationMessage>(16);
+
+        // Create a cancellation token and set of tasks to allow the server to shut down gracefully.
+        let cancellation_token = CancellationToken::new();
+        let task_master = TaskTracker::new();
+
+        // Apply changes that operators make to the ban list file by hand.
+        if settings.bans.is_persisted() {
+            task_master.spawn(reload_ban_list(
+                settings.clone(),
+                sessions.clone(),
+                cancellation_token.clone(),
+            ));
+        }
+
+        // Periodically remove publishes that were not refreshed in time.
+        if let Some(ttl) = policy.publish_ttl {
+            task_master.spawn(sweep_expired_publishes(
+                publishers.clone(),
+                ttl,
+                policy.ephemeral,
+                cancellation_token.clone(),
+            ));
+        }
+
+        // Serve the admin interface, if enabled.
+        #[cfg(unix)]
+        if let Some(path) = &args.admin_socket {
+            let listener = admin::bind(path).expect("Failed to open the admin socket");
+            tracing::info!("Serving the admin interface on {}", path.display());
+            task_master.spawn(admin::serve(
+                listener,
+                admin::AdminContext {
+                    publishers: publishers.clone(),
+                    sessions: sessions.clone(),
+                    stats: stats.clone(),
+                    ip_limiter: ip_limiter.clone(),
+                    settings: settings.clone(),
+                    start_time,
+                    ephemeral: policy.ephemeral,
+                    tokens: Arc::new(admin::AdminTokens::new(std::mem::take(
+                        &mut args.admin_tokens,
+                    ))),
+                },
+                cancellation_token.clone(),
+                task_master.clone(),
+            ));
+        }
+
+        // Echo back to any peer that connects to the echo endpoint.
+        if let Some(echo_end) = &echo_end {
+            task_master.spawn(handle_echo_loop(
+                echo_end.clone(),
+                cancellation_token.clone(),
+            ));
+        }
+
+        // Share the server's state with the task handling each client connection.
+        let context = Arc::new(ServerContext {
+            publishers,
+            relays,
+            sessions,
+            notifier,
+            policy,
+            settings,
+            auth,
+            ip_limiter,
+            echo_end,
+            stats,
+            webhook,
+        });
+
+        Self {
+            args,
+            certificate,
+            local_end,
+            context,
+            start_time,
+            cancellation_token,
+            task_master,
+        }
+    }
+
+    /// The address the server accepts clients on.
+    /// # Errors
+    /// Fails if the address of the server's socket can't be read.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.local_end.local_addr()
+    }
+
+    /// The certificate the server presents to clients, for them to pin.
+    pub fn certificate(&self) -> &rustls::Certificate {
+        &self.certificate
+    }
+
+    /// Serve clients until `shutdown` completes, then tell them the server is going away and shut down.
+    pub async fn run(self, shutdown: impl std::future::Future<Output = ()>) {
+        let Self {
+            args,
+            local_end,
+            context,
+            start_time,
+            cancellation_token,
+            task_master,
+            ..
+        } = self;
+
+        // Create a loop to handle QUIC connections, but allow cancelling the loop.
+        tokio::select! {
+            () = shutdown => tracing::info!("Shutting down server"),
+            () = handle_incoming_loop(local_end.clone(), context.clone(), cancellation_token.clone(), task_master.clone()) => {}
+        }
+
+        // Let clients know that the server is going away, if any are listening.
+        if context
+            .notifier
+            .send((
+                ServerNotification::Shutdown,
+                "The server is shutting down".into(),
+            ))
+            .is_ok()
+        {
+            tokio::time::sleep(SHUTDOWN_NOTICE_GRACE).await;
+        }
+
+        // Summarize the run before client tasks begin removing their publishes.
+        let stats = &context.stats;
+        let report = {
+            let publishers = context.publishers.read().await;
+            ShutdownReport {
+                uptime_seconds: start_time.elapsed().as_secs(),
+                peak_connections: stats.peak_connections.load(Ordering::Relaxed),
+                total_connections: stats.total_connections.load(Ordering::Relaxed),
+                total_introductions: stats.total_introductions.load(Ordering::Relaxed),
+                total_relays: stats.total_relays.load(Ordering::Relaxed),
+                relayed_bytes: stats.relayed_bytes.load(Ordering::Relaxed),
+                publishes_at_exit: publishers.values().map(HashMap::len).sum(),
+                hashes_at_exit: publishers.len(),
+                connections_at_exit: stats.active_connections.load(Ordering::Relaxed),
+            }
+        };
+
+        // Cancel the server's tasks.
+        cancellation_token.cancel();
+
+        // Close the QUIC endpoint with the DEADBEEF status.
+        local_end.close(quinn::VarInt::from_u32(0xDEAD_BEEF), &[]);
+        if let Some(echo_end) = &context.echo_end {
+            echo_end.close(quinn::VarInt::from_u32(0xDEAD_BEEF), &[]);
+        }
+
+        // Wait for the server's tasks to finish.
+        task_master.close();
+
+        // Remove the admin socket so the next run can create it again.
+        #[cfg(unix)]
+        if let Some(path) = &args.admin_socket {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove the admin socket {}: {e}", path.display());
+            }
+        }
+
+        tracing::info!(
+            uptime_seconds = report.uptime_seconds,
+            peak_connections = report.peak_connections,
+            total_connections = report.total_connections,
+            total_introductions = report.total_introductions,
+            total_relays = report.total_relays,
+            relayed_bytes = report.relayed_bytes,
+            publishes_at_exit = report.publishes_at_exit,
+            hashes_at_exit = report.hashes_at_exit,
+            connections_at_exit = report.connections_at_exit,
+            "Shutdown report"
+        );
+        if let Some(path) = &args.shutdown_report {
+            if let Err(e) = std::fs::File::create(path)
+                .map_err(|e| e.to_string())
+                .and_then(|f| serde_json::to_writer_pretty(f, &report).map_err(|e| e.to_string()))
+            {
+                tracing::error!(
+                    "Failed to write the shutdown report to {}: {e}",
+                    path.display()
+                );
+            }
+        }
+
+        tracing::info!("Server has shut down");
+    }
+}
+
+/// The server state shared by the tasks handling each client connection.
+#[derive(Debug)]
+struct ServerContext {
+    pub publishers: PublishersRef,
+    pub relays: RelaysRef,
+    pub sessions: SessionsRef,
+    pub notifier: broadcast::Sender<NotificationMessage>,
+    pub policy: ServerPolicy,
+    pub settings: Arc<RuntimeSettings>,
+    pub auth: Arc<auth::AuthTokens>,
+    pub ip_limiter: Arc<rate_limit::IpLimiter>,
+    pub echo_end: Option<quinn::Endpoint>,
+    pub stats: Arc<ServerStats>,
+    pub webhook: webhook::Webhook,
+}
+
+/// Process incoming QUIC connections into their own tasks, allowing for client-task cancellation.
+async fn handle_incoming_loop(
+    local_end: quinn::Endpoint,
+    context: Arc<ServerContext>,
+    cancellation_token: CancellationToken,
+    task_master: TaskTracker,
+) {
+    while let Some(connecting) = local_end.accept().await {
+        let refused = |reason| {
+            context
+                .webhook
+                .report(|| webhook::WebhookEvent::ConnectionRefused {
+                    address: connecting.remote_address().to_string(),
+                    reason,
+                });
+        };
+
+        // Drop connections from banned addresses without completing their handshake.
+        if context
+            .settings
+            .bans
+            .is_banned(connecting.remote_address().ip())
+        {
+            tracing::debug!("Refusing a connection from a banned address");
+            refused("banned");
+            drop(connecting);
+            continue;
+        }
+
+        // Refuse clients beyond the connection limit with a reason they can show the user.
+        let max_connections = context.settings.max_connections.load(Ordering::Relaxed);
+        if max_connections != 0
+            && context.stats.active_connections.load(Ordering::Relaxed) >= max_connections
+        {
+            refused("server_full");
+            task_master.spawn(async move {
+                if let Ok(connection) = connecting.await {
+                    connection.close(SERVER_FULL_CODE, SERVER_FULL_MESSAGE);
+                }
+            });
+            continue;
+        }
+
+        // Refuse addresses that already have as many connections open as they are allowed.
+        let Some(permit) = context
+            .ip_limiter
+            .try_connect(connecting.remote_address().ip())
+        else {
+            refused("too_many_connections");
+            task_master.spawn(async move {
+                if let Ok(connection) = connecting.await {
+                    connection.close(SERVER_FULL_CODE, TOO_MANY_CONNECTIONS_MESSAGE);
+                }
+            });
+            continue;
+        };
+
+        let cancellation_token = cancellation_token.clone();
+        let context = context.clone();
+        let client_disconnect_token = CancellationToken::new();
+        let client_task_master = task_master.clone();
+
+        task_master.spawn(async move {
+            context.stats.connection_opened();
+            tokio::select! {
+                // Allow the server to cancel client tasks.
+                () = cancellation_token.cancelled() => client_disconnect_token.cancel(),
+
+                // Handle this client's connection.
+                r = handle_quic_connection(connecting, &context, client_disconnect_token.clone(), client_task_master) => {
+                    // Let all tasks created for this client know that they should shut down.
+                    client_disconnect_token.cancel();
+
+                    if let Err(e) = r {
+                        match e {
+                            // Check for a graceful disconnect.
+                            ClientRequestError::Connection(quinn::ConnectionError::ApplicationClosed(r)) | ClientRequestError::RequestStream(quinn::ConnectionError::ApplicationClosed(r))
+                            if r.error_code == GOODBYE_CODE => {
+                                #[cfg(debug_assertions)]
+                                tracing::debug!("Client gracefully disconnected: {r}");
+                            }
+
+                            // Check for a timeout when waiting for the next request.
+                            ClientRequestError::RequestStream(quinn::ConnectionError::TimedOut) => {
+                                #[cfg(debug_assertions)]
+                                tracing::debug!("Client left without notice");
+                            }
+
+                            // Check for a client the operator disconnected.
+                            ClientRequestError::RequestStream(quinn::ConnectionError::LocallyClosed) => {
+                                tracing::info!("Client was disconnected by the server");
+                            }
+
+                            // If the client didn't gracefully disconnected, print the error.
+                            e => tracing::warn!("Failed to handle client connection: {e}"),
+                        }
+                    }
+                }
+            }
+            context.stats.connection_closed();
+            drop(permit);
+        });
+    }
+}
+
+/// Apply the server's QUIC transport policies to an endpoint configuration.
+fn apply_transport_policies(server_config: &mut quinn::ServerConfig) {
+    // Set custom keep alive policies.
+    server_config.transport_config(file_yeet_shared::server_transport_config());
+
+    // Let clients keep their connection when their address changes, such as after a NAT rebinding or a network change.
+    // Each request follows the migration, so that peers are introduced to the client's new address.
+    server_config.migration(true);
+}
+
+/// Errors encountered while handling a client request.
+#[derive(Debug, thiserror::Error)]
+enum ClientRequestError {
+    /// Failed to establish a QUIC connection.
+    #[error("QUIC connection error: {0}")]
+    Connection(quinn::ConnectionError),
+
+    /// Failed to establish a new request QUIC stream.
+    #[error("QUIC stream error: {0}")]
+    RequestStream(quinn::ConnectionError),
+
+    /// An invalid API request code was received from a client.
+    #[error("Invalid API request code: {0}")]
+    InvalidApiRequestCode(u16),
+
+    /// An I/O error on occurred when reading or writing to a QUIC stream.
+    #[error("I/O error on peer stream: {0}")]
+    IoError(std::io::Error),
+
+    /// Invalid content was sent by the client in the request.
+    #[error("Invalid request content was sent by the client")]
+    InvalidRequestContent,
+}
+
+#[derive(Debug)]
+struct ClientSession {
+    pub nonce: Nonce,
+    pub peer_addr: Arc<RwLock<PeerAddr>>,
+    pub client_pubs: Vec<PublisherRef>,
+    pub port_overridden: bool,
+    pub private_addr: Option<PeerAddr>,
+    pub accepts_relay: bool,
+    pub authenticated_user: Option<String>,
+    pub trace_parent: Option<String>,
+    pub ephemeral: bool,
+    pub bb: PooledBuffer,
+    pub stats: Arc<ServerStats>,
+    pub webhook: webhook::Webhook,
+    pub cancellation_token: CancellationToken,
+    pub task_master: TaskTracker,
+}
+impl ClientSession {
+    pub fn new(
+        socket_addr: SocketAddr,
+        ephemeral: bool,
+        stats: Arc<ServerStats>,
+        webhook: webhook::Webhook,
+        cancellation_token: CancellationToken,
+        task_master: TaskTracker,
+    ) -> Self {
+        let peer_addr = Arc::new(RwLock::new(PeerAddr::from(socket_addr)));
+        let nonce = random_nonce();
+        let bb = SERVER_MESSAGE_BUFFERS.take();
+
+        Self {
+            nonce,
+            peer_addr,
+            client_pubs: Vec::new(),
+            port_overridden: false,
+            private_addr: None,
+            accepts_relay: false,
+            authenticated_user: None,
+            trace_parent: None,
+            ephemeral,
+            bb,
+            stats,
+            webhook,
+            cancellation_token,
+            task_master,
+        }
+    }
+}
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        clear_buffer(&mut self.bb, self.ephemeral);
+    }
+}
+
+/// Handle the initial QUIC connection and attempt to determine whether the client wants to publish or subscribe.
+#[tracing::instrument(skip_all)]
+async fn handle_quic_connection(
+    connecting: quinn::Connecting,
+    context: &ServerContext,
+    cancellation_token: CancellationToken,
+    task_master: TaskTracker,
+) -> Result<(), ClientRequestError> {
+    let ServerContext {
+        publishers,
+        relays,
+        sessions,
+        notifier,
+        policy,
+        auth,
+        ip_limiter,
+        echo_end,
+        ..
+    } = context;
+    let policy = *policy;
+    let connection = connecting.await.map_err(ClientRequestError::Connection)?;
+    let socket_addr = connection.remote_address();
+    let mut port_used = socket_addr.port();
+
+    let mut session = ClientSession::new(
+        socket_addr,
+        policy.ephemeral,
+        context.stats.clone(),
+        context.webhook.clone(),
+        cancellation_token,
+        task_master,
+    );
+
+    // List the client for the operator until their session ends.
+    let _registration = SessionRegistration::new(sessions, &session, connection.clone());
+    loop {
+        // Accept a new stream for each client request.
+        // QUIC streams are very cheap and multiple streams lends itself to concurrent requests.
+        let mut client_streams: BiStream = connection
+            .accept_bi()
+            .await
+            .map_err(ClientRequestError::RequestStream)?
+            .into();
+
+        let mut api = read_api_request(&mut client_streams.recv).await?;
+
+        // Remember the trace context a request carries, to continue the client's trace and pass it on to publishers.
+        session.trace_parent = None;
+        if let ClientApiRequest::Traced = api {
+            session.trace_parent = Some(read_short_string(&mut client_streams.recv).await?);
+            api = read_api_request(&mut client_streams.recv).await?;
+            if let ClientApiRequest::Traced = api {
+                return Err(ClientRequestError::InvalidRequestContent);
+            }
+        }
+
+        // Give every log of the request the fields needed to find it, and time how long it takes.
+        let request_span = tracing::info_span!(
+            "request",
+            %api,
+            session = %session_id(session.nonce),
+            hash = tracing::field::Empty,
+        );
+        #[cfg(feature = "otel")]
+        if let Some(trace_parent) = &session.trace_parent {
+            telemetry::continue_trace(&request_span, trace_parent);
+        }
+        let request_start = Instant::now();
+        tracing::info!(parent: &request_span, "{api} from {}", session.peer_addr.read().await);
+
+        // Refuse requests involving other clients until this one authenticates, if the server requires it.
+        if auth.is_required()
+            && session.authenticated_user.is_none()
+            && matches!(
+                api,
+                ClientApiRequest::Publish
+                    | ClientApiRequest::Subscribe
+                    | ClientApiRequest::Introduction
+                    | ClientApiRequest::Relay
+            )
+        {
+            tracing::info!(parent: &request_span, "Rejecting {api} without authentication");
+            refuse_request(
+                api,
+                client_streams.send,
+                LookupStatus::Denied,
+                AUTH_REQUIRED_MESSAGE,
+            )
+            .await?;
+            clear_buffer(&mut session.bb, session.ephemeral);
+            continue;
+        }
+
+        // Refuse lookups beyond the address's request budget, so that one client can't flood the server.
+        if matches!(
+            api,
+            ClientApiRequest::Publish
+                | ClientApiRequest::Subscribe
+                | ClientApiRequest::Introduction
+        ) && !ip_limiter.try_request(socket_addr.ip())
+        {
+            tracing::info!(parent: &request_span, "Throttling {api}");
+            refuse_request(
+                api,
+                client_streams.send,
+                LookupStatus::Throttled,
+                RATE_LIMITED_MESSAGE,
+            )
+            .await?;
+            clear_buffer(&mut session.bb, session.ephemeral);
+            continue;
+        }
+
+        // Introduce the client by the address their connection migrated to, if it moved since their last request.
+        follow_migration(&session, connection.remote_address(), &mut port_used).await;
+
+        let result = async {
+            match api {
+                // Send a ping response to the client.
+                // Close the connection if we can't send the response.
+                ClientApiRequest::SocketPing => {
+                    socket_ping(client_streams.send, &session.peer_addr, session.ephemeral).await?;
+                }
+
+                // Update the client's address string with the new port.
+                // Close the connection if we can't read the new port.
+                ClientApiRequest::PortOverride => {
+                    port_override(&mut session, client_streams.recv, &mut port_used).await?;
+                }
+
+                // Create a new task to handle the client's file-publishing request.
+                // Close the connection if we can't read the file hash.
+                ClientApiRequest::Publish => {
+                    let mut hash = HashBytes::default();
+                    client_streams
+                        .recv
+                        .read_exact(&mut hash)
+                        .await
+                        .map_err(|_| {
+                            ClientRequestError::IoError(std::io::Error::from(
+                                std::io::ErrorKind::UnexpectedEof,
+                            ))
+                        })?;
+                    let algorithm = read_hash_algorithm(&mut client_streams.recv).await?;
+                    let file_size = client_streams.recv.read_u64().await.map_err(|_| {
+                        ClientRequestError::IoError(std::io::Error::from(
+                            std::io::ErrorKind::UnexpectedEof,
+                        ))
+                    })?;
+                    let room = read_short_string(&mut client_streams.recv).await?;
+                    let hints = PublishHints::read(&mut client_streams.recv).await?;
+                    record_request_hash(&hash);
+
+                    // Refuse the publish if the client hasn't told us which port to introduce them as.
+                    if policy.require_port_override && !session.port_overridden {
+                        tracing::info!("Rejecting publish without a port override");
+                        reject_request(client_streams.send, PORT_OVERRIDE_REQUIRED_MESSAGE).await?;
+                    } else {
+                        // Now that we have the peer's socket address and the file hash, we can handle the publish request.
+                        handle_publish(
+                            &mut session,
+                            client_streams,
+                            PublishRequest {
+                                key: (room, hash),
+                                algorithm,
+                                file_size,
+                                hints,
+                            },
+                            publishers.clone(),
+                            policy,
+                        )
+                        .await;
+                    }
+                }
+
+                // Handle the client's file-subscription request.
+                // Close the connection if we can't complete the request.
+                ClientApiRequest::Subscribe => {
+                    handle_subscribe(&mut session, client_streams, publishers).await?;
+                }
+
+                // Handle the client's request to be introduced to a specific peer over a certain file hash.
+                ClientApiRequest::Introduction => {
+                    handle_introduction(&mut session, client_streams, publishers).await?;
+                }
+
+                // Forward server notifications to the client for the rest of their session.
+                ClientApiRequest::Notifications => {
+                    handle_notifications(&session, client_streams.send, notifier);
+                }
+
+                // Have the echo peer connect to the client so they can test their reachability.
+                ClientApiRequest::TestIntroduction => {
+                    handle_test_introduction(&session, client_streams.send, echo_end.as_ref())
+                        .await?;
+                }
+
+                // Forward a peer-to-peer stream through the server for peers that cannot connect directly.
+                ClientApiRequest::Relay => {
+                    handle_relay(&mut session, client_streams, publishers, relays, policy).await?;
+                }
+
+                // Tell the client which optional features and policies this server has.
+                ClientApiRequest::Capabilities => {
+                    client_streams
+                        .send
+                        .write_u32(policy.capabilities(echo_end.is_some()).0)
+                        .await
+                        .map_err(ClientRequestError::IoError)?;
+                }
+
+                // Check the client's access token.
+                ClientApiRequest::Authenticate => {
+                    handle_authenticate(&mut session, client_streams, auth).await?;
+                }
+
+                // Tell the client which file hashes we have them publishing.
+                ClientApiRequest::ListPublishes => {
+                    handle_list_publishes(&session, client_streams.send, publishers).await?;
+                }
+
+                // Remember the client's address on its local network for peers behind the same public IP.
+                // Close the connection if we can't read the address.
+                ClientApiRequest::PrivateAddress => {
+                    private_address(&mut session, client_streams.recv).await?;
+                }
+
+                // Trace context was read ahead of the request it carries.
+                ClientApiRequest::Traced => unreachable!("Nested trace contexts are refused"),
+            }
+            Ok::<_, ClientRequestError>(())
+        }
+        .instrument(request_span.clone())
+        .await;
+        tracing::info!(
+            parent: &request_span,
+            latency_ms = request_start.elapsed().as_secs_f64() * 1000.,
+            "Handled {api}",
+        );
+        result?;
+
+        // Clear the scratch space before the next iteration.
+        // This is a low cost operation because it only changes an internal size value.
+        clear_buffer(&mut session.bb, session.ephemeral);
+    }
+}
+
+/// Lists a client in the session registry until dropped.
+struct SessionRegistration<'a> {
+    sessions: &'a SessionsRef,
+    nonce: Nonce,
+    ephemeral: bool,
+}
+impl<'a> SessionRegistration<'a> {
+    fn new(
+        sessions: &'a SessionsRef,
+        session: &ClientSession,
+        connection: quinn::Connection,
+    ) -> Self {
+        lock_sessions(sessions).insert(
+            session.nonce,
+            SessionHandle {
+                connection,
+                peer_addr: session.peer_addr.clone(),
+                connected_at: Instant::now(),
+            },
+        );
+        Self {
+            sessions,
+            nonce: session.nonce,
+            ephemeral: session.ephemeral,
+        }
+    }
+}
+impl Drop for SessionRegistration<'_> {
+    fn drop(&mut self) {
+        let mut sessions = lock_sessions(self.sessions);
+        sessions.remove(&self.nonce);
+
+        // Release the memory of removed entries rather than keeping it around for reuse.
+        if self.ephemeral {
+            sessions.shrink_to_fit();
+        }
+    }
+}
+
+/// Clear a scratch buffer for reuse, first zeroing its contents when the server must not leave client addresses in memory.
+fn clear_buffer(bb: &mut bytes::BytesMut, ephemeral: bool) {
+    if ephemeral {
+        bb.as_mut().zeroize();
+    }
+    bb.clear();
+}
+
+/// Add the file hash a request is about to the span of the request, so its logs can be found by file.
+fn record_request_hash(hash: &HashBytes) {
+    let span = tracing::Span::current();
+    if !span.is_disabled() {
+        span.record("hash", faster_hex::hex_string(hash).as_str());
+    }
+}
+
+/// Generate a random nonce to uniquely identify client connections.
+fn random_nonce() -> Nonce {
+    [rand::random(), rand::random()]
+}
+
+/// Read the `u16` code at the start of a request, or after the trace context of a traced request.
+async fn read_api_request(
+    quic_recv: &mut quinn::RecvStream,
+) -> Result<ClientApiRequest, ClientRequestError> {
+    ClientApiRequest::try_from(
+        quic_recv
+            .read_u16()
+            .await
+            .map_err(ClientRequestError::IoError)?,
+    )
+    .map_err(|e| ClientRequestError::InvalidApiRequestCode(e.number))
+}
+
+/// Read a `u8` length and UTF-8 string from a request, such as a room name or file name.
+async fn read_short_string(
+    quic_recv: &mut quinn::RecvStream,
+) -> Result<String, ClientRequestError> {
+    let text_len = quic_recv.read_u8().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let mut text = vec![0; text_len as usize];
+    quic_recv.read_exact(&mut text).await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    String::from_utf8(text).map_err(|_| ClientRequestError::InvalidRequestContent)
+}
+
+/// Read the `u8` tag of the algorithm a client hashed a file with.
+async fn read_hash_algorithm(
+    quic_recv: &mut quinn::RecvStream,
+) -> Result<HashAlgorithm, ClientRequestError> {
+    let algorithm = quic_recv.read_u8().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    HashAlgorithm::try_from(algorithm).map_err(|_| ClientRequestError::InvalidRequestContent)
+}
+
+/// Send a ping response to the client by sending the address we introduce them to peers as.
+#[tracing::instrument(skip(quic_send))]
+async fn socket_ping(
+    mut quic_send: quinn::SendStream,
+    peer_addr: &Arc<RwLock<PeerAddr>>,
+    ephemeral: bool,
+) -> Result<(), ClientRequestError> {
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+
+    // Format the ping response as a length and UTF-8 string.
+    {
+        let mut sock_string = peer_addr.read().await.to_string();
+        bb.put_u16(u16::try_from(sock_string.len()).expect("Message content length is invalid"));
+        bb.put(sock_string.as_bytes());
+        if ephemeral {
+            sock_string.zeroize();
+        }
+    }
+
+    // Send the ping response to the client.
+    let result = quic_send
+        .write_all(&bb)
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()));
+    clear_buffer(&mut bb, ephemeral);
+    result
+}
+
+/// Follow a client whose connection migrated to a new address, such as after their ISP renumbered them,
+/// so that pings report it and peers are introduced to it. A port override still applies at the new address.
+async fn follow_migration(
+    session: &ClientSession,
+    remote_address: SocketAddr,
+    port_used: &mut u16,
+) {
+    let mut address = PeerAddr::from(remote_address);
+    if session.port_overridden {
+        address.set_port(*port_used);
+    }
+    let mut peer_addr = session.peer_addr.write().await;
+    if *peer_addr != address {
+        tracing::info!("Client moved to a new address");
+        *peer_addr = address;
+        *port_used = address.port();
+    }
+}
+
+/// Tell the client that their request was refused, and why.
+/// The response begins with a zero length to match the error responses that clients already expect.
+async fn reject_request(
+    mut quic_send: quinn::SendStream,
+    reason: &str,
+) -> Result<(), ClientRequestError> {
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+    bb.put_u16(0);
+    bb.put_u16(u16::try_from(reason.len()).expect("Message content length is invalid"));
+    bb.put(reason.as_bytes());
+
+    quic_send
+        .write_all(&bb)
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()))?;
+    quic_send
+        .finish()
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()))
+}
+
+/// Refuse a request with a reason, in the form the client expects for that kind of request.
+async fn refuse_request(
+    api: ClientApiRequest,
+    mut quic_send: quinn::SendStream,
+    status: LookupStatus,
+    reason: &str,
+) -> Result<(), ClientRequestError> {
+    if let ClientApiRequest::Publish = api {
+        reject_request(quic_send, reason).await
+    } else {
+        write_lookup_status(&mut quic_send, status, Some(reason)).await
+    }
+}
+
+/// Check the access token a client presented and remember who they are if it is accepted.
+/// Servers that don't require authentication accept any token.
+#[tracing::instrument(skip_all)]
+async fn handle_authenticate(
+    session: &mut ClientSession,
+    mut client_streams: BiStream,
+    auth: &auth::AuthTokens,
+) -> Result<(), ClientRequestError> {
+    let token_len = client_streams
+        .recv
+        .read_u16()
+        .await
+        .map_err(ClientRequestError::IoError)? as usize;
+    if token_len > MAX_SERVER_COMMUNICATION_SIZE {
+        return Err(ClientRequestError::InvalidRequestContent);
+    }
+    let mut token = vec![0; token_len];
+    client_streams
+        .recv
+        .read_exact(&mut token)
+        .await
+        .map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?;
+
+    let user = if auth.is_required() {
+        auth.user_of(&token)
+    } else {
+        Some("anonymous")
+    };
+    token.zeroize();
+
+    if let Some(user) = user {
+        if !session.ephemeral {
+            tracing::info!("Authenticated as {user}");
+        }
+        session.authenticated_user = Some(user.to_owned());
+        write_lookup_status(&mut client_streams.send, LookupStatus::Found, None).await
+    } else {
+        tracing::info!("Rejecting an invalid access token");
+        tokio::time::sleep(INVALID_TOKEN_DELAY).await;
+        write_lookup_status(
+            &mut client_streams.send,
+            LookupStatus::Denied,
+            Some(INVALID_TOKEN_MESSAGE),
+        )
+        .await
+    }
+}
+
+/// Update the client's address string with the new port.
+#[tracing::instrument(skip(session, quic_recv))]
+async fn port_override(
+    session: &mut ClientSession,
+    mut quic_recv: quinn::RecvStream,
+    port_used: &mut u16,
+) -> Result<(), ClientRequestError> {
+    let port = quic_recv
+        .read_u16()
+        .await
+        .map_err(ClientRequestError::IoError)?;
+
+    // The client has completed the handshake for the port to introduce them as.
+    session.port_overridden = true;
+
+    // Avoid unnecessary lock contention.
+    if port == *port_used {
+        return Ok(());
+    }
+
+    // Update the shared address with the new port.
+    session.peer_addr.write().await.set_port(port);
+    tracing::info!("Overriding port to {port}");
+    *port_used = port;
+
+    // Update the client address for each
+    for pub_lock in &session.client_pubs {
+        let mut client = pub_lock.write().await;
+        client.address = session.peer_addr.clone();
+    }
+
+    Ok(())
+}
+
+/// Remember the client's address on its local network, to introduce them by to peers behind the same public IP.
+#[tracing::instrument(skip(session, quic_recv))]
+async fn private_address(
+    session: &mut ClientSession,
+    mut quic_recv: quinn::RecvStream,
+) -> Result<(), ClientRequestError> {
+    let address_len = quic_recv
+        .read_u8()
+        .await
+        .map_err(ClientRequestError::IoError)?;
+    let mut scratch_space = [0; 256];
+    let slice = &mut scratch_space[..address_len as usize];
+    quic_recv.read_exact(slice).await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let address: Option<PeerAddr> = std::str::from_utf8(slice).ok().and_then(|s| s.parse().ok());
+    let address = address.ok_or(ClientRequestError::InvalidRequestContent)?;
+
+    // Ephemeral servers don't advertise private addresses, so any sent anyway are dropped.
+    if session.ephemeral {
+        scratch_space.zeroize();
+        return Ok(());
+    }
+    session.private_addr = Some(address);
+
+    // Introduce the client's existing publishes by their private address too.
+    for pub_lock in &session.client_pubs {
+        pub_lock.write().await.private_address = Some(address);
+    }
+    Ok(())
+}
+
+/// The address to introduce a peer by: its private address when the other peer shares its public IP,
+/// so they connect over their local network instead of through their router.
+fn introduced_address(
+    public: &PeerAddr,
+    private: Option<PeerAddr>,
+    other_public: &PeerAddr,
+) -> PeerAddr {
+    private
+        .filter(|_| public.same_ip(other_public))
+        .unwrap_or(*public)
+}
+
+/// Handle QUIC connections for clients that want to publish a new file hash.
+#[tracing::instrument(
+    skip(session, client_streams, request, publishers),
+    fields(key = ?request.key, algorithm = %request.algorithm, file_size = request.file_size)
+)]
+async fn handle_publish(
+    session: &mut ClientSession,
+    mut client_streams: BiStream,
+    request: PublishRequest,
+    publishers: PublishersRef,
+    policy: ServerPolicy,
+) {
+    /// Helper to remove a publisher from the list of peers sharing a file hash.
+    /// A newer publish of the same file by the client is left alone, such as one made again after their address changed.
+    async fn try_remove_publisher(
+        session_nonce: Nonce,
+        key: &RoomHash,
+        publishers: PublishersRef,
+        publisher: &PublisherRef,
+        ephemeral: bool,
+    ) {
+        let mut publishers = publishers.write().await;
+        let is_current = publishers
+            .get(key)
+            .and_then(|file_publishers| file_publishers.get(&session_nonce))
+            .is_some_and(|published| Arc::ptr_eq(&published.publisher, publisher));
+        if is_current {
+            remove_publisher(&mut publishers, session_nonce, key, ephemeral);
+        }
+    }
+    /// Keep a publish alive for another TTL after the publisher asks.
+    async fn refresh_publisher(
+        session_nonce: Nonce,
+        key: &RoomHash,
+        publishers: &PublishersRef,
+        ttl: Option<Duration>,
+    ) {
+        let Some(ttl) = ttl else {
+            return;
+        };
+        let mut publishers = publishers.write().await;
+        if let Some(published) = publishers
+            .get_mut(key)
+            .and_then(|file_publishers| file_publishers.get_mut(&session_nonce))
+        {
+            published.expires_at = Some(Instant::now() + ttl);
+            published.refresh_requested = false;
+        }
+    }
+    /// A loop to handle messages to be sent to a client publishing a file hash.
+    async fn handle_publish_inner(
+        mut quic_send: quinn::SendStream,
+        mut rx: mpsc::Receiver<PublisherMessage>,
+        peer_addr: &Arc<RwLock<PeerAddr>>,
+        hash_hex: &str,
+        ephemeral: bool,
+        traced: bool,
+    ) {
+        #[cfg(debug_assertions)]
+        tracing::debug!(
+            "Starting publish task for client {} {hash_hex}",
+            peer_addr.read().await
+        );
+        let mut bb = SERVER_MESSAGE_BUFFERS.take();
+
+        while let Some(mut message) = rx.recv().await {
+            match &message {
+                // Mark refresh requests and heartbeats with lengths that no address can have.
+                PublisherMessage::Refresh => bb.put_u16(PUBLISH_REFRESH),
+                PublisherMessage::Heartbeat => bb.put_u16(PUBLISH_HEARTBEAT),
+
+                // End the publish with the same zero length and reason as a refused request.
+                PublisherMessage::Expired => {
+                    bb.put_u16(0);
+                    bb.put_u16(
+                        u16::try_from(PUBLISH_EXPIRED_MESSAGE.len())
+                            .expect("Message content length is invalid"),
+                    );
+                    bb.put(PUBLISH_EXPIRED_MESSAGE.as_bytes());
+                }
+
+                // Format the introduction as a length and UTF-8 string,
+                // after the subscriber's trace context if the publisher traced their publish.
+                PublisherMessage::Introduce(address, trace_parent) => {
+                    if traced {
+                        let trace_parent = trace_parent.as_deref().unwrap_or_default();
+                        bb.put_u16(PUBLISH_TRACE_CONTEXT);
+                        bb.put_u8(u8::try_from(trace_parent.len()).unwrap_or_default());
+                        bb.put(trace_parent.as_bytes());
+                    }
+                    bb.put_u16(
+                        u16::try_from(address.len()).expect("Message content length is invalid"),
+                    );
+                    bb.put(address.as_bytes());
+                }
+
+                // Mark relay offers with a length that no address can have.
+                PublisherMessage::Relay(token, address) => {
+                    bb.put_u16(RELAY_OFFER);
+                    bb.put_u64(*token);
+                    bb.put_u16(
+                        u16::try_from(address.len()).expect("Message content length is invalid"),
+                    );
+                    bb.put(address.as_bytes());
+                }
+            }
+
+            // Try to send the message to the client.
+            let result = quic_send.write_all(&bb).await;
+
+            // Clear the scratch space before the next iteration.
+            clear_buffer(&mut bb, ephemeral);
+
+            #[cfg(debug_assertions)]
+            tracing::debug!("Sent {message:?} to {}", peer_addr.read().await);
+
+            if ephemeral {
+                match &mut message {
+                    PublisherMessage::Introduce(address, _)
+                    | PublisherMessage::Relay(_, address) => {
+                        address.zeroize();
+                    }
+                    PublisherMessage::Refresh
+                    | PublisherMessage::Heartbeat
+                    | PublisherMessage::Expired => {}
+                }
+            }
+            if let Err(e) = result {
+                tracing::error!("Failed to send message to client: {e}");
+                return;
+            }
+
+            // Nothing more is sent on an expired publish.
+            if matches!(message, PublisherMessage::Expired) {
+                let _ = quic_send.finish().await;
+                return;
+            }
+        }
+    }
+
+    let PublishRequest {
+        key,
+        algorithm,
+        file_size,
+        hints,
+    } = request;
+
+    // Use a channel to handle buffering and flushing of messages.
+    // Ensures that the stream doesn't need to be cloned or passed between threads.
+    let (tx, rx) = mpsc::channel::<PublisherMessage>(4 * MAX_SERVER_COMMUNICATION_SIZE);
+    let heartbeat_tx = tx.clone();
+
+    let client = Arc::new(RwLock::new(Publisher {
+        address: session.peer_addr.clone(),
+        stream: tx,
+        accepts_relay: session.accepts_relay,
+        private_address: session.private_addr,
+    }));
+    session.client_pubs.push(client.clone());
+    let publisher = client.clone();
+
+    // Add the client to a list of peers publishing this hash.
+    // Wrap the lock in a block to ensure it is released quickly.
+    {
+        let mut publishers_lock = publishers.write().await;
+        let new_pub = PublishedFile::new(client, file_size, algorithm, hints, policy.publish_ttl);
+        if let Some(client_list) = publishers_lock.get_mut(&key) {
+            client_list.insert(session.nonce, new_pub);
+        } else {
+            publishers_lock.insert(key.clone(), HashMap::from([(session.nonce, new_pub)]));
+        }
+    }
+    session
+        .webhook
+        .report(|| webhook::WebhookEvent::PublishAdded {
+            session: session_id(session.nonce),
+            room: key.0.clone(),
+            hash: faster_hex::hex_string(&key.1),
+            file_size,
+        });
+
+    // Copy relevant session data to the task context.
+    let cancellation_token = session.cancellation_token.clone();
+    let peer_addr = session.peer_addr.clone();
+    let session_nonce = session.nonce;
+    let ephemeral = session.ephemeral;
+    let webhook = session.webhook.clone();
+    let traced = session.trace_parent.is_some();
+
+    tokio::task::spawn(async move {
+        let hash_hex = faster_hex::hex_string(&key.1);
+        let heartbeat_acknowledged = Notify::new();
+
+        tokio::select! {
+            // Allow the server to cancel the task.
+            () = cancellation_token.cancelled() => {}
+
+            // Allow the client to refresh, acknowledge heartbeats, or cancel their publish request.
+            () = async {
+                loop {
+                    match client_streams
+                        .recv
+                        .read_u8()
+                        .await
+                        .map_err(|_| ())
+                        .and_then(|c| PublishControl::try_from(c).map_err(|_| ()))
+                    {
+                        Ok(PublishControl::Refresh) => {
+                            refresh_publisher(session_nonce, &key, &publishers, policy.publish_ttl).await;
+                        }
+                        Ok(PublishControl::Heartbeat) => heartbeat_acknowledged.notify_one(),
+                        Ok(PublishControl::Cancel) | Err(()) => break,
+                    }
+                }
+            } => {}
+
+            // Drop publishers that can no longer be reached.
+            () = heartbeat_publisher(&heartbeat_tx, &heartbeat_acknowledged, policy) => {
+                tracing::info!("Dropping a publisher that stopped answering heartbeats");
+            }
+
+            // Handle the client's file-publishing task.
+            () = handle_publish_inner(client_streams.send, rx, &peer_addr, &hash_hex, ephemeral, traced) => {}
+        }
+
+        // Remove any reference there may be to this publish task.
+        try_remove_publisher(session_nonce, &key, publishers, &publisher, ephemeral).await;
+        webhook.report(|| webhook::WebhookEvent::PublishRemoved {
+            session: session_id(session_nonce),
+            room: key.0.clone(),
+            hash: hash_hex.clone(),
+        });
+
+        tracing::info!(
+            "Finishing publish task for client {} {hash_hex}",
+            peer_addr.read().await
+        );
+    });
+}
+
+/// Remove a publisher from the list of peers sharing a file hash.
+fn remove_publisher(
+    publishers: &mut HashMap<RoomHash, HashMap<Nonce, PublishedFile>>,
+    session_nonce: Nonce,
+    key: &RoomHash,
+    ephemeral: bool,
+) {
+    if let Some(file_publishers) = publishers.get_mut(key) {
+        // Remove this client from the file's list of publishers.
+        file_publishers.remove(&session_nonce);
+
+        // Remove the file hash from the map if no clients are publishing it.
+        if file_publishers.is_empty() {
+            publishers.remove(key);
+        } else if ephemeral {
+            file_publishers.shrink_to_fit();
+        }
+    }
+
+    // Release the memory of removed entries rather than keeping it around for reuse.
+    if ephemeral {
+        publishers.shrink_to_fit();
+    }
+}
+
+/// Periodically check that a publisher can still be reached, returning once they fail to answer in time.
+/// Never returns if the server doesn't check publishers.
+async fn heartbeat_publisher(
+    stream: &mpsc::Sender<PublisherMessage>,
+    acknowledged: &Notify,
+    policy: ServerPolicy,
+) {
+    let Some(interval) = policy.publisher_heartbeat else {
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        if stream.send(PublisherMessage::Heartbeat).await.is_err() {
+            return;
+        }
+        if tokio::time::timeout(policy.heartbeat_timeout, acknowledged.notified())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Periodically reload the ban list file, disconnecting clients whose addresses were newly banned.
+async fn reload_ban_list(
+    settings: Arc<RuntimeSettings>,
+    sessions: SessionsRef,
+    cancellation_token: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            () = cancellation_token.cancelled() => return,
+            () = tokio::time::sleep(ban_list::RELOAD_INTERVAL) => {}
+        }
+        match settings.bans.reload() {
+            Ok(true) => {
+                let disconnected = disconnect_banned(&sessions, &settings.bans);
+                tracing::info!(
+                    "Reloaded the ban list with {} ranges, disconnecting {disconnected} clients",
+                    settings.bans.ranges().len()
+                );
+            }
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Keeping the previous ban list: {e}"),
+        }
+    }
+}
+
+/// Periodically ask publishers to refresh their publishes, removing those that were not refreshed in time.
+async fn sweep_expired_publishes(
+    publishers: PublishersRef,
+    ttl: Duration,
+    ephemeral: bool,
+    cancellation_token: CancellationToken,
+) {
+    let mut interval = tokio::time::interval((ttl / 4).max(MIN_PUBLISH_SWEEP_INTERVAL));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            () = cancellation_token.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+
+        let now = Instant::now();
+        let mut publishers = publishers.write().await;
+        let mut expired = Vec::new();
+        for (key, file_publishers) in publishers.iter_mut() {
+            for (nonce, published) in file_publishers.iter_mut() {
+                let Some(expires_at) = published.expires_at else {
+                    continue;
+                };
+                let message = if expires_at <= now {
+                    expired.push((key.clone(), *nonce));
+                    PublisherMessage::Expired
+                } else if !published.refresh_requested && expires_at - now <= ttl / 2 {
+                    published.refresh_requested = true;
+                    PublisherMessage::Refresh
+                } else {
+                    continue;
+                };
+
+                // Avoid waiting on a publisher with a full queue while holding the map lock.
+                // A missed refresh request is sent again on a later sweep.
+                if published
+                    .publisher
+                    .read()
+                    .await
+                    .stream
+                    .try_send(message)
+                    .is_err()
+                {
+                    published.refresh_requested = false;
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            tracing::info!(
+                "Removing {} publishes that were not refreshed in time",
+                expired.len()
+            );
+        }
+        for (key, nonce) in expired {
+            remove_publisher(&mut publishers, nonce, &key, ephemeral);
+        }
+    }
+}
+
+/// Handle a client request to subscribe to a file hash, receiving a list of peers that are publishing this hash.
+async fn handle_subscribe(
+    session: &mut ClientSession,
+    mut client_streams: BiStream,
+    clients: &PublishersRef,
+) -> Result<(), ClientRequestError> {
+    // Start by getting the file hash from the client.
+    let mut hash = HashBytes::default();
+    client_streams
+        .recv
+        .read_exact(&mut hash)
+        .await
+        .map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?;
+    record_request_hash(&hash);
+    let algorithm = read_hash_algorithm(&mut client_streams.recv).await?;
+    let room = read_short_string(&mut client_streams.recv).await?;
+
+    // Read how many publishers the client wants, and where to continue an earlier listing from.
+    let requested = client_streams.recv.read_u16().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let cursor =
+        SubscribeCursor::from_wire(client_streams.recv.read_u64().await.map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?);
+
+    // Attempt to get the publishers that hashed the file with the same algorithm.
+    let key = (room, hash);
+    let read_lock = clients.read().await;
+    let Some(client_list) = read_lock
+        .get(&key)
+        .filter(|v| v.values().any(|published| published.algorithm == algorithm))
+    else {
+        #[cfg(debug_assertions)]
+        {
+            let mut hex_hash_bytes = [0; 2 * file_yeet_shared::HASH_BYTE_COUNT];
+            tracing::debug!(
+                "Failed to find client for hash {}",
+                faster_hex::hex_encode(&hash, &mut hex_hash_bytes)
+                    .expect("Failed to encode hash in hexadecimal"),
+            );
+        }
+
+        // Send the subscriber a message that no publishers are available.
+        drop(read_lock);
+        return write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+    };
+
+    // Introduce a uniformly random sample of the publishers when they don't all fit in one response,
+    // so that the publishers first in the map's iteration order aren't always the ones chosen.
+    // Publishers are visited in the order of their keys for the listing's seed, continuing after the last one
+    // visited on an earlier page, so that publishers coming and going between pages don't shift the others.
+    let seed = cursor.map_or_else(SubscribeCursor::new_seed, |c| c.seed);
+    let (page, mut remaining) = next_subscribe_page(
+        client_list
+            .iter()
+            .filter(|(_, published)| published.algorithm == algorithm)
+            .map(|(nonce, _)| nonce),
+        seed,
+        cursor.map(|c| c.last_key),
+        match requested {
+            0 => MAX_INTRODUCTIONS_SENT,
+            count => usize::from(count).min(MAX_INTRODUCTIONS_SENT),
+        },
+    );
+
+    // Copy the page out so that publishes and unpublishes aren't held up while the publishers are messaged.
+    let page: Vec<(u64, PublisherRef, u64, PublishHints)> = page
+        .into_iter()
+        .filter_map(|(publisher_key, nonce)| {
+            let published = client_list.get(&nonce)?;
+            Some((
+                publisher_key,
+                published.publisher.clone(),
+                published.file_size,
+                published.hints.clone(),
+            ))
+        })
+        .collect();
+    drop(read_lock);
+
+    // Write a temporary zero to the buffer for space efficiency.
+    // This will be overwritten later with the actual number of peers introduced.
+    session.bb.put_u8(LookupStatus::Found as u8);
+    session.bb.put_u16(0);
+
+    let subscriber_address = *session.peer_addr.read().await;
+    let mut n: u16 = 0;
+    let mut last_visited = None;
+    for (publisher_key, publisher, file_size, hints) in page {
+        let (publisher_address, private_address, stream) = {
+            let pub_client = publisher.read().await;
+            let address = *pub_client.address.read().await;
+            (
+                address,
+                pub_client.private_address,
+                pub_client.stream.clone(),
+            )
+        };
+        let mut client_address =
+            introduced_address(&publisher_address, private_address, &subscriber_address)
+                .to_string();
+
+        // Ensure that the message, including the cursor at its end, doesn't exceed the maximum size.
+        // The rest are left for another page.
+        if session.bb.len()
+            + (2 * size_of::<u64>() + size_of::<u8>())
+            + client_address.len()
+            + hints.encoded_len()
+            > MAX_SERVER_COMMUNICATION_SIZE
+        {
+            break;
+        }
+        last_visited = Some(publisher_key);
+        remaining -= 1;
+
+        // Feed the subscribing client's socket address to the task that handles communicating with the publisher.
+        // Only include the peer if the message was successfully passed.
+        if let Ok(()) = stream
+            .send(PublisherMessage::Introduce(
+                introduced_address(
+                    &subscriber_address,
+                    session.private_addr,
+                    &publisher_address,
+                )
+                .to_string(),
+                session.trace_parent.clone(),
+            ))
+            .await
+        {
+            // Send the publisher's socket address to the subscribing client.
+            session
+                .bb
+                .put_u8(u8::try_from(client_address.len()).unwrap());
+            session.bb.put(client_address.as_bytes());
+
+            // Send the file size to the subscribing client.
+            session.bb.put_u64(file_size);
+
+            // Pass along the publisher's hints about how to save the file, and their signature.
+            hints.put(&mut session.bb);
+
+            n += 1;
+        }
+        if session.ephemeral {
+            client_address.zeroize();
+        }
+    }
+
+    // Every publisher may have gone away while we were introducing them.
+    if n == 0 {
+        return write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+    }
+
+    // Overwrite the number of peers shared with the actual count, in big-endian.
+    session.bb[1..3].copy_from_slice(&n.to_be_bytes());
+
+    // Tell the client where to continue from if there are publishers left, or zero if there are none.
+    session.bb.put_u64(match last_visited {
+        Some(last_key) if remaining > 0 => SubscribeCursor { seed, last_key }.to_wire(),
+        _ => 0,
+    });
+
+    // Send the message to the client.
+    client_streams
+        .send
+        .write_all(&session.bb)
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()))?;
+    session
+        .stats
+        .total_introductions
+        .fetch_add(u64::from(n), Ordering::Relaxed);
+    session
+        .webhook
+        .report(|| webhook::WebhookEvent::Introduction {
+            session: session_id(session.nonce),
+            room: key.0.clone(),
+            hash: faster_hex::hex_string(&key.1),
+            publishers: n,
+        });
+
+    #[cfg(debug_assertions)]
+    if n != 0 {
+        tracing::debug!(
+            "Introduced {} peers to {}",
+            n,
+            session.peer_addr.read().await,
+        );
+    }
+
+    Ok(())
+}
+
+/// Send the client every file hash they are publishing, with the file size and room of each.
+#[tracing::instrument(skip_all)]
+async fn handle_list_publishes(
+    session: &ClientSession,
+    mut quic_send: quinn::SendStream,
+    publishers: &PublishersRef,
+) -> Result<(), ClientRequestError> {
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+    bb.put_u16(0);
+
+    let mut n: u16 = 0;
+    {
+        let read_lock = publishers.read().await;
+        for ((room, hash), file_publishers) in read_lock.iter() {
+            let Some(published) = file_publishers.get(&session.nonce) else {
+                continue;
+            };
+            bb.put(&hash[..]);
+            bb.put_u64(published.file_size);
+            bb.put_u8(u8::try_from(room.len()).expect("Room name length is invalid"));
+            bb.put(room.as_bytes());
+
+            n += 1;
+            if n == u16::MAX {
+                break;
+            }
+        }
+    }
+
+    // Overwrite the count with the number of publishes listed, in big-endian.
+    bb[..2].copy_from_slice(&n.to_be_bytes());
+
+    let result = quic_send
+        .write_all(&bb)
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()));
+    clear_buffer(&mut bb, session.ephemeral);
+    result
+}
+
+/// Handle a client request to be introduced to a specific client regarding a file they are publishing.
+async fn handle_introduction(
+    session: &mut ClientSession,
+    mut client_streams: BiStream,
+    clients: &PublishersRef,
+) -> Result<(), ClientRequestError> {
+    let mut hash = HashBytes::default();
+    client_streams
+        .recv
+        .read_exact(&mut hash)
+        .await
+        .map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?;
+    record_request_hash(&hash);
+
+    let address_len = client_streams.recv.read_u8().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+
+    let mut scratch_space = [0; 256];
+    let slice = &mut scratch_space[..address_len as usize];
+    client_streams.recv.read_exact(slice).await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let peer_address: Option<PeerAddr> =
+        std::str::from_utf8(slice).ok().and_then(|s| s.parse().ok());
+    if session.ephemeral {
+        scratch_space.zeroize();
+    }
+    let peer_address = peer_address.ok_or(ClientRequestError::InvalidRequestContent)?;
+    let room = read_short_string(&mut client_streams.recv).await?;
+
+    // Attempt to get the clients from the file-hash map.
+    let key = (room, hash);
+    let read_lock = clients.read().await;
+    let Some(client_list) = read_lock.get(&key).filter(|v| !v.is_empty()) else {
+        #[cfg(debug_assertions)]
+        {
+            let mut hex_hash_bytes = [0; 2 * file_yeet_shared::HASH_BYTE_COUNT];
+            tracing::debug!(
+                "Failed to find client for hash {}",
+                faster_hex::hex_encode(&hash, &mut hex_hash_bytes)
+                    .expect("Failed to encode hash in hexadecimal"),
+            );
+        }
+
+        // Send the subscriber a message that no publishers are available.
+        return write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+    };
+
+    let clients = client_list.iter();
+    let mut introduced = false;
+    for (_, pub_client) in clients {
+        // Get read access on client lock.
+        let pub_client = pub_client.publisher.read().await;
+        if pub_client.is_at(&peer_address).await {
+            let subscriber_address = *session.peer_addr.read().await;
+            let introduce_as = introduced_address(
+                &subscriber_address,
+                session.private_addr,
+                &*pub_client.address.read().await,
+            );
+
+            // Feed the subscribing client's socket address to the task that handles communicating with the publisher.
+            if let Ok(()) = pub_client
+                .stream
+                .send(PublisherMessage::Introduce(
+                    introduce_as.to_string(),
+                    session.trace_parent.clone(),
+                ))
+                .await
+            {
+                // Let the subscribing client know the introduction was made.
+                write_lookup_status(&mut client_streams.send, LookupStatus::Found, None).await?;
+                introduced = true;
+                session
+                    .stats
+                    .total_introductions
+                    .fetch_add(1, Ordering::Relaxed);
+                session
+                    .webhook
+                    .report(|| webhook::WebhookEvent::Introduction {
+                        session: session_id(session.nonce),
+                        room: key.0.clone(),
+                        hash: faster_hex::hex_string(&key.1),
+                        publishers: 1,
+                    });
+
+                #[cfg(debug_assertions)]
+                tracing::debug!(
+                    "Introduced publisher {} to {}",
+                    peer_address,
+                    session.peer_addr.read().await,
+                );
+            }
+
+            // We found the correct socket address, stop searching the client list.
+            break;
+        }
+    }
+
+    // Let the client know the peer is no longer publishing instead of leaving them waiting.
+    if !introduced {
+        write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Handle a client request to opt in to relays, to be relayed to a publisher, or to accept a relay offer.
+async fn handle_relay(
+    session: &mut ClientSession,
+    mut client_streams: BiStream,
+    clients: &PublishersRef,
+    relays: &RelaysRef,
+    policy: ServerPolicy,
+) -> Result<(), ClientRequestError> {
+    let role = client_streams.recv.read_u8().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let role = RelayRole::try_from(role).map_err(|_| ClientRequestError::InvalidRequestContent)?;
+
+    if !policy.allow_relay {
+        return write_lookup_status(
+            &mut client_streams.send,
+            LookupStatus::Denied,
+            Some(RELAY_DISABLED_MESSAGE),
+        )
+        .await;
+    }
+
+    match role {
+        // Publishes made from now on will be offered relays.
+        RelayRole::OptIn => {
+            session.accepts_relay = true;
+            write_lookup_status(&mut client_streams.send, LookupStatus::Found, None).await
+        }
+
+        // Offer the publisher a relay and wait for them to accept it.
+        RelayRole::Subscribe => request_relay(session, client_streams, clients, relays).await,
+
+        // Hand the publisher's stream to the subscriber waiting on this offer.
+        RelayRole::Publish => {
+            let token = client_streams.recv.read_u64().await.map_err(|_| {
+                ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            })?;
+            let waiting = remove_relay(relays, token, session.ephemeral).await;
+            match waiting {
+                Some(subscriber) => match subscriber.send(client_streams) {
+                    Ok(()) => Ok(()),
+
+                    // The subscriber stopped waiting before the offer was accepted.
+                    Err(mut client_streams) => {
+                        write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None)
+                            .await
+                    }
+                },
+                None => {
+                    write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None)
+                        .await
+                }
+            }
+        }
+    }
+}
+
+/// Offer a relay to the publisher the subscriber asked for, then forward their streams once it is accepted.
+async fn request_relay(
+    session: &ClientSession,
+    mut client_streams: BiStream,
+    clients: &PublishersRef,
+    relays: &RelaysRef,
+) -> Result<(), ClientRequestError> {
+    let mut hash = HashBytes::default();
+    client_streams
+        .recv
+        .read_exact(&mut hash)
+        .await
+        .map_err(|_| {
+            ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })?;
+    record_request_hash(&hash);
+
+    let address_len = client_streams.recv.read_u8().await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+
+    let mut scratch_space = [0; 256];
+    let slice = &mut scratch_space[..address_len as usize];
+    client_streams.recv.read_exact(slice).await.map_err(|_| {
+        ClientRequestError::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    })?;
+    let peer_address: Option<PeerAddr> =
+        std::str::from_utf8(slice).ok().and_then(|s| s.parse().ok());
+    if session.ephemeral {
+        scratch_space.zeroize();
+    }
+    let peer_address = peer_address.ok_or(ClientRequestError::InvalidRequestContent)?;
+    let room = read_short_string(&mut client_streams.recv).await?;
+
+    // Find the publisher's channel, releasing the map lock before waiting on anything.
+    let publisher = {
+        let read_lock = clients.read().await;
+        let mut found = None;
+        let key = (room, hash);
+        for pub_client in read_lock.get(&key).into_iter().flat_map(HashMap::values) {
+            let pub_client = pub_client.publisher.read().await;
+            if pub_client.is_at(&peer_address).await {
+                found = Some((pub_client.stream.clone(), pub_client.accepts_relay));
+                break;
+            }
+        }
+        found
+    };
+    let Some((publisher_stream, accepts_relay)) = publisher else {
+        return write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+    };
+    if !accepts_relay {
+        return write_lookup_status(
+            &mut client_streams.send,
+            LookupStatus::Denied,
+            Some("The publisher does not accept relays"),
+        )
+        .await;
+    }
+
+    // Register the offer before sending it so the publisher cannot accept it too early.
+    let token = rand::random::<u64>();
+    let (tx, rx) = oneshot::channel();
+    relays.lock().await.insert(token, tx);
+    if publisher_stream
+        .send(PublisherMessage::Relay(
+            token,
+            session.peer_addr.read().await.to_string(),
+        ))
+        .await
+        .is_err()
+    {
+        remove_relay(relays, token, session.ephemeral).await;
+        return write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+    }
+
+    // Wait for the publisher in a separate task so the subscriber's other requests aren't blocked.
+    // The task is tracked so that shutdown waits for relays in progress.
+    let relays = relays.clone();
+    let ephemeral = session.ephemeral;
+    let stats = session.stats.clone();
+    let cancellation_token = session.cancellation_token.clone();
+    session.task_master.spawn(async move {
+        tokio::select! {
+            // Allow the server or the subscriber's connection to cancel the relay.
+            () = cancellation_token.cancelled() => {}
+
+            r = tokio::time::timeout(RELAY_ACCEPT_TIMEOUT, rx) => {
+                if let Ok(Ok(publisher_streams)) = r {
+                    relay_streams(client_streams, publisher_streams, &stats).await;
+                } else {
+                    // Let the subscriber know the publisher never accepted.
+                    let _ = write_lookup_status(&mut client_streams.send, LookupStatus::NotFound, None).await;
+                }
+            }
+        }
+
+        // Forget the offer if it was never accepted.
+        remove_relay(&relays, token, ephemeral).await;
+    });
+    Ok(())
+}
+
+/// Remove a relay offer, shrinking the map of offers afterwards on ephemeral servers.
+async fn remove_relay(
+    relays: &RelaysRef,
+    token: u64,
+    ephemeral: bool,
+) -> Option<oneshot::Sender<BiStream>> {
+    let mut relays = relays.lock().await;
+    let waiting = relays.remove(&token);
+    if ephemeral {
+        relays.shrink_to_fit();
+    }
+    waiting
+}
+
+/// Tell both peers their relay is ready, then forward data between their streams until both directions finish.
+/// The peers run their own QUIC connection end-to-end through the relay, so the server only forwards ciphertext.
+async fn relay_streams(mut subscriber: BiStream, mut publisher: BiStream, stats: &ServerStats) {
+    if write_lookup_status(&mut subscriber.send, LookupStatus::Found, None)
+        .await
+        .is_err()
+        || write_lookup_status(&mut publisher.send, LookupStatus::Found, None)
+            .await
+            .is_err()
+    {
+        return;
+    }
+    stats.total_relays.fetch_add(1, Ordering::Relaxed);
+
+    let upload = async {
+        let n = tokio::io::copy(&mut subscriber.recv, &mut publisher.send).await;
+        let _ = publisher.send.finish().await;
+        n
+    };
+    let download = async {
+        let n = tokio::io::copy(&mut publisher.recv, &mut subscriber.send).await;
+        let _ = subscriber.send.finish().await;
+        n
+    };
+    let (upload, download) = tokio::join!(upload, download);
+    let relayed = upload.unwrap_or_default() + download.unwrap_or_default();
+    stats.relayed_bytes.fetch_add(relayed, Ordering::Relaxed);
+
+    #[cfg(debug_assertions)]
+    tracing::debug!("Relay finished after forwarding {relayed} bytes");
+}
+
+/// Respond to a subscribe or introduction request with a status, and a reason if the request was refused.
+async fn write_lookup_status(
+    quic_send: &mut quinn::SendStream,
+    status: LookupStatus,
+    reason: Option<&str>,
+) -> Result<(), ClientRequestError> {
+    let mut bb = SERVER_MESSAGE_BUFFERS.take();
+    bb.put_u8(status as u8);
+    if matches!(status, LookupStatus::Throttled | LookupStatus::Denied) {
+        let reason = reason.unwrap_or_default();
+        bb.put_u16(u16::try_from(reason.len()).expect("Message content length is invalid"));
+        bb.put(reason.as_bytes());
+    }
+
+    quic_send
+        .write_all(&bb)
+        .await
+        .map_err(|e| ClientRequestError::IoError(e.into()))
+}
+
+/// Tell the client which port the echo peer is on and have the echo peer connect to the client's advertised address.
+#[tracing::instrument(skip_all)]
+async fn handle_test_introduction(
+    session: &ClientSession,
+    mut quic_send: quinn::SendStream,
+    echo_end: Option<&quinn::Endpoint>,
+) -> Result<(), ClientRequestError> {
+    // Respond with a zero port if the echo peer is disabled.
+    let Some((echo_end, echo_address)) =
+        echo_end.and_then(|e| e.local_addr().ok().map(|a| (e.clone(), a)))
+    else {
+        return quic_send
+            .write_u16(0)
+            .await
+            .map_err(ClientRequestError::IoError);
+    };
+    quic_send
+        .write_u16(echo_address.port())
+        .await
+        .map_err(ClientRequestError::IoError)?;
+
+    // Connect to the client the same way a peer would, using the address we introduce them as.
+    let peer_addr = *session.peer_addr.read().await;
+    let target = if echo_address.is_ipv6() {
+        peer_addr.mapped_socket_addr()
+    } else {
+        peer_addr.socket_addr()
+    };
+    let connecting = match echo_end.connect(target, "peer") {
+        Ok(c) => c,
+        Err(e) => {
+            if session.ephemeral {
+                tracing::warn!("Echo peer failed to connect to a client: {e}");
+            } else {
+                tracing::warn!("Echo peer failed to connect to {peer_addr}: {e}");
+            }
+            return Ok(());
+        }
+    };
+
+    let cancellation_token = session.cancellation_token.clone();
+    tokio::task::spawn(async move {
+        tokio::select! {
+            () = cancellation_token.cancelled() => {}
+            r = tokio::time::timeout(ECHO_TIMEOUT, async move { echo_peer(connecting.await.ok()?).await }) => {
+                if !matches!(r, Ok(Some(()))) {
+                    tracing::debug!("Echo peer could not complete a test introduction with {peer_addr}");
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Accept connections to the echo peer, including those from clients punching through to it.
+async fn handle_echo_loop(echo_end: quinn::Endpoint, cancellation_token: CancellationToken) {
+    while let Some(connecting) = echo_end.accept().await {
+        let cancellation_token = cancellation_token.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                () = cancellation_token.cancelled() => {}
+                _ = tokio::time::timeout(ECHO_TIMEOUT, async move { echo_peer(connecting.await.ok()?).await }) => {}
+            }
+        });
+    }
+}
+
+/// Act as a subscribing peer to the client, then send back the payload the client sends us.
+async fn echo_peer(connection: quinn::Connection) -> Option<()> {
+    let (mut send, mut recv) = connection.open_bi().await.ok()?;
+    send.write_all(&file_yeet_shared::ECHO_HASH).await.ok()?;
+
+    // Offer no passphrase proof, since the client's test publish has no passphrase.
+    send.write_all(&[0]).await.ok()?;
+
+    let payload = recv
+        .read_to_end(file_yeet_shared::MAX_ECHO_PAYLOAD_SIZE)
+        .await
+        .ok()?;
+    send.write_all(&payload).await.ok()?;
+    send.finish().await.ok()?;
+    Some(())
+}
+
+/// Forward server-wide notifications to a client over the stream they opened for them.
+#[tracing::instrument(skip_all)]
+fn handle_notifications(
+    session: &ClientSession,
+    mut quic_send: quinn::SendStream,
+    notifier: &broadcast::Sender<NotificationMessage>,
+) {
+    let mut rx = notifier.subscribe();
+    let cancellation_token = session.cancellation_token.clone();
+
+    session.task_master.spawn(async move {
+        let mut bb = SERVER_MESSAGE_BUFFERS.take();
+        loop {
+            let (kind, message) = tokio::select! {
+                // Allow the server to cancel the task.
+                () = cancellation_token.cancelled() => break,
+
+                // Wait for the next notification to forward.
+                r = rx.recv() => match r {
+                    Ok(n) => n,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Client missed {n} notifications");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+
+            // Ensure that the message doesn't exceed the maximum size.
+            let Ok(len) = u16::try_from(message.len()) else {
+                continue;
+            };
+            if 2 * size_of::<u16>() + message.len() > MAX_SERVER_COMMUNICATION_SIZE {
+                continue;
+            }
+
+            // Format the notification as its kind, a length, and a UTF-8 string.
+            bb.put_u16(kind as u16);
+            bb.put_u16(len);
+            bb.put(message.as_bytes());
+
+            if let Err(e) = quic_send.write_all(&bb).await {
+                tracing::warn!("Failed to send notification to client: {e}");
+                break;
+            }
+            // Clear the scratch space before the next iteration.
+            bb.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session for a client at the given address, as the server creates for each connection.
+    fn test_session(address: SocketAddr) -> ClientSession {
+        ClientSession::new(
+            address,
+            false,
+            Arc::default(),
+            webhook::Webhook::default(),
+            CancellationToken::new(),
+            TaskTracker::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn migration_updates_publisher_address() {
+        let old_address: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        let new_address: SocketAddr = "198.51.100.9:5000".parse().unwrap();
+        let session = test_session(old_address);
+        let publisher = Publisher {
+            address: session.peer_addr.clone(),
+            stream: mpsc::channel(1).0,
+            accepts_relay: false,
+            private_address: None,
+        };
+        let mut port_used = old_address.port();
+
+        follow_migration(&session, new_address, &mut port_used).await;
+        assert_eq!(*publisher.address.read().await, PeerAddr::from(new_address));
+        assert_eq!(port_used, new_address.port());
+    }
+
+    #[tokio::test]
+    async fn migration_keeps_port_override() {
+        let old_address: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        let new_address: SocketAddr = "198.51.100.9:5000".parse().unwrap();
+        let mut session = test_session(old_address);
+        session.port_overridden = true;
+        let mut port_used = 7000;
+
+        follow_migration(&session, new_address, &mut port_used).await;
+        let mut expected = PeerAddr::from(new_address);
+        expected.set_port(7000);
+        assert_eq!(*session.peer_addr.read().await, expected);
+        assert_eq!(port_used, 7000);
+    }
+
+    #[test]
+    fn subscribe_cursor_round_trips() {
+        let seed = SubscribeCursor::new_seed();
+        let cursor = SubscribeCursor {
+            seed,
+            last_key: SubscribeCursor::key(seed, &[7, 9]),
+        };
+        assert_ne!(cursor.to_wire(), 0);
+        assert_eq!(SubscribeCursor::from_wire(cursor.to_wire()), Some(cursor));
+        assert_eq!(SubscribeCursor::from_wire(0), None);
+    }
+
+    #[test]
+    fn subscribe_pages_survive_publisher_changes() {
+        let seed = SubscribeCursor::new_seed();
+        let original: Vec<Nonce> = (0..30).map(|i| [i, u64::MAX - i]).collect();
+        let mut publishers = original.clone();
+        let mut visited = Vec::new();
+        let mut last_key = None;
+        for pages in 0.. {
+            let (page, remaining) = next_subscribe_page(publishers.iter(), seed, last_key, 4);
+            let Some(&(key, _)) = page.last() else {
+                break;
+            };
+            assert!(remaining >= page.len());
+            visited.extend(page.iter().map(|(_, nonce)| *nonce));
+            last_key = Some(key);
+
+            // Publishers come and go between pages.
+            publishers.push([100 + pages, pages]);
+            publishers.retain(|nonce| *nonce != original[usize::try_from(pages).unwrap()]);
+        }
+
+        // No publisher is listed twice, and none that stayed for the whole listing is skipped.
+        let mut unique = visited.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), visited.len());
+        for nonce in original.iter().filter(|nonce| publishers.contains(nonce)) {
+            assert!(visited.contains(nonce));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn admin_tokens_are_limited_to_their_scopes() {
+        use admin::{AdminScope, AdminTokens};
+
+        let open = AdminTokens::default();
+        assert!(open.authorize(None, AdminScope::Ban).is_ok());
+
+        let tokens = AdminTokens::new(HashMap::from([
+            (
+                "monitoring".to_owned(),
+                config::AdminTokenConfig {
+                    token: "read-token".to_owned(),
+                    scopes: vec![AdminScope::Read],
+                },
+            ),
+            (
+                "blank".to_owned(),
+                config::AdminTokenConfig {
+                    token: String::new(),
+                    scopes: vec![AdminScope::Ban],
+                },
+            ),
+        ]));
+        assert_eq!(
+            tokens.authorize(Some("read-token"), AdminScope::Read),
+            Ok("monitoring")
+        );
+        assert!(tokens
+            .authorize(Some("read-token"), AdminScope::Kick)
+            .is_err());
+        assert!(tokens.authorize(Some(""), AdminScope::Ban).is_err());
+        assert!(tokens.authorize(None, AdminScope::Read).is_err());
+    }
+}
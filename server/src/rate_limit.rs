@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+/// The number of tracked addresses above which idle addresses are forgotten when a new one connects.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// The limits applied to each source IP address, so that a single client can't exhaust a public server.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct IpLimits {
+    /// The most connections one address may have open at once, if limited.
+    pub max_connections: Option<NonZeroUsize>,
+
+    /// The number of lookup requests one address may make per minute, if limited.
+    pub requests_per_minute: Option<NonZeroU32>,
+
+    /// The most lookup requests one address may make in a burst before being held to the rate.
+    pub request_burst: Option<NonZeroU32>,
+}
+impl IpLimits {
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.max_connections.is_some() || self.requests_per_minute.is_some()
+    }
+
+    /// The size of each address's request bucket. Defaults to a minute's worth of requests.
+    fn burst(&self) -> f64 {
+        f64::from(
+            self.request_burst
+                .or(self.requests_per_minute)
+                .map_or(0, NonZeroU32::get),
+        )
+    }
+}
+
+/// The open connections and remaining requests of a source address.
+#[derive(Debug)]
+struct AddressState {
+    connections: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl AddressState {
+    /// Add the requests earned since the last refill, up to the burst size.
+    fn refill(&mut self, limits: &IpLimits) {
+        let now = Instant::now();
+        if let Some(rate) = limits.requests_per_minute {
+            let earned =
+                now.duration_since(self.last_refill).as_secs_f64() * f64::from(rate.get()) / 60.;
+            self.tokens = (self.tokens + earned).min(limits.burst());
+        }
+        self.last_refill = now;
+    }
+
+    /// Whether the address has nothing open and a full budget, so forgetting it changes nothing.
+    fn is_idle(&self, limits: &IpLimits) -> bool {
+        self.connections == 0 && self.tokens >= limits.burst()
+    }
+}
+
+/// Tracks the connections and request budget of each source IP address.
+/// The limits can be changed while the server runs, but connections opened while
+/// no limit was set are not counted towards one set later.
+#[derive(Debug)]
+pub struct IpLimiter {
+    limits: RwLock<IpLimits>,
+    ephemeral: bool,
+    addresses: Mutex<HashMap<IpAddr, AddressState>>,
+}
+impl IpLimiter {
+    pub fn new(limits: IpLimits, ephemeral: bool) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            ephemeral,
+            addresses: Mutex::default(),
+        }
+    }
+
+    /// Count a new connection from an address, or refuse it if the address is at its connection limit.
+    /// The connection is counted until the returned permit is dropped.
+    pub fn try_connect(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionPermit> {
+        let limits = self.limits();
+        if !limits.is_enabled() {
+            return Some(ConnectionPermit { limiter: None, ip });
+        }
+
+        let mut addresses = self.lock();
+        if !addresses.contains_key(&ip) && addresses.len() >= PRUNE_THRESHOLD {
+            addresses.retain(|_, state| {
+                state.refill(&limits);
+                !state.is_idle(&limits)
+            });
+        }
+        let state = addresses.entry(ip).or_insert_with(|| AddressState {
+            connections: 0,
+            tokens: limits.burst(),
+            last_refill: Instant::now(),
+        });
+        if limits
+            .max_connections
+            .is_some_and(|max| state.connections >= max.get())
+        {
+            return None;
+        }
+        state.connections += 1;
+
+        Some(ConnectionPermit {
+            limiter: Some(self.clone()),
+            ip,
+        })
+    }
+
+    /// Take a lookup request from an address's budget. Returns false if the address has none left.
+    pub fn try_request(&self, ip: IpAddr) -> bool {
+        let limits = self.limits();
+        if limits.requests_per_minute.is_none() {
+            return true;
+        }
+
+        let mut addresses = self.lock();
+        let Some(state) = addresses.get_mut(&ip) else {
+            return true;
+        };
+        state.refill(&limits);
+        if state.tokens < 1. {
+            return false;
+        }
+        state.tokens -= 1.;
+        true
+    }
+
+    /// The limits currently applied to each address.
+    pub fn limits(&self) -> IpLimits {
+        *self
+            .limits
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Replace the limits applied to each address. Addresses keep the requests they have left,
+    /// up to the new burst size.
+    pub fn set_limits(&self, limits: IpLimits) {
+        *self
+            .limits
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = limits;
+    }
+
+    /// Stop counting a connection, forgetting the address if nothing else is tracked for it.
+    fn disconnect(&self, ip: IpAddr) {
+        let limits = self.limits();
+        let mut addresses = self.lock();
+        let Some(state) = addresses.get_mut(&ip) else {
+            return;
+        };
+        state.connections = state.connections.saturating_sub(1);
+        state.refill(&limits);
+        if state.is_idle(&limits) {
+            addresses.remove(&ip);
+
+            // Release the memory of removed entries rather than keeping it around for reuse.
+            if self.ephemeral {
+                addresses.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Lock the address map. The map stays consistent even if a holder panicked, so poisoning is ignored.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, AddressState>> {
+        self.addresses
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// A connection counted against its address's limit until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Option<Arc<IpLimiter>>,
+    ip: IpAddr,
+}
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.disconnect(self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    fn limiter(limits: IpLimits) -> Arc<IpLimiter> {
+        Arc::new(IpLimiter::new(limits, false))
+    }
+
+    #[test]
+    fn requests_are_limited_to_the_burst() {
+        let limiter = limiter(IpLimits {
+            requests_per_minute: NonZeroU32::new(60),
+            request_burst: NonZeroU32::new(3),
+            ..IpLimits::default()
+        });
+        let _permit = limiter.try_connect(IP).unwrap();
+        assert!((0..3).all(|_| limiter.try_request(IP)));
+        assert!(!limiter.try_request(IP));
+    }
+
+    #[test]
+    fn requests_are_refilled_over_time() {
+        // A hundred requests a second, so the wait is short.
+        let limiter = limiter(IpLimits {
+            requests_per_minute: NonZeroU32::new(6000),
+            request_burst: NonZeroU32::new(1),
+            ..IpLimits::default()
+        });
+        let _permit = limiter.try_connect(IP).unwrap();
+        assert!(limiter.try_request(IP));
+        assert!(!limiter.try_request(IP));
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(limiter.try_request(IP));
+    }
+
+    #[test]
+    fn connections_are_released_when_dropped() {
+        let limiter = limiter(IpLimits {
+            max_connections: NonZeroUsize::new(2),
+            ..IpLimits::default()
+        });
+        let first = limiter.try_connect(IP).unwrap();
+        let _second = limiter.try_connect(IP).unwrap();
+        assert!(limiter.try_connect(IP).is_none());
+
+        // Other addresses have their own limit.
+        assert!(limiter
+            .try_connect("198.51.100.1".parse().unwrap())
+            .is_some());
+
+        drop(first);
+        assert!(limiter.try_connect(IP).is_some());
+    }
+
+    #[test]
+    fn unlimited_addresses_are_not_tracked() {
+        let limiter = limiter(IpLimits::default());
+        let _permits = (0..10)
+            .map(|_| limiter.try_connect(IP).unwrap())
+            .collect::<Vec<_>>();
+        assert!(limiter.try_request(IP));
+        assert!(limiter.lock().is_empty());
+    }
+}
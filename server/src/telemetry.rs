@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use opentelemetry::{propagation::TextMapPropagator as _, KeyValue};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+/// The service name the server's spans are exported under.
+const SERVICE_NAME: &str = "file_yeet_server";
+
+/// The W3C header that trace context is carried in.
+const TRACE_PARENT: &str = "traceparent";
+
+/// Build a layer that exports the server's spans to an OpenTelemetry collector over OTLP/gRPC.
+/// Spans are sent in batches from a background task, so exporting never holds up a request.
+pub fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<impl tracing_subscriber::Layer<S>, opentelemetry::trace::TraceError>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Send the spans still waiting in the batch to the collector before the server exits.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Make a request's span continue the trace of the client that sent it, given the W3C `traceparent` it carried.
+/// Clients without a trace of their own send an empty one, which leaves the span as its own trace.
+pub fn continue_trace(span: &tracing::Span, trace_parent: &str) {
+    if trace_parent.is_empty() {
+        return;
+    }
+    let carrier = HashMap::from([(TRACE_PARENT.to_owned(), trace_parent.to_owned())]);
+    span.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
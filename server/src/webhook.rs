@@ -0,0 +1,97 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+
+/// The most events waiting to be posted before new events are dropped, so a slow webhook can't grow memory forever.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// How long the webhook has to answer a single event before it is given up on.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events the server reports to its webhook, posted as JSON objects tagged by their `event` field.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A client started publishing a file.
+    PublishAdded {
+        session: String,
+        room: String,
+        hash: String,
+        file_size: u64,
+    },
+
+    /// A client stopped publishing a file, or its publish expired.
+    PublishRemoved {
+        session: String,
+        room: String,
+        hash: String,
+    },
+
+    /// A subscriber was introduced to the publishers of a file.
+    Introduction {
+        session: String,
+        room: String,
+        hash: String,
+        publishers: u16,
+    },
+
+    /// A connection was refused before any of its requests were read.
+    ConnectionRefused {
+        address: String,
+        reason: &'static str,
+    },
+}
+
+/// An event with the time it happened, as posted to the webhook.
+#[derive(serde::Serialize)]
+struct WebhookPayload {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: WebhookEvent,
+}
+
+/// A handle for reporting events to the webhook, which does nothing when no webhook is configured.
+/// Events are posted in order from a background task, so reporting never waits on the network.
+#[derive(Clone, Debug, Default)]
+pub struct Webhook(Option<mpsc::Sender<WebhookPayload>>);
+impl Webhook {
+    /// Start posting reported events to the given URL.
+    pub fn start(url: reqwest::Url) -> Self {
+        let (tx, mut rx) = mpsc::channel::<WebhookPayload>(MAX_QUEUED_EVENTS);
+        let client = reqwest::Client::builder()
+            .timeout(POST_TIMEOUT)
+            .build()
+            .expect("Failed to create the webhook's HTTP client");
+
+        tokio::task::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                let result = client
+                    .post(url.clone())
+                    .json(&payload)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+                if let Err(e) = result {
+                    tracing::warn!("Failed to post an event to the webhook: {e}");
+                }
+            }
+        });
+        Self(Some(tx))
+    }
+
+    /// Report an event to the webhook. The event is only built if a webhook is configured.
+    pub fn report(&self, event: impl FnOnce() -> WebhookEvent) {
+        let Some(tx) = &self.0 else {
+            return;
+        };
+        let payload = WebhookPayload {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            event: event(),
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(payload) {
+            tracing::warn!("Dropping a webhook event because the webhook is falling behind");
+        }
+    }
+}
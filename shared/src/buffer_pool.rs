@@ -0,0 +1,80 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Mutex, PoisonError},
+};
+
+use bytes::BytesMut;
+
+use crate::MAX_SERVER_COMMUNICATION_SIZE;
+
+/// Scratch buffers for the messages between clients and the server, shared by every request.
+pub static SERVER_MESSAGE_BUFFERS: BufferPool = BufferPool::new(MAX_SERVER_COMMUNICATION_SIZE, 256);
+
+/// A pool of scratch buffers of a fixed capacity, handed out and recycled so that each request or transfer
+/// doesn't allocate its own. A buffer returns to the pool, emptied, when its `PooledBuffer` is dropped.
+#[derive(Debug)]
+pub struct BufferPool {
+    capacity: usize,
+
+    /// The most buffers kept for reuse. Buffers returned beyond this are freed.
+    max_idle: usize,
+    idle: Mutex<Vec<BytesMut>>,
+}
+impl BufferPool {
+    /// Create an empty pool of buffers with the given capacity, keeping at most `max_idle` of them for reuse.
+    #[must_use]
+    pub const fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            capacity,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take an empty buffer from the pool, allocating a new one if none are idle.
+    pub fn take(&'static self) -> PooledBuffer {
+        let buffer = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.capacity));
+        PooledBuffer { buffer, pool: self }
+    }
+}
+
+/// A buffer borrowed from a `BufferPool`, used like the `BytesMut` it wraps.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: BytesMut,
+    pool: &'static BufferPool,
+}
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        // Buffers that were split, or grew well past the pool's size, are freed instead of kept.
+        let mut buffer = std::mem::take(&mut self.buffer);
+        if !(self.pool.capacity..=2 * self.pool.capacity).contains(&buffer.capacity()) {
+            return;
+        }
+        buffer.clear();
+        let mut idle = self
+            .pool
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < self.pool.max_idle {
+            idle.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+/// Errors that can occur when loading certificates and private keys from files.
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    /// Failed to read a certificate or key file.
+    #[error("Failed to read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    /// The certificate file did not contain any certificates.
+    #[error("No certificates were found in {0}")]
+    NoCertificates(PathBuf),
+
+    /// The key file did not contain a supported private key.
+    #[error("No PKCS#8, PKCS#1, or SEC1 private key was found in {0}")]
+    NoPrivateKey(PathBuf),
+}
+
+/// A certificate chain and the private key it was issued for.
+#[derive(Clone, Debug)]
+pub struct CertificateAndKey {
+    pub chain: Vec<rustls::Certificate>,
+    pub key: rustls::PrivateKey,
+}
+impl CertificateAndKey {
+    /// Load a certificate chain and its private key from files in either PEM or DER format.
+    /// # Errors
+    /// Fails if either file cannot be read, or is missing its certificates or key.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, CertificateError> {
+        Ok(Self {
+            chain: load_certificates(cert_path)?,
+            key: load_private_key(key_path)?,
+        })
+    }
+}
+
+/// Load a certificate chain, or a bundle of CA certificates, from a file in either PEM or DER format.
+/// # Errors
+/// Fails if the file cannot be read or contains no certificates.
+pub fn load_certificates(path: &Path) -> Result<Vec<rustls::Certificate>, CertificateError> {
+    // PEM files are text beginning with a boundary line, anything else is treated as a single DER item.
+    let bytes = std::fs::read(path).map_err(|e| CertificateError::Io(path.to_path_buf(), e))?;
+    let certificates = if is_pem(&bytes) {
+        rustls_pemfile::certs(&mut bytes.as_slice())
+            .map_err(|e| CertificateError::Io(path.to_path_buf(), e))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect()
+    } else {
+        vec![rustls::Certificate(bytes)]
+    };
+    if certificates.is_empty() {
+        return Err(CertificateError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+/// Load a private key from a file in either PEM or DER format.
+/// # Errors
+/// Fails if the file cannot be read or contains no supported private key.
+pub fn load_private_key(path: &Path) -> Result<rustls::PrivateKey, CertificateError> {
+    let bytes = std::fs::read(path).map_err(|e| CertificateError::Io(path.to_path_buf(), e))?;
+    if !is_pem(&bytes) {
+        return Ok(rustls::PrivateKey(bytes));
+    }
+
+    let mut reader = bytes.as_slice();
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| CertificateError::Io(path.to_path_buf(), e))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => return Ok(rustls::PrivateKey(key)),
+            Some(_) => {}
+            None => return Err(CertificateError::NoPrivateKey(path.to_path_buf())),
+        }
+    }
+}
+
+/// Load CA certificates from a file into a set of trust anchors.
+/// # Errors
+/// Fails if the file cannot be read, contains no certificates, or contains a certificate `rustls` can't parse.
+pub fn load_root_store(path: &Path) -> anyhow::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots
+            .add(&certificate)
+            .map_err(|e| anyhow::anyhow!("Invalid CA certificate in {}: {e}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// Whether the file contents look like PEM rather than DER.
+fn is_pem(bytes: &[u8]) -> bool {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    bytes[start..].starts_with(b"-----BEGIN")
+}
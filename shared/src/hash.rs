@@ -0,0 +1,144 @@
+use num_enum::TryFromPrimitive;
+use sha2::Digest as _;
+
+use crate::{HashBytes, HASH_BYTE_COUNT};
+
+/// The prefix marking BLAKE3 hashes in their text form.
+const BLAKE3_PREFIX: &str = "b3-";
+
+/// The smallest update worth splitting across threads when hashing with BLAKE3.
+const BLAKE3_PARALLEL_THRESHOLD: usize = 128 * 1024;
+
+/// The algorithms a file may be hashed with.
+/// Every algorithm produces `HASH_BYTE_COUNT` bytes. Publish and subscribe requests are tagged with the algorithm as a `u8`,
+/// so the server only introduces peers that agree on which algorithm made the hash.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    TryFromPrimitive,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[repr(u8)]
+pub enum HashAlgorithm {
+    /// The original algorithm, understood by every peer.
+    #[default]
+    Sha256,
+
+    /// A much faster algorithm that can hash large files on several threads.
+    Blake3,
+}
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        };
+        write!(f, "{str}")
+    }
+}
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!(
+                "Unknown hash algorithm `{s}`, expected sha256 or blake3"
+            )),
+        }
+    }
+}
+
+/// A file hash tagged with the algorithm that produced it.
+/// In text form, SHA-256 hashes are plain hexadecimal so they remain compatible with older peers,
+/// while BLAKE3 hashes are prefixed with `b3-`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileHash {
+    pub algorithm: HashAlgorithm,
+    pub bytes: HashBytes,
+}
+impl FileHash {
+    #[must_use]
+    pub fn new(algorithm: HashAlgorithm, bytes: HashBytes) -> Self {
+        Self { algorithm, bytes }
+    }
+}
+impl std::fmt::Display for FileHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.algorithm == HashAlgorithm::Blake3 {
+            f.write_str(BLAKE3_PREFIX)?;
+        }
+        f.write_str(&faster_hex::hex_string(&self.bytes))
+    }
+}
+impl std::str::FromStr for FileHash {
+    type Err = InvalidHash;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (algorithm, hex) = match s.get(..BLAKE3_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(BLAKE3_PREFIX) => {
+                (HashAlgorithm::Blake3, &s[BLAKE3_PREFIX.len()..])
+            }
+            _ => (HashAlgorithm::Sha256, s),
+        };
+
+        let mut bytes = HashBytes::default();
+        if hex.len() != 2 * HASH_BYTE_COUNT
+            || faster_hex::hex_decode(hex.as_bytes(), &mut bytes).is_err()
+        {
+            return Err(InvalidHash);
+        }
+        Ok(Self { algorithm, bytes })
+    }
+}
+
+/// The error when a file hash cannot be parsed from text.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "The file hash must be {} hexadecimal characters, prefixed with `{BLAKE3_PREFIX}` for BLAKE3",
+    HASH_BYTE_COUNT * 2
+)]
+pub struct InvalidHash;
+
+/// Incrementally hash data with any supported algorithm.
+pub enum FileHasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+impl FileHasher {
+    #[must_use]
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    /// Add data to the hash. Large updates are hashed on several threads when the algorithm allows it.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) if data.len() >= BLAKE3_PARALLEL_THRESHOLD => {
+                hasher.update_rayon(data);
+            }
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Get the hash of all the data added.
+    #[must_use]
+    pub fn finalize(self) -> HashBytes {
+        match self {
+            Self::Sha256(hasher) => hasher.finalize().into(),
+            Self::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
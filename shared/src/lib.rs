@@ -1,5 +1,5 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr, ToSocketAddrs as _},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV6, ToSocketAddrs as _},
     num::NonZeroU16,
     sync::Arc,
     time::Duration,
@@ -7,18 +7,55 @@ use std::{
 
 use num_enum::TryFromPrimitive;
 
+pub mod buffer_pool;
+pub mod certificates;
+pub mod hash;
+pub mod share;
+pub mod transport;
+#[cfg(all(unix, feature = "unix-socket"))]
+pub mod unix_socket;
+pub use buffer_pool::{BufferPool, PooledBuffer, SERVER_MESSAGE_BUFFERS};
+pub use hash::{FileHash, FileHasher, HashAlgorithm, InvalidHash};
+pub use share::{
+    compute_file_hash, compute_file_hash_with_algorithm, format_share_uri, parse_share_uri,
+    share_extension, ShareUri, ShareUriError,
+};
+pub use transport::{
+    set_congestion_controller, set_transport_tuning, CongestionController, TransportTuning,
+};
+
 /// Magic number for the default port.
 pub const DEFAULT_PORT: NonZeroU16 = unsafe { std::num::NonZeroU16::new_unchecked(7828) };
 
 /// Define a sane maximum payload size for the client-server messages.
 pub const MAX_SERVER_COMMUNICATION_SIZE: usize = 1024;
 
-/// Using SHA-256 for the hash; i.e., a 256 bit / 8 byte hash.
+/// Using SHA-256 or BLAKE3 for the hash; i.e., a 256 bit / 32 byte hash.
 pub const HASH_BYTE_COUNT: usize = 32;
 
-/// A block of raw SHA-256 bytes.
+/// A block of raw hash bytes. See `FileHash` for a hash tagged with its algorithm.
 pub type HashBytes = [u8; HASH_BYTE_COUNT];
 
+/// The longest room name, in bytes. Rooms are sent in requests as a `u8` length and a UTF-8 string.
+/// Hashes are only visible within the room they were published in, and the empty name is the default room.
+pub const MAX_ROOM_NAME_LENGTH: usize = u8::MAX as usize;
+
+/// The longest file name and MIME type a publisher may attach to a file, in bytes.
+/// Each is sent as a `u8` length and a UTF-8 string, where an empty string means no hint.
+/// Kept short so that several publishers still fit in a subscribe response.
+pub const MAX_FILE_METADATA_LENGTH: usize = 128;
+
+/// The length of a publisher's signature over a publish: a 32 byte Ed25519 public key,
+/// a 64 byte signature, and the `u64` Unix time it was signed at.
+/// Sent after the file hints as a `u8` flag, followed by the signature only when the flag is set.
+pub const PUBLISH_SIGNATURE_LENGTH: usize = 32 + 64 + std::mem::size_of::<u64>();
+
+/// The file hash the server's echo peer uses during test introductions.
+pub const ECHO_HASH: HashBytes = [0; HASH_BYTE_COUNT];
+
+/// The maximum size of a payload the server's echo peer will send back.
+pub const MAX_ECHO_PAYLOAD_SIZE: usize = 1024;
+
 /// The maximum number of seconds of inactivity before a QUIC connection is closed.
 /// Same for both the server and the client.
 pub const QUIC_TIMEOUT_SECONDS: u64 = 120;
@@ -35,6 +72,75 @@ pub struct SocketAddrHelper {
     pub hostname: String,
 }
 
+/// A peer's socket address, normalized so that IPv4 and IPv4-mapped IPv6 forms of the same
+/// address compare and hash equally.
+/// Dual-stack sockets report IPv4 peers in their mapped form, while IPv4 sockets and user input do not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerAddr(SocketAddrV6);
+impl PeerAddr {
+    /// Create a peer address from any socket address, storing it in the IPv6-mapped form.
+    #[must_use]
+    pub fn new(address: SocketAddr) -> Self {
+        match address {
+            SocketAddr::V4(v4) => {
+                Self(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+            }
+
+            // The flow label is not part of a peer's identity.
+            SocketAddr::V6(v6) => Self(SocketAddrV6::new(*v6.ip(), v6.port(), 0, v6.scope_id())),
+        }
+    }
+
+    /// Get the socket address in its most compatible form, i.e., IPv4 addresses are unmapped.
+    /// This is the form to connect with and to share with peers.
+    #[must_use]
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.0.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), self.0.port()),
+            None => SocketAddr::V6(self.0),
+        }
+    }
+
+    /// Get the socket address in its IPv6-mapped form, for use with dual-stack IPv6 sockets.
+    #[must_use]
+    pub fn mapped_socket_addr(&self) -> SocketAddr {
+        SocketAddr::V6(self.0)
+    }
+
+    /// The port of the peer address.
+    #[must_use]
+    pub fn port(&self) -> u16 {
+        self.0.port()
+    }
+
+    /// Change the port of the peer address.
+    pub fn set_port(&mut self, port: u16) {
+        self.0.set_port(port);
+    }
+
+    /// Whether both addresses have the same IP, as peers behind the same NAT do.
+    #[must_use]
+    pub fn same_ip(&self, other: &Self) -> bool {
+        self.0.ip() == other.0.ip()
+    }
+}
+impl From<SocketAddr> for PeerAddr {
+    fn from(address: SocketAddr) -> Self {
+        Self::new(address)
+    }
+}
+impl std::str::FromStr for PeerAddr {
+    type Err = std::net::AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<SocketAddr>().map(Self::new)
+    }
+}
+impl std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.socket_addr(), f)
+    }
+}
+
 /// The type of API requests that can be made by clients.
 /// Sent as a `u16` in QUIC requests to the server.
 #[derive(Debug, TryFromPrimitive)]
@@ -47,13 +153,53 @@ pub enum ClientApiRequest {
     PortOverride,
 
     /// Specify a file hash that this client wants to publish.
+    /// Followed by the hash, the `u8` `HashAlgorithm` that made it, the `u64` file size, the room to publish in,
+    /// the file name and MIME type hints, and an optional publisher signature.
     Publish,
 
     /// Specify a file hash that this client wants to subscribe to.
+    /// Followed by the hash, the `u8` `HashAlgorithm` that made it, the room to look for publishers in,
+    /// a `u16` number of publishers wanted or zero for as many as fit,
+    /// and a `u64` cursor from an earlier response to continue from or zero to start.
+    /// Each publisher in the response has their address, file size, file name and MIME type hints, and signature if any.
+    /// The publishers are followed by a `u64` cursor for the next page, which is zero when there are no more.
     Subscribe,
 
     /// Request to be introduced to a specific peer over a certain file hash.
+    /// Followed by the hash, a `u8` length and the peer's address as a UTF-8 string, and the room.
     Introduction,
+
+    /// Open a stream for the server to push notifications to the client.
+    Notifications,
+
+    /// Request that the server's echo peer connects to the client to test its peer-to-peer reachability.
+    TestIntroduction,
+
+    /// Have the server forward a peer-to-peer stream when the peers cannot connect directly.
+    /// Followed by a `u8` `RelayRole`.
+    Relay,
+
+    /// Ask which optional features and policies the server has. The server responds with a `u32` of `ServerCapabilities`.
+    Capabilities,
+
+    /// Present an access token to a server that requires one before publishing or subscribing.
+    /// Followed by a `u16` length and the UTF-8 token. The server responds with a `LookupStatus`.
+    Authenticate,
+
+    /// Ask which file hashes the server has this client publishing.
+    /// The server responds with a `u16` count, then each hash, its `u64` file size,
+    /// and a `u8` length and the UTF-8 room it was published in.
+    ListPublishes,
+
+    /// Tell the server the client's address on its local network, to be introduced by to peers behind the same public IP.
+    /// Followed by a `u8` length and the address as a UTF-8 string.
+    PrivateAddress,
+
+    /// Carry the trace context of the request that follows, so that the server's spans continue the client's trace.
+    /// Followed by a `u8` length and a W3C `traceparent` as a UTF-8 string, which is empty if the client has none,
+    /// then the request itself. Only sent to servers with `ServerCapabilities::TRACE_CONTEXT`.
+    /// Subscribers introduced to a traced publish are preceded by a `PUBLISH_TRACE_CONTEXT` message.
+    Traced,
 }
 impl std::fmt::Display for ClientApiRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -63,11 +209,180 @@ impl std::fmt::Display for ClientApiRequest {
             ClientApiRequest::Publish => "PUBLISH      ",
             ClientApiRequest::Subscribe => "SUBSCRIBE    ",
             ClientApiRequest::Introduction => "INTRODUCTION ",
+            ClientApiRequest::Notifications => "NOTIFICATIONS",
+            ClientApiRequest::TestIntroduction => "TEST_INTRO   ",
+            ClientApiRequest::Relay => "RELAY        ",
+            ClientApiRequest::Capabilities => "CAPABILITIES ",
+            ClientApiRequest::Authenticate => "AUTHENTICATE ",
+            ClientApiRequest::ListPublishes => "LIST_PUBS    ",
+            ClientApiRequest::PrivateAddress => "PRIVATE_ADDR ",
+            ClientApiRequest::Traced => "TRACED       ",
         };
         write!(f, "REQ: {str}")
     }
 }
 
+/// The status at the start of the server's response to a subscribe or introduction request.
+/// Sent as a `u8`. Throttled and denied statuses are followed by a `u16` length and a UTF-8 reason.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum LookupStatus {
+    /// The request succeeded and any response content follows.
+    Found,
+
+    /// No publishers are available for the requested file or peer.
+    NotFound,
+
+    /// The client is making too many requests and should try again later.
+    Throttled,
+
+    /// The server refused the request.
+    Denied,
+}
+impl std::fmt::Display for LookupStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            LookupStatus::Found => "Found",
+            LookupStatus::NotFound => "No publishers available",
+            LookupStatus::Throttled => "Too many requests, try again later",
+            LookupStatus::Denied => "Request denied by the server",
+        };
+        write!(f, "{str}")
+    }
+}
+
+/// The part a client plays in a relay request. Sent as a `u8` after `ClientApiRequest::Relay`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RelayRole {
+    /// Let the server offer relays to this client's future publishes.
+    /// The server responds with a `LookupStatus`.
+    OptIn,
+
+    /// Ask for a relay to a publisher of a file hash.
+    /// Followed by the hash, a `u8` length, the publisher's address as a UTF-8 string, and the room.
+    Subscribe,
+
+    /// Accept a relay offered on a publish stream. Followed by the `u64` token of the offer.
+    Publish,
+}
+
+/// The message length that marks a relay offer on a publish stream, in place of a subscriber's address.
+/// Followed by a `u64` token that the publisher uses to accept the relay,
+/// then a `u16` length and the subscriber's address as a UTF-8 string.
+pub const RELAY_OFFER: u16 = u16::MAX;
+
+/// The message length that asks a publisher to refresh their publish before it expires, in place of a subscriber's address.
+/// The publisher responds with `PublishControl::Refresh`.
+pub const PUBLISH_REFRESH: u16 = u16::MAX - 1;
+
+/// The message length that checks a publisher can still be reached, in place of a subscriber's address.
+/// The publisher responds with `PublishControl::Heartbeat`, or is dropped if the server doesn't hear back in time.
+pub const PUBLISH_HEARTBEAT: u16 = u16::MAX - 2;
+
+/// The message length that marks the trace context of the next subscriber introduced to a traced publish,
+/// in place of a subscriber's address. Followed by a `u8` length and the subscriber's W3C `traceparent`
+/// as a UTF-8 string, which is empty if the subscriber's request carried none.
+pub const PUBLISH_TRACE_CONTEXT: u16 = u16::MAX - 3;
+
+/// Messages a publisher may send on their publish stream. Sent as a `u8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PublishControl {
+    /// Stop publishing the file.
+    Cancel,
+
+    /// Keep the publish alive for another period, as asked by a `PUBLISH_REFRESH` message.
+    Refresh,
+
+    /// Acknowledge a `PUBLISH_HEARTBEAT` message.
+    Heartbeat,
+}
+
+/// The optional features and policies a server advertises to clients, sent as `u32` flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerCapabilities(pub u32);
+impl ServerCapabilities {
+    /// The server forwards streams between peers that cannot connect directly.
+    pub const RELAY: u32 = 1;
+
+    /// The server hosts an echo peer for testing reachability.
+    pub const ECHO_PEER: u32 = 1 << 1;
+
+    /// The server requires a port override before accepting publishes.
+    pub const PORT_OVERRIDE_REQUIRED: u32 = 1 << 2;
+
+    /// The server keeps nothing on disk, does not log client activity, and scrubs client addresses from memory it frees.
+    pub const EPHEMERAL: u32 = 1 << 3;
+
+    /// The server requires clients to authenticate with an access token before publishing or subscribing.
+    pub const AUTH_REQUIRED: u32 = 1 << 4;
+
+    /// The server introduces peers behind the same public IP by the private addresses they report.
+    pub const PRIVATE_ADDRESSES: u32 = 1 << 5;
+
+    /// The server accepts trace context ahead of requests and forwards subscribers' trace context to publishers.
+    pub const TRACE_CONTEXT: u32 = 1 << 6;
+
+    /// Whether the server advertises every given flag.
+    #[must_use]
+    pub fn contains(self, flags: u32) -> bool {
+        self.0 & flags == flags
+    }
+}
+impl std::fmt::Display for ServerCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (Self::RELAY, "relay"),
+            (Self::ECHO_PEER, "echo peer"),
+            (Self::PORT_OVERRIDE_REQUIRED, "port override required"),
+            (Self::EPHEMERAL, "ephemeral"),
+            (Self::AUTH_REQUIRED, "token required"),
+            (Self::PRIVATE_ADDRESSES, "private addresses"),
+            (Self::TRACE_CONTEXT, "trace context"),
+        ];
+        let mut first = true;
+        for (flag, name) in names {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of notifications the server may push to clients over a notification stream.
+/// Sent as a `u16`, followed by a `u16` length and a UTF-8 message.
+#[derive(Clone, Copy, Debug, TryFromPrimitive)]
+#[repr(u16)]
+pub enum ServerNotification {
+    /// The server is shutting down.
+    Shutdown,
+
+    /// A server policy affecting clients has changed.
+    PolicyChange,
+
+    /// A message broadcast to all clients by the server administrator.
+    Broadcast,
+}
+impl std::fmt::Display for ServerNotification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ServerNotification::Shutdown => "Server shutting down",
+            ServerNotification::PolicyChange => "Server policy changed",
+            ServerNotification::Broadcast => "Server broadcast",
+        };
+        write!(f, "{str}")
+    }
+}
+
 /// Helper to get either the socket address corresponding to the user's input, or the default of IPv4 localhost.
 ///
 /// # Errors
@@ -123,20 +438,11 @@ impl From<(quinn::SendStream, quinn::RecvStream)> for BiStream {
     }
 }
 
-/// Set reasonable transport config defaults for the server as well as peers when receiving connections.
-/// # Panics
-/// If the conversion from `Duration` to `IdleTimeout` of the max idle timeout fails.
+/// Set reasonable transport config defaults for the server as well as peers when receiving connections,
+/// adjusted by any tuning given to `set_transport_tuning`.
 #[must_use]
 pub fn server_transport_config() -> Arc<quinn::TransportConfig> {
-    // Set custom keep alive policies.
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.max_idle_timeout(Some(
-        Duration::from_secs(QUIC_TIMEOUT_SECONDS)
-            .try_into()
-            .unwrap(),
-    ));
-    transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
-    Arc::new(transport_config)
+    Arc::new(transport::transport_config(Duration::from_secs(30)))
 }
 
 /// Generate a self-signed certificate for use with QUIC.
@@ -148,6 +454,224 @@ pub fn generate_self_signed_cert() -> anyhow::Result<(rustls::Certificate, rustl
     Ok((rustls::Certificate(cert.serialize_der()?), key))
 }
 
+/// Allow peers to connect using self-signed certificates.
+/// Necessary for using the QUIC protocol.
+#[derive(Debug)]
+struct SkipAllServerVerification;
+
+/// Skip all server verification.
+impl rustls::client::ServerCertVerifier for SkipAllServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Request a certificate from connecting peers to learn their identity, without requiring one.
+/// Identities are only recognized, not trusted, so any certificate is accepted.
+struct AcceptAnyClientCertificate;
+
+/// Accept any client certificate, or none.
+impl rustls::server::ClientCertVerifier for AcceptAnyClientCertificate {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
+        Ok(rustls::server::ClientCertVerified::assertion())
+    }
+}
+
+/// Build a QUIC server config for accepting peer connections with a persistent certificate.
+/// Peers that connect with a certificate of their own can be identified by it.
+/// # Errors
+/// Fails if `rustls` rejects the certificate or private key.
+pub fn configure_peer_server(
+    cert: rustls::Certificate,
+    key: rustls::PrivateKey,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(AcceptAnyClientCertificate))
+        .with_single_cert(vec![cert], key)?;
+    crypto.max_early_data_size = u32::MAX;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Build a QUIC server config for the rendezvous server that only accepts clients presenting a certificate
+/// issued by one of the given CA certificates. Clients without one fail the handshake.
+/// # Errors
+/// Fails if `rustls` rejects the certificate chain or private key.
+pub fn configure_server_with_client_auth(
+    identity: certificates::CertificateAndKey,
+    client_roots: rustls::RootCertStore,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(
+            rustls::server::AllowAnyAuthenticatedClient::new(client_roots).boxed(),
+        )
+        .with_single_cert(identity.chain, identity.key)?;
+    crypto.max_early_data_size = u32::MAX;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Build a QUIC client config for connecting to the server that skips verification during the handshake,
+/// so that its certificate can be checked against the one trusted on first use once connected.
+/// The client certificate, if any, is presented to servers that require one.
+/// # Errors
+/// Fails if `rustls` rejects the client certificate chain or private key.
+pub fn configure_server_verification_on_first_use(
+    client_certificate: Option<certificates::CertificateAndKey>,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipAllServerVerification {}));
+    let crypto = match client_certificate {
+        Some(identity) => builder.with_client_auth_cert(identity.chain, identity.key)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(peer_client_config(crypto))
+}
+
+/// Build a QUIC client config that will skip server verification.
+#[must_use]
+pub fn configure_peer_verification() -> quinn::ClientConfig {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipAllServerVerification {}))
+        .with_no_client_auth();
+    peer_client_config(crypto)
+}
+
+/// Build a QUIC client config that will skip server verification and identify us to peers with a certificate.
+/// # Errors
+/// Fails if `rustls` rejects the certificate or private key.
+pub fn configure_peer_verification_with_identity(
+    cert: rustls::Certificate,
+    key: rustls::PrivateKey,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipAllServerVerification {}))
+        .with_client_auth_cert(vec![cert], key)?;
+    Ok(peer_client_config(crypto))
+}
+
+/// Build a QUIC client config for connecting to the server that only accepts a certificate issued
+/// for the server's name by one of the given CA certificates.
+/// The client certificate, if any, is presented to servers that require one.
+/// # Errors
+/// Fails if `rustls` rejects the client certificate chain or private key.
+pub fn configure_server_verification_with_roots(
+    roots: rustls::RootCertStore,
+    client_certificate: Option<certificates::CertificateAndKey>,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let crypto = match client_certificate {
+        Some(identity) => builder.with_client_auth_cert(identity.chain, identity.key)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(peer_client_config(crypto))
+}
+
+/// Build a QUIC client config for connecting to the server that only accepts the certificate with the given SHA-256 fingerprint.
+/// The client certificate, if any, is presented to servers that require one.
+/// # Errors
+/// Fails if `rustls` rejects the client certificate chain or private key.
+pub fn configure_server_verification_with_fingerprint(
+    fingerprint: [u8; 32],
+    client_certificate: Option<certificates::CertificateAndKey>,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedServerCertificate { fingerprint }));
+    let crypto = match client_certificate {
+        Some(identity) => builder.with_client_auth_cert(identity.chain, identity.key)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(peer_client_config(crypto))
+}
+
+/// Parse a SHA-256 certificate fingerprint given as hex, optionally separated by colons as tools like `openssl` print them.
+/// # Errors
+/// Fails if the text is not 32 bytes of hex.
+pub fn parse_certificate_fingerprint(s: &str) -> Result<[u8; 32], String> {
+    let hex = s.trim().replace(':', "");
+    let mut fingerprint = [0; 32];
+    if hex.len() != 2 * fingerprint.len() {
+        return Err(format!(
+            "A SHA-256 fingerprint must be {} hex characters",
+            2 * fingerprint.len()
+        ));
+    }
+    faster_hex::hex_decode(hex.as_bytes(), &mut fingerprint)
+        .map_err(|e| format!("Invalid fingerprint {s:?}: {e}"))?;
+    Ok(fingerprint)
+}
+
+/// Accept only a server certificate with a SHA-256 fingerprint chosen by the user.
+/// The handshake signature is still checked against the certificate's key.
+#[derive(Debug)]
+struct PinnedServerCertificate {
+    fingerprint: [u8; 32],
+}
+
+/// Compare the server's certificate against the expected fingerprint.
+impl rustls::client::ServerCertVerifier for PinnedServerCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        use sha2::Digest as _;
+        if sha2::Sha256::digest(&end_entity.0).as_slice() == self.fingerprint {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "The server's certificate does not match the expected fingerprint".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Wrap the TLS configuration for connecting to peers or the server with our QUIC transport policies.
+fn peer_client_config(crypto: rustls::ClientConfig) -> quinn::ClientConfig {
+    let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+
+    // Send keep alive packets at a fraction of the idle timeout.
+    client_config.transport_config(Arc::new(transport::transport_config(Duration::from_secs(
+        QUIC_TIMEOUT_SECONDS / 6,
+    ))));
+    client_config
+}
+
 // // Rustls 0.22.0 version of the above
 // impl rustls::client::dangerous::ServerCertVerifier for SkipAllServerVerification {
 
@@ -193,3 +717,35 @@ pub fn generate_self_signed_cert() -> anyhow::Result<(rustls::Certificate, rustl
 //         .with_custom_certificate_verifier(Arc::new(SkipAllServerVerification {}))
 //         .with_no_client_auth()
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_addresses_compare_across_ip_versions() {
+        let v4 = "203.0.113.7:4000".parse::<PeerAddr>().unwrap();
+        let mapped = "[::ffff:203.0.113.7]:4000".parse::<PeerAddr>().unwrap();
+        assert_eq!(v4, mapped);
+        assert_eq!(v4.to_string(), "203.0.113.7:4000");
+        assert_eq!(
+            mapped.mapped_socket_addr().to_string(),
+            "[::ffff:203.0.113.7]:4000"
+        );
+
+        let v6 = "[2001:db8::1]:4000".parse::<PeerAddr>().unwrap();
+        assert_eq!(v6.socket_addr(), "[2001:db8::1]:4000".parse().unwrap());
+        assert!("203.0.113.7".parse::<PeerAddr>().is_err());
+    }
+
+    #[test]
+    fn peer_addresses_change_port() {
+        let mut address = "203.0.113.7:4000".parse::<PeerAddr>().unwrap();
+        let original = address;
+        address.set_port(5000);
+        assert_eq!(address.port(), 5000);
+        assert_ne!(address, original);
+        assert!(address.same_ip(&original));
+        assert!(!address.same_ip(&"203.0.113.8:4000".parse().unwrap()));
+    }
+}
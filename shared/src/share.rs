@@ -0,0 +1,241 @@
+use std::{io::Read as _, num::NonZeroU16, path::Path};
+
+use crate::{FileHash, FileHasher, HashAlgorithm, HashBytes, DEFAULT_PORT, HASH_BYTE_COUNT};
+
+/// The URI scheme used to share a file as a single string.
+pub const SHARE_URI_SCHEME: &str = "fyeet";
+
+/// Everything needed to download a shared file: the server, the file hash, and an optional file extension hint.
+/// Formatted as `fyeet://server:port/hash[:ext]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShareUri {
+    /// The server's hostname or IP address, without IPv6 brackets.
+    pub server_address: String,
+    pub server_port: NonZeroU16,
+    pub hash: FileHash,
+    pub extension: Option<String>,
+}
+impl std::str::FromStr for ShareUri {
+    type Err = ShareUriError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_share_uri(s)
+    }
+}
+impl std::fmt::Display for ShareUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format_share_uri(
+            &self.server_address,
+            self.server_port,
+            &self.hash,
+            self.extension.as_deref(),
+        ))
+    }
+}
+
+/// Errors that may occur when parsing a share URI.
+#[derive(Debug, thiserror::Error)]
+pub enum ShareUriError {
+    #[error("The URI does not start with `{SHARE_URI_SCHEME}://`")]
+    InvalidScheme,
+    #[error("The URI is missing a server address")]
+    MissingServer,
+    #[error("The server port is invalid")]
+    InvalidPort,
+    #[error("The URI is missing a file hash")]
+    MissingHash,
+    #[error("The file hash must be {} hexadecimal characters, prefixed with `b3-` for BLAKE3", HASH_BYTE_COUNT * 2)]
+    InvalidHash,
+    #[error("The file extension is empty or contains invalid characters")]
+    InvalidExtension,
+}
+
+/// Get a file's size and its SHA-256 hash, the identifier peers use to request the file.
+/// # Errors
+/// Fails if the file cannot be opened or read.
+pub fn compute_file_hash(path: &Path) -> std::io::Result<(u64, HashBytes)> {
+    compute_file_hash_with_algorithm(path, HashAlgorithm::Sha256)
+}
+
+/// Get a file's size and its hash using the given algorithm.
+/// # Errors
+/// Fails if the file cannot be opened or read.
+pub fn compute_file_hash_with_algorithm(
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<(u64, HashBytes)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = FileHasher::new(algorithm);
+    // Large reads let BLAKE3 hash on several threads.
+    let mut buf = vec![0; 1024 * 1024];
+    let mut file_size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        file_size += n as u64;
+    }
+    Ok((file_size, hasher.finalize()))
+}
+
+/// Whether a file extension can be carried as a hint in a share URI.
+fn is_valid_extension(extension: &str) -> bool {
+    !extension.is_empty()
+        && extension
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// The extension of a file's path that a share URI can carry as a hint, if any.
+#[must_use]
+pub fn share_extension(path: &Path) -> Option<&str> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| is_valid_extension(e))
+}
+
+/// Format a share URI of the form `fyeet://server:port/hash[:ext]`.
+#[must_use]
+pub fn format_share_uri(
+    server_address: &str,
+    server_port: NonZeroU16,
+    hash: &FileHash,
+    extension: Option<&str>,
+) -> String {
+    let hash_hex = hash.to_string();
+
+    // IPv6 addresses must be wrapped in brackets to separate them from the port.
+    let server = if server_address.contains(':') {
+        format!("[{server_address}]:{server_port}")
+    } else {
+        format!("{server_address}:{server_port}")
+    };
+    match extension.filter(|e| !e.is_empty()) {
+        Some(extension) => format!("{SHARE_URI_SCHEME}://{server}/{hash_hex}:{extension}"),
+        None => format!("{SHARE_URI_SCHEME}://{server}/{hash_hex}"),
+    }
+}
+
+/// Parse a share URI of the form `fyeet://server[:port]/hash[:ext]`.
+/// The default server port is used if none is given.
+/// # Errors
+/// Fails if the URI is malformed or the hash is not a valid SHA-256 or BLAKE3 hash in hexadecimal.
+pub fn parse_share_uri(uri: &str) -> Result<ShareUri, ShareUriError> {
+    let rest = uri
+        .trim()
+        .split_once("://")
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case(SHARE_URI_SCHEME))
+        .map(|(_, rest)| rest)
+        .ok_or(ShareUriError::InvalidScheme)?;
+    let (server, file) = rest
+        .trim_end_matches('/')
+        .rsplit_once('/')
+        .ok_or(ShareUriError::MissingHash)?;
+
+    // Separate the host from the port, taking care with bracketed IPv6 addresses.
+    let (server_address, port) = if let Some(bracketed) = server.strip_prefix('[') {
+        let (host, port) = bracketed
+            .split_once(']')
+            .ok_or(ShareUriError::MissingServer)?;
+        match port {
+            "" => (host, None),
+            port => (
+                host,
+                Some(port.strip_prefix(':').ok_or(ShareUriError::InvalidPort)?),
+            ),
+        }
+    } else {
+        match server.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (server, None),
+        }
+    };
+    if server_address.is_empty() {
+        return Err(ShareUriError::MissingServer);
+    }
+    let server_port = match port {
+        Some(port) => port.parse().map_err(|_| ShareUriError::InvalidPort)?,
+        None => DEFAULT_PORT,
+    };
+
+    // Parse the hash and the optional extension hint.
+    let (hash_hex, extension) = match file.split_once(':') {
+        Some((hash_hex, extension)) => {
+            if !is_valid_extension(extension) {
+                return Err(ShareUriError::InvalidExtension);
+            }
+            (hash_hex, Some(extension.to_owned()))
+        }
+        None => (file, None),
+    };
+    if hash_hex.is_empty() {
+        return Err(ShareUriError::MissingHash);
+    }
+    let hash = hash_hex.parse().map_err(|_| ShareUriError::InvalidHash)?;
+
+    Ok(ShareUri {
+        server_address: server_address.to_owned(),
+        server_port,
+        hash,
+        extension,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_HEX: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn share_uris_round_trip() {
+        let hash = HASH_HEX.parse::<FileHash>().unwrap();
+        let blake3 = format!("b3-{HASH_HEX}").parse::<FileHash>().unwrap();
+        assert_eq!(blake3.algorithm, HashAlgorithm::Blake3);
+        for (server_address, hash, extension) in [
+            ("example.com", hash, Some("tar.gz")),
+            ("2001:db8::1", hash, None),
+            ("203.0.113.7", blake3, Some("mp4")),
+        ] {
+            let uri = ShareUri {
+                server_address: server_address.to_owned(),
+                server_port: NonZeroU16::new(4000).unwrap(),
+                hash,
+                extension: extension.map(str::to_owned),
+            };
+            assert_eq!(uri.to_string().parse::<ShareUri>().unwrap(), uri);
+        }
+    }
+
+    #[test]
+    fn share_uris_parse_with_defaults() {
+        let uri = parse_share_uri(&format!("FYEET://[2001:db8::1]/{HASH_HEX}/")).unwrap();
+        assert_eq!(uri.server_address, "2001:db8::1");
+        assert_eq!(uri.server_port, DEFAULT_PORT);
+        assert_eq!(uri.hash.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(uri.extension, None);
+    }
+
+    #[test]
+    fn invalid_share_uris_are_rejected() {
+        for (uri, expected) in [
+            (format!("http://example.com/{HASH_HEX}"), "InvalidScheme"),
+            ("fyeet://example.com".to_owned(), "MissingHash"),
+            (format!("fyeet://:4000/{HASH_HEX}"), "MissingServer"),
+            (format!("fyeet://example.com:0/{HASH_HEX}"), "InvalidPort"),
+            (
+                format!("fyeet://[2001:db8::1]4000/{HASH_HEX}"),
+                "InvalidPort",
+            ),
+            ("fyeet://example.com/abc".to_owned(), "InvalidHash"),
+            (
+                format!("fyeet://example.com/{HASH_HEX}:"),
+                "InvalidExtension",
+            ),
+        ] {
+            let error = parse_share_uri(&uri).unwrap_err();
+            assert_eq!(format!("{error:?}"), expected, "{uri}");
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use crate::QUIC_TIMEOUT_SECONDS;
+
+/// Settings for the QUIC transport, for links where the defaults limit throughput,
+/// such as those with a high bandwidth-delay product. Settings left unset keep Quinn's defaults,
+/// or the timeouts this crate has always used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportTuning {
+    /// The most unacknowledged bytes a peer may send on a single stream.
+    pub stream_receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes a peer may send across every stream of a connection.
+    pub receive_window: Option<NonZeroU64>,
+
+    /// The most unacknowledged bytes to send across every stream of a connection.
+    pub send_window: Option<NonZeroU64>,
+
+    /// The size of the UDP datagrams to send before path MTU discovery finds a larger size, in bytes.
+    pub initial_mtu: Option<u16>,
+
+    /// The number of seconds of inactivity before a connection is closed.
+    pub idle_timeout: Option<NonZeroU64>,
+
+    /// The number of seconds between keep-alive packets on an otherwise idle connection.
+    pub keep_alive_interval: Option<NonZeroU64>,
+
+    /// The algorithm that paces how fast data is sent.
+    pub congestion_controller: CongestionController,
+}
+
+/// The congestion control algorithms QUIC connections can pace their sending with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionController {
+    /// Backs off sharply on packet loss. Quinn's default, and the safe choice on shared or congested links.
+    #[default]
+    Cubic,
+
+    /// Paces sending by the measured bandwidth and round-trip time instead of reacting to loss,
+    /// which keeps long-distance transfers over lossy links fast.
+    Bbr,
+}
+impl std::fmt::Display for CongestionController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cubic => write!(f, "cubic"),
+            Self::Bbr => write!(f, "bbr"),
+        }
+    }
+}
+impl std::str::FromStr for CongestionController {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cubic" => Ok(Self::Cubic),
+            "bbr" => Ok(Self::Bbr),
+            _ => Err(format!(
+                "Unknown congestion controller {s:?}, expected `cubic` or `bbr`"
+            )),
+        }
+    }
+}
+
+/// The tuning applied to transport configurations built from now on.
+static TUNING: RwLock<TransportTuning> = RwLock::new(TransportTuning {
+    stream_receive_window: None,
+    receive_window: None,
+    send_window: None,
+    initial_mtu: None,
+    idle_timeout: None,
+    keep_alive_interval: None,
+    congestion_controller: CongestionController::Cubic,
+});
+
+/// Set the tuning for the QUIC connections configured afterwards. Existing connections keep their settings.
+pub fn set_transport_tuning(tuning: TransportTuning) {
+    *TUNING.write().unwrap_or_else(PoisonError::into_inner) = tuning;
+}
+
+/// Set only the congestion controller for the QUIC connections configured afterwards, keeping the rest of the tuning.
+pub fn set_congestion_controller(congestion_controller: CongestionController) {
+    TUNING
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .congestion_controller = congestion_controller;
+}
+
+/// Get the tuning that new QUIC connections are configured with.
+#[must_use]
+pub fn transport_tuning() -> TransportTuning {
+    *TUNING.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Build a transport configuration from the current tuning.
+/// Without a tuned keep-alive interval, `default_keep_alive` is used, capped at half the idle timeout
+/// so that a shortened timeout doesn't close connections between keep-alive packets.
+pub(crate) fn transport_config(default_keep_alive: Duration) -> quinn::TransportConfig {
+    let tuning = transport_tuning();
+    let mut transport_config = quinn::TransportConfig::default();
+    if let Some(window) = tuning.stream_receive_window {
+        transport_config.stream_receive_window(var_int(window.get()));
+    }
+    if let Some(window) = tuning.receive_window {
+        transport_config.receive_window(var_int(window.get()));
+    }
+    if let Some(window) = tuning.send_window {
+        transport_config.send_window(window.get());
+    }
+    if let Some(mtu) = tuning.initial_mtu {
+        transport_config.initial_mtu(mtu);
+    }
+    match tuning.congestion_controller {
+        CongestionController::Cubic => {
+            transport_config
+                .congestion_controller_factory(Arc::new(quinn::congestion::CubicConfig::default()));
+        }
+        CongestionController::Bbr => {
+            transport_config
+                .congestion_controller_factory(Arc::new(quinn::congestion::BbrConfig::default()));
+        }
+    }
+
+    // Set custom keep alive policies.
+    let idle_timeout = Duration::from_secs(
+        tuning
+            .idle_timeout
+            .map_or(QUIC_TIMEOUT_SECONDS, NonZeroU64::get),
+    );
+    transport_config.max_idle_timeout(Some(
+        idle_timeout
+            .try_into()
+            .unwrap_or_else(|_| quinn::VarInt::MAX.into()),
+    ));
+    transport_config.keep_alive_interval(Some(tuning.keep_alive_interval.map_or_else(
+        || default_keep_alive.min(idle_timeout / 2),
+        |seconds| Duration::from_secs(seconds.get()),
+    )));
+    transport_config
+}
+
+/// Convert a size to a QUIC variable-length integer, saturating at the largest one.
+fn var_int(value: u64) -> quinn::VarInt {
+    quinn::VarInt::from_u64(value).unwrap_or(quinn::VarInt::MAX)
+}
@@ -0,0 +1,164 @@
+use std::{
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use quinn::udp::{RecvMeta, UdpState};
+
+/// The extension of the socket files endpoints bind to.
+const SOCKET_EXTENSION: &str = "sock";
+
+/// The first port tried when binding to any available port.
+const FIRST_DYNAMIC_PORT: u16 = 49152;
+
+/// Carries QUIC datagrams over Unix domain sockets instead of UDP, for local testing on machines without UDP.
+///
+/// Every endpoint is a socket file in a shared directory, named by the port of the loopback address it stands in for.
+/// Endpoints address each other with those loopback addresses, so the server introduces peers to each other as usual.
+#[derive(Debug)]
+pub struct UnixDatagramSocket {
+    socket: tokio::net::UnixDatagram,
+    directory: PathBuf,
+    local_addr: SocketAddr,
+}
+impl UnixDatagramSocket {
+    /// Bind to the socket file for a port in the directory, or to the first available port if none is given.
+    pub fn bind(directory: &Path, port: Option<u16>) -> io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+
+        let ports: Box<dyn Iterator<Item = u16>> = match port {
+            Some(port) => Box::new(std::iter::once(port)),
+            None => Box::new(FIRST_DYNAMIC_PORT..=u16::MAX),
+        };
+        let mut last_error = io::Error::from(io::ErrorKind::AddrInUse);
+        for port in ports {
+            match tokio::net::UnixDatagram::bind(socket_path(directory, port)) {
+                Ok(socket) => {
+                    return Ok(Self {
+                        socket,
+                        directory: directory.to_path_buf(),
+                        local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_error = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+}
+impl Drop for UnixDatagramSocket {
+    fn drop(&mut self) {
+        // Free the port for other endpoints. The socket file is not removed when the socket closes.
+        let _ = std::fs::remove_file(socket_path(&self.directory, self.local_addr.port()));
+    }
+}
+impl quinn::AsyncUdpSocket for UnixDatagramSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        cx: &mut Context,
+        transmits: &[quinn::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        for transmit in transmits {
+            let path = socket_path(&self.directory, transmit.destination.port());
+
+            // Send segmented transmits as separate datagrams, since there is no segmentation offload to do it for us.
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                match self.socket.poll_send_to(cx, segment, &path) {
+                    Poll::Ready(Ok(_)) => {}
+
+                    // Like UDP, datagrams to an endpoint that isn't listening are lost.
+                    Poll::Ready(Err(e))
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                        ) => {}
+                    Poll::Ready(Err(e)) => {
+                        return if sent == 0 {
+                            Poll::Ready(Err(e))
+                        } else {
+                            Poll::Ready(Ok(sent))
+                        }
+                    }
+                    Poll::Pending => {
+                        return if sent == 0 {
+                            Poll::Pending
+                        } else {
+                            Poll::Ready(Ok(sent))
+                        }
+                    }
+                }
+            }
+            sent += 1;
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let (Some(buf), Some(meta)) = (bufs.first_mut(), meta.first_mut()) else {
+            return Poll::Ready(Ok(0));
+        };
+        let mut read_buf = tokio::io::ReadBuf::new(buf);
+        let sender = std::task::ready!(self.socket.poll_recv_from(cx, &mut read_buf))?;
+        let len = read_buf.filled().len();
+
+        // Reply to the sender at the loopback address its socket file stands in for.
+        let port = sender
+            .as_pathname()
+            .and_then(Path::file_stem)
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default();
+        *meta = RecvMeta {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            len,
+            stride: len,
+            ecn: None,
+            dst_ip: None,
+        };
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+/// The socket file standing in for a loopback port.
+fn socket_path(directory: &Path, port: u16) -> PathBuf {
+    directory.join(format!("{port}.{SOCKET_EXTENSION}"))
+}
+
+/// Create a QUIC endpoint that communicates over Unix domain sockets in the directory instead of UDP.
+/// Binds to the given port, or the first available port if none is given.
+///
+/// # Errors
+/// Fails if the directory cannot be created or no socket file could be bound.
+pub fn bind_endpoint(
+    directory: &Path,
+    port: Option<u16>,
+    server_config: Option<quinn::ServerConfig>,
+) -> io::Result<quinn::Endpoint> {
+    let socket = UnixDatagramSocket::bind(directory, port)?;
+    quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+}